@@ -3,11 +3,23 @@ use std::{
     File,
     OpenOptions,
   },
-  os::unix::fs::FileExt
+  io::{
+    self,
+    ErrorKind,
+    IoSlice,
+    IoSliceMut,
+    Read,
+    Seek,
+    SeekFrom,
+    Write
+  },
+  os::unix::fs::FileExt,
+  rc::Rc
 };
 
 use crate::fs::{
   block::{
+    BlockDevice,
     BlockSectorT,
     BLOCK_SECTOR_SIZE
   },
@@ -38,14 +50,36 @@ impl AtaController {
   }
 
   ///Add a new device given a file path at the given CHANNEL
-  pub fn add_device(&mut self, file_name: &str, channel_num: usize) -> Result<(), ControllerError> {
+  pub fn add_device(&mut self, file_name: &str, channel_num: usize) -> Result<Rc<AtaDisk>, ControllerError> {
     self.channels[channel_num].add_device(channel_num, file_name)
   }
+
+  ///Adds a new device given a file path at the first free position on any channel
+  pub fn attach(&mut self, file_name: &str) -> Result<Rc<AtaDisk>, ControllerError> {
+    for channel_num in 0..CHANNEL_COUNT {
+      match self.add_device(file_name, channel_num) {
+        Err(ControllerError::ChannelOccupied(_)) => continue,
+        result => return result
+      }
+    }
+
+    Err(ControllerError::ControllerFull)
+  }
+
+  ///Removes the device named NAME, freeing its position
+  pub fn detach(&mut self, name: &str) -> Result<(), ControllerError> {
+    self.channels.iter_mut()
+      .flat_map(|channel| channel.devices.iter_mut())
+      .find(|device| device.as_ref().is_some_and(|disk| disk.name() == name))
+      .and_then(|device| device.take())
+      .map(|_| ())
+      .ok_or(ControllerError::NoSuchDevice(name.to_owned()))
+  }
 }
 
 struct Channel {
   name: String,
-  devices: [Option<AtaDisk>; DEVICE_COUNT]
+  devices: [Option<Rc<AtaDisk>>; DEVICE_COUNT]
 }
 
 impl Channel {
@@ -56,12 +90,12 @@ impl Channel {
     }
   }
 
-  pub fn add_device(&mut self, channel_num: usize, file_name: &str) -> Result<(), ControllerError> {
+  pub fn add_device(&mut self, channel_num: usize, file_name: &str) -> Result<Rc<AtaDisk>, ControllerError> {
     for (i, device) in self.devices.iter_mut().enumerate() {
-      if let None = device {
-        let new_device = AtaDisk::new(file_name, channel_num, i)?;
-        let _ = device.insert(new_device);
-        return Ok(());
+      if device.is_none() {
+        let new_device = Rc::new(AtaDisk::new(file_name, channel_num, i)?);
+        let _ = device.insert(new_device.clone());
+        return Ok(new_device);
       }
     }
 
@@ -84,7 +118,7 @@ pub struct AtaDisk {
 }
 
 impl AtaDisk {
-  fn new (file_name: &str, channel_num: usize, disk_num: usize) -> Result<Self, ControllerError> {
+  pub(crate) fn new (file_name: &str, channel_num: usize, disk_num: usize) -> Result<Self, ControllerError> {
     let disk_name = format!("hd{}", (channel_num * 2) + disk_num);
     let file_descriptor = OpenOptions::new().read(true).write(true).open(&file_name)?;
 
@@ -100,6 +134,16 @@ impl AtaDisk {
     )
   }
 
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  ///Returns the number of whole sectors in the backing file
+  pub fn size(&self) -> Result<BlockSectorT, ControllerError> {
+    let len = self.file_descriptor.metadata()?.len() / BLOCK_SECTOR_SIZE as u64;
+    Ok(len.min(BlockSectorT::MAX as u64) as BlockSectorT)
+  }
+
   ///Reads exactly SIZE OF BUFFER from SECTOR_NUM into BUFFER
   ///
   ///Functionally, we use this to read an entire sector of a time from the file
@@ -121,6 +165,90 @@ impl AtaDisk {
     self.file_descriptor.write_all_at(buffer, (sector * BLOCK_SECTOR_SIZE) as u64)?;
     Ok(())
   }
+
+  ///Reads the consecutive sectors from SECTOR into BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE,
+  ///in a single read of the file
+  pub fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+    self.file_descriptor.read_exact_at(buffer, sector as u64 * BLOCK_SECTOR_SIZE as u64)?;
+    Ok(())
+  }
+
+  ///Writes BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE, to the consecutive sectors from SECTOR
+  ///in a single write to the file
+  pub fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+    self.file_descriptor.write_all_at(buffer, sector as u64 * BLOCK_SECTOR_SIZE as u64)?;
+    Ok(())
+  }
+
+  ///Reads the consecutive sectors from SECTOR into each of BUFFERS in turn, scattering them with vectored reads of
+  ///the file instead of one read per buffer
+  pub fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), ControllerError> {
+    let mut file = &self.file_descriptor;
+    file.seek(SeekFrom::Start(sector as u64 * BLOCK_SECTOR_SIZE as u64))?;
+
+    let mut slices = buffers.iter_mut().map(|buffer| IoSliceMut::new(buffer)).collect::<Vec<_>>();
+    let mut rest = slices.as_mut_slice();
+    IoSliceMut::advance_slices(&mut rest, 0);
+
+    while !rest.is_empty() {
+      match file.read_vectored(rest) {
+        Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+        Ok(read) => IoSliceMut::advance_slices(&mut rest, read),
+        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into())
+      }
+    }
+    Ok(())
+  }
+
+  ///Writes each of BUFFERS in turn to the consecutive sectors from SECTOR, gathering them with vectored writes to
+  ///the file instead of one write per buffer
+  pub fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), ControllerError> {
+    let mut file = &self.file_descriptor;
+    file.seek(SeekFrom::Start(sector as u64 * BLOCK_SECTOR_SIZE as u64))?;
+
+    let mut slices = buffers.iter().map(|buffer| IoSlice::new(buffer)).collect::<Vec<_>>();
+    let mut rest = slices.as_mut_slice();
+    IoSlice::advance_slices(&mut rest, 0);
+
+    while !rest.is_empty() {
+      match file.write_vectored(rest) {
+        Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+        Ok(written) => IoSlice::advance_slices(&mut rest, written),
+        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into())
+      }
+    }
+    Ok(())
+  }
+}
+
+impl BlockDevice for AtaDisk {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    AtaDisk::read(self, sector, buffer)
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    AtaDisk::write(self, sector, buffer)
+  }
+
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    AtaDisk::read_sectors(self, sector, buffer)
+  }
+
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    AtaDisk::write_sectors(self, sector, buffer)
+  }
+
+  fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), ControllerError> {
+    AtaDisk::read_vectored(self, sector, buffers)
+  }
+
+  fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), ControllerError> {
+    AtaDisk::write_vectored(self, sector, buffers)
+  }
 }
 
 #[cfg(test)]
@@ -192,4 +320,24 @@ mod tests {
     assert_eq!(buffer, generated_buffer[1 * BLOCK_SECTOR_SIZE as usize..])
   }
 
+  #[test]
+  fn test_multi_sector_and_vectored() {
+    let (file, generated_buffer) = setup_test_file(4 * BLOCK_SECTOR_SIZE as usize);
+    let disk = AtaDisk::new(file.path().to_str().unwrap(), 0, 0).unwrap();
+    let mut buffer = [0u8; 3 * BLOCK_SECTOR_SIZE as usize];
+
+    disk.read_sectors(1, &mut buffer).unwrap();
+    assert_eq!(buffer, generated_buffer[BLOCK_SECTOR_SIZE as usize..]);
+    assert!(disk.read_sectors(2, &mut buffer).is_err());
+
+    let (first, second) = ([1u8; BLOCK_SECTOR_SIZE as usize], [2u8; 2 * BLOCK_SECTOR_SIZE as usize]);
+    disk.write_vectored(1, &[&first, &second]).unwrap();
+
+    let (mut head, mut tail) = ([0u8; 2 * BLOCK_SECTOR_SIZE as usize], [0u8; 2 * BLOCK_SECTOR_SIZE as usize]);
+    disk.read_vectored(0, &mut [&mut head, &mut tail]).unwrap();
+    assert_eq!(head[..BLOCK_SECTOR_SIZE as usize], generated_buffer[..BLOCK_SECTOR_SIZE as usize]);
+    assert_eq!(head[BLOCK_SECTOR_SIZE as usize..], first);
+    assert_eq!(tail, second);
+  }
+
 }
@@ -1,9 +1,10 @@
 use std::{
   cell::RefCell,
+  cmp::min,
   mem
 };
 
-use bytemuck::{cast_vec, pod_collect_to_vec};
+use bytemuck::pod_collect_to_vec;
 
 use crate::fs::{
   block::Block,
@@ -95,13 +96,13 @@ impl Bitmap {
   ///Returns a vector of the BITS in the BITMAP
   pub fn get_bits(&self) -> Vec<u8> {
     let len = self.get_file_size();
-    let bits = cast_vec::<ElementType, u8>(self.inner.borrow().clone());
+    let bits = pod_collect_to_vec::<ElementType, u8>(&self.inner.borrow());
 
     assert_eq!(len as usize, bits.len());
     bits
   }
 
-  fn get_file_size(&self) -> u32 {
+  pub fn get_file_size(&self) -> u32 {
     byte_cnt(self.bit_cnt)
   }
 
@@ -153,21 +154,21 @@ impl Bitmap {
 
   pub fn set_multiple(&self, start: u32, cnt: u32, val: bool) {
     assert!(start < self.bit_cnt);
-    assert!(start + cnt < self.bit_cnt);
+    assert!(start + cnt <= self.bit_cnt);
 
-    (start..start + cnt).for_each(|i| self.set(start + i, val));
+    (start..start + cnt).for_each(|i| self.set(i, val));
   }
 
   pub fn count(&self, start: u32, cnt: u32, val: bool) -> u32 {
     assert!(start < self.bit_cnt);
-    assert!(start + cnt < self.bit_cnt);
+    assert!(start + cnt <= self.bit_cnt);
 
     (start..start + cnt).fold(0, |acc, i| if self.test(i) == val { acc + 1 } else { acc })
   }
 
   fn contains(&self, start: u32, cnt: u32, val: bool) -> bool {
     assert!(start < self.bit_cnt);
-    assert!(start + cnt < self.bit_cnt);
+    assert!(start + cnt <= self.bit_cnt);
 
     (start..start + cnt).any(|i| self.test(i) == val)
   }
@@ -184,40 +185,84 @@ impl Bitmap {
     !self.contains(start, cnt, false)
   }
 
-  fn scan(&self, start: u32, cnt: u32, val: bool) -> Result<u32, BitmapError> {
+  ///Finds the first run of CNT consecutive bits set to VAL at or after START, returning the index of its first bit
+  ///
+  ///Elements without a single bit set to VAL are skipped whole
+  pub fn scan(&self, start: u32, cnt: u32, val: bool) -> Result<u32, BitmapError> {
     assert!(start <= self.bit_cnt);
 
-    if cnt > self.bit_cnt {
-      todo!("Error: not enough bits");
+    if cnt > self.bit_cnt - start {
+      return Err(BitmapError::NoContiguousAllocationFound(cnt))
     }
 
-    let last = self.bit_cnt - cnt;
-    return match (start..=last).find(|i| !self.contains(*i, cnt, !val)) {
-      Some(index) => {
-        Ok(index)
-      },
-      None => {
-        Err(BitmapError::NoContiguousAllocationFound(cnt))
+    let skip = if val { 0 } else { ElementType::MAX };
+    let mut run = 0u32;
+    let mut idx = start;
+
+    while idx < self.bit_cnt {
+      if idx.is_multiple_of(ELEMENT_BITS) && self.inner.borrow()[element_idx(idx) as usize] == skip {
+        run = 0;
+        idx += ELEMENT_BITS;
+        continue;
+      }
+
+      if self.test(idx) == val {
+        run += 1;
+
+        if run == cnt {
+          return Ok(idx + 1 - cnt)
+        }
+      } else {
+        run = 0;
       }
+      idx += 1;
+    }
+    Err(BitmapError::NoContiguousAllocationFound(cnt))
+  }
+
+  ///Returns the length of the run of bits set to VAL starting at START, counting at most MAX bits
+  pub fn run_len(&self, start: u32, max: u32, val: bool) -> u32 {
+    let end = min(start.saturating_add(max), self.bit_cnt);
+    (start..end).take_while(|&i| self.test(i) == val).count() as u32
+  }
+
+  ///Finds the longest run of bits set to VAL, returning the index of its first bit and its length
+  ///
+  ///The first of several equally long runs wins. Returns NONE if no bit is set to VAL
+  pub fn longest_run(&self, val: bool) -> Option<(u32, u32)> {
+    self.runs(val).into_iter().reduce(|best, run| if run.1 > best.1 { run } else { best })
+  }
+
+  ///Returns the index of the first bit and the length of every maximal run of bits set to VAL, in order
+  pub fn runs(&self, val: bool) -> Vec<(u32, u32)> {
+    let mut runs = Vec::new();
+    let mut idx = 0u32;
+
+    while let Ok(start) = self.scan(idx, 1, val) {
+      let len = self.run_len(start, self.bit_cnt - start, val);
+
+      runs.push((start, len));
+      idx = start + len;
     }
+    runs
   }
 
   pub fn scan_and_flip(&self, start: u32, cnt: u32, val: bool) -> Result<u32, BitmapError> {
     let idx = self.scan(start, cnt, val)?;
 
-    self.set_multiple(start, cnt, !val);
+    self.set_multiple(idx, cnt, !val);
     Ok(idx)
   }
 
   pub fn read_from_file(&self, block: &Block, cache: &Cache, file: &File) -> Result<(), BitmapError> {
     let len = self.get_file_size();
-    let mut buffer = Vec::<u8>::with_capacity(len as usize);
+    let mut buffer = vec![0u8; len as usize];
 
     let bytes_read = file.read_at(block, cache, &mut buffer, len, 0)?;
 
     assert_eq!(bytes_read, len);
 
-    let mut read_bits = cast_vec::<u8, ElementType>(buffer);
+    let mut read_bits = pod_collect_to_vec::<u8, ElementType>(&buffer);
     read_bits[(element_cnt(self.bit_cnt) - 1) as usize] &= last_mask(self);
     self.inner.replace(read_bits);
 
@@ -1,11 +1,15 @@
-use std::cell::Cell;
+use std::{
+  cell::{Cell, RefCell},
+  io::Write,
+  rc::Rc
+};
 
 use crate::fs::{
-  ata::AtaDisk,
   fserrors::{
     block_errors::BlockError,
     controller_errors::ControllerError
-  }
+  },
+  iostat::{Access, IoStats, Origin}
 };
 
 pub const BLOCK_SECTOR_SIZE: u32 = 512u32;
@@ -15,20 +19,25 @@ pub struct Block<'disk> {
   name: String,
   file_name: String,
   size: BlockSectorT,
-  write_cnt: Cell<u32>,
-  read_cnt: Cell<u32>,
-  hardware: HardwareOps<'disk>
+  stats: Cell<IoStats>,
+  ///What the sectors being accessed belong to. Accesses nobody tagged are to INODEs
+  origin: Cell<Origin>,
+  ///Where every sector access is logged, if anywhere
+  trace: RefCell<Option<Box<dyn Write>>>,
+  hardware: Box<dyn BlockDevice + 'disk>
 }
 
 impl<'disk> Block<'disk> {
-  pub fn new(name: String, file_name: String, size: BlockSectorT, hardware: HardwareOps<'disk>) -> Self {
+  ///Builds a BLOCK of SIZE sectors whose reads and writes go to HARDWARE
+  pub fn new(name: String, file_name: String, size: BlockSectorT, hardware: impl BlockDevice + 'disk) -> Self {
     Self {
       name,
       file_name,
       size,
-      write_cnt: Cell::new(0u32),
-      read_cnt: Cell::new(0u32),
-      hardware
+      stats: Cell::new(IoStats::default()),
+      origin: Cell::new(Origin::Inode),
+      trace: RefCell::new(None),
+      hardware: Box::new(hardware)
     }
   }
 
@@ -40,58 +49,273 @@ impl<'disk> Block<'disk> {
     &self.name
   }
 
-  fn check_sector(&self, sector: BlockSectorT) -> Result<(), BlockError> {
-    if sector >= self.size {
-      return Err(BlockError::SectorOutOfBounds(sector));
+  pub(crate) fn io_stats(&self) -> IoStats {
+    self.stats.get()
+  }
+
+  pub fn reset_io_stats(&self) {
+    self.stats.set(IoStats::default());
+  }
+
+  pub fn origin(&self) -> Origin {
+    self.origin.get()
+  }
+
+  ///Runs F with every sector it accesses counted and traced as ORIGIN
+  pub fn tagged<T>(&self, origin: Origin, f: impl FnOnce() -> T) -> T {
+    let outer = self.origin.replace(origin);
+    let result = f();
+    self.origin.set(outer);
+    result
+  }
+
+  ///Logs every sector access to TRACE from now on, or stops logging if it is NONE
+  pub fn set_trace(&self, trace: Option<Box<dyn Write>>) {
+    *self.trace.borrow_mut() = trace;
+  }
+
+  ///Counts ACCESS to SECTOR under the current origin and logs it if tracing
+  pub fn record(&self, access: Access, sector: BlockSectorT) {
+    let origin = self.origin.get();
+
+    let mut stats = self.stats.get();
+    stats.count(access, origin);
+    self.stats.set(stats);
+
+    //A trace is a diagnostic, so failing to write one does not fail the access
+    if let Some(trace) = self.trace.borrow_mut().as_mut() {
+      let _ = writeln!(trace, "{} {} {} {}", self.name, access, sector, origin);
     }
-    Ok(())
+  }
+
+  ///Checks that the LEN bytes from SECTOR, which must be whole sectors, lie on BLOCK, returning how many sectors they are
+  fn check_sectors(&self, sector: BlockSectorT, len: usize) -> Result<u32, BlockError> {
+    assert_eq!(len % BLOCK_SECTOR_SIZE as usize, 0, "Buffer does not hold whole sectors");
+
+    let cnt = (len / BLOCK_SECTOR_SIZE as usize) as u64;
+    if sector as u64 + cnt > self.size as u64 {
+      return Err(BlockError::SectorOutOfBounds(sector.max(self.size)));
+    }
+    Ok(cnt as u32)
+  }
+
+  ///Counts ACCESS to each of the CNT sectors from SECTOR
+  fn record_all(&self, access: Access, sector: BlockSectorT, cnt: u32) {
+    (sector..sector + cnt).for_each(|sector| self.record(access, sector));
   }
 
   pub fn read_to_buffer(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), BlockError> {
     assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
+    self.read_sectors(sector, buffer)
+  }
 
-    self.check_sector(sector)?;
-    self.hardware.read(sector, buffer)?;
+  pub fn write_from_buffer(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), BlockError> {
+    assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
+    self.write_sectors(sector, buffer)
+  }
 
-    let curr_read_cnt = self.read_cnt.get();
-    self.read_cnt.set(curr_read_cnt + 1);
+  ///Reads the consecutive sectors from SECTOR into BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE
+  pub fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), BlockError> {
+    let cnt = self.check_sectors(sector, buffer.len())?;
+    self.hardware.read_sectors(sector, buffer)?;
+    self.record_all(Access::Read, sector, cnt);
+    Ok(())
+  }
 
+  ///Writes BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE, to the consecutive sectors from SECTOR
+  pub fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), BlockError> {
+    let cnt = self.check_sectors(sector, buffer.len())?;
+    self.hardware.write_sectors(sector, buffer)?;
+    self.record_all(Access::Write, sector, cnt);
     Ok(())
   }
 
-  pub fn write_from_buffer(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), BlockError> {
-    assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
+  ///Reads the consecutive sectors from SECTOR into each of BUFFERS in turn, which all hold whole sectors
+  pub fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), BlockError> {
+    let cnt = self.check_sectors(sector, buffers.iter().map(|buffer| buffer.len()).sum())?;
+    self.hardware.read_vectored(sector, buffers)?;
+    self.record_all(Access::Read, sector, cnt);
+    Ok(())
+  }
 
-    self.check_sector(sector)?;
-    self.hardware.write(sector, buffer)?;
+  ///Writes each of BUFFERS in turn, which all hold whole sectors, to the consecutive sectors from SECTOR
+  pub fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), BlockError> {
+    let cnt = self.check_sectors(sector, buffers.iter().map(|buffer| buffer.len()).sum())?;
+    self.hardware.write_vectored(sector, buffers)?;
+    self.record_all(Access::Write, sector, cnt);
+    Ok(())
+  }
+}
+
+
+///Storage a BLOCK reads and writes whole sectors of
+///
+///Bounds are checked by the BLOCK, so a device only sees sectors below the size the BLOCK was given
+pub trait BlockDevice {
+  ///Reads SECTOR into BUFFER, which is exactly BLOCK_SECTOR_SIZE bytes
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError>;
+
+  ///Writes BUFFER, which is exactly BLOCK_SECTOR_SIZE bytes, to SECTOR
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError>;
+
+  ///Reads the consecutive sectors from SECTOR into BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE
+  ///
+  ///Devices that can transfer several sectors at once should override this, which reads them one by one
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    buffer.chunks_exact_mut(BLOCK_SECTOR_SIZE as usize)
+      .zip(sector..)
+      .try_for_each(|(chunk, sector)| self.read(sector, chunk))
+  }
+
+  ///Writes BUFFER, whose length is a multiple of BLOCK_SECTOR_SIZE, to the consecutive sectors from SECTOR
+  ///
+  ///Devices that can transfer several sectors at once should override this, which writes them one by one
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    buffer.chunks_exact(BLOCK_SECTOR_SIZE as usize)
+      .zip(sector..)
+      .try_for_each(|(chunk, sector)| self.write(sector, chunk))
+  }
 
-    let curr_write_cnt = self.write_cnt.get();
-    self.write_cnt.set(curr_write_cnt + 1);
+  ///Reads the consecutive sectors from SECTOR into each of BUFFERS in turn, which all hold whole sectors
+  fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), ControllerError> {
+    let mut next = sector;
+    for buffer in buffers {
+      self.read_sectors(next, buffer)?;
+      next += (buffer.len() / BLOCK_SECTOR_SIZE as usize) as BlockSectorT;
+    }
+    Ok(())
+  }
 
+  ///Writes each of BUFFERS in turn, which all hold whole sectors, to the consecutive sectors from SECTOR
+  fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), ControllerError> {
+    let mut next = sector;
+    for buffer in buffers {
+      self.write_sectors(next, buffer)?;
+      next += (buffer.len() / BLOCK_SECTOR_SIZE as usize) as BlockSectorT;
+    }
     Ok(())
   }
 }
 
+impl<D: BlockDevice + ?Sized> BlockDevice for &D {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    (**self).read(sector, buffer)
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    (**self).write(sector, buffer)
+  }
+
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    (**self).read_sectors(sector, buffer)
+  }
+
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    (**self).write_sectors(sector, buffer)
+  }
 
+  fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), ControllerError> {
+    (**self).read_vectored(sector, buffers)
+  }
+
+  fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), ControllerError> {
+    (**self).write_vectored(sector, buffers)
+  }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Rc<D> {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    (**self).read(sector, buffer)
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    (**self).write(sector, buffer)
+  }
+
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    (**self).read_sectors(sector, buffer)
+  }
 
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    (**self).write_sectors(sector, buffer)
+  }
+
+  fn read_vectored(&self, sector: BlockSectorT, buffers: &mut [&mut [u8]]) -> Result<(), ControllerError> {
+    (**self).read_vectored(sector, buffers)
+  }
+
+  fn write_vectored(&self, sector: BlockSectorT, buffers: &[&[u8]]) -> Result<(), ControllerError> {
+    (**self).write_vectored(sector, buffers)
+  }
+}
+
+type ReadFn<'disk> = Box<dyn Fn(BlockSectorT, &mut [u8]) -> Result<(), ControllerError> + 'disk>;
+type WriteFn<'disk> = Box<dyn Fn(BlockSectorT, &[u8]) -> Result<(), ControllerError> + 'disk>;
+
+///A BLOCKDEVICE made of a pair of functions, such as ones wrapping another device
+///
+///Both functions take any number of whole sectors, so runs of them reach the device in one call
 pub struct HardwareOps<'disk> {
-  read: Box<dyn Fn(BlockSectorT, &mut [u8]) -> Result<(), ControllerError> + 'disk>,
-  write: Box<dyn Fn(BlockSectorT, &[u8]) -> Result<(), ControllerError> + 'disk>
+  read: ReadFn<'disk>,
+  write: WriteFn<'disk>
 }
 
 impl<'disk> HardwareOps <'disk> {
-  pub fn new(disk: &'disk AtaDisk) -> Self {
+  ///Builds HARDWAREOPS sharing ownership of DISK, with sector 0 at sector START of DISK
+  pub fn from_disk<D: BlockDevice + ?Sized + 'disk>(disk: Rc<D>, start: BlockSectorT) -> Self {
+    let write_disk = disk.clone();
+
+    Self::from_sector_fns(
+      move |sector: BlockSectorT, buffer: &mut [u8]| disk.read_sectors(start + sector, buffer),
+      move |sector: BlockSectorT, buffer: &[u8]| write_disk.write_sectors(start + sector, buffer)
+    )
+  }
+
+  ///Builds HARDWAREOPS from arbitrary READ and WRITE functions of a single sector, such as ones wrapping another disk
+  pub fn from_fns(
+    read: impl Fn(BlockSectorT, &mut [u8]) -> Result<(), ControllerError> + 'disk,
+    write: impl Fn(BlockSectorT, &[u8]) -> Result<(), ControllerError> + 'disk
+  ) -> Self {
+    Self::from_sector_fns(
+      move |sector: BlockSectorT, buffer: &mut [u8]| {
+        buffer.chunks_exact_mut(BLOCK_SECTOR_SIZE as usize)
+          .zip(sector..)
+          .try_for_each(|(chunk, sector)| read(sector, chunk))
+      },
+      move |sector: BlockSectorT, buffer: &[u8]| {
+        buffer.chunks_exact(BLOCK_SECTOR_SIZE as usize)
+          .zip(sector..)
+          .try_for_each(|(chunk, sector)| write(sector, chunk))
+      }
+    )
+  }
+
+  ///Builds HARDWAREOPS from READ and WRITE functions taking the consecutive sectors from the one given
+  pub fn from_sector_fns(
+    read: impl Fn(BlockSectorT, &mut [u8]) -> Result<(), ControllerError> + 'disk,
+    write: impl Fn(BlockSectorT, &[u8]) -> Result<(), ControllerError> + 'disk
+  ) -> Self {
     Self {
-      read: Box::new(move |sector: BlockSectorT, buffer: &mut [u8]| disk.read(sector, buffer)),
-      write: Box::new(move |sector: BlockSectorT, buffer: &[u8]| disk.write(sector, buffer))
+      read: Box::new(read),
+      write: Box::new(write)
     }
   }
+}
+
+impl BlockDevice for HardwareOps<'_> {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    (self.read)(sector, buffer)
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    (self.write)(sector, buffer)
+  }
 
-  pub fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
     (self.read)(sector, buffer)
   }
 
-  pub fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
     (self.write)(sector, buffer)
   }
 }
@@ -1,49 +1,401 @@
 use std::{
-  array::from_fn,
   cell::{
     Cell,
     RefCell,
     RefMut
+  },
+  collections::HashMap,
+  time::{
+    Duration,
+    Instant
   }
 };
 
+use clap::ValueEnum;
+
 use crate::fs::{
   block::{
     Block,
     BlockSectorT,
     BLOCK_SECTOR_SIZE
   },
-  fserrors::cache_errors::CacheError
+  fserrors::cache_errors::CacheError,
+  iostat::{
+    Access,
+    Origin
+  },
+  journal::Journal,
+  policy::{
+    CachePolicy,
+    Policy
+  }
 };
 
-const CACHE_SIZE: usize = 64usize;
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+///When writes through the CACHE reach disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheMode {
+  ///Once they are evicted or written behind
+  #[default]
+  WriteBack,
+  ///Straight away, keeping the entry clean
+  WriteThrough,
+  ///Whole sectors of file data bypass the CACHE entirely, as if every file was opened for direct I/O
+  Direct
+}
+
+///How to size the CACHE of a mounted FILESYSTEM and which entries it evicts
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheOptions {
+  ///Number of sectors the CACHE holds, the size recorded in the SUPERBLOCK if not given
+  pub size: Option<u32>,
+  pub policy: CachePolicy,
+  pub mode: CacheMode,
+  pub write_behind: WriteBehind
+}
+
+///When the CACHE writes dirty entries back without waiting for them to be evicted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteBehind {
+  ///Number of dirty entries that starts a write-behind, half the CACHE if not given
+  pub dirty_limit: Option<u32>,
+  ///Time since the last write-behind after which the next access starts one, never if not given
+  pub interval: Option<Duration>
+}
+
+impl Default for WriteBehind {
+  fn default() -> Self {
+    Self {
+      dirty_limit: None,
+      interval: Some(DEFAULT_FLUSH_INTERVAL)
+    }
+  }
+}
+
+///Counters of a CACHE since it was built or they were last reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct CacheStats {
+  pub policy: CachePolicy,
+  pub mode: CacheMode,
+  pub capacity: usize,
+  ///Number of entries holding a sector
+  pub filled: usize,
+  ///Number of entries not yet written back
+  pub dirty: usize,
+  pub hits: u64,
+  pub misses: u64,
+  ///Number of sectors read ahead of being needed
+  pub prefetched: u64,
+  ///Number of write-behinds started, by the dirty limit or the interval
+  pub write_behinds: u64,
+  ///Number of sectors read or written bypassing the cache
+  pub direct: u64
+}
 
+impl CacheStats {
+  ///Returns the percentage of accesses that were hits, 0 if there were none
+  pub fn hit_rate(&self) -> f64 {
+    match self.hits + self.misses {
+      0 => 0.0,
+      total => self.hits as f64 * 100.0 / total as f64
+    }
+  }
+}
 
-///A simple write-back cache with CACHE_SIZE (64) entries
+///A simple write-back cache with a fixed number of entries, evicted as its POLICY decides
+///
+///Dirty entries are also written behind, once there are too many of them or too long has passed since the last time.
+///There are no threads to do so in the background, so the access that notices it does.
+///Metadata written inside a transaction goes to the JOURNAL instead, and reaches disk when it commits
 pub(crate) struct Cache {
-  inner: [RefCell<CacheEntry>; CACHE_SIZE],
-  clock: Cell<usize>
+  inner: Box<[RefCell<CacheEntry>]>,
+  ///Slot of every cached sector
+  index: RefCell<HashMap<BlockSectorT, usize>>,
+  ///Slots never filled, or whose sector could not be read in
+  free: RefCell<Vec<usize>>,
+  policy: RefCell<Box<dyn Policy>>,
+  kind: CachePolicy,
+  mode: CacheMode,
+  hits: Cell<u64>,
+  misses: Cell<u64>,
+  prefetched: Cell<u64>,
+  ///Number of dirty entries
+  dirty: Cell<usize>,
+  dirty_limit: usize,
+  flush_interval: Option<Duration>,
+  last_flush: Cell<Instant>,
+  write_behinds: Cell<u64>,
+  direct: Cell<u64>,
+  journal: Journal
 }
 
 impl Cache {
-  pub fn new() -> Self {
+  ///Builds an empty CACHE holding up to CAPACITY sectors, which evicts them as POLICY decides and writes them
+  ///to disk as MODE and WRITE_BEHIND decide
+  pub fn new(capacity: usize, policy: CachePolicy, mode: CacheMode, write_behind: WriteBehind) -> Self {
     Self {
-      inner: from_fn::<_, CACHE_SIZE, _>(|_| RefCell::new(CacheEntry::new())),
-      clock:Cell::new(0usize)
+      inner: (0..capacity).map(|_| RefCell::new(CacheEntry::new())).collect(),
+      index: RefCell::new(HashMap::with_capacity(capacity)),
+      free: RefCell::new((0..capacity).rev().collect()),
+      policy: RefCell::new(policy.build(capacity)),
+      kind: policy,
+      mode,
+      hits: Cell::new(0u64),
+      misses: Cell::new(0u64),
+      prefetched: Cell::new(0u64),
+      dirty: Cell::new(0usize),
+      dirty_limit: match write_behind.dirty_limit {
+        Some(limit) => limit as usize,
+        None => (capacity / 2).max(1)
+      },
+      flush_interval: write_behind.interval,
+      last_flush: Cell::new(Instant::now()),
+      write_behinds: Cell::new(0u64),
+      direct: Cell::new(0u64),
+      journal: Journal::new()
+    }
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      policy: self.kind,
+      mode: self.mode,
+      capacity: self.inner.len(),
+      filled: self.index.borrow().len(),
+      dirty: self.dirty.get(),
+      hits: self.hits.get(),
+      misses: self.misses.get(),
+      prefetched: self.prefetched.get(),
+      write_behinds: self.write_behinds.get(),
+      direct: self.direct.get()
+    }
+  }
+
+  ///Zeroes the hit, miss, read-ahead, write-behind and direct I/O counters
+  pub fn reset_stats(&self) {
+    self.hits.set(0);
+    self.misses.set(0);
+    self.prefetched.set(0);
+    self.write_behinds.set(0);
+    self.direct.set(0);
+  }
+
+  pub fn mode(&self) -> CacheMode {
+    self.mode
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.inner.len()
+  }
+
+  ///Counts an access to SECTOR as a hit if HIT, otherwise as a miss, tracing hits on BLOCK
+  ///
+  ///Misses reach BLOCK, which traces them itself
+  fn count(&self, block: &Block, sector: BlockSectorT, hit: bool) {
+    let counter = match hit {
+      true => &self.hits,
+      false => &self.misses
+    };
+    counter.set(counter.get() + 1);
+
+    if hit {
+      block.record(Access::Hit, sector);
+    }
+  }
+
+  ///Opens the journal on BLOCK, replaying any transaction committed before a crash
+  ///
+  ///Returns the number of sectors replayed
+  pub fn open_journal(&self, block: &Block) -> Result<u32, CacheError> {
+    self.journal.open(block)
+  }
+
+  ///Returns the number of sectors the journal region takes up, 0 if BLOCK has none
+  pub fn journal_size(&self) -> u32 {
+    self.journal.size()
+  }
+
+  ///Returns the number of sectors a single transaction can log, 0 if BLOCK has no journal
+  pub fn journal_capacity(&self) -> u32 {
+    self.journal.capacity() as u32
+  }
+
+  ///Begins a transaction, which may be nested in another one
+  pub fn begin(&self) {
+    self.journal.begin()
+  }
+
+  ///Ends a transaction, committing everything logged since the outermost one began
+  pub fn commit(&self, block: &Block) -> Result<(), CacheError> {
+    if self.journal.end() {
+      if self.journal.is_aborted() {
+        return Err(CacheError::JournalAborted())
+      }
+      self.commit_pending(block)?;
+    }
+    Ok(())
+  }
+
+  ///Commits everything the current transaction logged so far, without ending it
+  ///
+  ///A crash after this leaves the transaction half done on disk, so it is only called where that can at worst leak sectors
+  pub fn checkpoint(&self, block: &Block) -> Result<(), CacheError> {
+    if self.journal.is_aborted() {
+      return Err(CacheError::JournalAborted())
+    }
+    self.commit_pending(block)
+  }
+
+  ///Returns how many more FREEMAP sectors the current transaction can log before it has to be committed in pieces
+  pub fn freemap_room(&self) -> usize {
+    self.journal.freemap_room()
+  }
+
+  ///Tracks the CNT sectors from SECTOR as allocated, which the current transaction writes without logging
+  pub fn allocated(&self, sector: BlockSectorT, cnt: u32) {
+    self.journal.record_allocated(sector, cnt)
+  }
+
+  ///Tracks the CNT sectors from SECTOR as released
+  pub fn released(&self, sector: BlockSectorT, cnt: u32) {
+    self.journal.record_released(sector, cnt)
+  }
+
+  ///Aborts the current transaction, dropping the cached copies of every sector it wrote
+  ///
+  ///Those are read from BLOCK again, as last committed. Nothing is written until the journal is opened again
+  fn abort(&self) {
+    for sector in self.journal.abort() {
+      if let Some((slot, mut entry)) = self.lookup(sector) {
+        if entry.dirty {
+          self.dirty.set(self.dirty.get() - 1);
+        }
+        entry.dirty = false;
+        entry.occupied = false;
+        self.index.borrow_mut().remove(&sector);
+        self.policy.borrow_mut().remove(slot);
+        self.free.borrow_mut().push(slot);
+      }
     }
   }
 
+  ///Flushes the data sectors of the current transaction, then commits its metadata to the journal
+  fn commit_pending(&self, block: &Block) -> Result<(), CacheError> {
+    for sector in self.journal.take_data() {
+      if let Some((_, mut entry)) = self.lookup(sector) {
+        self.flush_entry(block, &mut entry)?;
+      }
+    }
+    self.journal.commit(block)
+  }
+
   ///Closes the cache, flushing all filled entries back to disk
   ///
   ///You MUST call this before dropping CACHE
   pub fn close(&self, block: &Block) -> Result<(), CacheError> {
+    self.flush(block)
+  }
+
+  ///Writes every dirty entry back to BLOCK, keeping them cached
+  pub fn flush(&self, block: &Block) -> Result<(), CacheError> {
     self.inner.iter()
       .filter(|entry| {
         entry.borrow().occupied
       })
       .try_for_each(|entry| {
-        entry.borrow_mut().flush(block)
-      })
+        self.flush_entry(block, &mut entry.borrow_mut())
+      })?;
+
+    self.last_flush.set(Instant::now());
+    Ok(())
+  }
+
+  ///Writes ENTRY back to BLOCK if it is dirty, keeping count of the dirty entries left
+  fn flush_entry(&self, block: &Block, entry: &mut CacheEntry) -> Result<(), CacheError> {
+    let dirty = entry.dirty;
+    entry.flush(block)?;
+
+    if dirty {
+      self.dirty.set(self.dirty.get() - 1);
+    }
+    Ok(())
+  }
+
+  ///Writes every dirty entry back to BLOCK if there are too many, or the last time was too long ago
+  fn write_behind(&self, block: &Block) -> Result<(), CacheError> {
+    let due = self.flush_interval.is_some_and(|interval| self.last_flush.get().elapsed() >= interval);
+
+    if self.dirty.get() >= self.dirty_limit || due {
+      self.write_behinds.set(self.write_behinds.get() + 1);
+      self.flush(block)?;
+    }
+    Ok(())
+  }
+
+  ///Returns how many of the CNT sectors from SECTOR there are before the first one logged or cached
+  fn missing(&self, sector: BlockSectorT, cnt: u32) -> u32 {
+    let index = self.index.borrow();
+    (sector..sector + cnt)
+      .take_while(|sector| !self.journal.is_logged(*sector) && !index.contains_key(sector))
+      .count() as u32
+  }
+
+  ///Reads the CNT sectors from SECTOR on BLOCK into the cache ahead of them being needed, skipping those there already
+  ///
+  ///Each run of sectors not there is read at once. Neither hits nor misses are counted
+  pub fn read_ahead(&self, block: &Block, sector: BlockSectorT, cnt: u32) -> Result<(), CacheError> {
+    let end = sector + cnt;
+    let mut next = sector;
+
+    while next < end {
+      match self.missing(next, end - next) {
+        0 => next += 1,
+        run => {
+          let mut images = vec![0u8; run as usize * BLOCK_SECTOR_SIZE as usize];
+          self.fill_run(block, next, &mut images)?;
+          self.prefetched.set(self.prefetched.get() + run as u64);
+          next += run;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  ///Reads SECTOR on BLOCK into the free ENTRY at SLOT
+  ///
+  ///The slot stays free if the sector could not be read into it
+  fn fill(&self, block: &Block, slot: usize, entry: &mut CacheEntry, sector: BlockSectorT) -> Result<(), CacheError> {
+    if let Err(e) = block.read_to_buffer(sector, &mut entry.inner) {
+      self.free.borrow_mut().push(slot);
+      return Err(e.into())
+    }
+    self.occupy(block, slot, entry, sector);
+    Ok(())
+  }
+
+  ///Reads the consecutive sectors from SECTOR on BLOCK into IMAGES at once, then caches each of them
+  ///
+  ///None of the sectors may be logged or cached already
+  fn fill_run(&self, block: &Block, sector: BlockSectorT, images: &mut [u8]) -> Result<(), CacheError> {
+    block.read_sectors(sector, images)?;
+
+    for (image, sector) in images.chunks_exact(BLOCK_SECTOR_SIZE as usize).zip(sector..) {
+      let (slot, mut entry) = self.evict(block, sector)?;
+      entry.inner.copy_from_slice(image);
+      self.occupy(block, slot, &mut entry, sector);
+    }
+    Ok(())
+  }
+
+  ///Tags the free ENTRY at SLOT, whose content is already in place, as a clean copy of SECTOR
+  fn occupy(&self, block: &Block, slot: usize, entry: &mut CacheEntry, sector: BlockSectorT) {
+    entry.disk_sector = Some(sector);
+    entry.origin = block.origin();
+    entry.dirty = false;
+    entry.occupied = true;
+    self.index.borrow_mut().insert(sector, slot);
+    self.policy.borrow_mut().insert(slot, sector);
   }
 
   ///Reads a cache entry with tag SECTOR on BLOCK to BUFFER
@@ -52,94 +404,236 @@ impl Cache {
   pub fn read_to_buffer(&self, block: &Block, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), CacheError> {
     assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
 
-    let mut entry;
-    let slot = self.lookup(sector);
+    if self.journal.lookup(sector, buffer) {
+      self.count(block, sector, true);
+      return Ok(())
+    }
 
-    if let None = slot {
-      entry = self.evict(block)?;
+    let mut entry;
+    let found = self.lookup(sector);
+    self.count(block, sector, found.is_some());
 
-      entry.disk_sector = Some(sector);
-      entry.dirty = false;
-      entry.occupied = true;
-      block.read_to_buffer(sector, &mut entry.inner)?;
+    if let Some((slot, hit)) = found {
+      self.policy.borrow_mut().touch(slot);
+      entry = hit;
     } else {
-      entry = slot.unwrap();
+      let slot;
+      (slot, entry) = self.evict(block, sector)?;
+      self.fill(block, slot, &mut entry, sector)?;
     }
 
-    entry.access = true;
     buffer.copy_from_slice(entry.inner.as_slice());
+    drop(entry);
 
-    Ok(())
+    self.write_behind(block)
   }
 
-  ///Writes a cache entry with tag SECTOR on BLOCK from BUFFER
+  ///Reads the consecutive sectors from SECTOR on BLOCK to BUFFER through the cache
+  ///
+  ///Each run of sectors missed is read from BLOCK at once.
+  ///Caller must guarantee the length of BUFFER to be a multiple of BLOCK_SECTOR_SIZE
+  pub fn read_sectors(&self, block: &Block, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), CacheError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+
+    let cnt = (buffer.len() / BLOCK_SECTOR_SIZE as usize) as u32;
+    let mut i = 0u32;
+
+    while i < cnt {
+      let rest = &mut buffer[i as usize * BLOCK_SECTOR_SIZE as usize..];
+
+      match self.missing(sector + i, cnt - i) {
+        0 => {
+          self.read_to_buffer(block, sector + i, &mut rest[..BLOCK_SECTOR_SIZE as usize])?;
+          i += 1;
+        },
+        run => {
+          self.fill_run(block, sector + i, &mut rest[..run as usize * BLOCK_SECTOR_SIZE as usize])?;
+          self.misses.set(self.misses.get() + run as u64);
+          i += run;
+        }
+      }
+    }
+
+    self.write_behind(block)
+  }
+
+  ///Writes the metadata sector SECTOR on BLOCK from BUFFER, logging it if a transaction is open
   ///
   ///Caller must gurantee BUFFER to be of length BLOCK_SECTOR_SIZE
   pub fn write_from_buffer(&self, block: &Block, sector: BlockSectorT, buffer: &[u8]) -> Result<(), CacheError> {
     assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
 
-    let mut entry;
-    let slot = self.lookup(sector);
+    if !self.journal.in_transaction() {
+      return self.write_cached(block, sector, buffer)
+    }
 
-    if let None = slot {
-      entry = self.evict(block)?;
+    if self.journal.is_aborted() {
+      return Err(CacheError::JournalAborted())
+    }
 
-      entry.disk_sector = Some(sector);
-      entry.dirty = false;
-      entry.occupied = true;
-      block.read_to_buffer(sector, &mut entry.inner)?;
-    } else {
-      entry = slot.unwrap();
+    //Nothing committed points at a sector allocated since, so it is written like data and needs no room in the journal
+    if self.journal.is_fresh(sector) {
+      self.journal.record_data(sector);
+      return self.write_cached(block, sector, buffer)
     }
 
-    entry.access = true;
-    entry.dirty = true;
-    entry.inner.copy_from_slice(buffer);
+    //Committing part of a transaction anywhere but at a FREEMAP write could leave it half done on disk, so one still
+    //too large for the journal is aborted
+    if self.journal.is_full(sector) {
+      self.abort();
+      return Err(CacheError::JournalFull(self.journal.capacity() as u32))
+    }
+    self.journal.record(sector, buffer, block.origin());
 
+    //The logged image is the newest content, the home sector is written at commit
+    if let Some((slot, mut entry)) = self.lookup(sector) {
+      self.policy.borrow_mut().touch(slot);
+      if entry.dirty {
+        self.dirty.set(self.dirty.get() - 1);
+      }
+      entry.dirty = false;
+      entry.inner.copy_from_slice(buffer);
+    }
     Ok(())
   }
 
-  ///Evict a cache entry back to BLOCK, and returns a mutable reference to that entry for immediate use
-  fn evict(&self, block: &Block) -> Result<RefMut<CacheEntry>, CacheError> {
-    let mut entry;
+  ///Writes the data sector SECTOR on BLOCK from BUFFER, which is never logged
+  ///
+  ///Caller must gurantee BUFFER to be of length BLOCK_SECTOR_SIZE
+  pub fn write_data(&self, block: &Block, sector: BlockSectorT, buffer: &[u8]) -> Result<(), CacheError> {
+    assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
 
-    loop {
-      let clock = self.clock.get();
-      entry = self.inner[clock].borrow_mut();
+    if self.journal.in_transaction() {
+      self.journal.record_data(sector);
+    }
+    self.write_cached(block, sector, buffer)
+  }
+
+  ///Reads the consecutive data sectors from SECTOR on BLOCK straight into BUFFER, without caching them
+  ///
+  ///A copy logged or cached is newer than the one on disk, so it is read instead. Each run of sectors in neither is read
+  ///from BLOCK at once. Caller must guarantee the length of BUFFER to be a multiple of BLOCK_SECTOR_SIZE
+  pub fn read_direct(&self, block: &Block, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), CacheError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
 
-      if !entry.occupied {
-        return Ok(entry);
+    let cnt = (buffer.len() / BLOCK_SECTOR_SIZE as usize) as u32;
+    let mut i = 0u32;
+
+    while i < cnt {
+      let rest = &mut buffer[i as usize * BLOCK_SECTOR_SIZE as usize..];
+
+      match self.missing(sector + i, cnt - i) {
+        0 => {
+          let copy = &mut rest[..BLOCK_SECTOR_SIZE as usize];
+          if !self.journal.lookup(sector + i, copy) {
+            if let Some((_, entry)) = self.lookup(sector + i) {
+              copy.copy_from_slice(&entry.inner);
+            }
+          }
+          i += 1;
+        },
+        run => {
+          block.read_sectors(sector + i, &mut rest[..run as usize * BLOCK_SECTOR_SIZE as usize])?;
+          self.direct.set(self.direct.get() + run as u64);
+          i += run;
+        }
       }
+    }
+    Ok(())
+  }
 
-      if entry.access {
-        entry.access = false;
-      } else {
-        break;
+  ///Writes the consecutive data sectors from SECTOR on BLOCK straight from BUFFER at once, without caching them
+  ///
+  ///Cached copies are updated to match, so they are never written back over BUFFER.
+  ///Caller must guarantee the length of BUFFER to be a multiple of BLOCK_SECTOR_SIZE
+  pub fn write_direct(&self, block: &Block, sector: BlockSectorT, buffer: &[u8]) -> Result<(), CacheError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+
+    if self.journal.is_aborted() {
+      return Err(CacheError::JournalAborted())
+    }
+
+    let sectors = sector..sector + (buffer.len() / BLOCK_SECTOR_SIZE as usize) as u32;
+    if self.journal.in_transaction() {
+      sectors.clone().for_each(|sector| self.journal.record_data(sector));
+    }
+    block.write_sectors(sector, buffer)?;
+    self.direct.set(self.direct.get() + sectors.len() as u64);
+
+    for (image, sector) in buffer.chunks_exact(BLOCK_SECTOR_SIZE as usize).zip(sectors) {
+      if let Some((_, mut entry)) = self.lookup(sector) {
+        if entry.dirty {
+          self.dirty.set(self.dirty.get() - 1);
+        }
+        entry.dirty = false;
+        entry.inner.copy_from_slice(image);
       }
+    }
+    Ok(())
+  }
 
-      self.clock.set((clock + 1) % CACHE_SIZE);
+  ///Writes a cache entry with tag SECTOR on BLOCK from BUFFER, and through to BLOCK in CACHEMODE::WRITETHROUGH
+  ///
+  ///The whole sector is overwritten, so a sector not yet cached is never read first
+  fn write_cached(&self, block: &Block, sector: BlockSectorT, buffer: &[u8]) -> Result<(), CacheError> {
+    if self.journal.is_aborted() {
+      return Err(CacheError::JournalAborted())
     }
 
-    let clock = self.clock.get();
-    entry = self.inner[clock].borrow_mut();
+    let mut entry;
+    let found = self.lookup(sector);
+    self.count(block, sector, found.is_some());
+
+    if let Some((slot, hit)) = found {
+      self.policy.borrow_mut().touch(slot);
+      entry = hit;
+    } else {
+      let slot;
+      (slot, entry) = self.evict(block, sector)?;
+      self.occupy(block, slot, &mut entry, sector);
+    }
 
-    if entry.occupied {
-      entry.flush(block)?;
+    if !entry.dirty {
+      self.dirty.set(self.dirty.get() + 1);
     }
+    entry.dirty = true;
+    entry.origin = block.origin();
+    entry.inner.copy_from_slice(buffer);
+
+    //The entry stays dirty if it could not be written through, so it is retried later
+    if self.mode == CacheMode::WriteThrough {
+      self.flush_entry(block, &mut entry)?;
+    }
+    drop(entry);
+
+    self.write_behind(block)
+  }
+
+  ///Frees a cache entry for SECTOR, writing the one the POLICY picks back to BLOCK if none is free
+  ///
+  ///Returns the slot and a mutable reference to its entry for immediate use
+  fn evict(&self, block: &Block, sector: BlockSectorT) -> Result<(usize, RefMut<'_, CacheEntry>), CacheError> {
+    if let Some(slot) = self.free.borrow_mut().pop() {
+      return Ok((slot, self.inner[slot].borrow_mut()))
+    }
+
+    let mut policy = self.policy.borrow_mut();
+    let slot = policy.victim(sector);
+    let mut entry = self.inner[slot].borrow_mut();
+
+    self.flush_entry(block, &mut entry)?;
     entry.occupied = false;
-    Ok(entry)
+    if let Some(evicted) = entry.disk_sector {
+      self.index.borrow_mut().remove(&evicted);
+    }
+    policy.remove(slot);
+    Ok((slot, entry))
   }
 
-  ///Searches for a cache entry with tag SECTOR
-  fn lookup(&self, sector: BlockSectorT) -> Option<RefMut<CacheEntry>> {
-    self.inner.iter().find_map(|entry| {
-      let entry = entry.borrow_mut();
-      if entry.disk_sector == Some(sector) && entry.occupied {
-        Some(entry)
-      } else {
-        None
-      }
-    })
+  ///Looks up the cache entry with tag SECTOR in the index, returning its slot along with it
+  fn lookup(&self, sector: BlockSectorT) -> Option<(usize, RefMut<'_, CacheEntry>)> {
+    let slot = *self.index.borrow().get(&sector)?;
+    Some((slot, self.inner[slot].borrow_mut()))
   }
 }
 
@@ -147,7 +641,8 @@ impl Cache {
 struct CacheEntry {
   inner: [u8; BLOCK_SECTOR_SIZE as usize],
   disk_sector: Option<BlockSectorT>,
-  access: bool,
+  ///What the sector belonged to when last read or written, which a write-back is counted as
+  origin: Origin,
   dirty: bool,
   occupied: bool
 }
@@ -157,7 +652,7 @@ impl CacheEntry {
     Self {
       inner: [0u8; BLOCK_SECTOR_SIZE as usize],
       disk_sector: None,
-      access: false,
+      origin: Origin::Inode,
       dirty: false,
       occupied: false
     }
@@ -172,7 +667,7 @@ impl CacheEntry {
     if self.dirty {
       match self.disk_sector {
         Some(disk_sector) => {
-          block.write_from_buffer(disk_sector, &self.inner)?;
+          block.tagged(self.origin, || block.write_from_buffer(disk_sector, &self.inner))?;
           self.dirty = false;
         },
         None => {
@@ -183,3 +678,234 @@ impl CacheEntry {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{rc::Rc, time::Instant};
+
+  use super::*;
+  use crate::fs::{
+    block::{BlockDevice, HardwareOps},
+    device::RamDisk,
+    file_sys::{tests::setup_file_sys, FileSystem},
+    fserrors::{superblock_errors::SuperblockError, FSErrors}
+  };
+
+  const SECTORS: u32 = 64;
+  ///Leaves dirty entries until they are evicted
+  const NO_WRITE_BEHIND: WriteBehind = WriteBehind { dirty_limit: Some(u32::MAX), interval: None };
+
+  #[test]
+  fn test_every_policy_writes_back() {
+    for policy in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::TwoQ, CachePolicy::Arc] {
+      let block = Block::new("hd0".to_owned(), String::new(), SECTORS, RamDisk::new(SECTORS));
+      let cache = Cache::new(8, policy, CacheMode::WriteBack, NO_WRITE_BEHIND);
+      let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+      //Four times as many sectors as the cache holds, so most are evicted dirty
+      for sector in 0..32 {
+        cache.write_data(&block, sector, &[sector as u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+      }
+      for sector in (0..32).rev() {
+        cache.read_to_buffer(&block, sector, &mut buffer).unwrap();
+        assert_eq!(buffer, [sector as u8; BLOCK_SECTOR_SIZE as usize], "{:?}", policy);
+      }
+
+      let stats = cache.stats();
+      assert_eq!((stats.capacity, stats.filled), (8, 8));
+      assert_eq!(stats.hits + stats.misses, 64);
+      assert!(stats.hits >= 8, "{:?}", policy);
+
+      cache.reset_stats();
+      assert_eq!(cache.stats().hits + cache.stats().misses, 0);
+      cache.close(&block).unwrap();
+      assert_eq!(cache.stats().dirty, 0);
+    }
+  }
+
+  #[test]
+  fn test_size_set_at_mount() {
+    let mut file_sys = setup_file_sys(256);
+    file_sys.close().unwrap();
+
+    let block = file_sys.block;
+    let options = CacheOptions { size: Some(16), policy: CachePolicy::Arc, ..CacheOptions::default() };
+    let mut file_sys = FileSystem::new(block, None, options).unwrap();
+    assert_eq!(file_sys.cache.stats().capacity, 16);
+    assert_eq!(file_sys.cache.stats().policy, CachePolicy::Arc);
+    file_sys.close().unwrap();
+
+    let block = file_sys.block;
+    let options = CacheOptions { size: Some(4), ..CacheOptions::default() };
+    assert!(matches!(FileSystem::new(block, None, options), Err(FSErrors::SuperblockError(SuperblockError::InvalidOption(_, 4, _)))));
+  }
+
+  #[test]
+  fn test_write_behind() {
+    let block = Block::new("hd0".to_owned(), String::new(), SECTORS, RamDisk::new(SECTORS));
+    let write_behind = WriteBehind { dirty_limit: Some(4), interval: None };
+    let cache = Cache::new(16, CachePolicy::Lru, CacheMode::WriteBack, write_behind);
+
+    for sector in 0..3 {
+      cache.write_data(&block, sector, &[1u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    }
+    assert_eq!((cache.stats().dirty, cache.stats().write_behinds), (3, 0));
+
+    //The fourth dirty entry reaches the limit, and all of them are written back without being evicted
+    cache.write_data(&block, 3, &[1u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    assert_eq!((cache.stats().dirty, cache.stats().write_behinds), (0, 1));
+    assert_eq!(cache.stats().filled, 4);
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    block.read_to_buffer(3, &mut buffer).unwrap();
+    assert_eq!(buffer, [1u8; BLOCK_SECTOR_SIZE as usize]);
+
+    //Once the interval has passed, the next access writes back whatever is dirty
+    let write_behind = WriteBehind { dirty_limit: None, interval: Some(Duration::ZERO) };
+    let cache = Cache::new(16, CachePolicy::Lru, CacheMode::WriteBack, write_behind);
+    cache.write_data(&block, 5, &[2u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    assert_eq!((cache.stats().dirty, cache.stats().write_behinds), (0, 1));
+  }
+
+  #[test]
+  fn test_read_ahead() {
+    let mut file_sys = setup_file_sys(256);
+    let content = [7u8; 8 * BLOCK_SECTOR_SIZE as usize];
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", &content, content.len() as u32).unwrap();
+    file_sys.close().unwrap();
+
+    //A fresh mount holds none of the file
+    let mut file_sys = FileSystem::new(file_sys.block, None, CacheOptions::default()).unwrap();
+    let file = file_sys.open("a").unwrap();
+    let file = file.borrow();
+    file_sys.cache.reset_stats();
+
+    //Only the first of the eight sectors is missed, the rest are read ahead of the reads reaching them
+    for _ in 0..8 {
+      assert_eq!(file.read(&file_sys.block, &file_sys.cache, &mut buffer, BLOCK_SECTOR_SIZE).unwrap(), BLOCK_SECTOR_SIZE);
+      assert_eq!(buffer, [7u8; BLOCK_SECTOR_SIZE as usize]);
+    }
+
+    let stats = file_sys.cache.stats();
+    assert_eq!(stats.prefetched, 7);
+    assert!(stats.hits >= 7);
+
+    //A read elsewhere in the file is not sequential, so nothing more is read ahead
+    file_sys.cache.reset_stats();
+    file.read_at(&file_sys.block, &file_sys.cache, &mut buffer, 10, 100).unwrap();
+    assert_eq!(file_sys.cache.stats().prefetched, 0);
+  }
+
+  #[test]
+  fn test_write_through() {
+    let block = Block::new("hd0".to_owned(), String::new(), SECTORS, RamDisk::new(SECTORS));
+    let cache = Cache::new(8, CachePolicy::Clock, CacheMode::WriteThrough, NO_WRITE_BEHIND);
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+    cache.write_data(&block, 1, &[3u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(cache.stats().dirty, 0);
+
+    block.read_to_buffer(1, &mut buffer).unwrap();
+    assert_eq!(buffer, [3u8; BLOCK_SECTOR_SIZE as usize]);
+  }
+
+  #[test]
+  fn test_direct_io_coherent() {
+    let block = Block::new("hd0".to_owned(), String::new(), SECTORS, RamDisk::new(SECTORS));
+    let cache = Cache::new(8, CachePolicy::Clock, CacheMode::WriteBack, NO_WRITE_BEHIND);
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+    //A dirty cached copy is newer than the disk, so a direct read returns it
+    cache.write_data(&block, 1, &[1u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    cache.read_direct(&block, 1, &mut buffer).unwrap();
+    assert_eq!(buffer, [1u8; BLOCK_SECTOR_SIZE as usize]);
+
+    //A direct write updates the cached copy, which is clean and never written back over it
+    cache.write_direct(&block, 1, &[2u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(cache.stats().dirty, 0);
+    cache.read_to_buffer(&block, 1, &mut buffer).unwrap();
+    assert_eq!(buffer, [2u8; BLOCK_SECTOR_SIZE as usize]);
+
+    //Sectors not cached are moved without filling an entry
+    cache.write_direct(&block, 2, &[4u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    cache.read_direct(&block, 2, &mut buffer).unwrap();
+    assert_eq!(buffer, [4u8; BLOCK_SECTOR_SIZE as usize]);
+    assert_eq!((cache.stats().filled, cache.stats().direct), (1, 3));
+  }
+
+  #[test]
+  fn test_runs_batched() {
+    let disk = Rc::new(RamDisk::new(SECTORS));
+    let (reads, writes) = (Rc::new(Cell::new(0u32)), Rc::new(Cell::new(0u32)));
+    let (read_disk, write_disk, read_cnt, write_cnt) = (disk.clone(), disk.clone(), reads.clone(), writes.clone());
+
+    let hardware = HardwareOps::from_sector_fns(
+      move |sector, buffer| {
+        read_cnt.set(read_cnt.get() + 1);
+        read_disk.read_sectors(sector, buffer)
+      },
+      move |sector, buffer| {
+        write_cnt.set(write_cnt.get() + 1);
+        write_disk.write_sectors(sector, buffer)
+      }
+    );
+    let block = Block::new("hd0".to_owned(), String::new(), SECTORS, hardware);
+    let cache = Cache::new(8, CachePolicy::Lru, CacheMode::WriteBack, NO_WRITE_BEHIND);
+
+    let content: Vec<u8> = (0..6 * BLOCK_SECTOR_SIZE).map(|i| (i / BLOCK_SECTOR_SIZE) as u8).collect();
+    cache.write_direct(&block, 10, &content).unwrap();
+    assert_eq!((writes.get(), cache.stats().direct), (1, 6));
+
+    //Sector 12 is cached and dirty, which splits the run read from disk around it
+    cache.write_data(&block, 12, &[9u8; BLOCK_SECTOR_SIZE as usize]).unwrap();
+    let mut buffer = vec![0u8; 6 * BLOCK_SECTOR_SIZE as usize];
+    cache.read_direct(&block, 10, &mut buffer).unwrap();
+    assert_eq!(reads.get(), 2);
+    assert!(buffer[2 * BLOCK_SECTOR_SIZE as usize..3 * BLOCK_SECTOR_SIZE as usize].iter().all(|&byte| byte == 9));
+    assert_eq!(buffer[3 * BLOCK_SECTOR_SIZE as usize..], content[3 * BLOCK_SECTOR_SIZE as usize..]);
+
+    //Filling the cache reads the same runs, and the sectors are hits afterwards
+    reads.set(0);
+    cache.reset_stats();
+    cache.read_sectors(&block, 10, &mut buffer).unwrap();
+    assert_eq!(reads.get(), 2);
+    assert_eq!((cache.stats().hits, cache.stats().misses), (1, 5));
+
+    cache.read_sectors(&block, 10, &mut buffer).unwrap();
+    assert_eq!((reads.get(), cache.stats().hits), (2, 7));
+    assert_eq!(buffer[..2 * BLOCK_SECTOR_SIZE as usize], content[..2 * BLOCK_SECTOR_SIZE as usize]);
+  }
+
+  ///Times hits against caches of increasing size under every policy, which should take about as long whatever the size
+  ///
+  ///Run with: cargo test --release bench_lookup -- --ignored --nocapture
+  #[test]
+  #[ignore]
+  fn bench_lookup() {
+    const LOOKUPS: u32 = 200_000;
+
+    for policy in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::TwoQ, CachePolicy::Arc] {
+      for capacity in [64u32, 512, 4096] {
+        let block = Block::new("hd0".to_owned(), String::new(), capacity, RamDisk::new(capacity));
+        let cache = Cache::new(capacity as usize, policy, CacheMode::WriteBack, NO_WRITE_BEHIND);
+        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+        for sector in 0..capacity {
+          cache.write_data(&block, sector, &buffer).unwrap();
+        }
+
+        let start = Instant::now();
+        for i in 0..LOOKUPS {
+          cache.read_to_buffer(&block, (i * 7919) % capacity, &mut buffer).unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        assert_eq!(cache.stats().hits, LOOKUPS as u64);
+        println!("{:?}, {} entries: {:?} per lookup", policy, capacity, elapsed / LOOKUPS);
+      }
+    }
+  }
+}
@@ -0,0 +1,223 @@
+use std::{
+  cell::RefCell,
+  collections::BTreeMap,
+  io,
+  ops::Range
+};
+
+use crate::fs::{
+  block::{
+    BlockDevice,
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
+  fserrors::controller_errors::ControllerError
+};
+
+type Sector = [u8; BLOCK_SECTOR_SIZE as usize];
+
+///Byte range of the LEN bytes from SECTOR on a device of SIZE sectors, or an error like reading past the end of a file would give
+fn sector_range(sector: BlockSectorT, len: usize, size: BlockSectorT) -> Result<Range<usize>, ControllerError> {
+  let start = sector as usize * BLOCK_SECTOR_SIZE as usize;
+
+  if start + len > size as usize * BLOCK_SECTOR_SIZE as usize {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("sector {} past the end of the device", sector.max(size))).into())
+  }
+  Ok(start..start + len)
+}
+
+///A device of zeroed sectors held in memory, lost once dropped
+pub(crate) struct RamDisk {
+  data: RefCell<Vec<u8>>
+}
+
+impl RamDisk {
+  pub fn new(size: BlockSectorT) -> Self {
+    Self {
+      data: RefCell::new(vec![0u8; size as usize * BLOCK_SECTOR_SIZE as usize])
+    }
+  }
+
+  pub fn size(&self) -> BlockSectorT {
+    (self.data.borrow().len() / BLOCK_SECTOR_SIZE as usize) as BlockSectorT
+  }
+}
+
+impl BlockDevice for RamDisk {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
+    self.read_sectors(sector, buffer)
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len(), BLOCK_SECTOR_SIZE as usize);
+    self.write_sectors(sector, buffer)
+  }
+
+  fn read_sectors(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+    buffer.copy_from_slice(&self.data.borrow()[sector_range(sector, buffer.len(), self.size())?]);
+    Ok(())
+  }
+
+  fn write_sectors(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    assert_eq!(buffer.len() % BLOCK_SECTOR_SIZE as usize, 0);
+    let range = sector_range(sector, buffer.len(), self.size())?;
+    self.data.borrow_mut()[range].copy_from_slice(buffer);
+    Ok(())
+  }
+}
+
+///A copy-on-write overlay over BASE, which is only ever read
+///
+///Writes are kept in memory and shadow the sectors of BASE they were made to, until committed to BASE or discarded
+pub(crate) struct CowDisk<D: BlockDevice> {
+  base: D,
+  written: RefCell<BTreeMap<BlockSectorT, Box<Sector>>>
+}
+
+impl<D: BlockDevice> CowDisk<D> {
+  pub fn new(base: D) -> Self {
+    Self {
+      base,
+      written: RefCell::new(BTreeMap::new())
+    }
+  }
+
+  ///Sectors written since the overlay was made, committed or discarded, in ascending order
+  pub fn written_sectors(&self) -> Vec<BlockSectorT> {
+    self.written.borrow().keys().copied().collect()
+  }
+
+  ///Drops every write, so reads see BASE again
+  pub fn discard(&self) {
+    self.written.borrow_mut().clear();
+  }
+
+  ///Writes every sector written so far through to BASE, stopping at the first that fails
+  ///
+  ///Sectors already written through are no longer kept, so a failed commit may be retried
+  pub fn commit(&self) -> Result<(), ControllerError> {
+    let mut written = self.written.borrow_mut();
+
+    while let Some((sector, data)) = written.pop_first() {
+      if let Err(e) = self.base.write(sector, data.as_slice()) {
+        written.insert(sector, data);
+        return Err(e)
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<D: BlockDevice> BlockDevice for CowDisk<D> {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    match self.written.borrow().get(&sector) {
+      Some(data) => {
+        buffer.copy_from_slice(data.as_slice());
+        Ok(())
+      },
+      None => self.base.read(sector, buffer)
+    }
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    let mut data = Box::new([0u8; BLOCK_SECTOR_SIZE as usize]);
+    data.copy_from_slice(buffer);
+    self.written.borrow_mut().insert(sector, data);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::block::Block;
+
+  fn sector(byte: u8) -> Sector {
+    [byte; BLOCK_SECTOR_SIZE as usize]
+  }
+
+  #[test]
+  fn test_ram_disk() {
+    let disk = RamDisk::new(4);
+    let mut buffer = sector(1);
+
+    disk.read(3, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(0));
+
+    disk.write(3, &sector(7)).unwrap();
+    disk.read(3, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(7));
+
+    assert!(disk.read(4, &mut buffer).is_err());
+    assert!(disk.write(4, &buffer).is_err());
+  }
+
+  #[test]
+  fn test_cow_disk() {
+    let base = RamDisk::new(4);
+    base.write(0, &sector(1)).unwrap();
+
+    let overlay = CowDisk::new(&base);
+    let mut buffer = sector(0);
+
+    overlay.write(1, &sector(2)).unwrap();
+    overlay.read(0, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(1));
+    overlay.read(1, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(2));
+
+    //The base never saw the write
+    base.read(1, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(0));
+
+    overlay.discard();
+    overlay.read(1, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(0));
+
+    overlay.write(2, &sector(3)).unwrap();
+    assert_eq!(overlay.written_sectors(), vec![2]);
+    overlay.commit().unwrap();
+    assert!(overlay.written_sectors().is_empty());
+
+    base.read(2, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(3));
+  }
+
+  #[test]
+  fn test_block_over_overlay() {
+    let base = RamDisk::new(8);
+    let block = Block::new("hd0".to_owned(), String::new(), 8, CowDisk::new(&base));
+    let mut buffer = sector(0);
+
+    block.write_from_buffer(5, &sector(9)).unwrap();
+    block.read_to_buffer(5, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(9));
+    assert!(block.read_to_buffer(8, &mut buffer).is_err());
+
+    base.read(5, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(0));
+  }
+
+  #[test]
+  fn test_block_multi_sector() {
+    let block = Block::new("hd0".to_owned(), String::new(), 8, RamDisk::new(8));
+    let (first, second) = ([1u8; BLOCK_SECTOR_SIZE as usize], [2u8; 2 * BLOCK_SECTOR_SIZE as usize]);
+
+    block.write_vectored(5, &[&first, &second]).unwrap();
+    let mut buffer = [0u8; 4 * BLOCK_SECTOR_SIZE as usize];
+    block.read_sectors(4, &mut buffer).unwrap();
+    assert_eq!(buffer[..BLOCK_SECTOR_SIZE as usize], sector(0));
+    assert_eq!(buffer[BLOCK_SECTOR_SIZE as usize..2 * BLOCK_SECTOR_SIZE as usize], first);
+    assert_eq!(buffer[2 * BLOCK_SECTOR_SIZE as usize..], second);
+
+    //A run reaching past the end fails as a whole
+    assert!(block.read_sectors(5, &mut buffer).is_err());
+    assert!(block.write_sectors(6, &second).is_ok());
+    assert!(block.write_sectors(7, &second).is_err());
+
+    let stats = block.io_stats();
+    assert_eq!((stats.total_reads(), stats.total_writes()), (4, 5));
+  }
+}
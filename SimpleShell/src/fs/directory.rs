@@ -1,12 +1,14 @@
 use std::{
-  cell::{Ref, RefCell, RefMut}, mem::size_of, rc::Rc, str::SplitAsciiWhitespace
+  cell::{RefCell, RefMut}, mem::size_of, rc::Rc
 };
 
 use crate::fs::{
-  block::BlockSectorT,
+  block::{BlockSectorT, BLOCK_SECTOR_SIZE},
   file_sys::FileSystem,
-  fserrors::dir_errors::DirError,
-  inode::{DiskInode, InodeList, MemoryInode}
+  freemap::Freemap,
+  fserrors::{dir_errors::DirError, inode_errors::InodeError},
+  inode::{DiskInode, InodeList, MemoryInode},
+  journal::Journal
 };
 
 use bytemuck::{from_bytes, bytes_of, Pod, Zeroable};
@@ -14,9 +16,16 @@ use bytemuck::{from_bytes, bytes_of, Pod, Zeroable};
 use super::{file_sys::ROOT_DIR_SECTOR};
 
 
-const NAME_MAX: usize = 31;
+pub(crate) const NAME_MAX: usize = 31;
 const DIR_ENTRY_SIZE: u32 = size_of::<DiskDirectory>() as u32;
-
+const DIR_SIGNATURE: u32 = 0x44495248;
+const ENTRIES_PER_BUCKET: u32 = 14u32;
+
+///A DIRECTORY is a hash table of NAMEs to INODE sectors
+///
+///Block 0 of its INODE holds a DIR HEADER, blocks 1 through BUCKET_CNT the primary BUCKETs.
+///A BUCKET that fills up chains to an overflow BUCKET appended past the end of the DIRECTORY.
+///The table doubles once it holds as many entries as its primary BUCKETs have slots for
 pub(crate) struct MemoryDirectory {
   inode: Rc<RefCell<MemoryInode>>,
   pos: u32,
@@ -32,11 +41,16 @@ pub fn split_path(path: &str) -> (&str, &str) {
   }
 }
 
+///32 bit FNV-1a hash of NAME, used to pick its BUCKET
+fn name_hash(name: &str) -> u32 {
+  name.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
 impl MemoryDirectory {
   fn new(inode: Rc<RefCell<MemoryInode>>) -> Self {
     Self {
       inode,
-      pos: DIR_ENTRY_SIZE,
+      pos: BLOCK_SECTOR_SIZE,
       open_cnt: 1u32
     }
   }
@@ -45,20 +59,40 @@ impl MemoryDirectory {
     self.inode.clone()
   }
 
-  pub fn new_on_disk(state: &mut FileSystem, sector: BlockSectorT, entry_cnt: u32) -> Result<(), DirError> {
-    let _ = DiskInode::new(state, sector, DIR_ENTRY_SIZE * entry_cnt, true)?;
+  ///Creates a new DIRECTORY at SECTOR with a single empty BUCKET and a parent entry pointing back at itself
+  ///
+  ///Directories start out this small and grow as ADD fills them up
+  pub fn new_on_disk(state: &mut FileSystem, sector: BlockSectorT) -> Result<(), DirError> {
+    Self::new_on_disk_with_capacity(state, sector, ENTRIES_PER_BUCKET)
+  }
+
+  ///Creates a DIRECTORY at SECTOR with enough buckets for CAPACITY entries before it has to grow
+  pub fn new_on_disk_with_capacity(state: &mut FileSystem, sector: BlockSectorT, capacity: u32) -> Result<(), DirError> {
+    let bucket_cnt = capacity.div_ceil(ENTRIES_PER_BUCKET).max(1).next_power_of_two();
+    DiskInode::new(state, sector, BLOCK_SECTOR_SIZE * (bucket_cnt + 1), true)?;
 
     let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
-    let dir_entry = DiskDirectory::new(sector);
+    let dir = Self::new(inode);
+    let header = DirHeader::new(sector, bucket_cnt);
 
-    let bytes_wrote = inode.borrow_mut().write_at(state, bytes_of(&dir_entry), DIR_ENTRY_SIZE, 0)?;
+    let result = dir.write_header(state, &header);
+    dir.close(state)?;
+    result
+  }
 
-    if bytes_wrote != DIR_ENTRY_SIZE {
-      return Err(DirError::CreationFailedBytesMissing())
-    }
+  ///Closes DIR, releasing the open count it holds on its INODE
+  pub fn close(&self, state: &mut FileSystem) -> Result<(), DirError> {
+    let sector = self.inode.borrow().inode_num();
+    InodeList::close_inode(state, sector)?;
     Ok(())
   }
 
+  ///Opens the DIRECTORY whose INODE is at SECTOR, which must be closed by the caller
+  pub fn open_sector(state: &mut FileSystem, sector: BlockSectorT) -> Result<Rc<RefCell<MemoryDirectory>>, DirError> {
+    let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+    Ok(Rc::new(RefCell::new(Self::new(inode))))
+  }
+
   pub fn open_root(state: &mut FileSystem) -> Result<Rc<RefCell<MemoryDirectory>>, DirError> {
     if state.cwd.is_none() {
       let root_inode = state.inode_list.open_inode(&state.block, &state.cache, ROOT_DIR_SECTOR)?;
@@ -72,93 +106,144 @@ impl MemoryDirectory {
     }
   }
 
+  ///Opens the DIRECTORY at PATH, relative to the CWD unless it starts with a '/'
+  ///
+  ///The returned DIRECTORY holds its own open count and must be closed by the caller
   pub fn open_path(state: &mut FileSystem, path: &str) -> Result<Rc<RefCell<MemoryDirectory>>, DirError> {
-    let mut curr_dir = if path.starts_with("/") {
-      Self::open_root(state)?
+    let start_sector = if path.starts_with("/") {
+      ROOT_DIR_SECTOR
     } else {
-      match &state.cwd {
-        Some(cwd) => {
-          cwd.clone()
-        },
-        None => {
-          Self::open_root(state)?
-        }
-      }
+      Self::open_root(state)?.borrow().inode.borrow().inode_num()
     };
 
+    let start_inode = state.inode_list.open_inode(&state.block, &state.cache, start_sector)?;
+    let mut curr_dir = Self::new(start_inode);
+
     for token in path.split("/").filter(|&x| !x.is_empty()) {
-      let next_dir;
-
-      match curr_dir.borrow().lookup(state, token)? {
-        Some((entry, _)) => {
-          let next_inode = state.inode_list.open_inode(&state.block, &state.cache, entry.sector)?;
-          next_dir = MemoryDirectory::new(next_inode);
-        },
-        None => return Err(DirError::EntryNotFound(path.to_string()))
+      let next_inode = match curr_dir.search(state, token) {
+        Ok(inode) => inode,
+        Err(e) => {
+          curr_dir.close(state)?;
+          return Err(e)
+        }
+      };
+      curr_dir.close(state)?;
+
+      if !next_inode.borrow().is_dir() {
+        let sector = next_inode.borrow().inode_num();
+        InodeList::close_inode(state, sector)?;
+        return Err(DirError::EntryNotFound(path.to_string()))
       }
+      curr_dir = Self::new(next_inode);
+    }
+    Ok(Rc::new(RefCell::new(curr_dir)))
+  }
+
+  fn read_header(&self, state: &FileSystem) -> Result<DirHeader, DirError> {
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    self.inode.borrow().read_at(&state.block, &state.cache, &mut buffer, BLOCK_SECTOR_SIZE, 0)?;
+
+    let header = from_bytes::<DirHeader>(&buffer).to_owned();
+
+    if header.sign != DIR_SIGNATURE {
+      return Err(DirError::CorruptHeader(self.inode.borrow().inode_num()))
+    }
+    Ok(header)
+  }
+
+  fn write_header(&self, state: &mut FileSystem, header: &DirHeader) -> Result<(), DirError> {
+    let bytes_wrote = self.inode.borrow_mut().write_at(state, bytes_of(header), BLOCK_SECTOR_SIZE, 0)?;
+
+    if bytes_wrote != BLOCK_SECTOR_SIZE {
+      return Err(DirError::CreationFailedBytesMissing())
+    }
+    Ok(())
+  }
+
+  fn read_bucket(&self, state: &FileSystem, blk: u32) -> Result<DirBucket, DirError> {
+    //A BUCKET chain leading past the end of DIR was corrupted on disk
+    if blk >= self.block_cnt() {
+      return Err(DirError::CorruptHeader(self.inode.borrow().inode_num()))
+    }
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    self.inode.borrow().read_at(&state.block, &state.cache, &mut buffer, BLOCK_SECTOR_SIZE, blk * BLOCK_SECTOR_SIZE)?;
+
+    Ok(from_bytes::<DirBucket>(&buffer).to_owned())
+  }
+
+  ///Writes BUCKET as block BLK of DIR, extending DIR if BLK is one past its end
+  fn write_bucket(&self, state: &mut FileSystem, blk: u32, bucket: &DirBucket) -> Result<(), DirError> {
+    let bytes_wrote = self.inode.borrow_mut().write_at(state, bytes_of(bucket), BLOCK_SECTOR_SIZE, blk * BLOCK_SECTOR_SIZE)?;
 
-      curr_dir = Rc::new(RefCell::new(next_dir));
+    if bytes_wrote != BLOCK_SECTOR_SIZE {
+      return Err(DirError::CreationFailedBytesMissing())
     }
-    Ok(curr_dir)
+    Ok(())
   }
 
+  ///Returns the number of blocks, header included, that make up DIR
+  fn block_cnt(&self) -> u32 {
+    self.inode.borrow().len() / BLOCK_SECTOR_SIZE
+  }
 
   ///Searches a given DIRECTORY for a DIRECTORY ENTRY with the given PAT
   ///
+  ///Only the BUCKET chain PAT hashes to is read.
   ///Returns a tuple containing the DISK ENTRY and its OFST within DIRECTORY if found, None if not
   fn lookup(&self, state: &FileSystem, pat: &str) -> Result<Option<(DiskDirectory, u32)>, DirError> {
-    let mut ofst = DIR_ENTRY_SIZE;
-    let mut buffer = [0u8; DIR_ENTRY_SIZE as usize];
+    let header = self.read_header(state)?;
+    let mut blk = header.bucket_of(pat);
 
-    while self.inode.borrow_mut().read_at(&state.block, &state.cache, &mut buffer, DIR_ENTRY_SIZE, ofst)? == DIR_ENTRY_SIZE {
-      let entry = from_bytes::<DiskDirectory>(&buffer);
-      let entry_name = entry.name_to_string();
+    while blk != 0 {
+      let bucket = self.read_bucket(state, blk)?;
 
-      if entry.in_use == 1u8 && entry_name == pat {
-        return Ok(Some((entry.to_owned(), ofst)))
-      }
+      let found = bucket.entries.iter().position(|entry| {
+        entry.in_use == 1u8 && entry.name_to_string() == pat
+      });
 
-      ofst += DIR_ENTRY_SIZE;
+      if let Some(slot) = found {
+        let ofst = blk * BLOCK_SECTOR_SIZE + slot as u32 * DIR_ENTRY_SIZE;
+        return Ok(Some((bucket.entries[slot], ofst)))
+      }
+      blk = bucket.next;
     }
     Ok(None)
   }
 
-
   pub fn search(&self, state: &mut FileSystem, pat: &str) -> Result<Rc<RefCell<MemoryInode>>, DirError> {
-    let mut buffer = [0u8; DIR_ENTRY_SIZE as usize];
-
     match pat {
       "." => {
         let sector = self.inode.borrow().inode_num();
-        return Ok(state.inode_list.open_inode(&state.block, &state.cache, sector)?)
+        Ok(state.inode_list.open_inode(&state.block, &state.cache, sector)?)
       },
       ".." => {
-        self.inode.borrow().read_at(&state.block, &state.cache, &mut buffer, DIR_ENTRY_SIZE, 0)?;
-        let entry = from_bytes::<DiskDirectory>(&buffer);
-        return Ok(state.inode_list.open_inode(&state.block, &state.cache, entry.sector)?)
+        let header = self.read_header(state)?;
+        Ok(state.inode_list.open_inode(&state.block, &state.cache, header.parent)?)
       },
       _ => {
         match self.lookup(state, pat)? {
-          Some((entry, _)) => return Ok(state.inode_list.open_inode(&state.block, &state.cache, entry.sector)?),
-          None => return Err(DirError::EntryNotFound(pat.to_string()))
+          Some((entry, _)) => Ok(state.inode_list.open_inode(&state.block, &state.cache, entry.sector)?),
+          None => Err(DirError::EntryNotFound(pat.to_string()))
         }
       }
     }
   }
 
   pub fn is_empty(&self, state: &FileSystem) -> Result<bool, DirError> {
-    let mut buffer = [0u8; DIR_ENTRY_SIZE as usize];
-    let mut ofst = DIR_ENTRY_SIZE;
+    Ok(self.read_header(state)?.entry_cnt == 0)
+  }
 
-    while self.inode.borrow_mut().read_at(&state.block, &state.cache, &mut buffer, DIR_ENTRY_SIZE, ofst)? == DIR_ENTRY_SIZE {
-      let entry = from_bytes::<DiskDirectory>(&buffer);
-      if entry.in_use == 1u8 {
-        return Ok(false)
-      }
+  ///Returns the sector of the DIRECTORY the parent entry of DIR points at
+  pub fn parent(&self, state: &FileSystem) -> Result<BlockSectorT, DirError> {
+    Ok(self.read_header(state)?.parent)
+  }
 
-      ofst += DIR_ENTRY_SIZE;
-    }
-    return Ok(true)
+  ///Points the parent entry of DIR at the DIRECTORY at PARENT
+  pub fn set_parent(&self, state: &mut FileSystem, parent: BlockSectorT) -> Result<(), DirError> {
+    let mut header = self.read_header(state)?;
+    header.parent = parent;
+    self.write_header(state, &header)
   }
 
   pub fn add(dir: RefMut<Self>, state: &mut FileSystem, name: &str, sector: BlockSectorT, is_dir: bool) -> Result<(), DirError> {
@@ -170,83 +255,261 @@ impl MemoryDirectory {
       return Err(DirError::EntryAlreadyExists(name.to_string()))
     }
 
-    let mut entry = DiskDirectory::new(0);
-
+    //Point the child's parent entry back at DIR
     if is_dir {
       let child_inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
-      let child_inode_sector = child_inode.borrow().inode_num();
-      entry.sector = child_inode_sector;
+      let child_dir = Self::new(child_inode);
+
+      let result = child_dir.read_header(state).and_then(|mut child_header| {
+        child_header.parent = dir.inode.borrow().inode_num();
+        child_dir.write_header(state, &child_header)
+      });
+      child_dir.close(state)?;
+      result?;
+    }
 
-      let bytes_wrote = child_inode.borrow_mut().write_at(state, bytes_of(&entry), DIR_ENTRY_SIZE, 0)?;
+    let mut header = dir.read_header(state)?;
 
-      if bytes_wrote != DIR_ENTRY_SIZE {
-        InodeList::close_inode(state, child_inode_sector)?;
-        return Err(DirError::CreationFailedBytesMissing())
-      }
+    if header.entry_cnt >= header.bucket_cnt * ENTRIES_PER_BUCKET {
+      let bucket_cnt = header.bucket_cnt * 2;
+      dir.rehash(state, &mut header, bucket_cnt)?;
     }
 
-    let mut ofst = 0;
-    let mut buffer = [0u8; DIR_ENTRY_SIZE as usize];
+    let mut entry = DiskDirectory::new(sector);
+    entry.in_use = 1u8;
+    entry.name[..name.len()].copy_from_slice(name.as_bytes());
 
-    while dir.inode.borrow_mut().read_at(&state.block, &state.cache, &mut buffer, DIR_ENTRY_SIZE, ofst)? == DIR_ENTRY_SIZE {
-      let read_entry = from_bytes::<DiskDirectory>(&buffer);
-      if read_entry.in_use == 1u8 {
-        break;
+    dir.insert(state, &header, entry)?;
+
+    header.entry_cnt += 1;
+    dir.write_header(state, &header)
+  }
+
+  ///Places ENTRY in the first free slot of its BUCKET chain, appending an overflow BUCKET if the chain is full
+  fn insert(&self, state: &mut FileSystem, header: &DirHeader, entry: DiskDirectory) -> Result<(), DirError> {
+    let mut blk = header.bucket_of(&entry.name_to_string());
+
+    loop {
+      let mut bucket = self.read_bucket(state, blk)?;
+
+      if let Some(slot) = bucket.entries.iter().position(|entry| entry.in_use == 0u8) {
+        bucket.entries[slot] = entry;
+        return self.write_bucket(state, blk, &bucket)
       }
-      ofst += DIR_ENTRY_SIZE;
-    }
 
-    entry.in_use = 1u8;
-    entry.name.copy_from_slice(name.as_bytes());
-    entry.sector = sector;
+      if bucket.next == 0 {
+        let mut overflow = DirBucket::new();
+        overflow.entries[0] = entry;
 
-    let bytes_wrote = dir.inode.borrow_mut().write_at(state, bytes_of(&entry), DIR_ENTRY_SIZE, ofst)?;
-    if bytes_wrote != DIR_ENTRY_SIZE {
-      return Err(DirError::CreationFailedBytesMissing())
+        bucket.next = self.block_cnt();
+        self.write_bucket(state, bucket.next, &overflow)?;
+        return self.write_bucket(state, blk, &bucket)
+      }
+      blk = bucket.next;
+    }
+  }
+
+  ///Rebuilds DIR with BUCKET_CNT primary BUCKETs, dropping all overflow BUCKETs and free slots
+  ///
+  ///The new table is written to a fresh file, whose sectors then take the place of the old table in one transaction.
+  ///DIR keeps every entry in its old table until then, and the file frees the old table once closed
+  fn rehash(&self, state: &mut FileSystem, header: &mut DirHeader, bucket_cnt: u32) -> Result<(), DirError> {
+    let entries = self.read_entries(state)?;
+    header.bucket_cnt = bucket_cnt;
+
+    let mut table = vec![DirBucket::new(); bucket_cnt as usize + 1];
+    for entry in entries {
+      Self::place(&mut table, header, entry);
     }
 
+    let mut bytes = bytes_of(header).to_vec();
+    table[1..].iter().for_each(|bucket| bytes.extend_from_slice(bytes_of(bucket)));
+
+    Journal::transaction(state, |state| -> Result<(), InodeError> {
+      let sector = Freemap::allocate(state, 1)?;
+      let created = DiskInode::new(state, sector, 0, false);
+
+      let fresh = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+      fresh.borrow_mut().remove();
+
+      let result = created.and_then(|_| {
+        let len = bytes.len() as u32;
+        fresh.borrow_mut().write_at(state, &bytes, len, 0)?;
+        self.inode.borrow_mut().swap_data(&mut fresh.borrow_mut(), &state.block, &state.cache)
+      });
+      InodeList::close_inode(state, sector)?;
+      result
+    })?;
     Ok(())
   }
 
+  ///Places ENTRY in the first free slot of its BUCKET chain in TABLE, appending an overflow BUCKET if the chain is full
+  fn place(table: &mut Vec<DirBucket>, header: &DirHeader, entry: DiskDirectory) {
+    let mut blk = header.bucket_of(&entry.name_to_string()) as usize;
+
+    loop {
+      if let Some(slot) = table[blk].entries.iter().position(|entry| entry.in_use == 0u8) {
+        table[blk].entries[slot] = entry;
+        return
+      }
+
+      if table[blk].next == 0 {
+        table[blk].next = table.len() as u32;
+        table.push(DirBucket::new());
+      }
+      blk = table[blk].next as usize;
+    }
+  }
+
   ///Removes an entry with NAME in DIR
   pub fn remove(dir: RefMut<Self>, state: &mut FileSystem, name: &str) -> Result<(), DirError> {
     match dir.lookup(state, name)? {
-      Some((mut sub_entry, ofst)) => {
+      Some((sub_entry, ofst)) => {
         let sub_inode = state.inode_list.open_inode(&state.block, &state.cache, sub_entry.sector)?;
 
         if sub_inode.borrow().is_dir() {
-          let sub_dir = Self::new(sub_inode);
+          let sub_dir = Self::new(sub_inode.clone());
           if !sub_dir.is_empty(state)? {
+            sub_dir.close(state)?;
             return Err(DirError::CannotDeleteNonEmptyDir(name.to_string()))
           }
         }
 
-        sub_entry.in_use = 0u8;
-        let bytes_wrote = dir.inode.borrow_mut().write_at(state, bytes_of(&sub_entry), DIR_ENTRY_SIZE, ofst)?;
-        if bytes_wrote != DIR_ENTRY_SIZE {
-          return Err(DirError::CreationFailedBytesMissing())
+        if let Err(e) = dir.clear_entry(state, sub_entry, ofst) {
+          InodeList::close_inode(state, sub_entry.sector)?;
+          return Err(e)
         }
 
+        //The INODE is only deallocated once every opener has closed it
+        sub_inode.borrow_mut().remove();
+        InodeList::close_inode(state, sub_entry.sector)?;
         return Ok(())
       },
       None => return Err(DirError::EntryNotFound(name.to_string()))
     }
   }
 
+  ///Removes the entry NAME from DIR without opening or deallocating the INODE it points at
+  pub fn unlink(dir: RefMut<Self>, state: &mut FileSystem, name: &str) -> Result<(), DirError> {
+    match dir.lookup(state, name)? {
+      Some((entry, ofst)) => dir.clear_entry(state, entry, ofst),
+      None => Err(DirError::EntryNotFound(name.to_string()))
+    }
+  }
+
+  ///Frees the slot of ENTRY at OFST in DIR
+  fn clear_entry(&self, state: &mut FileSystem, mut entry: DiskDirectory, ofst: u32) -> Result<(), DirError> {
+    entry.in_use = 0u8;
+    let bytes_wrote = self.inode.borrow_mut().write_at(state, bytes_of(&entry), DIR_ENTRY_SIZE, ofst)?;
+    if bytes_wrote != DIR_ENTRY_SIZE {
+      return Err(DirError::CreationFailedBytesMissing())
+    }
+
+    let mut header = self.read_header(state)?;
+    header.entry_cnt -= 1;
+    self.write_header(state, &header)
+  }
+
+  ///Shrinks DIR to the fewest BUCKETs that can hold its entries. Returns the number of entry slots reclaimed
+  pub fn compact(dir: RefMut<Self>, state: &mut FileSystem) -> Result<u32, DirError> {
+    let mut header = dir.read_header(state)?;
+    let bucket_cnt = header.entry_cnt.div_ceil(ENTRIES_PER_BUCKET).max(1).next_power_of_two();
+    let old_blocks = dir.block_cnt();
+
+    //A table of the right size without overflow BUCKETs has nothing left to reclaim
+    if bucket_cnt == header.bucket_cnt && old_blocks == bucket_cnt + 1 {
+      return Ok(0)
+    }
+
+    dir.rehash(state, &mut header, bucket_cnt)?;
+    Ok((old_blocks.saturating_sub(dir.block_cnt())) * ENTRIES_PER_BUCKET)
+  }
+
+  ///Reads every entry in use in DIR, in BUCKET order
+  fn read_entries(&self, state: &FileSystem) -> Result<Vec<DiskDirectory>, DirError> {
+    let mut result = Vec::<DiskDirectory>::new();
+
+    for blk in 1..self.block_cnt() {
+      let bucket = self.read_bucket(state, blk)?;
+      result.extend(bucket.entries.iter().filter(|entry| entry.in_use == 1u8));
+    }
+    Ok(result)
+  }
+
+  ///Reads the NAME and INODE sector of every entry in use in DIR
+  pub fn read_links(&self, state: &FileSystem) -> Result<Vec<(String, BlockSectorT)>, DirError> {
+    let entries = self.read_entries(state)?;
+    Ok(entries.iter().map(|entry| (entry.name_to_string(), entry.sector)).collect())
+  }
+
+  ///Reads the NAME and INODE sector of every entry removed from DIR whose slot has not been reused yet
+  pub fn read_stale_links(&self, state: &FileSystem) -> Result<Vec<(String, BlockSectorT)>, DirError> {
+    let mut result = Vec::<(String, BlockSectorT)>::new();
+
+    for blk in 1..self.block_cnt() {
+      let bucket = self.read_bucket(state, blk)?;
+      result.extend(bucket.entries.iter()
+        .filter(|entry| entry.in_use == 0u8 && entry.name[0] != 0u8)
+        .map(|entry| (entry.name_to_string(), entry.sector)));
+    }
+    Ok(result)
+  }
+
   ///Reads all directory entries in the given DIR and returns in Vec
   pub fn read_names(&self, state: &mut FileSystem) -> Result<Vec<String>, DirError> {
-    let mut buffer = [0u8; DIR_ENTRY_SIZE as usize];
-    let mut ofst = DIR_ENTRY_SIZE;
-    let mut result = Vec::<String>::new();
-
-    while self.inode.borrow().read_at(&state.block, &state.cache, &mut buffer, DIR_ENTRY_SIZE, ofst)? == DIR_ENTRY_SIZE {
-      let entry = from_bytes::<DiskDirectory>(&buffer);
-      if entry.in_use == 1u8 {
-        result.push(entry.name_to_string());
-      }
-      ofst += DIR_ENTRY_SIZE;
+    let entries = self.read_entries(state)?;
+    Ok(entries.iter().map(|entry| entry.name_to_string()).collect())
+  }
+}
+
+///Block 0 of every DIRECTORY
+///
+///Safety: the size of this struct must be exactly BLOCK_SECTOR_SIZE bytes in size
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct DirHeader {
+  parent: BlockSectorT,
+  bucket_cnt: u32,
+  entry_cnt: u32,
+  sign: u32,
+  _padding: [u8; 496]
+}
+
+impl DirHeader {
+  fn new(parent: BlockSectorT, bucket_cnt: u32) -> Self {
+    Self {
+      parent,
+      bucket_cnt,
+      entry_cnt: 0u32,
+      sign: DIR_SIGNATURE,
+      _padding: [0u8; 496]
+    }
+  }
+
+  ///Returns the block of the primary BUCKET NAME hashes to
+  fn bucket_of(&self, name: &str) -> u32 {
+    1 + name_hash(name) % self.bucket_cnt
+  }
+}
+
+///A block of DIRECTORY ENTRIES, chained to an overflow block at NEXT (0 if none)
+///
+///Safety: the size of this struct must be exactly BLOCK_SECTOR_SIZE bytes in size
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct DirBucket {
+  entries: [DiskDirectory; ENTRIES_PER_BUCKET as usize],
+  next: u32,
+  _padding: [u8; 4]
+}
+
+impl DirBucket {
+  fn new() -> Self {
+    Self {
+      entries: [DiskDirectory::new(0); ENTRIES_PER_BUCKET as usize],
+      next: 0u32,
+      _padding: [0u8; 4]
     }
-    Ok(result)
   }
 }
 
@@ -268,6 +531,231 @@ impl DiskDirectory {
   }
 
   fn name_to_string(&self) -> String {
-    String::from_utf8_lossy(&self.name).to_string()
+    let len = self.name.iter().position(|&c| c == 0).unwrap_or(NAME_MAX);
+    String::from_utf8_lossy(&self.name[..len]).to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Instant;
+
+  use super::*;
+  use crate::fs::{
+    block::{Block, BlockDevice},
+    cache::CacheOptions,
+    device::{CowDisk, RamDisk},
+    fault::{Fault, FaultKind, FaultyDisk, Op, Trigger},
+    file_sys::tests::setup_file_sys,
+    fserrors::FSErrors,
+    fsck::Fsck,
+    inode::InodeFormat
+  };
+
+  fn mount<'a>(disk: impl BlockDevice + 'a, format: Option<InodeFormat>) -> Result<FileSystem<'a>, FSErrors> {
+    FileSystem::new(Block::new("hd0".to_owned(), String::new(), 1024, disk), format, CacheOptions::default())
+  }
+
+  fn root_header(state: &mut FileSystem) -> DirHeader {
+    let root = MemoryDirectory::open_root(state).unwrap();
+    let header = root.as_ref().borrow().read_header(state).unwrap();
+    header
+  }
+
+  fn root_blocks(state: &mut FileSystem) -> u32 {
+    let root = MemoryDirectory::open_root(state).unwrap();
+    let blocks = root.as_ref().borrow().block_cnt();
+    blocks
+  }
+
+  #[test]
+  fn test_root_starts_with_single_bucket() {
+    let mut file_sys = setup_file_sys(1024);
+    let header = root_header(&mut file_sys);
+
+    assert_eq!(root_blocks(&mut file_sys), 2);
+    assert_eq!({ header.bucket_cnt }, 1);
+    assert_eq!({ header.parent }, ROOT_DIR_SECTOR);
+  }
+
+  #[test]
+  fn test_grows_on_add() {
+    let mut file_sys = setup_file_sys(1024);
+
+    for i in 0..ENTRIES_PER_BUCKET {
+      file_sys.create(&format!("file{}", i), 0, false).unwrap();
+    }
+    assert_eq!({ root_header(&mut file_sys).bucket_cnt }, 1);
+
+    for i in ENTRIES_PER_BUCKET..40 {
+      file_sys.create(&format!("file{}", i), 0, false).unwrap();
+    }
+    let header = root_header(&mut file_sys);
+    assert_eq!({ header.bucket_cnt }, 4);
+    assert_eq!({ header.entry_cnt }, 40);
+
+    let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+    let names = root.as_ref().borrow().read_names(&mut file_sys).unwrap();
+    assert_eq!(names.len(), 40);
+
+    for i in 0..40 {
+      assert!(root.as_ref().borrow().lookup(&file_sys, &format!("file{}", i)).unwrap().is_some());
+    }
+  }
+
+  #[test]
+  fn test_rehash_survives_crash() {
+    let base = Rc::new(RamDisk::new(1024));
+
+    let mut file_sys = mount(base.clone(), Some(InodeFormat::Extent)).unwrap();
+    for i in 0..ENTRIES_PER_BUCKET {
+      file_sys.create(&format!("file{}", i), 0, false).unwrap();
+    }
+    file_sys.close().unwrap();
+
+    for after in 0.. {
+      //The root is full, so adding to it doubles its table
+      let cow = Rc::new(CowDisk::new(base.clone()));
+      let disk = FaultyDisk::new(cow.clone());
+      disk.inject(Fault { op: Op::Write, trigger: Trigger::After(after), kind: FaultKind::Fail });
+      let created = mount(disk, None).and_then(|mut file_sys| {
+        file_sys.create("grown", 0, false)?;
+        file_sys.close()
+      });
+
+      let mut file_sys = mount(cow, None).unwrap();
+      let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+      for i in 0..ENTRIES_PER_BUCKET {
+        assert!(root.as_ref().borrow().lookup(&file_sys, &format!("file{}", i)).unwrap().is_some(), "crash after {} writes", after);
+      }
+      assert_eq!(Fsck::check(&mut file_sys).unwrap().problems(), &[], "crash after {} writes", after);
+
+      if created.is_ok() {
+        assert_eq!({ root_header(&mut file_sys).bucket_cnt }, 2);
+        break;
+      }
+    }
+  }
+
+  #[test]
+  fn test_overflow_bucket() {
+    let mut file_sys = setup_file_sys(1024);
+    let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+    let header = root_header(&mut file_sys);
+
+    //Fill the only bucket by hand so the next insert has to chain
+    for i in 0..=ENTRIES_PER_BUCKET {
+      let mut entry = DiskDirectory::new(100 + i);
+      let name = format!("entry{}", i);
+      entry.in_use = 1u8;
+      entry.name[..name.len()].copy_from_slice(name.as_bytes());
+      root.as_ref().borrow().insert(&mut file_sys, &header, entry).unwrap();
+    }
+
+    assert_eq!(root_blocks(&mut file_sys), 3);
+    let (entry, ofst) = root.as_ref().borrow().lookup(&file_sys, "entry14").unwrap().unwrap();
+    assert_eq!({ entry.sector }, 114);
+    assert_eq!(ofst, 2 * BLOCK_SECTOR_SIZE);
+  }
+
+  #[test]
+  fn test_reuses_free_slot() {
+    let mut file_sys = setup_file_sys(1024);
+
+    for name in ["a", "b", "c"] {
+      file_sys.create(name, 0, false).unwrap();
+    }
+    let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+    let (_, b_ofst) = root.as_ref().borrow().lookup(&file_sys, "b").unwrap().unwrap();
+
+    file_sys.remove("b").unwrap();
+    assert!(root.as_ref().borrow().lookup(&file_sys, "b").unwrap().is_none());
+    file_sys.create("b", 0, false).unwrap();
+
+    let (_, new_b_ofst) = root.as_ref().borrow().lookup(&file_sys, "b").unwrap().unwrap();
+    assert_eq!(new_b_ofst, b_ofst);
+    assert_eq!(root_blocks(&mut file_sys), 2);
+  }
+
+  #[test]
+  fn test_compact_reclaims_buckets() {
+    let mut file_sys = setup_file_sys(1024);
+
+    for i in 0..30 {
+      file_sys.create(&format!("file{}", i), 0, false).unwrap();
+    }
+    let old_blocks = root_blocks(&mut file_sys);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    for i in 10..30 {
+      file_sys.remove(&format!("file{}", i)).unwrap();
+    }
+
+    let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+    let reclaimed = MemoryDirectory::compact(root.borrow_mut(), &mut file_sys).unwrap();
+    let new_blocks = root_blocks(&mut file_sys);
+
+    assert_eq!({ root_header(&mut file_sys).bucket_cnt }, 1);
+    assert_eq!(reclaimed, (old_blocks - new_blocks) * ENTRIES_PER_BUCKET);
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before + 20 + old_blocks - new_blocks);
+
+    for i in 0..10 {
+      assert!(root.as_ref().borrow().lookup(&file_sys, &format!("file{}", i)).unwrap().is_some());
+    }
+    assert_eq!(MemoryDirectory::compact(root.borrow_mut(), &mut file_sys).unwrap(), 0);
+  }
+
+  #[test]
+  fn test_subdirectory() {
+    let mut file_sys = setup_file_sys(1024);
+
+    file_sys.create("sub", 0, true).unwrap();
+    file_sys.create("sub/inner", 0, false).unwrap();
+    file_sys.create("sub/nested", 0, true).unwrap();
+    file_sys.create("sub/nested/deep", 0, false).unwrap();
+
+    let sub = MemoryDirectory::open_path(&mut file_sys, "/sub").unwrap();
+    let mut names = sub.as_ref().borrow().read_names(&mut file_sys).unwrap();
+    names.sort();
+    assert_eq!(names, vec!["inner", "nested"]);
+
+    let parent = sub.as_ref().borrow().search(&mut file_sys, "..").unwrap();
+    assert_eq!(parent.borrow().inode_num(), ROOT_DIR_SECTOR);
+    InodeList::close_inode(&mut file_sys, ROOT_DIR_SECTOR).unwrap();
+    sub.as_ref().borrow().close(&mut file_sys).unwrap();
+
+    assert!(file_sys.open("sub/nested/deep").is_ok());
+    assert!(file_sys.remove("sub/nested").is_err());
+    assert!(file_sys.create("sub/missing/file", 0, false).is_err());
+  }
+
+  ///Times lookups against directories of increasing size
+  ///
+  ///Run with: cargo test --release bench_lookup -- --ignored --nocapture
+  #[test]
+  #[ignore]
+  fn bench_lookup() {
+    const LOOKUPS: u32 = 2000;
+
+    for entry_cnt in [100u32, 1000, 5000] {
+      let mut file_sys = setup_file_sys(entry_cnt * 2 + 1024);
+
+      for i in 0..entry_cnt {
+        file_sys.create(&format!("file{}", i), 0, false).unwrap();
+      }
+      let root = MemoryDirectory::open_root(&mut file_sys).unwrap();
+
+      let start = Instant::now();
+      for i in 0..LOOKUPS {
+        let name = format!("file{}", (i * 7919) % entry_cnt);
+        assert!(root.as_ref().borrow().lookup(&file_sys, &name).unwrap().is_some());
+      }
+      let elapsed = start.elapsed();
+
+      println!(
+        "{} entries, {} buckets: {:?} per lookup",
+        entry_cnt, { root.as_ref().borrow().read_header(&file_sys).unwrap().bucket_cnt }, elapsed / LOOKUPS
+      );
+    }
   }
 }
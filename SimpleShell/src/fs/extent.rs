@@ -0,0 +1,565 @@
+use std::cmp::min;
+
+use crate::fs::{
+  block::{
+    Block, BlockSectorT, BLOCK_SECTOR_SIZE
+  },
+  cache::Cache,
+  file_sys::FileSystem,
+  freemap::Freemap,
+  fserrors::inode_errors::InodeError,
+  inode::EMPTY_BUFFER
+};
+
+use bytemuck::{
+  bytes_of, from_bytes, Pod, Zeroable
+};
+
+const INLINE_EXTENTS_CNT: usize = 59usize;
+const EXTENTS_PER_BLOCK: usize = 63usize;
+
+///A run of LEN contiguous data sectors starting at START
+///
+///A START of 0 is a hole of LEN sectors that reads as zeros
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub(crate) struct Extent {
+  pub start: BlockSectorT,
+  pub len: u32
+}
+
+impl Extent {
+  ///Returns an EXTENT covering only SECTOR
+  pub fn single(sector: BlockSectorT) -> Self {
+    Self { start: sector, len: 1 }
+  }
+
+  fn hole(len: u32) -> Self {
+    Self { start: 0, len }
+  }
+
+  pub fn is_hole(&self) -> bool {
+    self.start == 0
+  }
+
+  ///Returns the sector directly after the last sector of EXTENT
+  pub fn end(&self) -> BlockSectorT {
+    self.start + self.len
+  }
+
+  ///Returns the LEN sectors of EXTENT starting OFST sectors into it
+  fn slice(&self, ofst: u32, len: u32) -> Self {
+    match self.is_hole() {
+      true => Self::hole(len),
+      false => Self { start: self.start + ofst, len }
+    }
+  }
+}
+
+///The pointer area of an extent-based ON DISK INODE
+///
+///The first EXTENT_CNT extents are stored inline, the rest in a chain of EXTENT BLOCKs starting at OVERFLOW
+///
+///Safety: the size of this struct must be exactly that of the pointer area of a DISK INODE
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub(crate) struct ExtentTable {
+  extents: [Extent; INLINE_EXTENTS_CNT],
+  extent_cnt: u32,
+  overflow: BlockSectorT,
+  _padding: [u8; 4] //We need this to satisfy bytemuck
+}
+
+///A sector holding extents that did not fit inline, linked to the NEXT one in the chain
+///
+///Safety: the size of this struct must be exactly BLOCK_SECTOR_SIZE bytes in size
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct ExtentBlock {
+  extents: [Extent; EXTENTS_PER_BLOCK],
+  next: BlockSectorT,
+  _padding: [u8; 4] //We need this to satisfy bytemuck
+}
+
+///The in-memory list of every extent of an INODE, along with the EXTENT BLOCKs they are stored in
+pub(crate) struct ExtentList {
+  extents: Vec<Extent>,
+  blocks: Vec<BlockSectorT>,
+  ///The EXTENT BLOCKs as loaded, so those left unchanged are not written again
+  loaded: Vec<ExtentBlock>
+}
+
+impl ExtentList {
+  ///Reads the extents described by TABLE, following its overflow chain
+  ///
+  ///The chain is followed no further than EXTENT_CNT calls for, nor back to a block it already passed, so a corrupt
+  ///one fails rather than loops
+  pub fn load(block: &Block, cache: &Cache, table: &ExtentTable) -> Result<Self, InodeError> {
+    let extent_cnt = table.extent_cnt as usize;
+    let inline = { table.extents };
+
+    let mut extents = inline[..min(extent_cnt, INLINE_EXTENTS_CNT)].to_vec();
+    let mut blocks = Vec::<BlockSectorT>::new();
+    let mut loaded = Vec::<ExtentBlock>::new();
+    let mut next = table.overflow;
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+    while next != 0 && extents.len() < extent_cnt && !blocks.contains(&next) {
+      cache.read_to_buffer(block, next, &mut buffer)?;
+      let extent_block = from_bytes::<ExtentBlock>(&buffer).to_owned();
+      let stored = { extent_block.extents };
+
+      let limit = min(extent_cnt - extents.len(), EXTENTS_PER_BLOCK);
+      extents.extend_from_slice(&stored[..limit]);
+      blocks.push(next);
+      next = extent_block.next;
+      loaded.push(extent_block);
+    }
+
+    if next != 0 || extents.len() != extent_cnt {
+      return Err(InodeError::BrokenExtentChain(blocks.last().copied().unwrap_or(table.overflow)))
+    }
+
+    //Extents running past the last sector there can be are as corrupt as a broken chain
+    let overflows = extents.iter().any(|extent| extent.start.checked_add(extent.len).is_none());
+    if overflows || extents.iter().try_fold(0u32, |total, extent| total.checked_add(extent.len)).is_none() {
+      return Err(InodeError::BrokenExtentChain(table.overflow))
+    }
+    Ok(Self { extents, blocks, loaded })
+  }
+
+  ///Writes the extents back into TABLE, allocating EXTENT BLOCKs as the list grew
+  ///
+  ///EXTENT BLOCKs no longer needed are added to RELEASED, for the caller to release once TABLE is on disk
+  pub fn store(mut self, state: &mut FileSystem, table: &mut ExtentTable, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    let inline_cnt = min(self.extents.len(), INLINE_EXTENTS_CNT);
+    let overflow = &self.extents[inline_cnt..];
+    let needed = overflow.len().div_ceil(EXTENTS_PER_BLOCK);
+
+    while self.blocks.len() < needed {
+      self.blocks.push(Freemap::allocate(state, 1)?);
+    }
+    released.extend(self.blocks.split_off(needed).into_iter().map(Extent::single));
+
+    for (i, chunk) in overflow.chunks(EXTENTS_PER_BLOCK).enumerate() {
+      let mut extents = [Extent::zeroed(); EXTENTS_PER_BLOCK];
+      extents[..chunk.len()].copy_from_slice(chunk);
+
+      let extent_block = ExtentBlock {
+        extents,
+        next: self.blocks.get(i + 1).copied().unwrap_or(0),
+        _padding: [0u8; 4]
+      };
+      if self.loaded.get(i).is_none_or(|loaded| bytes_of(loaded) != bytes_of(&extent_block)) {
+        state.cache.write_from_buffer(&state.block, self.blocks[i], bytes_of(&extent_block))?;
+      }
+    }
+
+    let mut inline = [Extent::zeroed(); INLINE_EXTENTS_CNT];
+    inline[..inline_cnt].copy_from_slice(&self.extents[..inline_cnt]);
+
+    table.extents = inline;
+    table.extent_cnt = self.extents.len() as u32;
+    table.overflow = self.blocks.first().copied().unwrap_or(0);
+    Ok(())
+  }
+
+  ///Returns the extents in file order
+  pub fn extents(&self) -> &[Extent] {
+    &self.extents
+  }
+
+  ///Returns the number of data sectors covered by every extent
+  pub fn sector_cnt(&self) -> u32 {
+    self.extents.iter().map(|extent| extent.len).sum()
+  }
+
+  ///Returns every allocated data sector in file order
+  pub fn sectors(&self) -> Vec<BlockSectorT> {
+    self.extents.iter().filter(|extent| !extent.is_hole()).flat_map(|extent| extent.start..extent.end()).collect()
+  }
+
+  ///Finds the data sector at IDX within the file, if it is covered by an extent. Holes map to sector 0
+  pub fn idx_to_sector(&self, mut idx: u32) -> Option<BlockSectorT> {
+    for extent in &self.extents {
+      if idx < extent.len {
+        return Some(extent.slice(idx, 1).start)
+      }
+      idx -= extent.len;
+    }
+    None
+  }
+
+  ///Appends EXTENT to EXTENTS, merging it into the last extent if they are contiguous or both holes
+  fn push(extents: &mut Vec<Extent>, extent: Extent) {
+    if extent.len == 0 {
+      return
+    }
+
+    match extents.last_mut() {
+      Some(last) if last.is_hole() && extent.is_hole() => last.len += extent.len,
+      Some(last) if !last.is_hole() && last.end() == extent.start => last.len += extent.len,
+      _ => extents.push(extent)
+    }
+  }
+
+  ///Allocates up to CNT zeroed sectors to follow PREV in a file
+  ///
+  ///PREV is grown in place while the sectors after it are free, otherwise the longest run available is taken
+  fn allocate(state: &mut FileSystem, prev: Option<&Extent>, cnt: u32) -> Result<Extent, InodeError> {
+    let in_place = match prev {
+      Some(prev) if !prev.is_hole() => Freemap::allocate_at(state, prev.end(), cnt)?,
+      _ => 0
+    };
+
+    let (start, len) = match in_place {
+      0 => Freemap::allocate_run(state, cnt)?,
+      len => (prev.unwrap().end(), len)
+    };
+
+    //Nothing points at the run yet, so it goes straight back if it can not be zeroed
+    for sector in start..start + len {
+      if let Err(e) = state.cache.write_data(&state.block, sector, &EMPTY_BUFFER) {
+        Freemap::release(state, start, len)?;
+        return Err(e.into())
+      }
+    }
+    Ok(Extent { start, len })
+  }
+
+  ///Appends CNT zeroed data sectors to the end of the list
+  pub fn grow(&mut self, state: &mut FileSystem, mut cnt: u32) -> Result<(), InodeError> {
+    while cnt > 0 {
+      let extent = Self::allocate(state, self.extents.last(), cnt)?;
+      Self::push(&mut self.extents, extent);
+      cnt -= extent.len;
+    }
+    Ok(())
+  }
+
+  ///Allocates zeroed data sectors for every hole in the list
+  pub fn fill_holes(&mut self, state: &mut FileSystem) -> Result<(), InodeError> {
+    let mut extents = Vec::<Extent>::with_capacity(self.extents.len());
+
+    for &extent in &self.extents {
+      if !extent.is_hole() {
+        Self::push(&mut extents, extent);
+        continue;
+      }
+
+      let mut rmn = extent.len;
+
+      while rmn > 0 {
+        let filled = Self::allocate(state, extents.last(), rmn)?;
+        Self::push(&mut extents, filled);
+        rmn -= filled.len;
+      }
+    }
+
+    self.extents = extents;
+    Ok(())
+  }
+
+  ///Allocates a zeroed data sector for the hole at IDX, which may lie past the last extent, returning its sector
+  pub fn fill(&mut self, state: &mut FileSystem, idx: u32) -> Result<BlockSectorT, InodeError> {
+    let covered = self.sector_cnt();
+
+    //Nothing is pushed until the sector is allocated, so a failure leaves the list as it was
+    if idx >= covered {
+      let hole = Extent::hole(idx - covered);
+      let prev = match hole.len {
+        0 => self.extents.last().copied(),
+        _ => Some(hole)
+      };
+      let filled = Self::allocate(state, prev.as_ref(), 1)?;
+      Self::push(&mut self.extents, hole);
+      Self::push(&mut self.extents, filled);
+      return Ok(filled.start)
+    }
+
+    //Split the hole around IDX, preferring the sector right after the extent before it
+    let mut extents = Vec::<Extent>::with_capacity(self.extents.len() + 2);
+    let mut base = 0u32;
+    let mut sector = 0;
+
+    for &extent in &self.extents {
+      if (base..base + extent.len).contains(&idx) {
+        assert!(extent.is_hole(), "Sector {} is not a hole", idx);
+        let rel_idx = idx - base;
+
+        Self::push(&mut extents, Extent::hole(rel_idx));
+        let filled = Self::allocate(state, extents.last(), 1)?;
+        Self::push(&mut extents, filled);
+        Self::push(&mut extents, Extent::hole(extent.len - rel_idx - 1));
+        sector = filled.start;
+      } else {
+        Self::push(&mut extents, extent);
+      }
+      base += extent.len;
+    }
+
+    self.extents = extents;
+    Ok(sector)
+  }
+
+  ///Turns the CNT data sectors starting at FIRST into a hole, adding the sectors it covered to RELEASED
+  pub fn punch(&mut self, first: u32, cnt: u32, released: &mut Vec<Extent>) {
+    let mut extents = Vec::<Extent>::with_capacity(self.extents.len() + 2);
+    let mut base = 0u32;
+
+    for &extent in &self.extents {
+      let lo = first.clamp(base, base + extent.len) - base;
+      let hi = (first + cnt).clamp(base, base + extent.len) - base;
+
+      Self::push(&mut extents, extent.slice(0, lo));
+      Self::push(&mut extents, Extent::hole(hi - lo));
+      Self::push(&mut extents, extent.slice(hi, extent.len - hi));
+
+      if !extent.is_hole() && hi > lo {
+        released.push(extent.slice(lo, hi - lo));
+      }
+      base += extent.len;
+    }
+
+    self.extents = extents;
+    self.trim();
+  }
+
+  ///Points the data sectors of the list, in order, at the contiguous run starting at TARGET, adding the old ones to RELEASED
+  pub fn relocate(&mut self, target: BlockSectorT, released: &mut Vec<Extent>) {
+    let mut extents = Vec::<Extent>::with_capacity(self.extents.len());
+    let mut next = target;
+
+    for &extent in &self.extents {
+      if extent.is_hole() {
+        Self::push(&mut extents, extent);
+        continue
+      }
+      Self::push(&mut extents, Extent { start: next, len: extent.len });
+      released.push(extent);
+      next += extent.len;
+    }
+
+    self.extents = extents;
+  }
+
+  ///Drops trailing holes, which are implied by the length of the INODE
+  fn trim(&mut self) {
+    while self.extents.last().is_some_and(|extent| extent.is_hole()) {
+      self.extents.pop();
+    }
+  }
+
+  ///Keeps the first KEEP data sectors, adding every sector after them to RELEASED
+  pub fn truncate(&mut self, keep: u32, released: &mut Vec<Extent>) {
+    let mut rmn = keep;
+    let mut kept = 0usize;
+
+    for extent in self.extents.iter_mut() {
+      let len = min(rmn, extent.len);
+
+      if len < extent.len {
+        if !extent.is_hole() {
+          released.push(extent.slice(len, extent.len - len));
+        }
+        extent.len = len;
+      }
+      if len > 0 {
+        kept += 1;
+      }
+      rmn -= len;
+    }
+
+    self.extents.truncate(kept);
+    self.trim();
+  }
+
+  ///Adds every data sector and EXTENT BLOCK of the list to RELEASED
+  pub fn release(mut self, released: &mut Vec<Extent>) {
+    self.truncate(0, released);
+    released.extend(self.blocks.into_iter().map(Extent::single));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, mem::size_of, rc::Rc};
+
+  use super::*;
+  use crate::fs::{
+    file_sys::tests::setup_file_sys,
+    fsck::{Fsck, Problem},
+    inode::{InodeFormat, InodeList, MemoryInode}
+  };
+
+  use bytemuck::from_bytes_mut;
+
+  fn open(state: &mut FileSystem, path: &str) -> Rc<RefCell<MemoryInode>> {
+    state.create(path, 0, false).unwrap();
+    let file = state.open(path).unwrap();
+    let inode = file.borrow().inode(state).unwrap();
+    file.borrow().close(state).unwrap();
+    inode
+  }
+
+  fn extents(state: &FileSystem, inode: &Rc<RefCell<MemoryInode>>) -> Vec<Extent> {
+    inode.borrow().extents(&state.block, &state.cache).unwrap().unwrap()
+  }
+
+  #[test]
+  fn test_extent_structs_fit() {
+    assert_eq!(size_of::<ExtentBlock>(), BLOCK_SECTOR_SIZE as usize);
+    assert_eq!(size_of::<ExtentTable>(), 484);
+  }
+
+  #[test]
+  fn test_contiguous_file_is_one_extent() {
+    let mut file_sys = setup_file_sys(2048);
+    file_sys.inode_format = InodeFormat::Extent;
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    let inode = open(&mut file_sys, "a");
+    inode.borrow_mut().reserve(&mut file_sys, 300 * BLOCK_SECTOR_SIZE).unwrap();
+    inode.borrow_mut().write_at(&mut file_sys, &[0xAB], 1, 300 * BLOCK_SECTOR_SIZE - 1).unwrap();
+
+    //Growing again extends the same extent in place
+    inode.borrow_mut().reserve(&mut file_sys, 400 * BLOCK_SECTOR_SIZE).unwrap();
+
+    let runs = extents(&file_sys, &inode);
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].len, 400);
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 400 + 1);
+
+    let mut buffer = [0u8; 1];
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 1, 300 * BLOCK_SECTOR_SIZE - 1).unwrap();
+    assert_eq!(buffer, [0xAB]);
+
+    inode.borrow_mut().shrink(&mut file_sys, 10 * BLOCK_SECTOR_SIZE).unwrap();
+    assert_eq!(extents(&file_sys, &inode)[0].len, 10);
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 10 + 1);
+  }
+
+  #[test]
+  fn test_fragmented_free_space_overflows() {
+    let mut file_sys = setup_file_sys(2048);
+    file_sys.inode_format = InodeFormat::Extent;
+
+    //Leave 100 single sector holes by filling the disk in pairs and removing every other file
+    for i in 0..200 {
+      file_sys.create(&format!("f{}", i), BLOCK_SECTOR_SIZE, false).unwrap();
+    }
+    for i in (0..200).step_by(2) {
+      file_sys.remove(&format!("f{}", i)).unwrap();
+    }
+    let frag_free = file_sys.freemap.num_free_sectors();
+    let largest = file_sys.freemap.inner.longest_run(false).unwrap().1;
+
+    //Ask for more than the largest run so every hole gets used up as its own extent
+    let inode = open(&mut file_sys, "big");
+    let len = (largest + 150) * BLOCK_SECTOR_SIZE;
+    inode.borrow_mut().reserve(&mut file_sys, len).unwrap();
+    inode.borrow_mut().write_at(&mut file_sys, &[0xEF], 1, len - 1).unwrap();
+
+    let runs = extents(&file_sys, &inode);
+    assert!(runs.len() > INLINE_EXTENTS_CNT);
+    assert_eq!(runs.iter().map(|run| run.len).sum::<u32>(), largest + 150);
+
+    let sectors = inode.borrow().data_sectors(&file_sys.block, &file_sys.cache).unwrap();
+    let mut buffer = [0u8; 1];
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 1, len - 1).unwrap();
+    assert_eq!(buffer, [0xEF]);
+    assert_eq!(sectors.len() as u32, largest + 150);
+
+    //Data sectors, the inode and the overflow blocks
+    let overflow_blocks = (runs.len() - INLINE_EXTENTS_CNT).div_ceil(EXTENTS_PER_BLOCK) as u32;
+    assert_eq!(frag_free - file_sys.freemap.num_free_sectors(), largest + 150 + 1 + overflow_blocks);
+
+    let sector = inode.borrow().inode_num();
+    file_sys.remove("big").unwrap();
+    InodeList::close_inode(&mut file_sys, sector).unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), frag_free);
+  }
+
+  #[test]
+  fn test_holes() {
+    let mut file_sys = setup_file_sys(2048);
+    file_sys.inode_format = InodeFormat::Extent;
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    //Writes past EOF only allocate the sectors they touch
+    let inode = open(&mut file_sys, "sparse");
+    for idx in [20, 5, 10] {
+      inode.borrow_mut().write_at(&mut file_sys, &[idx as u8], 1, idx * BLOCK_SECTOR_SIZE).unwrap();
+    }
+
+    let runs = extents(&file_sys, &inode);
+    let layout: Vec<(bool, u32)> = runs.iter().map(|run| (run.is_hole(), run.len)).collect();
+    assert_eq!(layout, [(true, 5), (false, 1), (true, 4), (false, 1), (true, 9), (false, 1)]);
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 3 + 1);
+
+    let mut buffer = [0xFFu8; BLOCK_SECTOR_SIZE as usize];
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, BLOCK_SECTOR_SIZE, 6 * BLOCK_SECTOR_SIZE).unwrap();
+    assert!(buffer.iter().all(|&b| b == 0));
+
+    //Punching merges the freed sector into the holes around it
+    inode.borrow_mut().punch(&mut file_sys, 10 * BLOCK_SECTOR_SIZE, BLOCK_SECTOR_SIZE).unwrap();
+    let layout: Vec<(bool, u32)> = extents(&file_sys, &inode).iter().map(|run| (run.is_hole(), run.len)).collect();
+    assert_eq!(layout, [(true, 5), (false, 1), (true, 14), (false, 1)]);
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 2 + 1);
+
+    //Reserving fills every hole
+    let len = inode.borrow().len();
+    inode.borrow_mut().reserve(&mut file_sys, len).unwrap();
+    assert_eq!(inode.borrow().allocated_sectors(&file_sys.block, &file_sys.cache).unwrap(), 21);
+
+    let mut byte = [0u8];
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut byte, 1, 20 * BLOCK_SECTOR_SIZE).unwrap();
+    assert_eq!(byte, [20]);
+  }
+
+  #[test]
+  fn test_corrupt_chain_fails_to_load() {
+    for cycle in [false, true] {
+      let mut file_sys = setup_file_sys(2048);
+      file_sys.inode_format = InodeFormat::Extent;
+
+      //Single sector holes, so the file needs an overflow chain
+      for i in 0..200 {
+        file_sys.create(&format!("f{}", i), BLOCK_SECTOR_SIZE, false).unwrap();
+      }
+      for i in (0..200).step_by(2) {
+        file_sys.remove(&format!("f{}", i)).unwrap();
+      }
+      let largest = file_sys.freemap.inner.longest_run(false).unwrap().1;
+
+      let inode = open(&mut file_sys, "big");
+      inode.borrow_mut().reserve(&mut file_sys, (largest + 150) * BLOCK_SECTOR_SIZE).unwrap();
+      let sector = inode.borrow().inode_num();
+      InodeList::close_inode(&mut file_sys, sector).unwrap();
+
+      //Either loop the chain back onto itself, or claim more extents than it holds
+      let mut buffer = EMPTY_BUFFER;
+      file_sys.cache.read_to_buffer(&file_sys.block, sector, &mut buffer).unwrap();
+      let table = from_bytes_mut::<ExtentTable>(&mut buffer[..size_of::<ExtentTable>()]);
+      let overflow = table.overflow;
+      table.extent_cnt = match cycle {
+        true => u32::MAX,
+        false => table.extent_cnt + 1000
+      };
+      let table = *table;
+      file_sys.cache.write_from_buffer(&file_sys.block, sector, &buffer).unwrap();
+
+      if cycle {
+        let mut chained = EMPTY_BUFFER;
+        file_sys.cache.read_to_buffer(&file_sys.block, overflow, &mut chained).unwrap();
+        from_bytes_mut::<ExtentBlock>(&mut chained).next = overflow;
+        file_sys.cache.write_from_buffer(&file_sys.block, overflow, &chained).unwrap();
+      }
+
+      assert!(ExtentList::load(&file_sys.block, &file_sys.cache, &table).is_err());
+      let fsck = Fsck::check(&mut file_sys).unwrap();
+      assert!(fsck.problems().contains(&Problem::CorruptInode { sector }));
+      assert!(!fsck.problems().contains(&Problem::LeakedSector { sector }));
+    }
+  }
+}
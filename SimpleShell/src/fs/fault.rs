@@ -0,0 +1,315 @@
+use std::{
+  cell::{
+    Cell,
+    RefCell
+  },
+  io
+};
+
+use crate::fs::{
+  block::{
+    BlockDevice,
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
+  fserrors::controller_errors::ControllerError
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Op {
+  Read,
+  Write
+}
+
+///When a FAULT fires
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Trigger {
+  ///On every OP at the sector
+  Sector(BlockSectorT),
+  ///On every OP once this many OPs of its kind have reached the device
+  After(u32)
+}
+
+///What happens when a FAULT fires
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FaultKind {
+  ///The OP fails without touching the device or the buffer
+  Fail,
+  ///The OP succeeds, but the byte at the offset is flipped in what is read or written
+  Corrupt(usize),
+  ///Only the given number of leading bytes are transferred, then the OP fails
+  Torn(usize)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fault {
+  pub op: Op,
+  pub trigger: Trigger,
+  pub kind: FaultKind
+}
+
+///A BLOCKDEVICE passing every OP through to INNER, except those a FAULT fires on
+pub(crate) struct FaultyDisk<D: BlockDevice> {
+  inner: D,
+  faults: RefCell<Vec<Fault>>,
+  reads: Cell<u32>,
+  writes: Cell<u32>,
+  ///Number of OPs a FAULT fired on
+  injected: Cell<u32>
+}
+
+impl<D: BlockDevice> FaultyDisk<D> {
+  pub fn new(inner: D) -> Self {
+    Self {
+      inner,
+      faults: RefCell::new(Vec::new()),
+      reads: Cell::new(0u32),
+      writes: Cell::new(0u32),
+      injected: Cell::new(0u32)
+    }
+  }
+
+  pub fn inject(&self, fault: Fault) {
+    self.faults.borrow_mut().push(fault);
+  }
+
+  ///Removes every FAULT, so OPs reach INNER untouched again
+  pub fn clear(&self) {
+    self.faults.borrow_mut().clear();
+  }
+
+  pub fn injected(&self) -> u32 {
+    self.injected.get()
+  }
+
+  ///Returns the kind of the first FAULT firing on OP at SECTOR, counting OP either way
+  fn fires(&self, op: Op, sector: BlockSectorT) -> Option<FaultKind> {
+    let cnt = match op {
+      Op::Read => &self.reads,
+      Op::Write => &self.writes
+    };
+
+    let fired = self.faults.borrow().iter()
+      .find(|fault| fault.op == op && match fault.trigger {
+        Trigger::Sector(target) => target == sector,
+        Trigger::After(limit) => cnt.get() >= limit
+      })
+      .map(|fault| fault.kind);
+
+    cnt.set(cnt.get() + 1);
+    if fired.is_some() {
+      self.injected.set(self.injected.get() + 1);
+    }
+    fired
+  }
+}
+
+fn injected_error(op: Op, sector: BlockSectorT) -> ControllerError {
+  io::Error::other(format!("injected {:?} fault at sector {}", op, sector)).into()
+}
+
+impl<D: BlockDevice> BlockDevice for FaultyDisk<D> {
+  fn read(&self, sector: BlockSectorT, buffer: &mut [u8]) -> Result<(), ControllerError> {
+    match self.fires(Op::Read, sector) {
+      None => self.inner.read(sector, buffer),
+      Some(FaultKind::Fail) => Err(injected_error(Op::Read, sector)),
+      Some(FaultKind::Corrupt(offset)) => {
+        self.inner.read(sector, buffer)?;
+        buffer[offset % BLOCK_SECTOR_SIZE as usize] ^= 0xff;
+        Ok(())
+      },
+      Some(FaultKind::Torn(len)) => {
+        let mut whole = [0u8; BLOCK_SECTOR_SIZE as usize];
+        self.inner.read(sector, &mut whole)?;
+
+        let len = len.min(whole.len());
+        buffer[..len].copy_from_slice(&whole[..len]);
+        Err(injected_error(Op::Read, sector))
+      }
+    }
+  }
+
+  fn write(&self, sector: BlockSectorT, buffer: &[u8]) -> Result<(), ControllerError> {
+    match self.fires(Op::Write, sector) {
+      None => self.inner.write(sector, buffer),
+      Some(FaultKind::Fail) => Err(injected_error(Op::Write, sector)),
+      Some(FaultKind::Corrupt(offset)) => {
+        let mut corrupted = [0u8; BLOCK_SECTOR_SIZE as usize];
+        corrupted.copy_from_slice(buffer);
+        corrupted[offset % BLOCK_SECTOR_SIZE as usize] ^= 0xff;
+        self.inner.write(sector, &corrupted)
+      },
+      Some(FaultKind::Torn(len)) => {
+        //The rest of the sector keeps what it held, as if power was lost partway through
+        let mut torn = [0u8; BLOCK_SECTOR_SIZE as usize];
+        self.inner.read(sector, &mut torn)?;
+
+        let len = len.min(torn.len());
+        torn[..len].copy_from_slice(&buffer[..len]);
+        self.inner.write(sector, &torn)?;
+        Err(injected_error(Op::Write, sector))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use rand::{rngs::StdRng, Rng, SeedableRng};
+
+  use super::*;
+  use crate::fs::{
+    block::Block,
+    cache::CacheOptions,
+    device::RamDisk,
+    file_sys::FileSystem,
+    fserrors::FSErrors,
+    fsck::{Fsck, Problem},
+    inode::InodeFormat
+  };
+
+  const SECTORS: u32 = 512;
+  const NAMES: [&str; 6] = ["a", "b", "c", "d", "d/x", "d/y"];
+
+  fn sector(byte: u8) -> [u8; BLOCK_SECTOR_SIZE as usize] {
+    [byte; BLOCK_SECTOR_SIZE as usize]
+  }
+
+  fn mount<'a>(disk: impl BlockDevice + 'a, format: Option<InodeFormat>) -> Result<FileSystem<'a>, FSErrors> {
+    FileSystem::new(Block::new("hd0".to_owned(), String::new(), SECTORS, disk), format, CacheOptions::default())
+  }
+
+  ///Runs CNT random operations on FILE_SYS, carrying on past any that fail
+  fn workload(file_sys: &mut FileSystem, rng: &mut StdRng, cnt: u32) {
+    for _ in 0..cnt {
+      let name = NAMES[rng.gen_range(0..NAMES.len())];
+
+      let _ = match rng.gen_range(0..5) {
+        0 => file_sys.create(name, 0, name == "d"),
+        1 => file_sys.remove(name),
+        //Writing a DIRECTORY as a file would overwrite its entries
+        2 | 3 if name == "d" => continue,
+        2 => {
+          let content = vec![rng.gen::<u8>(); rng.gen_range(1..4 * BLOCK_SECTOR_SIZE as usize)];
+          file_sys.util_append(name, &content, content.len() as u32)
+        },
+        3 => {
+          let content = vec![rng.gen::<u8>(); rng.gen_range(1..2 * BLOCK_SECTOR_SIZE as usize)];
+          file_sys.util_write(name, &content, content.len() as u32)
+        },
+        _ => {
+          let mut buffer = [0u8; 64];
+          file_sys.util_read(name, &mut buffer, 64)
+        }
+      };
+    }
+  }
+
+  #[test]
+  fn test_fail_and_corrupt() {
+    let disk = FaultyDisk::new(RamDisk::new(4));
+    let mut buffer = sector(0);
+
+    disk.inject(Fault { op: Op::Read, trigger: Trigger::Sector(1), kind: FaultKind::Fail });
+    disk.inject(Fault { op: Op::Write, trigger: Trigger::Sector(2), kind: FaultKind::Corrupt(3) });
+
+    assert!(disk.read(1, &mut buffer).is_err());
+    disk.write(1, &sector(5)).unwrap();
+    disk.write(2, &sector(5)).unwrap();
+
+    disk.read(2, &mut buffer).unwrap();
+    assert_eq!(buffer[3], !5);
+    assert_eq!(buffer[4], 5);
+    assert_eq!(disk.injected(), 2);
+
+    disk.clear();
+    disk.read(1, &mut buffer).unwrap();
+    assert_eq!(buffer, sector(5));
+  }
+
+  #[test]
+  fn test_torn_write_after_ops() {
+    let disk = FaultyDisk::new(RamDisk::new(4));
+    let mut buffer = sector(0);
+
+    disk.write(0, &sector(1)).unwrap();
+    disk.inject(Fault { op: Op::Write, trigger: Trigger::After(2), kind: FaultKind::Torn(100) });
+
+    disk.write(1, &sector(1)).unwrap();
+    assert!(disk.write(0, &sector(2)).is_err());
+    assert!(disk.write(1, &sector(2)).is_err());
+
+    disk.read(0, &mut buffer).unwrap();
+    assert!(buffer[..100].iter().all(|&byte| byte == 2));
+    assert!(buffer[100..].iter().all(|&byte| byte == 1));
+  }
+
+  #[test]
+  fn test_filesystem_errors_surface() {
+    let disk = Rc::new(FaultyDisk::new(RamDisk::new(SECTORS)));
+    let mut file_sys = mount(disk.clone(), Some(InodeFormat::Indexed)).unwrap();
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.close().unwrap();
+
+    //Reads of anything not cached fail, and every write fails
+    let mut file_sys = mount(disk.clone(), None).unwrap();
+    disk.inject(Fault { op: Op::Read, trigger: Trigger::After(0), kind: FaultKind::Fail });
+    disk.inject(Fault { op: Op::Write, trigger: Trigger::After(0), kind: FaultKind::Fail });
+
+    assert!(file_sys.open("a").is_err());
+    assert!(file_sys.create("b", 0, false).is_err());
+    assert!(disk.injected() > 0);
+  }
+
+  ///Crashes random workloads with random faults, then checks that every image mounts again cleanly
+  ///
+  ///Faults stop the device for good, as a crash would, so what reached it before must be consistent
+  #[test]
+  fn test_random_faults_leave_image_consistent() {
+    for seed in 0..200u64 {
+      let mut rng = StdRng::seed_from_u64(seed);
+      let format = match seed % 2 {
+        0 => InodeFormat::Indexed,
+        _ => InodeFormat::Extent
+      };
+
+      let base = Rc::new(RamDisk::new(SECTORS));
+      let mut file_sys = mount(base.clone(), Some(format)).unwrap();
+      workload(&mut file_sys, &mut rng, 20);
+      file_sys.close().unwrap();
+
+      let disk = Rc::new(FaultyDisk::new(base.clone()));
+      let kind = match rng.gen_range(0..2) {
+        0 => FaultKind::Fail,
+        _ => FaultKind::Torn(rng.gen_range(0..BLOCK_SECTOR_SIZE as usize))
+      };
+      let after = rng.gen_range(0..200);
+
+      //Reads of a random sector fail too, which must surface as errors rather than bad data written back
+      disk.inject(Fault { op: Op::Read, trigger: Trigger::Sector(rng.gen_range(0..SECTORS)), kind: FaultKind::Fail });
+      //The first FAULT firing wins, so only the write at AFTER is torn
+      disk.inject(Fault { op: Op::Write, trigger: Trigger::After(after + 1), kind: FaultKind::Fail });
+      disk.inject(Fault { op: Op::Write, trigger: Trigger::After(after), kind });
+
+      //The faulty mount is dropped without closing, losing whatever it still held in memory
+      if let Ok(mut file_sys) = mount(disk.clone(), None) {
+        workload(&mut file_sys, &mut rng, 40);
+        let _ = file_sys.close();
+      }
+
+      //Files removed while still open are only freed once closed, so a crash may leave them orphaned.
+      //Anything else would mean a transaction reached the image only in part
+      let mut file_sys = mount(base.clone(), None).unwrap();
+      let fsck = Fsck::check(&mut file_sys).unwrap();
+
+      let unexpected = fsck.problems().iter().filter(|problem| !matches!(problem, Problem::OrphanInode { .. })).collect::<Vec<_>>();
+      assert!(unexpected.is_empty(), "seed {} ({:?} after {} writes): {:?}", seed, kind, after, unexpected);
+
+      fsck.repair(&mut file_sys).unwrap();
+      assert_eq!(Fsck::check(&mut file_sys).unwrap().problems(), &[], "seed {}", seed);
+      file_sys.close().unwrap();
+    }
+  }
+}
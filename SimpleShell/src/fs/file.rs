@@ -7,7 +7,7 @@ use std::{
 };
 
 use crate::fs::{
-  block::Block,
+  block::{Block, BLOCK_SECTOR_SIZE},
   cache::Cache,
   file_sys::FileSystem,
   fserrors::file_errors::FileError,
@@ -85,6 +85,8 @@ impl FileTableEntry {
 pub(crate) struct File {
   inode: Rc<RefCell<MemoryInode>>,
   deny_write: Cell<bool>,
+  append: Cell<bool>,
+  direct: Cell<bool>,
   pos: Cell<u32>
 }
 
@@ -93,6 +95,8 @@ impl File {
     Self {
       inode,
       deny_write: Cell::new(false),
+      append: Cell::new(false),
+      direct: Cell::new(false),
       pos: Cell::new(0u32)
     }
   }
@@ -125,6 +129,25 @@ impl File {
     self.inode.borrow().len()
   }
 
+  ///Returns the number of bytes of data sectors allocated to FILE, which holes make smaller than its length
+  pub fn allocated_len(&self, block: &Block, cache: &Cache) -> Result<u32, FileError> {
+    Ok(self.inode.borrow().allocated_sectors(block, cache)? * BLOCK_SECTOR_SIZE)
+  }
+
+  ///Makes every following WRITE on FILE start at its current length rather than its position
+  pub fn set_append(&self, append: bool) {
+    self.append.set(append)
+  }
+
+  ///Makes every following READ and WRITE of whole sectors on FILE bypass the cache, like O_DIRECT
+  pub fn set_direct(&self, direct: bool) {
+    self.direct.set(direct)
+  }
+
+  pub fn is_direct(&self) -> bool {
+    self.direct.get()
+  }
+
   pub fn seek(&self, ofst: u32) {
     self.pos.set(ofst)
   }
@@ -133,6 +156,11 @@ impl File {
     self.pos.get()
   }
 
+  ///Returns whether the user UID in group GID is granted ACCESS to FILE
+  pub fn check_access(&self, uid: u16, gid: u16, access: u16) -> bool {
+    self.inode.borrow().check_access(uid, gid, access)
+  }
+
   pub fn inode(&self, state: &mut FileSystem) -> Result<Rc<RefCell<MemoryInode>>, FileError> {
     let sector_num = self.inode.borrow().inode_num();
     let inode = state.inode_list.open_inode(&state.block, &state.cache, sector_num)?;
@@ -143,26 +171,48 @@ impl File {
   pub fn read(&self, block: &Block, cache: &Cache, buffer: &mut [u8], len: u32) -> Result<u32, FileError> {
     let pos = self.pos.get();
 
-    let bytes_read = self.inode.borrow_mut().read_at(block, cache, buffer, len, pos)?;
+    let bytes_read = self.read_at(block, cache, buffer, len, pos)?;
     self.pos.set(pos + bytes_read);
     Ok(bytes_read)
   }
 
   pub fn read_at(&self, block: &Block, cache: &Cache, buffer: &mut [u8], len: u32, ofst: u32) -> Result<u32, FileError> {
-    let bytes_read = self.inode.borrow_mut().read_at(block, cache, buffer, len, ofst)?;
+    let bytes_read = match self.direct.get() {
+      true => self.inode.borrow().read_direct(block, cache, buffer, len, ofst)?,
+      false => self.inode.borrow().read_at(block, cache, buffer, len, ofst)?
+    };
+    self.inode.borrow_mut().touch_access(block, cache)?;
     Ok(bytes_read)
   }
 
   pub fn write(&self, state: &mut FileSystem, buffer: &[u8], len: u32) -> Result<u32, FileError> {
-    let pos = self.pos.get();
+    let pos = match self.append.get() {
+      true => self.len(),
+      false => self.pos.get()
+    };
 
-    let bytes_wrote = self.inode.borrow_mut().write_at(state, buffer, len, pos)?;
+    let bytes_wrote = self.write_at(state, buffer, len, pos)?;
     self.pos.set(pos + bytes_wrote);
     Ok(bytes_wrote)
   }
 
   pub fn write_at(&self, state: &mut FileSystem, buffer: &[u8], len: u32, ofst: u32) -> Result<u32, FileError> {
-    let bytes_wrote = self.inode.borrow_mut().write_at(state, buffer, len, ofst)?;
+    let bytes_wrote = match self.direct.get() {
+      true => self.inode.borrow_mut().write_direct(state, buffer, len, ofst)?,
+      false => self.inode.borrow_mut().write_at(state, buffer, len, ofst)?
+    };
     Ok(bytes_wrote)
   }
+
+  ///Sets the length of FILE to LEN, releasing sectors past it or extending it with a hole
+  pub fn truncate(&self, state: &mut FileSystem, len: u32) -> Result<(), FileError> {
+    self.inode.borrow_mut().truncate(state, len)?;
+    Ok(())
+  }
+
+  ///Deallocates LEN bytes starting at OFST, leaving a hole that reads as zeros
+  pub fn punch(&self, state: &mut FileSystem, ofst: u32, len: u32) -> Result<(), FileError> {
+    self.inode.borrow_mut().punch(state, ofst, len)?;
+    Ok(())
+  }
 }
@@ -1,22 +1,31 @@
-use std::{borrow::Borrow, cell::{Ref, RefCell}, fs::OpenOptions, io::{BufReader, BufWriter, Read, Write}, os::unix::fs::MetadataExt, path::Path, rc::Rc};
+use std::{borrow::Borrow, cell::{Ref, RefCell}, collections::{BTreeMap, BTreeSet}, fs::OpenOptions, io::{stdout, BufReader, BufWriter, LineWriter, Read, Write}, os::unix::fs::MetadataExt, rc::Rc};
 
 use bytemuck::from_bytes;
 
 use crate::fs::{
+  ata::AtaDisk,
   block::Block,
-  cache::Cache,
+  cache::{Cache, CacheOptions},
   directory::MemoryDirectory,
   file::FileTable,
   freemap::Freemap,
-  fserrors::FSErrors,
-  inode::InodeList, util::hex_dump
+  frag::FragReport,
+  fsck::Fsck,
+  fserrors::{dir_errors::DirError, FSErrors},
+  inode::InodeList,
+  iostat::{format_bytes, ORIGINS},
+  journal::{Journal, JOURNAL_SECTOR},
+  map::SectorMap,
+  superblock::{check_cache_size, FormatOptions, Superblock, FEATURE_JOURNAL, SUPERBLOCK_SECTOR},
+  util::hex_dump
 };
 
-use super::{block::BLOCK_SECTOR_SIZE, directory::split_path, file::File, inode::{DiskInode, MemoryInode, INODE_SIGNATURE}};
+use super::{block::{BlockSectorT, BLOCK_SECTOR_SIZE}, directory::split_path, file::File, inode::{DiskInode, InodeFormat, MemoryInode, ACCESS_READ, ACCESS_WRITE}};
 
 pub const FREE_MAP_SECTOR: u32 = 0u32;
 pub const ROOT_DIR_SECTOR: u32 = 1u32;
-pub const MAX_FILES_PER_DIRECTORY: u32 = 1000u32;
+///Number of bytes copied between the host and the file system at a time, so that runs of sectors move together
+const COPY_CHUNK: u32 = 64 * BLOCK_SECTOR_SIZE;
 
 pub struct FileSystem<'file_sys> {
   pub block: Block<'file_sys>,
@@ -24,94 +33,219 @@ pub struct FileSystem<'file_sys> {
   pub file_table: FileTable,
   pub freemap: Freemap,
   pub inode_list: InodeList,
-  pub cwd: Option<Rc<RefCell<MemoryDirectory>>>
+  pub cwd: Option<Rc<RefCell<MemoryDirectory>>>,
+  pub uid: u16,
+  pub gid: u16,
+  pub inode_format: InodeFormat
 }
 
 impl<'file_sys> FileSystem<'file_sys> {
-  pub fn new(block: Block<'file_sys>, format: bool) -> Result<Self, FSErrors> {
+  ///Mounts the file system on BLOCK with a CACHE as CACHE_OPTIONS describe, first formatting it with new INODEs in
+  ///FORMAT if one is given
+  pub fn new(block: Block<'file_sys>, format: Option<InodeFormat>, cache_options: CacheOptions) -> Result<Self, FSErrors> {
+    let options = format.map(|inode_format| FormatOptions { inode_format, ..FormatOptions::default() });
+    Self::with_options(block, options, cache_options)
+  }
+
+  ///Mounts the file system on BLOCK, first formatting it as OPTIONS describe if given
+  ///
+  ///Otherwise BLOCK must hold a SUPERBLOCK this version can mount. The CACHE is sized by the SUPERBLOCK unless
+  ///CACHE_OPTIONS give a size
+  pub fn with_options(block: Block<'file_sys>, options: Option<FormatOptions>, cache_options: CacheOptions) -> Result<Self, FSErrors> {
     let block_size = block.get_size();
 
+    let superblock = match &options {
+      Some(options) => Superblock::new(block_size, options)?,
+      None => Superblock::read(&block)?
+    };
+
+    let cache_size = cache_options.size.unwrap_or(superblock.cache_size);
+    check_cache_size(cache_size)?;
+
     let mut file_sys = Self {
       block,
-      cache: Cache::new(),
+      cache: Cache::new(cache_size as usize, cache_options.policy, cache_options.mode, cache_options.write_behind),
       freemap: Freemap::new(block_size),
       file_table: FileTable::new(),
       inode_list: InodeList::new(),
-      cwd: None
+      cwd: None,
+      uid: 0u16,
+      gid: 0u16,
+      inode_format: InodeFormat::default()
     };
 
-    if format {
-      Self::format(&mut file_sys)?;
+    if options.is_some() {
+      Self::format(&mut file_sys, &superblock)?;
+    }
+
+    //A transaction committed before a crash is finished before anything else is read
+    if superblock.has_features(FEATURE_JOURNAL) {
+      let replayed = file_sys.cache.open_journal(&file_sys.block)?;
+      if replayed > 0 {
+        println!("Replayed {} sectors from the journal", replayed);
+      }
     }
 
     Freemap::open_from_file(&mut file_sys)?;
+
+    //New INODEs take the format the root directory was created with
+    let root_inode = file_sys.inode_list.open_inode(&file_sys.block, &file_sys.cache, ROOT_DIR_SECTOR)?;
+    file_sys.inode_format = root_inode.as_ref().borrow().format();
+    InodeList::close_inode(&mut file_sys, ROOT_DIR_SECTOR)?;
+
     println!("Number of free sectors: {}", file_sys.freemap.num_free_sectors());
 
     Ok(file_sys)
   }
 
-  fn format(&mut self) -> Result<(), FSErrors> {
+  ///Creates a sparse host file at PATH of SECTORS sectors, and formats it as OPTIONS describe
+  pub fn mkfs(path: &str, sectors: BlockSectorT, options: FormatOptions) -> Result<(), FSErrors> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.set_len(sectors as u64 * BLOCK_SECTOR_SIZE as u64)?;
+    drop(file);
+
+    let disk = AtaDisk::new(path, 0, 0)?;
+    let block = Block::new(disk.name().to_owned(), path.to_owned(), sectors, disk);
+
+    let mut file_sys = Self::with_options(block, Some(options), CacheOptions::default())?;
+    file_sys.close()
+  }
+
+  fn format(&mut self, superblock: &Superblock) -> Result<(), FSErrors> {
     println!("Formatting file system...");
+    self.inode_format = superblock.inode_format();
+
+    self.freemap.inner.set(SUPERBLOCK_SECTOR, true);
+    superblock.write(&self.block)?;
+
+    //The journal region is reserved right after the SUPERBLOCK
+    if superblock.journal_size > 0 {
+      self.freemap.inner.set_multiple(JOURNAL_SECTOR, superblock.journal_size, true);
+      Journal::create_on_disk(&self.block, superblock.journal_size)?;
+    }
+
     Freemap::create_on_disk(self)?;
-    MemoryDirectory::new_on_disk(self, ROOT_DIR_SECTOR, MAX_FILES_PER_DIRECTORY)?;
+    MemoryDirectory::new_on_disk_with_capacity(self, ROOT_DIR_SECTOR, superblock.root_capacity)?;
+
+    //Every user may create files in the root directory
+    let root_inode = self.inode_list.open_inode(&self.block, &self.cache, ROOT_DIR_SECTOR)?;
+    root_inode.borrow_mut().set_mode(&self.block, &self.cache, 0o777)?;
+    InodeList::close_inode(self, ROOT_DIR_SECTOR)?;
+
     Freemap::close(self)?;
     Ok(())
   }
 
+  ///Acts as the user UID in group GID for all following operations. UID 0 bypasses permission checks
+  pub fn set_user(&mut self, uid: u16, gid: u16) {
+    self.uid = uid;
+    self.gid = gid;
+  }
+
+  ///Checks that the current user is granted ACCESS to INODE, closing INODE if not
+  fn check_access(&mut self, inode: &Rc<RefCell<MemoryInode>>, access: u16, path: &str) -> Result<(), FSErrors> {
+    if inode.as_ref().borrow().check_access(self.uid, self.gid, access) {
+      return Ok(())
+    }
+
+    let sector = inode.as_ref().borrow().inode_num();
+    InodeList::close_inode(self, sector)?;
+    Err(FSErrors::PermissionDenied(path.to_string()))
+  }
+
 
   pub fn close(&mut self) -> Result<(), FSErrors> {
+    FileTable::close(self)?;
+    if let Some(cwd) = self.cwd.take() {
+      cwd.as_ref().borrow().close(self)?;
+    }
     Freemap::close(self)?;
     Cache::close(&self.cache, &self.block)?;
-    FileTable::close(self)?;
     Ok(())
   }
 
+  ///Writes every dirty sector in the CACHE back to BLOCK, keeping them cached
+  pub fn sync(&self) -> Result<(), FSErrors> {
+    self.cache.flush(&self.block)?;
+    Ok(())
+  }
+
+  ///Creates a file, or an empty directory if IS_DIR, at PATH
+  ///
+  ///Directories ignore INIT_SIZE and grow as entries are added to them
   pub fn create(&mut self, path: &str, init_size: u32, is_dir: bool) -> Result<(), FSErrors> {
-    let (_, suffix) = split_path(path);
-    let dir = MemoryDirectory::open_root(self)?;
+    Journal::transaction(self, |state| {
+      let (prefix, suffix) = split_path(path);
+      let dir = MemoryDirectory::open_path(state, prefix)?;
+      state.check_access(&dir.as_ref().borrow().get_inode(), ACCESS_WRITE, path)?;
+
+      let sector = Freemap::allocate(state, 1)?;
+      let created = match is_dir {
+        true => MemoryDirectory::new_on_disk(state, sector).map_err(FSErrors::DirError),
+        false => DiskInode::new(state, sector, init_size, false).map_err(FSErrors::InodeError)
+      };
 
-    let sector = Freemap::allocate(self, 1)?;
-    if let Err(e) = DiskInode::new(self, sector, init_size, is_dir) {
-      Freemap::release(self, sector, 1)?;
-      return Err(FSErrors::InodeError(e));
-    }
+      let result = created.and_then(|_| {
+        MemoryDirectory::add(dir.borrow_mut(), state, suffix, sector, is_dir).map_err(FSErrors::DirError)
+      });
 
-    if let Err(e) = MemoryDirectory::add(dir.borrow_mut(), self, suffix, sector, is_dir) {
-      Freemap::release(self, sector, 1)?;
-      return Err(FSErrors::DirError(e));
-    }
+      //The new INODE is on disk even if it failed part way, and removing it gives back
+      //the data sectors it got along with SECTOR once it is closed
+      if result.is_err() {
+        let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+        inode.borrow_mut().remove();
+        InodeList::close_inode(state, sector)?;
+      }
 
-    Ok(())
+      dir.as_ref().borrow().close(state)?;
+      result
+    })
   }
 
+  ///Opens the file at PATH for reading
   pub fn open(&mut self, path: &str) -> Result<Rc<RefCell<File>>, FSErrors> {
-    let (_, suffix) = split_path(path);
-    let dir = MemoryDirectory::open_root(self)?;
-    let inode;
+    let inode = self.open_inode(path)?;
+    self.check_access(&inode, ACCESS_READ, path)?;
+
+    Ok(Rc::new(RefCell::new(File::open(inode))))
+  }
+
+  ///Opens the INODE at PATH without checking permissions
+  fn open_inode(&mut self, path: &str) -> Result<Rc<RefCell<MemoryInode>>, FSErrors> {
+    let (prefix, suffix) = split_path(path);
+    let dir = MemoryDirectory::open_path(self, prefix)?;
 
-    match suffix.len() {
+    let inode = match suffix.len() {
       0 => {
-        inode = dir.as_ref().borrow().get_inode();
+        Ok(dir.as_ref().borrow().get_inode())
       },
       _ => {
-        inode = dir.as_ref().borrow().search(self, path)?;
+        let inode = dir.as_ref().borrow().search(self, suffix);
+        dir.as_ref().borrow().close(self)?;
+        inode
       }
-    }
-    Ok(Rc::new(RefCell::new(File::open(inode))))
+    }?;
+    Ok(inode)
   }
 
   pub fn remove(&mut self, path: &str) -> Result<(), FSErrors> {
-    let (prefix, suffix) = split_path(path);
-    let dir = MemoryDirectory::open_path(self, prefix)?;
-
-    MemoryDirectory::remove(dir.borrow_mut(), self, suffix)?;
-    Ok(())
+    Journal::transaction(self, |state| {
+      let (prefix, suffix) = split_path(path);
+      let dir = MemoryDirectory::open_path(state, prefix)?;
+      state.check_access(&dir.as_ref().borrow().get_inode(), ACCESS_WRITE, path)?;
+
+      let result = MemoryDirectory::remove(dir.borrow_mut(), state, suffix);
+      dir.as_ref().borrow().close(state)?;
+      Ok(result?)
+    })
   }
 
   pub fn chdir(&mut self, path: &str) -> Result<(), FSErrors> {
     let dir = MemoryDirectory::open_path(self, path)?;
 
-    self.cwd = Some(dir);
+    if let Some(cwd) = self.cwd.replace(dir) {
+      cwd.as_ref().borrow().close(self)?;
+    }
     Ok(())
   }
 
@@ -177,27 +311,80 @@ impl<'file_sys> FileSystem<'file_sys> {
     if name.len() >= 255 {
       return Err(FSErrors::InvalidName(name.to_string(), name.len()))
     }
-    self.create(name, len, false)
+    self.create(name, len, is_dir)
   }
 
-  pub fn util_write(&mut self, name: &str, buffer: &[u8], len: u32) -> Result<(), FSErrors> {
-    let opened = FileTable::get_by_name(&self.file_table, name);
+  ///Reclaims the free entries at the end of the directory at PATH
+  pub fn util_compact(&mut self, path: &str) -> Result<(), FSErrors> {
+    let dir = MemoryDirectory::open_path(self, path)?;
 
-    let file = match opened {
-      Some(file) => {
-        file
-      },
-      None => {
-        let file = self.open(name)?;
-        FileTable::add_by_name(&mut self.file_table, file.clone(), name);
-        file
+    let reclaimed = MemoryDirectory::compact(dir.borrow_mut(), self);
+    dir.as_ref().borrow().close(self)?;
+    println!("Reclaimed {} directory entries", reclaimed?);
+    Ok(())
+  }
+
+  ///Returns the FILE at NAME from the FILE TABLE, opening and adding it if the user may write it
+  ///
+  ///A FILE the user may not write is closed again rather than left open in the FILE TABLE
+  fn open_for_write(&mut self, name: &str) -> Result<Rc<RefCell<File>>, FSErrors> {
+    if let Some(file) = FileTable::get_by_name(&self.file_table, name) {
+      let allowed = file.as_ref().borrow().check_access(self.uid, self.gid, ACCESS_WRITE);
+      return match allowed {
+        true => Ok(file),
+        false => Err(FSErrors::PermissionDenied(name.to_string()))
       }
-    };
+    }
+
+    let file = File::open(self.open_inode(name)?);
+    if !file.check_access(self.uid, self.gid, ACCESS_WRITE) {
+      file.close(self)?;
+      return Err(FSErrors::PermissionDenied(name.to_string()))
+    }
 
+    let file = Rc::new(RefCell::new(file));
+    FileTable::add_by_name(&mut self.file_table, file.clone(), name);
+    Ok(file)
+  }
+
+  pub fn util_write(&mut self, name: &str, buffer: &[u8], len: u32) -> Result<(), FSErrors> {
+    let file = self.open_for_write(name)?;
     file.borrow_mut().write(self, buffer, len)?;
     Ok(())
   }
 
+  ///Sets the length of the file at NAME to LEN, shrinking it or extending it with a hole
+  pub fn util_truncate(&mut self, name: &str, len: u32) -> Result<(), FSErrors> {
+    let file = self.open_for_write(name)?;
+    file.as_ref().borrow().truncate(self, len)?;
+    Ok(())
+  }
+
+  ///Deallocates LEN bytes of the file at NAME starting at OFST, leaving a hole that reads as zeros
+  pub fn util_punch(&mut self, name: &str, ofst: u32, len: u32) -> Result<(), FSErrors> {
+    let file = self.open_for_write(name)?;
+    file.as_ref().borrow().punch(self, ofst, len)?;
+    Ok(())
+  }
+
+  ///Writes LEN bytes of BUFFER to the end of the file at NAME
+  ///
+  ///A separate append-mode handle is used, so the position kept in the FILETABLE is left untouched
+  pub fn util_append(&mut self, name: &str, buffer: &[u8], len: u32) -> Result<(), FSErrors> {
+    let file = File::open(self.open_inode(name)?);
+    file.set_append(true);
+
+    if !file.check_access(self.uid, self.gid, ACCESS_WRITE) {
+      file.close(self)?;
+      return Err(FSErrors::PermissionDenied(name.to_string()))
+    }
+
+    let result = file.write(self, buffer, len);
+    file.close(self)?;
+    result?;
+    Ok(())
+  }
+
   pub fn util_read(&mut self, name: &str, buffer: &mut [u8], len: u32) -> Result<(), FSErrors> {
     let opened = FileTable::get_by_name(&self.file_table, name);
 
@@ -232,8 +419,9 @@ impl<'file_sys> FileSystem<'file_sys> {
 
     let curr_ofst = file.as_ref().borrow().tell();
     let len = file.as_ref().borrow().len();
+    let allocated = file.as_ref().borrow().allocated_len(&self.block, &self.cache)?;
     file.as_ref().borrow().seek(curr_ofst);
-    println!("Size of file: {} is {} bytes", name, len);
+    println!("Size of file: {} is {} bytes ({} bytes allocated)", name, len, allocated);
     Ok(())
   }
 
@@ -255,6 +443,62 @@ impl<'file_sys> FileSystem<'file_sys> {
     Ok(())
   }
 
+  ///Prints the metadata of the file or directory at PATH
+  pub fn util_stat(&mut self, path: &str) -> Result<(), FSErrors> {
+    let inode = self.open_inode(path)?;
+
+    {
+      let inode = inode.as_ref().borrow();
+      let mode = inode.mode();
+      let (uid, gid) = inode.owner();
+      let (ctime, mtime, atime) = inode.times();
+
+      let perms: String = (0..9).rev().map(|bit| {
+        match mode & (1 << bit) {
+          0 => '-',
+          _ => ['x', 'w', 'r'][bit % 3]
+        }
+      }).collect();
+
+      println!("File: {}", path);
+      println!("Inode: {}  Type: {}  Size: {}", inode.inode_num(), if inode.is_dir() { "directory" } else { "file" }, inode.len());
+      println!("Mode: {:04o} ({}{})  Uid: {}  Gid: {}", mode, if inode.is_dir() { 'd' } else { '-' }, perms, uid, gid);
+      println!("Created: {}  Modified: {}  Accessed: {}", ctime, mtime, atime);
+    }
+
+    let sector = inode.as_ref().borrow().inode_num();
+    InodeList::close_inode(self, sector)?;
+    Ok(())
+  }
+
+  ///Sets the permission bits of PATH to MODE. Only its owner (or UID 0) may do so
+  pub fn util_chmod(&mut self, path: &str, mode: u16) -> Result<(), FSErrors> {
+    let inode = self.open_inode(path)?;
+    let sector = inode.as_ref().borrow().inode_num();
+
+    let result = match self.uid == 0 || inode.as_ref().borrow().owner().0 == self.uid {
+      true => inode.borrow_mut().set_mode(&self.block, &self.cache, mode).map_err(FSErrors::InodeError),
+      false => Err(FSErrors::PermissionDenied(path.to_string()))
+    };
+
+    InodeList::close_inode(self, sector)?;
+    result
+  }
+
+  ///Gives PATH to the user UID in group GID. Only UID 0 may do so
+  pub fn util_chown(&mut self, path: &str, uid: u16, gid: u16) -> Result<(), FSErrors> {
+    if self.uid != 0 {
+      return Err(FSErrors::PermissionDenied(path.to_string()))
+    }
+
+    let inode = self.open_inode(path)?;
+    let sector = inode.as_ref().borrow().inode_num();
+
+    let result = inode.borrow_mut().set_owner(&self.block, &self.cache, uid, gid);
+    InodeList::close_inode(self, sector)?;
+    Ok(result?)
+  }
+
   pub fn util_close(&mut self, name: &str) -> Result<(), FSErrors> {
     FileTable::remove_by_name(self, name)?;
     Ok(())
@@ -265,32 +509,110 @@ impl<'file_sys> FileSystem<'file_sys> {
     println!("Number of free sectors: {}", num_free_sectors);
   }
 
-  pub fn util_copy_in(&mut self, name: &str) -> Result<(), FSErrors> {
+  ///Writes every dirty sector in the CACHE back to disk, printing how many there were
+  pub fn util_sync(&self) -> Result<(), FSErrors> {
+    let dirty = self.cache.stats().dirty;
+    self.sync()?;
+    println!("Wrote back {} dirty sectors of {}", dirty, self.block.get_name());
+    Ok(())
+  }
+
+  ///Prints the hit and miss counters of the CACHE, then zeroes them if RESET
+  pub fn util_cachestats(&self, reset: bool) {
+    let stats = self.cache.stats();
+
+    println!("Policy: {:?}  Mode: {:?}  Capacity: {} sectors  Filled: {}  Dirty: {}", stats.policy, stats.mode, stats.capacity, stats.filled, stats.dirty);
+    println!("Hits: {}  Misses: {}  Hit rate: {:.1}%", stats.hits, stats.misses, stats.hit_rate());
+    println!("Read ahead: {} sectors  Write-behinds: {}  Direct: {} sectors", stats.prefetched, stats.write_behinds, stats.direct);
+
+    if reset {
+      self.cache.reset_stats();
+    }
+  }
+
+  ///Prints the sectors read and written on BLOCK by origin, along with the hits and misses of the CACHE, then zeroes
+  ///both if RESET
+  pub fn util_iostat(&self, reset: bool) {
+    let io = self.block.io_stats();
+    let cache = self.cache.stats();
+
+    println!("{}: Read {} sectors ({})  Written {} sectors ({})", self.block.get_name(),
+      io.total_reads(), format_bytes(io.total_reads()), io.total_writes(), format_bytes(io.total_writes()));
+    println!("Cache hits: {}  Misses: {}  Hit rate: {:.1}%", cache.hits, cache.misses, cache.hit_rate());
+    println!("{:<12}{:>10}{:>10}", "Origin", "Reads", "Writes");
+    for origin in ORIGINS {
+      println!("{:<12}{:>10}{:>10}", origin, io.reads(origin), io.writes(origin));
+    }
+
+    for (name, metadata) in [("Metadata", true), ("Data", false)] {
+      let (reads, writes) = io.totals(metadata);
+      println!("{}: {} read  {} written", name, format_bytes(reads), format_bytes(writes));
+    }
+
+    if reset {
+      self.block.reset_io_stats();
+      self.cache.reset_stats();
+    }
+  }
+
+  ///Starts logging every sector access on BLOCK to the host file at PATH, or to stdout if there is none.
+  ///Stops logging if not ON
+  pub fn util_trace(&self, on: bool, path: Option<&str>) -> Result<(), FSErrors> {
+    let trace: Option<Box<dyn Write>> = match (on, path) {
+      (false, _) => None,
+      (true, Some(path)) => Some(Box::new(LineWriter::new(OpenOptions::new().append(true).create(true).open(path)?))),
+      (true, None) => Some(Box::new(stdout()))
+    };
+
+    self.block.set_trace(trace);
+    Ok(())
+  }
+
+  ///Prints what every sector holds as a grid WIDTH sectors wide, limited to the sectors owned by PATH if given
+  pub fn util_map(&mut self, path: Option<&str>, width: u32) -> Result<(), FSErrors> {
+    let mut map = SectorMap::collect(self)?;
+
+    if let Some(path) = path {
+      let inode = self.open_inode(path)?;
+      let sector = inode.as_ref().borrow().inode_num();
+      InodeList::close_inode(self, sector)?;
+
+      let (data, owned) = SectorMap::inode_sectors(self, sector)?;
+      map.filter(&data.into_iter().chain(owned).chain([sector]).collect());
+    }
+
+    print!("{}", map.render(width));
+    Ok(())
+  }
+
+  ///Returns whether copying LEN bytes is better done with direct I/O, being more than the CACHE holds
+  ///
+  ///Copying it through the CACHE would only evict everything else for sectors that are not read again
+  fn copies_direct(&self, len: u64) -> bool {
+    len > self.cache.capacity() as u64 * BLOCK_SECTOR_SIZE as u64
+  }
+
+  ///Copies the host file at NAME into a new file at TARGET
+  pub fn util_copy_in(&mut self, name: &str, target_file_name: &str) -> Result<(), FSErrors> {
     let source_file = OpenOptions::new().read(true).open(name)?;
     let source_file_size = source_file.metadata().unwrap().size(); //TODO: u32
 
-    let target_file_name = Path::new(name)
-      .file_name()
-      .and_then(|name|{
-        name.to_str()
-      })
-      .ok_or_else(|| {
-        FSErrors::InvalidName(name.to_string(), name.len())
-      })?;
-
     println!("Source File Name: {}", name);
     println!("Target File Name: {}", target_file_name);
     println!("Size of source file: {}", source_file_size);
 
     self.create(target_file_name, 10, false)?;
     let target_file = self.open(target_file_name)?;
+    target_file.borrow_mut().set_direct(self.copies_direct(source_file_size));
 
     let mut reader = BufReader::new(source_file);
-    let mut buffer = [0u8; 1024];
+    let mut buffer = Vec::with_capacity(COPY_CHUNK as usize);
     let mut bytes_written = 0;
 
     loop {
-      let bytes_read = reader.read(&mut buffer)? as u32;
+      //Whole chunks are read, so every write but the last stays aligned to sectors
+      buffer.clear();
+      let bytes_read = (&mut reader).take(COPY_CHUNK as u64).read_to_end(&mut buffer)? as u32;
 
       if bytes_read == 0 {
         break;
@@ -309,7 +631,8 @@ impl<'file_sys> FileSystem<'file_sys> {
     Ok(())
   }
 
-  pub fn util_copy_out(&mut self, name: &str) -> Result<(), FSErrors> {
+  ///Copies the file at NAME out to the host file at TARGET, replacing what it held
+  pub fn util_copy_out(&mut self, name: &str, target: &str) -> Result<(), FSErrors> {
     let opened = FileTable::get_by_name(&self.file_table, name);
 
     let (source_file, close) = match opened {
@@ -323,24 +646,32 @@ impl<'file_sys> FileSystem<'file_sys> {
       }
     };
 
-    let target_file = OpenOptions::new().write(true).create(true).open(name)?;
+    let target_file = OpenOptions::new().write(true).create(true).truncate(true).open(target)?;
+
+    let was_direct = source_file.borrow_mut().is_direct();
+    let len = source_file.borrow_mut().len();
+    source_file.borrow_mut().set_direct(self.copies_direct(len as u64));
 
     let mut writer = BufWriter::new(target_file);
-    let mut buffer = [0u8; 1024];
-    let mut bytes_written = 0;
+    let mut buffer = vec![0u8; COPY_CHUNK as usize];
     let mut ofst = 0;
 
-    loop {
-      let bytes_read = source_file.borrow_mut().read_at(&self.block, &self.cache, &mut buffer, 1024, ofst)?;
+    let copied = (|| -> Result<(), FSErrors> {
+      loop {
+        let bytes_read = source_file.borrow_mut().read_at(&self.block, &self.cache, &mut buffer, COPY_CHUNK, ofst)?;
 
-      if bytes_read == 0 {
-        break;
+        if bytes_read == 0 {
+          return Ok(())
+        }
+
+        writer.write_all(&buffer[..bytes_read as usize])?;
+        ofst += bytes_read;
       }
+    })();
 
-      let actual_bytes_written = writer.write(&buffer)? as u32;
-      bytes_written += actual_bytes_written;
-      ofst += actual_bytes_written;
-    }
+    //The file may be open elsewhere, which expects it as it was
+    source_file.borrow_mut().set_direct(was_direct);
+    copied?;
 
     if close {
       FileTable::remove_by_name(self, name)?;
@@ -396,114 +727,679 @@ impl<'file_sys> FileSystem<'file_sys> {
     Ok(())
   }
 
-  pub fn util_frag_degree(&mut self) -> Result<(), FSErrors> {
-    let root = MemoryDirectory::open_root(self)?;
-    let entry_names = root.as_ref().borrow().read_names(self)?;
+  ///Reports the extents of every file and directory and a histogram of the free runs, as a table or as JSON
+  pub fn util_frag_degree(&mut self, json: bool) -> Result<(), FSErrors> {
+    let report = FragReport::collect(self)?;
 
-    let (fragmented_files, total_files) = entry_names.iter().try_fold((0, 0), |acc, name| -> Result<(i32, i32), FSErrors> {
-      let opened = FileTable::get_by_name(&self.file_table, name);
+    match json {
+      true => println!("{}", report.to_json()),
+      false => print!("{}", report)
+    }
+    Ok(())
+  }
 
-      let (file, close) = match opened {
-        Some(file) => (file.clone(), false),
-        None => {
-          let file = self.open(name)?;
-          FileTable::add_by_name(&mut self.file_table, file.clone(), name);
-          (file, true)
+  ///Moves the data sectors of every file and directory into a contiguous run, printing progress as it goes
+  ///
+  ///Each one is copied into its new run and repointed at it in a single transaction, so an interruption leaves it
+  ///either moved or untouched. Anything without a free run large enough to hold it, or whose pointers would not fit
+  ///in the journal, is left where it is
+  pub fn util_defrag(&mut self) -> Result<(), FSErrors> {
+    let tree = self.walk_tree()?;
+    let (mut moved, mut skipped) = (0, 0);
+
+    for (i, (path, sector, _)) in tree.iter().enumerate() {
+      let inode = self.inode_list.open_inode(&self.block, &self.cache, *sector)?;
+
+      let result = (|| -> Result<String, FSErrors> {
+        let data_sectors = inode.as_ref().borrow().data_sectors(&self.block, &self.cache)?;
+        let cnt = data_sectors.len() as u32;
+
+        if data_sectors.windows(2).all(|window| window[1] == window[0] + 1) {
+          return Ok("already contiguous".to_string())
         }
-      };
 
-      let memory_inode = file.as_ref().borrow().inode(self)?;
-      let data_sectors = memory_inode.as_ref().borrow().data_sectors(&self.block, &self.cache)?;
+        let Ok(target) = self.freemap.inner.scan(0, cnt, false) else {
+          skipped += 1;
+          return Ok(format!("no free run of {} sectors, skipped", cnt))
+        };
+
+        //The copies go to free sectors and need no logging, but the INODE, its pointer blocks, the FREEMAP sectors
+        //covering either run and the INODE of the FREEMAP do
+        let owned = inode.as_ref().borrow().owned_sectors(&self.block, &self.cache)?;
+        let bits = BLOCK_SECTOR_SIZE * 8;
+        let bitmap_sectors: BTreeSet<_> = owned.iter().copied().chain(target..target + cnt).map(|sector| sector / bits).collect();
+        let logged = 2 + (owned.len() - data_sectors.len()) as u32 + bitmap_sectors.len() as u32;
+        let capacity = self.cache.journal_capacity();
+
+        if capacity > 0 && logged > capacity {
+          skipped += 1;
+          return Ok(format!("needs {} journal sectors but a transaction holds {}, skipped", logged, capacity))
+        }
 
-      let fragmented = data_sectors.windows(2).any(|window| window[1] - window[0] > 3);
+        let target = Journal::transaction(self, |state| -> Result<BlockSectorT, FSErrors> {
+          let target = Freemap::allocate(state, cnt)?;
+          inode.borrow_mut().relocate(state, target)?;
+          Ok(target)
+        })?;
 
-      if close {
-        FileTable::remove_by_name(self, name)?;
-      }
+        moved += 1;
+        Ok(format!("moved {} sectors to {}..{}", cnt, target, target + cnt))
+      })();
+
+      InodeList::close_inode(self, *sector)?;
+      println!("[{}/{}] {}: {}", i + 1, tree.len(), path, result?);
+    }
 
-      Ok((
-        acc.0 + if fragmented { 1 } else { 0 },
-        acc.1 + 1
-        ))
-    })?;
+    println!("Moved: {}, skipped: {}, total: {}", moved, skipped, tree.len());
+    Ok(())
+  }
+
+  ///Checks the file system for inconsistencies, fixing them if REPAIR
+  pub fn util_fsck(&mut self, repair: bool) -> Result<(), FSErrors> {
+    let fsck = Fsck::check(self)?;
+    let problem_cnt = fsck.problems().len();
+
+    for problem in fsck.problems() {
+      println!("{}", problem);
+    }
+    println!("{} problems found", problem_cnt);
 
-    println!("Fragmented Files: {}", fragmented_files);
-    println!("Total Files: {}", total_files);
-    if total_files > 0 {
-      println!("Fragmentation %: {:.2}%", (fragmented_files as f64 / total_files as f64) * 100.0);
-    } else {
-      println!("Fragmentation %: 0%");
+    if repair && problem_cnt > 0 {
+      fsck.repair(self)?;
+      println!("Repaired {} problems", problem_cnt);
     }
     Ok(())
   }
 
-  pub fn util_defrag(&mut self) -> Result<(), FSErrors> {
-    struct TempFile {
-      file_name: String,
-      content: Vec<u8>
+  ///Recovers deleted files and directories whose INODE and sectors are all still free
+  ///
+  ///Each is linked back under the name a stale directory entry still holds for it, or as recovered_file-SECTOR
+  ///in the root directory. Its sectors are reserved before anything is written and its exact length is kept.
+  ///With DRY_RUN, what would be recovered is only listed
+  pub fn util_recover(&mut self, dry_run: bool) -> Result<(), FSErrors> {
+    let names = self.stale_names()?;
+    let size = self.block.get_size();
+    let mut recovered = 0u32;
+
+    for sector in 0..size {
+      if self.freemap.inner.test(sector) {
+        continue
+      }
+
+      let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+      self.cache.read_to_buffer(&self.block, sector, &mut buffer)?;
+      if !from_bytes::<DiskInode>(&buffer).is_valid() {
+        continue
+      }
+
+      let inode = self.inode_list.open_inode(&self.block, &self.cache, sector)?;
+      let (len, is_dir) = (inode.as_ref().borrow().len(), inode.as_ref().borrow().is_dir());
+      let owned = inode.as_ref().borrow().owned_sectors(&self.block, &self.cache);
+      InodeList::close_inode(self, sector)?;
+
+      //A sector reallocated since would be clobbered by recovering, or already clobbered what is recovered.
+      //One used twice means the INODE is not what it seems
+      let mut seen = BTreeSet::<BlockSectorT>::new();
+      let mut sectors = match owned {
+        Ok(owned) if owned.iter().all(|&used| used < size && used != sector && !self.freemap.inner.test(used) && seen.insert(used)) => owned,
+        _ => continue
+      };
+      sectors.push(sector);
+
+      let (dir, path, name) = match names.get(&sector) {
+        Some((dir, path, name)) => (*dir, path.clone(), name.clone()),
+        None => (ROOT_DIR_SECTOR, "/".to_string(), format!("recovered_file-{}", sector))
+      };
+
+      if dry_run {
+        println!("Would recover {}{} ({} bytes)", path, name, len);
+        recovered += 1;
+        continue
+      }
+
+      let linked = Journal::transaction(self, |state| {
+        for &used in &sectors {
+          Freemap::allocate_at(state, used, 1)?;
+        }
+
+        //A name taken since falls back to one in the root directory
+        let linked = match state.link(dir, &name, sector, is_dir) {
+          Err(FSErrors::DirError(DirError::EntryAlreadyExists(_))) => {
+            let name = format!("recovered_file-{}", sector);
+            state.link(ROOT_DIR_SECTOR, &name, sector, is_dir).map(|_| format!("/{}", name))
+          },
+          result => result.map(|_| format!("{}{}", path, name))
+        };
+
+        if linked.is_err() {
+          for &used in &sectors {
+            Freemap::release(state, used, 1)?;
+          }
+        }
+        linked
+      })?;
+
+      println!("Recovered {} ({} bytes)", linked, len);
+      recovered += 1;
     }
 
-    let root = MemoryDirectory::open_root(self)?;
-    let entry_names = root.as_ref().borrow().read_names(self)?;
+    println!("{} files {}", recovered, if dry_run { "recoverable" } else { "recovered" });
+    Ok(())
+  }
 
-    let mut temp_files = Vec::new();
+  ///Adds an entry NAME for the INODE at SECTOR to the DIRECTORY at DIR
+  fn link(&mut self, dir: BlockSectorT, name: &str, sector: BlockSectorT, is_dir: bool) -> Result<(), FSErrors> {
+    let dir = MemoryDirectory::open_sector(self, dir)?;
+    let result = MemoryDirectory::add(dir.borrow_mut(), self, name, sector, is_dir);
+    dir.as_ref().borrow().close(self)?;
+    Ok(result?)
+  }
 
-    for name in entry_names {
-      let file = self.open(&name)?;
-      let file_len = file.as_ref().borrow().len();
+  ///Lists the path, INODE sector and type of every file and directory reachable from the root directory
+  ///
+  ///The root directory comes first. Paths of directories end with a slash
+  pub fn walk_tree(&mut self) -> Result<Vec<(String, BlockSectorT, bool)>, FSErrors> {
+    let mut found = Vec::new();
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![(ROOT_DIR_SECTOR, "/".to_string())];
+
+    while let Some((sector, path)) = stack.pop() {
+      if !visited.insert(sector) {
+        continue
+      }
+      found.push((path.clone(), sector, true));
 
-      let mut content = vec![0u8; file_len as usize];
-      file.borrow_mut().read_at(&self.block, &self.cache, &mut content, file_len, 0)?;
+      let dir = MemoryDirectory::open_sector(self, sector)?;
+      let links = dir.as_ref().borrow().read_links(self);
+      dir.as_ref().borrow().close(self)?;
 
-      temp_files.push(TempFile {
-        file_name: name.to_string(),
-        content,
-      });
+      for (name, target) in links? {
+        let inode = self.inode_list.open_inode(&self.block, &self.cache, target)?;
+        let is_dir = inode.as_ref().borrow().is_dir();
+        InodeList::close_inode(self, target)?;
 
-      self.remove(&name)?;
+        match is_dir {
+          true => stack.push((target, format!("{}{}/", path, name))),
+          false => found.push((format!("{}{}", path, name), target, false))
+        }
+      }
     }
+    Ok(found)
+  }
 
-    self.util_freespace();
+  ///Maps the INODE sector of every stale entry in the directory tree to the DIRECTORY holding it,
+  ///the path of that DIRECTORY and the name the entry had
+  fn stale_names(&mut self) -> Result<BTreeMap<BlockSectorT, (BlockSectorT, String, String)>, FSErrors> {
+    let mut names = BTreeMap::new();
 
-    for file in temp_files {
-      self.create(&file.file_name, file.content.len() as u32, false)?;
+    for (path, sector, is_dir) in self.walk_tree()? {
+      if !is_dir {
+        continue
+      }
+
+      let dir = MemoryDirectory::open_sector(self, sector)?;
+      let stale = dir.as_ref().borrow().read_stale_links(self);
+      dir.as_ref().borrow().close(self)?;
 
-      let new_file = self.open(&file.file_name)?;
-      new_file.borrow_mut().write(self, &file.content, file.content.len() as u32)?;
+      for (name, target) in stale? {
+        names.insert(target, (sector, path.clone(), name));
+      }
     }
+    Ok(names)
+  }
+}
 
-    Ok(())
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::fs::device::RamDisk;
+
+  ///Formats a fresh FILESYSTEM of SECTORS sectors held in memory
+  pub(crate) fn setup_file_sys(sectors: u32) -> FileSystem<'static> {
+    setup_file_sys_with_format(sectors, InodeFormat::Indexed)
   }
 
-  pub fn util_recover(&mut self) -> Result<(), FSErrors> {
-    for idx in 0..self.freemap.inner.get_size() {
-      if !self.freemap.inner.test(idx) {
-        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+  ///Formats a fresh FILESYSTEM of SECTORS sectors whose new INODEs use FORMAT
+  pub(crate) fn setup_file_sys_with_format(sectors: u32, format: InodeFormat) -> FileSystem<'static> {
+    let block = Block::new("hd0".to_string(), "test".to_string(), sectors, RamDisk::new(sectors));
 
-        self.cache.read_to_buffer(&self.block, idx, &mut buffer)?;
-        let recovered_inode = from_bytes::<DiskInode>(&buffer).to_owned();
+    FileSystem::new(block, Some(format), CacheOptions::default()).unwrap()
+  }
 
-        if recovered_inode.sign == INODE_SIGNATURE {
-          let recovered_name = format!("recovered_file-{}", idx);
+  #[test]
+  fn test_write_and_read_back() {
+    let mut file_sys = setup_file_sys(2048);
+    let mut buffer = [0u8; 5];
 
-          let is_dir = match recovered_inode.is_dir {
-            0u8 => { false },
-            1u8 => { true },
-            _ => panic!()
-          };
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", b"hello", 5).unwrap();
+    file_sys.util_seek("a", 0).unwrap();
+    file_sys.util_read("a", &mut buffer, 5).unwrap();
 
-          self.create(&recovered_name, recovered_inode.len, is_dir)?;
-          let recovered_file = self.open(&recovered_name)?;
-          let sectors = recovered_file.borrow_mut().inode(self)?.borrow_mut().data_sectors(&self.block, &self.cache)?;
+    assert_eq!(&buffer, b"hello");
+    file_sys.close().unwrap();
+  }
 
-          for sector in sectors.iter() {
-            self.cache.read_to_buffer(&self.block, *sector, &mut buffer)?;
-            recovered_file.borrow_mut().write(self, &buffer, BLOCK_SECTOR_SIZE)?;
-          }
-          recovered_file.borrow_mut().close(self)?;
+  #[test]
+  fn test_permissions_enforced() {
+    let mut file_sys = setup_file_sys(1024);
+
+    file_sys.set_user(7, 3);
+    file_sys.create("mine", 0, false).unwrap();
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("dir/inner", 0, false).unwrap();
+    file_sys.util_chmod("mine", 0o600).unwrap();
+
+    file_sys.set_user(8, 3);
+    assert!(matches!(file_sys.open("mine"), Err(FSErrors::PermissionDenied(_))));
+    assert!(matches!(file_sys.util_write("mine", b"x", 1), Err(FSErrors::PermissionDenied(_))));
+    assert!(matches!(file_sys.util_truncate("mine", 0), Err(FSErrors::PermissionDenied(_))));
+    assert!(matches!(file_sys.util_punch("mine", 0, 1), Err(FSErrors::PermissionDenied(_))));
+    //Nothing denied is left open
+    assert!(FileTable::get_by_name(&file_sys.file_table, "mine").is_none());
+    assert!(matches!(file_sys.remove("dir/inner"), Err(FSErrors::PermissionDenied(_))));
+    assert!(matches!(file_sys.util_chmod("mine", 0o666), Err(FSErrors::PermissionDenied(_))));
+    assert!(file_sys.open("dir/inner").is_ok());
+
+    file_sys.set_user(0, 0);
+    file_sys.util_chown("mine", 8, 3).unwrap();
+    file_sys.set_user(8, 3);
+    assert!(file_sys.open("mine").is_ok());
+    assert!(file_sys.util_write("mine", b"x", 1).is_ok());
+  }
+
+  #[test]
+  fn test_remove_releases_sectors() {
+    let mut file_sys = setup_file_sys(2048);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    file_sys.create("a", 4096, false).unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before - 9);
+
+    file_sys.remove("a").unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+  }
+
+  #[test]
+  fn test_mixed_inode_formats() {
+    let mut file_sys = setup_file_sys_with_format(2048, InodeFormat::Extent);
+    assert_eq!(file_sys.inode_format, InodeFormat::Extent);
+
+    file_sys.create("extent", 0, false).unwrap();
+    file_sys.util_write("extent", &[1u8; 2000], 2000).unwrap();
+
+    file_sys.inode_format = InodeFormat::Indexed;
+    file_sys.create("indexed", 0, false).unwrap();
+    file_sys.util_write("indexed", &[2u8; 2000], 2000).unwrap();
+
+    for (name, byte, format) in [("extent", 1u8, InodeFormat::Extent), ("indexed", 2u8, InodeFormat::Indexed)] {
+      let inode = file_sys.open_inode(name).unwrap();
+      assert_eq!(inode.as_ref().borrow().format(), format);
+
+      let mut buffer = [0u8; 2000];
+      assert_eq!(inode.as_ref().borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 2000, 0).unwrap(), 2000);
+      assert!(buffer.iter().all(|&b| b == byte));
+
+      let sector = inode.as_ref().borrow().inode_num();
+      InodeList::close_inode(&mut file_sys, sector).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_seek_past_eof_is_sparse() {
+    let mut file_sys = setup_file_sys(2048);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", b"x", 1).unwrap();
+    file_sys.util_seek("a", 100 * BLOCK_SECTOR_SIZE).unwrap();
+    file_sys.util_write("a", b"y", 1).unwrap();
+
+    //Only the inode and the two sectors written to
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 2);
+
+    let file = FileTable::get_by_name(&file_sys.file_table, "a").unwrap();
+    assert_eq!(file.as_ref().borrow().len(), 100 * BLOCK_SECTOR_SIZE + 1);
+    assert_eq!(file.as_ref().borrow().allocated_len(&file_sys.block, &file_sys.cache).unwrap(), 2 * BLOCK_SECTOR_SIZE);
+
+    file_sys.util_punch("a", 0, BLOCK_SECTOR_SIZE).unwrap();
+    assert_eq!(file.as_ref().borrow().allocated_len(&file_sys.block, &file_sys.cache).unwrap(), BLOCK_SECTOR_SIZE);
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 1);
+  }
+
+  #[test]
+  fn test_write_only_extends_by_what_it_wrote() {
+    let mut file_sys = setup_file_sys(256);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    //Past what the pointers can map, nothing is written and the length stays
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_seek("a", 2_000_000_000).unwrap();
+    assert!(file_sys.util_write("a", b"x", 1).is_err());
+    let file = FileTable::get_by_name(&file_sys.file_table, "a").unwrap();
+    assert_eq!(file.as_ref().borrow().len(), 0);
+
+    file_sys.util_seek("a", u32::MAX - 1).unwrap();
+    assert!(file_sys.util_write("a", b"xyz", 3).is_err());
+    file_sys.util_seek("a", 0).unwrap();
+
+    //Running out of sectors leaves the file as long as the sectors that were filled
+    let content = vec![1u8; 300 * BLOCK_SECTOR_SIZE as usize];
+    assert!(file_sys.util_write("a", &content, content.len() as u32).is_err());
+    let len = file.as_ref().borrow().len();
+    assert!(len > 0 && len < content.len() as u32);
+    assert_eq!(file.as_ref().borrow().allocated_len(&file_sys.block, &file_sys.cache).unwrap(), len);
+
+    file_sys.util_rm("a").unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+    assert!(Fsck::check(&mut file_sys).unwrap().problems().is_empty());
+  }
+
+  #[test]
+  fn test_truncate() {
+    let mut file_sys = setup_file_sys(2048);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", &[7u8; 130 * BLOCK_SECTOR_SIZE as usize], 130 * BLOCK_SECTOR_SIZE).unwrap();
+
+    //The inode, the data and one indirect block
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 130 + 1);
+
+    file_sys.util_truncate("a", 10).unwrap();
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 1);
+
+    //The new length reached the disk itself, not only the cache
+    let file = FileTable::get_by_name(&file_sys.file_table, "a").unwrap();
+    let sector = file.as_ref().borrow().inode(&mut file_sys).unwrap().as_ref().borrow().inode_num();
+    InodeList::close_inode(&mut file_sys, sector).unwrap();
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    file_sys.block.read_to_buffer(sector, &mut buffer).unwrap();
+    assert_eq!({ from_bytes::<DiskInode>(&buffer).len }, 10);
+
+    //Extending leaves a hole, and the bytes cut off earlier stay gone
+    file_sys.util_truncate("a", 3 * BLOCK_SECTOR_SIZE).unwrap();
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 1);
+
+    let mut content = [0xFFu8; 20];
+    file.as_ref().borrow().read_at(&file_sys.block, &file_sys.cache, &mut content, 20, 0).unwrap();
+    assert_eq!(content[..10], [7u8; 10]);
+    assert_eq!(content[10..], [0u8; 10]);
+
+    //Past what the pointers can map the length is left alone, so the file can still be removed
+    assert!(file_sys.util_truncate("a", 2_000_000_000).is_err());
+    assert_eq!(file.as_ref().borrow().len(), 3 * BLOCK_SECTOR_SIZE);
+    file_sys.util_rm("a").unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+  }
+
+  #[test]
+  fn test_append() {
+    let mut file_sys = setup_file_sys(1024);
+    let mut buffer = [0u8; 6];
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", b"abc", 3).unwrap();
+    file_sys.util_seek("a", 0).unwrap();
+    file_sys.util_append("a", b"def", 3).unwrap();
+
+    //The position kept for FS WRITE is unaffected
+    file_sys.util_write("a", b"X", 1).unwrap();
+    file_sys.util_seek("a", 0).unwrap();
+    file_sys.util_read("a", &mut buffer, 6).unwrap();
+    assert_eq!(&buffer, b"Xbcdef");
+
+    //An append-mode handle ignores its own position too
+    let file = file_sys.open("a").unwrap();
+    file.as_ref().borrow().set_append(true);
+    file.as_ref().borrow().write(&mut file_sys, b"g", 1).unwrap();
+    assert_eq!(file.as_ref().borrow().len(), 7);
+    assert_eq!(file.as_ref().borrow().tell(), 7);
+    file.as_ref().borrow().close(&mut file_sys).unwrap();
+  }
+
+  #[test]
+  fn test_copy_in_bypasses_cache() {
+    let mut file_sys = setup_file_sys(2048);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big");
+    let content: Vec<u8> = (0..100 * BLOCK_SECTOR_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+
+    //Twice what the cache holds is copied with direct I/O, leaving only the partial last sector cached
+    file_sys.cache.reset_stats();
+    file_sys.util_copy_in(path.to_str().unwrap(), "big").unwrap();
+    assert_eq!(file_sys.cache.stats().direct, 100);
+
+    let mut buffer = vec![0u8; content.len()];
+    file_sys.util_read("big", &mut buffer, content.len() as u32).unwrap();
+    assert_eq!(buffer, content);
+
+    //Direct reads see what is only cached, and small copies still go through the cache
+    let file = file_sys.open("big").unwrap();
+    file.as_ref().borrow().set_direct(true);
+    file.as_ref().borrow().write_at(&mut file_sys, &[9u8; 10], 10, 0).unwrap();
+    file.as_ref().borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, BLOCK_SECTOR_SIZE, 0).unwrap();
+    assert_eq!(buffer[..11], [9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 10]);
+    assert!(!file_sys.copies_direct(BLOCK_SECTOR_SIZE as u64));
+    file.as_ref().borrow().close(&mut file_sys).unwrap();
+  }
+
+  #[test]
+  fn test_mkfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("disk.img");
+    let path = path.to_str().unwrap();
+    let options = FormatOptions { cache_size: 16, root_capacity: 100, inode_format: InodeFormat::Extent, journal_size: Some(8) };
+
+    FileSystem::mkfs(path, 2048, options).unwrap();
+    assert!(FileSystem::mkfs(path, 2048, options).is_err(), "An existing image was overwritten");
+
+    //The image is sparse, with only the sectors written at format time allocated
+    let metadata = std::fs::metadata(path).unwrap();
+    assert_eq!(metadata.len(), 2048 * BLOCK_SECTOR_SIZE as u64);
+    assert!(metadata.blocks() * 512 < metadata.len());
+
+    let block = Block::new("hd0".to_owned(), path.to_owned(), 2048, AtaDisk::new(path, 0, 0).unwrap());
+    let mut file_sys = FileSystem::new(block, None, CacheOptions::default()).unwrap();
+
+    assert_eq!(file_sys.inode_format, InodeFormat::Extent);
+    assert_eq!(file_sys.cache.journal_size(), 8);
+    assert_eq!(Superblock::read(&file_sys.block).unwrap().cache_size, 16);
+
+    //Room for 100 entries takes 8 buckets after the header
+    let root = file_sys.inode_list.open_inode(&file_sys.block, &file_sys.cache, ROOT_DIR_SECTOR).unwrap();
+    assert_eq!(root.as_ref().borrow().len(), 9 * BLOCK_SECTOR_SIZE);
+    InodeList::close_inode(&mut file_sys, ROOT_DIR_SECTOR).unwrap();
+    file_sys.close().unwrap();
+  }
+
+  ///Returns the data sectors of the file at PATH
+  fn file_sectors(file_sys: &mut FileSystem, path: &str) -> Vec<BlockSectorT> {
+    let file = file_sys.open(path).unwrap();
+    let inode = file.as_ref().borrow().inode(file_sys).unwrap();
+    let sectors = inode.as_ref().borrow().data_sectors(&file_sys.block, &file_sys.cache).unwrap();
+    let sector = inode.as_ref().borrow().inode_num();
+    InodeList::close_inode(file_sys, sector).unwrap();
+    file.as_ref().borrow().close(file_sys).unwrap();
+    sectors
+  }
+
+  #[test]
+  fn test_defrag() {
+    for format in [InodeFormat::Indexed, InodeFormat::Extent] {
+      let mut file_sys = setup_file_sys_with_format(1024, format);
+      let chunk = |i: u32| [i as u8; BLOCK_SECTOR_SIZE as usize];
+
+      //Appending to both in turn interleaves their sectors
+      file_sys.create("dir", 0, true).unwrap();
+      file_sys.create("a", 0, false).unwrap();
+      file_sys.create("dir/b", 0, false).unwrap();
+
+      for i in 0..6 {
+        file_sys.util_append("a", &chunk(i), BLOCK_SECTOR_SIZE).unwrap();
+        file_sys.util_append("dir/b", &chunk(i + 100), BLOCK_SECTOR_SIZE).unwrap();
+      }
+      assert!(file_sectors(&mut file_sys, "a").windows(2).any(|window| window[1] != window[0] + 1));
+      let free_before = file_sys.freemap.num_free_sectors();
+
+      file_sys.util_defrag().unwrap();
+      assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+      assert!(Fsck::check(&mut file_sys).unwrap().problems().is_empty());
+
+      for (path, base) in [("a", 0), ("dir/b", 100)] {
+        let sectors = file_sectors(&mut file_sys, path);
+        assert_eq!(sectors.len(), 6);
+        assert!(sectors.windows(2).all(|window| window[1] == window[0] + 1), "{} is still fragmented", path);
+
+        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+        let file = file_sys.open(path).unwrap();
+        for i in 0..6 {
+          file.as_ref().borrow().read(&file_sys.block, &file_sys.cache, &mut buffer, BLOCK_SECTOR_SIZE).unwrap();
+          assert_eq!(buffer, chunk(i + base));
         }
+        file.as_ref().borrow().close(&mut file_sys).unwrap();
       }
     }
-    Ok(())
+  }
+
+  #[test]
+  fn test_defrag_skips_what_the_journal_cannot_hold() {
+    let block = Block::new("hd0".to_owned(), String::new(), 2048, RamDisk::new(2048));
+    let options = FormatOptions { journal_size: Some(8), inode_format: InodeFormat::Indexed, ..FormatOptions::default() };
+    let mut file_sys = FileSystem::with_options(block, Some(options), CacheOptions::default()).unwrap();
+    let chunk = [7u8; BLOCK_SECTOR_SIZE as usize];
+
+    //Fragmented, then grown into the doubly indirect range, so five pointer blocks would be repointed at once
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.create("b", 0, false).unwrap();
+    for i in 0..(crate::fs::inode::DIRECT_BLOCKS_CNT + 5 * 128) {
+      file_sys.util_append("a", &chunk, BLOCK_SECTOR_SIZE).unwrap();
+      if i < 10 {
+        file_sys.util_append("b", &chunk, BLOCK_SECTOR_SIZE).unwrap();
+      }
+    }
+    let before = file_sectors(&mut file_sys, "a");
+
+    file_sys.util_defrag().unwrap();
+    assert_eq!(file_sectors(&mut file_sys, "a"), before);
+
+    //Nothing was aborted, so the file system is still writable
+    file_sys.create("c", BLOCK_SECTOR_SIZE, false).unwrap();
+    assert!(Fsck::check(&mut file_sys).unwrap().problems().is_empty());
+  }
+
+  #[test]
+  fn test_split_what_the_journal_cannot_hold() {
+    for format in [InodeFormat::Indexed, InodeFormat::Extent] {
+      let block = Block::new("hd0".to_owned(), String::new(), 40960, RamDisk::new(40960));
+      let options = FormatOptions { journal_size: Some(10), inode_format: format, ..FormatOptions::default() };
+      let mut file_sys = FileSystem::with_options(block, Some(options), CacheOptions::default()).unwrap();
+      let free_before = file_sys.freemap.num_free_sectors();
+
+      //Each of these changes more FREEMAP sectors than the journal holds, so it is committed in pieces
+      file_sys.create("big", 37000 * BLOCK_SECTOR_SIZE, false).unwrap();
+      assert!(file_sys.freemap.num_free_sectors() < free_before - 37000);
+      file_sys.remove("big").unwrap();
+      assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+      file_sys.util_recover(false).unwrap();
+      file_sys.util_truncate("big", BLOCK_SECTOR_SIZE).unwrap();
+      assert_eq!(file_sys.freemap.num_free_sectors(), free_before - 2);
+
+      //Nothing was aborted, so the file system is still writable
+      file_sys.create("small", BLOCK_SECTOR_SIZE, false).unwrap();
+      assert!(Fsck::check(&mut file_sys).unwrap().problems().is_empty());
+    }
+  }
+
+  #[test]
+  fn test_recover() {
+    let mut file_sys = setup_file_sys(1024);
+    let content: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("taken", 0, false).unwrap();
+    file_sys.create("dir/notes", 0, false).unwrap();
+    file_sys.util_write("dir/notes", &content, 1000).unwrap();
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    file_sys.util_rm("dir/notes").unwrap();
+    file_sys.remove("taken").unwrap();
+    file_sys.create("taken", 0, false).unwrap();
+    let free_removed = file_sys.freemap.num_free_sectors();
+
+    //A dry run leaves everything as it was
+    file_sys.util_recover(true).unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_removed);
+    assert!(file_sys.open("dir/notes").is_err());
+
+    //The old TAKEN shares its INODE sector with the new one, so only NOTES comes back
+    file_sys.util_recover(false).unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+
+    let file = file_sys.open("dir/notes").unwrap();
+    let mut buffer = vec![0u8; 1000];
+    assert_eq!(file.as_ref().borrow().len(), 1000);
+    file.as_ref().borrow().read(&file_sys.block, &file_sys.cache, &mut buffer, 1000).unwrap();
+    assert_eq!(buffer, content);
+    file.as_ref().borrow().close(&mut file_sys).unwrap();
+  }
+
+  #[test]
+  fn test_recover_without_stale_entry() {
+    let mut file_sys = setup_file_sys(1024);
+
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("dir/a", 0, false).unwrap();
+    file_sys.util_write("dir/a", b"hello", 5).unwrap();
+
+    let file = file_sys.open("dir/a").unwrap();
+    let sector = file.as_ref().borrow().inode(&mut file_sys).unwrap().as_ref().borrow().inode_num();
+    InodeList::close_inode(&mut file_sys, sector).unwrap();
+    file.as_ref().borrow().close(&mut file_sys).unwrap();
+
+    //The stale entry for A goes away with DIR
+    file_sys.util_rm("dir/a").unwrap();
+    file_sys.remove("dir").unwrap();
+    file_sys.util_recover(false).unwrap();
+
+    let mut buffer = [0u8; 5];
+    assert!(file_sys.open("dir").is_ok());
+    file_sys.util_read(&format!("recovered_file-{}", sector), &mut buffer, 5).unwrap();
+    assert_eq!(&buffer, b"hello");
+  }
+
+  #[test]
+  fn test_recover_skips_corrupt_inodes() {
+    let mut file_sys = setup_file_sys(1024);
+    file_sys.create("long", 0, false).unwrap();
+    file_sys.create("shared", 2 * BLOCK_SECTOR_SIZE, false).unwrap();
+
+    let mut sectors = Vec::new();
+    for name in ["long", "shared"] {
+      let file = file_sys.open(name).unwrap();
+      sectors.push(file.as_ref().borrow().inode(&mut file_sys).unwrap().as_ref().borrow().inode_num());
+      InodeList::close_inode(&mut file_sys, sectors[sectors.len() - 1]).unwrap();
+      file.as_ref().borrow().close(&mut file_sys).unwrap();
+      file_sys.remove(name).unwrap();
+    }
+    let free_removed = file_sys.freemap.num_free_sectors();
+
+    //LONG claims more than an INODE can hold, and both data sectors of SHARED are the same one
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    file_sys.cache.read_to_buffer(&file_sys.block, sectors[0], &mut buffer).unwrap();
+    bytemuck::from_bytes_mut::<DiskInode>(&mut buffer).len = u32::MAX;
+    file_sys.cache.write_from_buffer(&file_sys.block, sectors[0], &buffer).unwrap();
+
+    file_sys.cache.read_to_buffer(&file_sys.block, sectors[1], &mut buffer).unwrap();
+    buffer.copy_within(0..4, 4);
+    file_sys.cache.write_from_buffer(&file_sys.block, sectors[1], &buffer).unwrap();
+
+    file_sys.util_recover(false).unwrap();
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_removed);
+    assert!(file_sys.open("long").is_err());
+    assert!(file_sys.open("shared").is_err());
   }
 }
@@ -0,0 +1,231 @@
+use std::fmt::{
+  Display,
+  Formatter,
+  Result as FmtResult
+};
+
+use crate::fs::{
+  bitmap::Bitmap,
+  file_sys::FileSystem,
+  fserrors::FSErrors,
+  inode::InodeList
+};
+
+///How the data sectors of one file or directory are laid out on disk
+#[derive(Debug, PartialEq)]
+pub(crate) struct FileFrag {
+  pub path: String,
+  pub is_dir: bool,
+  ///Number of data sectors, holes excluded
+  pub sectors: u32,
+  ///Number of runs of physically contiguous data sectors
+  pub extents: u32
+}
+
+impl FileFrag {
+  pub fn avg_extent_len(&self) -> f64 {
+    match self.extents {
+      0 => 0.0,
+      extents => self.sectors as f64 / extents as f64
+    }
+  }
+
+  ///Fraction of the gaps between consecutive data sectors that are breaks, from 0 when contiguous to 1 when no two are adjacent
+  pub fn score(&self) -> f64 {
+    match self.sectors {
+      0 | 1 => 0.0,
+      sectors => (self.extents - 1) as f64 / (sectors - 1) as f64
+    }
+  }
+}
+
+///A fragmentation report of every file and directory on a FILESYSTEM and of its free space
+pub(crate) struct FragReport {
+  pub files: Vec<FileFrag>,
+  pub free_sectors: u32,
+  ///Lengths of the runs of free sectors, in disk order
+  pub free_runs: Vec<u32>
+}
+
+impl FragReport {
+  ///Measures every file and directory reachable from the root directory, and the FREEMAP
+  pub fn collect(state: &mut FileSystem) -> Result<Self, FSErrors> {
+    let mut files = Vec::new();
+
+    for (path, sector, is_dir) in state.walk_tree()? {
+      let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+      let data_sectors = inode.as_ref().borrow().data_sectors(&state.block, &state.cache);
+      InodeList::close_inode(state, sector)?;
+
+      let data_sectors = data_sectors?;
+      let breaks = data_sectors.windows(2).filter(|window| window[1] != window[0] + 1).count() as u32;
+
+      files.push(FileFrag {
+        path,
+        is_dir,
+        sectors: data_sectors.len() as u32,
+        extents: if data_sectors.is_empty() { 0 } else { breaks + 1 }
+      });
+    }
+
+    Ok(Self {
+      files,
+      free_sectors: state.freemap.num_free_sectors(),
+      free_runs: state.freemap.inner.runs(false).into_iter().map(|(_, len)| len).collect()
+    })
+  }
+
+  ///Files and directories with their data in more than one extent
+  pub fn fragmented(&self) -> usize {
+    self.files.iter().filter(|file| file.extents > 1).count()
+  }
+
+  pub fn largest_free_run(&self) -> u32 {
+    self.free_runs.iter().copied().max().unwrap_or(0)
+  }
+
+  ///Fraction of the free sectors outside the largest free run, from 0 when all free space is one run
+  pub fn free_score(&self) -> f64 {
+    match self.free_sectors {
+      0 => 0.0,
+      free => 1.0 - self.largest_free_run() as f64 / free as f64
+    }
+  }
+
+  ///Counts the free runs by length, bucket K holding the runs of 2^K up to 2^(K+1) - 1 sectors
+  pub fn free_histogram(&self) -> Vec<u32> {
+    let mut histogram = Vec::new();
+
+    for &len in &self.free_runs {
+      let bucket = len.ilog2() as usize;
+
+      if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+      }
+      histogram[bucket] += 1;
+    }
+    histogram
+  }
+
+  ///Renders the report as a JSON object
+  pub fn to_json(&self) -> String {
+    let files = self.files.iter().map(|file| format!(
+      "{{\"path\":{},\"dir\":{},\"sectors\":{},\"extents\":{},\"avg_extent_len\":{:.3},\"score\":{:.3}}}",
+      json_string(&file.path), file.is_dir, file.sectors, file.extents, file.avg_extent_len(), file.score()
+    )).collect::<Vec<_>>();
+
+    let histogram = self.free_histogram().iter().enumerate().map(|(bucket, cnt)| format!(
+      "{{\"min\":{},\"max\":{},\"runs\":{}}}", 1u32 << bucket, (1u64 << (bucket + 1)) - 1, cnt
+    )).collect::<Vec<_>>();
+
+    format!(
+      "{{\"files\":[{}],\"fragmented\":{},\"free\":{{\"sectors\":{},\"runs\":{},\"largest_run\":{},\"score\":{:.3},\"histogram\":[{}]}}}}",
+      files.join(","), self.fragmented(), self.free_sectors, self.free_runs.len(), self.largest_free_run(), self.free_score(), histogram.join(",")
+    )
+  }
+}
+
+impl Display for FragReport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    writeln!(f, "{:<32} {:>8} {:>8} {:>8} {:>6}", "Path", "Sectors", "Extents", "Avg len", "Score")?;
+    for file in &self.files {
+      writeln!(f, "{:<32} {:>8} {:>8} {:>8.2} {:>6.2}", file.path, file.sectors, file.extents, file.avg_extent_len(), file.score())?;
+    }
+
+    writeln!(f, "Fragmented: {} of {}", self.fragmented(), self.files.len())?;
+    writeln!(f, "Free sectors: {} in {} runs, largest {}, score {:.2}", self.free_sectors, self.free_runs.len(), self.largest_free_run(), self.free_score())?;
+
+    for (bucket, cnt) in self.free_histogram().iter().enumerate() {
+      writeln!(f, "{:>6}-{:<6} {}", 1u32 << bucket, (1u64 << (bucket + 1)) - 1, cnt)?;
+    }
+    Ok(())
+  }
+}
+
+///Quotes S as a JSON string
+fn json_string(s: &str) -> String {
+  let mut quoted = String::with_capacity(s.len() + 2);
+  quoted.push('"');
+
+  for c in s.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      c => quoted.push(c)
+    }
+  }
+
+  quoted.push('"');
+  quoted
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::{
+    block::BLOCK_SECTOR_SIZE,
+    file_sys::tests::setup_file_sys
+  };
+
+  fn report_with_free_runs(free_runs: Vec<u32>) -> FragReport {
+    FragReport { files: Vec::new(), free_sectors: free_runs.iter().sum(), free_runs }
+  }
+
+  #[test]
+  fn test_scores() {
+    let file = |sectors, extents| FileFrag { path: "/a".to_string(), is_dir: false, sectors, extents };
+
+    assert_eq!(file(0, 0).score(), 0.0);
+    assert_eq!(file(8, 1).score(), 0.0);
+    assert_eq!(file(8, 8).score(), 1.0);
+    assert_eq!(file(8, 2).avg_extent_len(), 4.0);
+  }
+
+  #[test]
+  fn test_free_histogram() {
+    let bitmap = Bitmap::new(32);
+    bitmap.set_multiple(1, 2, true);
+    bitmap.set_multiple(10, 1, true);
+
+    //Free runs of 1, 7 and 21 sectors
+    let runs = bitmap.runs(false);
+    assert_eq!(runs, vec![(0, 1), (3, 7), (11, 21)]);
+
+    let report = report_with_free_runs(runs.into_iter().map(|(_, len)| len).collect());
+    assert_eq!(report.free_histogram(), vec![1, 0, 1, 0, 1]);
+    assert_eq!(report.largest_free_run(), 21);
+    assert!((report.free_score() - 8.0 / 29.0).abs() < 1e-9);
+    assert_eq!(report_with_free_runs(Vec::new()).free_score(), 0.0);
+  }
+
+  #[test]
+  fn test_collect_and_json() {
+    let mut file_sys = setup_file_sys(1024);
+    let chunk = [7u8; BLOCK_SECTOR_SIZE as usize];
+
+    file_sys.create("a\"b", 0, false).unwrap();
+    file_sys.create("c", 0, false).unwrap();
+    for _ in 0..3 {
+      file_sys.util_append("a\"b", &chunk, BLOCK_SECTOR_SIZE).unwrap();
+      file_sys.util_append("c", &chunk, BLOCK_SECTOR_SIZE).unwrap();
+    }
+
+    let report = FragReport::collect(&mut file_sys).unwrap();
+    let file = report.files.iter().find(|file| file.path == "/a\"b").unwrap();
+    assert_eq!((file.sectors, file.extents), (3, 3));
+    assert_eq!(report.free_sectors, file_sys.freemap.num_free_sectors());
+
+    let json = report.to_json();
+    assert!(json.contains("{\"path\":\"/a\\\"b\",\"dir\":false,\"sectors\":3,\"extents\":3,\"avg_extent_len\":1.000,\"score\":1.000}"));
+    assert!(json.starts_with("{\"files\":[{\"path\":\"/\",\"dir\":true"));
+
+    //Defragmenting leaves every file in one extent
+    file_sys.util_defrag().unwrap();
+    let report = FragReport::collect(&mut file_sys).unwrap();
+    assert!(report.files.iter().all(|file| file.extents <= 1));
+  }
+}
@@ -1,6 +1,9 @@
 use crate::fs::{
   bitmap::Bitmap,
-  block::BlockSectorT,
+  block::{
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
   file_sys::{
     FREE_MAP_SECTOR,
     ROOT_DIR_SECTOR,
@@ -13,18 +16,21 @@ use crate::fs::{
 
 pub (crate) struct Freemap {
   pub inner: Bitmap,
-  file: Option<File>
+  file: Option<File>,
+  ///The bits as last written to FILE, so only the sectors that changed since are written again
+  written: Vec<u8>
 }
 
 impl Freemap {
   pub fn new(block_size: u32) -> Self {
-    let bitmap = Bitmap::new(block_size - 1);
+    let bitmap = Bitmap::new(block_size);
     bitmap.mark(FREE_MAP_SECTOR);
     bitmap.mark(ROOT_DIR_SECTOR);
 
     Self {
       inner: bitmap,
-      file: None
+      file: None,
+      written: Vec::new()
     }
   }
 
@@ -35,15 +41,19 @@ impl Freemap {
     let mut freemap_file = File::open(freemap_inode);
     state.freemap.inner.read_from_file(&state.block, &state.cache, &mut freemap_file)?;
     state.freemap.file = Some(freemap_file);
+    state.freemap.written = state.freemap.inner.get_bits();
 
     Ok(())
   }
 
+  ///Creates the FREEMAP file on disk and keeps it open
+  ///
+  ///Sectors allocated for the file itself are only tracked in memory until it exists, then written out
   pub fn create_on_disk(state: &mut FileSystem) -> Result<(), FreemapError> {
-    let size = state.freemap.inner.get_size();
+    let size = state.freemap.inner.get_file_size();
     let _ = DiskInode::new(state, FREE_MAP_SECTOR, size, false)?;
     let freemap_inode = state.inode_list.open_inode(&state.block, &state.cache, FREE_MAP_SECTOR)?;
-    let mut freemap_file = File::open(freemap_inode);
+    state.freemap.file = Some(File::open(freemap_inode));
 
     Self::write_to_file(state)?;
     Ok(())
@@ -54,32 +64,67 @@ impl Freemap {
       return Err(FreemapError::NoFileAssigned())
     }
 
-    let file = state.freemap.file.take().unwrap();
     Self::write_to_file(state)?;
+    let file = state.freemap.file.take().unwrap();
     file.close(state)?;
     Ok(())
   }
 
+  ///Allocates CNT contiguous sectors, writing the result to file if it is open
   pub fn allocate(state: &mut FileSystem, cnt: u32) -> Result<BlockSectorT, FreemapError> {
-    if let None = state.freemap.file {
-      return Err(FreemapError::NoFileAssigned())
-    }
-
     let sector = state.freemap.inner.scan_and_flip(0, cnt, false)?;
-    Self::write_to_file(state)?;
+    state.cache.allocated(sector, cnt);
+
+    if state.freemap.file.is_some() {
+      Self::write_to_file(state)?;
+    }
     Ok(sector)
   }
 
-  ///Releases CNT sectors starting from SECTOR, writing the result to file
-  pub fn release(state: &mut FileSystem, sector: BlockSectorT, cnt: u32) -> Result<(), FreemapError> {
-    if let None = state.freemap.file {
-      return Err(FreemapError::NoFileAssigned())
+  ///Allocates up to CNT contiguous sectors, returning the first sector and the number allocated
+  ///
+  ///A run of the full CNT is preferred, otherwise the longest free run on the FREEMAP is taken
+  pub fn allocate_run(state: &mut FileSystem, cnt: u32) -> Result<(BlockSectorT, u32), FreemapError> {
+    let (sector, len) = match state.freemap.inner.scan(0, cnt, false) {
+      Ok(sector) => (sector, cnt),
+      Err(e) => state.freemap.inner.longest_run(false).ok_or(e)?
+    };
+    state.freemap.inner.set_multiple(sector, len, true);
+    state.cache.allocated(sector, len);
+
+    if state.freemap.file.is_some() {
+      Self::write_to_file(state)?;
     }
+    Ok((sector, len))
+  }
+
+  ///Allocates up to CNT sectors starting exactly at SECTOR, stopping at the first one already in use
+  ///
+  ///Returns the number of sectors allocated, which may be 0
+  pub fn allocate_at(state: &mut FileSystem, sector: BlockSectorT, cnt: u32) -> Result<u32, FreemapError> {
+    let len = state.freemap.inner.run_len(sector, cnt, false);
 
+    if len > 0 {
+      state.freemap.inner.set_multiple(sector, len, true);
+      state.cache.allocated(sector, len);
+
+      if state.freemap.file.is_some() {
+        Self::write_to_file(state)?;
+      }
+    }
+    Ok(len)
+  }
+
+  ///Releases CNT sectors starting from SECTOR, writing the result to file if it is open
+  pub fn release(state: &mut FileSystem, sector: BlockSectorT, cnt: u32) -> Result<(), FreemapError> {
     assert!(state.freemap.inner.all(sector, cnt));
     state.freemap.inner.set_multiple(sector, cnt, false);
+    state.cache.released(sector, cnt);
 
-    Self::write_to_file(state)
+    if state.freemap.file.is_some() {
+      Self::write_to_file(state)?;
+    }
+    Ok(())
   }
 
   ///Returns the numebr of free sectors on the FREEMAP
@@ -87,15 +132,33 @@ impl Freemap {
     self.inner.count(0, self.inner.get_size(), false)
   }
 
+  ///Writes the sectors of the FREEMAP file whose bits changed since they were last written
   pub fn write_to_file(state: &mut FileSystem) -> Result<(), FreemapError> {
     let bits = state.freemap.inner.get_bits();
-    let len = bits.len() as u32;
+    let written = std::mem::take(&mut state.freemap.written);
 
+    //The FILE is put back even if writing fails, so later changes still reach it
     let file = state.freemap.file.take().unwrap();
-    let bytes_wrote = file.write_at(state, &bits, len, 0)?;
-
-    assert_eq!(bytes_wrote, len);
+    let result = bits.chunks(BLOCK_SECTOR_SIZE as usize).enumerate()
+      .map(|(i, chunk)| (i as u32 * BLOCK_SECTOR_SIZE, chunk))
+      .filter(|&(ofst, chunk)| written.get(ofst as usize..ofst as usize + chunk.len()) != Some(chunk))
+      .try_for_each(|(ofst, chunk)| {
+        //Sectors are allocated before anything points at them and released once nothing does, so a crash after
+        //committing here can only leak them. This is where a transaction too large for the journal is split
+        if state.cache.freemap_room() == 0 {
+          state.cache.checkpoint(&state.block)?;
+        }
+
+        let len = chunk.len() as u32;
+        assert_eq!(file.write_at(state, chunk, len, ofst)?, len);
+        Ok::<(), FreemapError>(())
+      });
     state.freemap.file = Some(file);
-    Ok(())
+
+    //What failed to be written is written again next time
+    if result.is_ok() {
+      state.freemap.written = bits;
+    }
+    result
   }
 }
@@ -0,0 +1,504 @@
+use std::{
+  collections::{
+    BTreeMap,
+    BTreeSet
+  },
+  fmt::{
+    Display,
+    Formatter,
+    Result as FmtResult
+  }
+};
+
+use bytemuck::from_bytes;
+
+use crate::fs::{
+  block::{
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
+  directory::MemoryDirectory,
+  file_sys::{
+    FileSystem,
+    FREE_MAP_SECTOR,
+    ROOT_DIR_SECTOR
+  },
+  freemap::Freemap,
+  fserrors::{
+    dir_errors::DirError,
+    FSErrors
+  },
+  inode::{
+    DiskInode,
+    InodeList
+  },
+  journal::{
+    Journal,
+    JOURNAL_SECTOR
+  },
+  superblock::SUPERBLOCK_SECTOR
+};
+
+const LOST_FOUND: &str = "/lost+found";
+
+///An inconsistency found by FSCK
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+  ///SECTOR is in use, but free on the FREEMAP
+  UnmarkedSector { sector: BlockSectorT },
+  ///SECTOR is in use on the FREEMAP, but nothing uses it
+  LeakedSector { sector: BlockSectorT },
+  ///SECTOR is used by the INODE at INODE, but was already claimed by the one at OWNER
+  DoubleAllocated { sector: BlockSectorT, inode: BlockSectorT, owner: BlockSectorT },
+  ///A valid INODE at SECTOR that no DIRECTORY entry leads to
+  OrphanInode { sector: BlockSectorT },
+  ///The entry NAME in the DIRECTORY at DIR points at TARGET, which holds no valid INODE
+  DanglingEntry { dir: BlockSectorT, name: String, target: BlockSectorT },
+  ///The INODE at SECTOR is corrupt, so the sectors it still maps are kept rather than trusted or freed
+  CorruptInode { sector: BlockSectorT },
+  ///The parent entry of the DIRECTORY at DIR points at FOUND instead of EXPECTED
+  BadParent { dir: BlockSectorT, found: BlockSectorT, expected: BlockSectorT }
+}
+
+impl Display for Problem {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::UnmarkedSector { sector } => write!(f, "Sector {} is in use but marked free", sector),
+      Self::LeakedSector { sector } => write!(f, "Sector {} is marked used but nothing uses it", sector),
+      Self::DoubleAllocated { sector, inode, owner } => write!(f, "Sector {} of inode {} is already used by inode {}", sector, inode, owner),
+      Self::OrphanInode { sector } => write!(f, "Inode {} is not in any directory", sector),
+      Self::DanglingEntry { dir, name, target } => write!(f, "Entry {} in directory {} points at sector {}, which is not an inode", name, dir, target),
+      Self::CorruptInode { sector } => write!(f, "Inode {} is corrupt, the sectors it maps are kept", sector),
+      Self::BadParent { dir, found, expected } => write!(f, "Parent of directory {} is {}, should be {}", dir, found, expected)
+    }
+  }
+}
+
+///What checking the INODE an entry points at found
+enum Claim {
+  File,
+  Dir,
+  ///The sector holds no INODE
+  Invalid,
+  ///The sector holds an INODE whose fields or sectors can not be trusted
+  Corrupt,
+  ///The INODE uses sectors another one already claimed
+  Conflict
+}
+
+///A consistency check of a FILESYSTEM, and the changes that would fix what it found
+pub(crate) struct Fsck {
+  problems: Vec<Problem>,
+  ///Every sector found in use, mapped to the INODE using it
+  claimed: BTreeMap<BlockSectorT, BlockSectorT>,
+  ///Sectors of corrupt INODEs, which are left marked used without being claimed
+  kept: BTreeSet<BlockSectorT>,
+  ///Entries to remove from the DIRECTORY at the given sector
+  unlinks: Vec<(BlockSectorT, String)>,
+  ///DIRECTORIES whose parent entry must point at the given sector
+  reparents: Vec<(BlockSectorT, BlockSectorT)>,
+  ///INODEs to link into LOST_FOUND, and whether they are DIRECTORIES
+  orphans: Vec<(BlockSectorT, bool)>
+}
+
+impl Fsck {
+  ///Walks the directory tree from ROOT_DIR_SECTOR, cross-checking every INODE against the FREEMAP
+  pub fn check(state: &mut FileSystem) -> Result<Self, FSErrors> {
+    let mut fsck = Self {
+      problems: Vec::new(),
+      claimed: BTreeMap::new(),
+      kept: BTreeSet::new(),
+      unlinks: Vec::new(),
+      reparents: Vec::new(),
+      orphans: Vec::new()
+    };
+
+    fsck.claim(state, FREE_MAP_SECTOR)?;
+    match fsck.claim(state, ROOT_DIR_SECTOR)? {
+      Claim::Dir => fsck.walk(state, ROOT_DIR_SECTOR, Some(ROOT_DIR_SECTOR))?,
+      _ => return Err(FSErrors::DirError(DirError::CorruptHeader(ROOT_DIR_SECTOR)))
+    }
+
+    fsck.find_orphans(state)?;
+
+    //Pointers of a corrupt INODE may be garbage, so they only keep sectors that are already marked
+    fsck.kept.retain(|&sector| state.freemap.inner.test(sector));
+
+    let reserved = Self::reserved(state);
+    for sector in 0..state.block.get_size() {
+      let used = reserved.contains(&sector) || fsck.claimed.contains_key(&sector);
+
+      match (used, state.freemap.inner.test(sector)) {
+        (true, false) => fsck.problems.push(Problem::UnmarkedSector { sector }),
+        (false, true) if !fsck.kept.contains(&sector) => fsck.problems.push(Problem::LeakedSector { sector }),
+        _ => {}
+      }
+    }
+    Ok(fsck)
+  }
+
+  pub fn problems(&self) -> &[Problem] {
+    &self.problems
+  }
+
+  ///Fixes every problem found, as a single transaction unless the rebuilt FREEMAP alone outgrows the journal
+  ///
+  ///The FREEMAP is rebuilt from the sectors in use, dangling and conflicting entries are removed,
+  ///parent entries are corrected and orphans are linked into LOST_FOUND as #SECTOR. Corrupt INODEs are left as they are
+  pub fn repair(self, state: &mut FileSystem) -> Result<(), FSErrors> {
+    Journal::transaction(state, |state| {
+      //Rebuilt first, so nothing allocated below can take a sector in use
+      let bitmap = &state.freemap.inner;
+      bitmap.set_multiple(0, bitmap.get_size(), false);
+      Self::reserved(state).iter().chain(self.claimed.keys()).chain(&self.kept).for_each(|&sector| bitmap.mark(sector));
+      Freemap::write_to_file(state)?;
+
+      for (dir_sector, name) in &self.unlinks {
+        let dir = MemoryDirectory::open_sector(state, *dir_sector)?;
+        let result = MemoryDirectory::unlink(dir.borrow_mut(), state, name);
+        dir.as_ref().borrow().close(state)?;
+        result?;
+      }
+
+      for &(dir_sector, parent) in &self.reparents {
+        let dir = MemoryDirectory::open_sector(state, dir_sector)?;
+        let result = dir.as_ref().borrow().set_parent(state, parent);
+        dir.as_ref().borrow().close(state)?;
+        result?;
+      }
+
+      if self.orphans.is_empty() {
+        return Ok(())
+      }
+
+      if MemoryDirectory::open_path(state, LOST_FOUND).and_then(|dir| dir.as_ref().borrow().close(state)).is_err() {
+        state.create(LOST_FOUND, 0, true)?;
+      }
+
+      let lost_found = MemoryDirectory::open_path(state, LOST_FOUND)?;
+      let result = self.orphans.iter().try_for_each(|&(sector, is_dir)| {
+        MemoryDirectory::add(lost_found.borrow_mut(), state, &format!("#{}", sector), sector, is_dir)
+      });
+      lost_found.as_ref().borrow().close(state)?;
+      Ok(result?)
+    })
+  }
+
+  ///Returns the sectors in use outside of any INODE: the FREEMAP INODE, the SUPERBLOCK and the journal region
+  fn reserved(state: &FileSystem) -> BTreeSet<BlockSectorT> {
+    let mut reserved = BTreeSet::from([FREE_MAP_SECTOR, SUPERBLOCK_SECTOR]);
+    reserved.extend(JOURNAL_SECTOR..JOURNAL_SECTOR + state.cache.journal_size());
+    reserved
+  }
+
+  ///Reads whatever is at SECTOR as an INODE, or None if SECTOR is out of bounds
+  fn read_disk_inode(state: &FileSystem, sector: BlockSectorT) -> Result<Option<DiskInode>, FSErrors> {
+    if sector >= state.block.get_size() {
+      return Ok(None)
+    }
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    state.cache.read_to_buffer(&state.block, sector, &mut buffer)?;
+    Ok(Some(from_bytes::<DiskInode>(&buffer).to_owned()))
+  }
+
+  ///Reads the INODE at SECTOR, or None if SECTOR is out of bounds or holds no valid INODE
+  fn read_inode(state: &FileSystem, sector: BlockSectorT) -> Result<Option<DiskInode>, FSErrors> {
+    Ok(Self::read_disk_inode(state, sector)?.filter(DiskInode::is_valid))
+  }
+
+  ///Claims the INODE at SECTOR and every sector it uses, unless one of them is already claimed
+  fn claim(&mut self, state: &mut FileSystem, sector: BlockSectorT) -> Result<Claim, FSErrors> {
+    let is_dir = match Self::read_inode(state, sector)? {
+      Some(inode) => inode.is_dir == 1,
+      None => return self.keep(state, sector)
+    };
+
+    let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+    let owned = inode.as_ref().borrow().owned_sectors(&state.block, &state.cache);
+    InodeList::close_inode(state, sector)?;
+
+    let owned = match owned {
+      Ok(owned) if owned.iter().all(|&owned| owned < state.block.get_size()) => owned,
+      _ => return self.keep(state, sector)
+    };
+
+    //A DIRECTORY is only of use if its header can be read
+    if is_dir {
+      let dir = MemoryDirectory::open_sector(state, sector)?;
+      let header = dir.as_ref().borrow().parent(state);
+      dir.as_ref().borrow().close(state)?;
+
+      if header.is_err() {
+        return self.keep(state, sector)
+      }
+    }
+
+    let conflicts: Vec<_> = std::iter::once(sector).chain(owned.iter().copied())
+      .filter_map(|used| self.claimed.get(&used).map(|&owner| (used, owner)))
+      .collect();
+
+    if !conflicts.is_empty() {
+      for (used, owner) in conflicts {
+        self.problems.push(Problem::DoubleAllocated { sector: used, inode: sector, owner });
+      }
+      return Ok(Claim::Conflict)
+    }
+
+    self.claimed.insert(sector, sector);
+    self.claimed.extend(owned.into_iter().map(|used| (used, sector)));
+
+    match is_dir {
+      true => Ok(Claim::Dir),
+      false => Ok(Claim::File)
+    }
+  }
+
+  ///Keeps the sector of a corrupt INODE at SECTOR and those it can still map, so a directory entry never points at a
+  ///freed sector. Returns Claim::Invalid instead if SECTOR holds no INODE at all
+  fn keep(&mut self, state: &mut FileSystem, sector: BlockSectorT) -> Result<Claim, FSErrors> {
+    if !Self::read_disk_inode(state, sector)?.is_some_and(|inode| inode.has_signature()) {
+      return Ok(Claim::Invalid)
+    }
+
+    let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+    let mappable = inode.as_ref().borrow().mappable_sectors(&state.block, &state.cache);
+    InodeList::close_inode(state, sector)?;
+
+    let size = state.block.get_size();
+    self.kept.insert(sector);
+    self.kept.extend(mappable.unwrap_or_default().into_iter().filter(|&mapped| mapped < size));
+    self.problems.push(Problem::CorruptInode { sector });
+    Ok(Claim::Corrupt)
+  }
+
+  ///Claims everything under the DIRECTORY at SECTOR, checking its parent entry points at PARENT if given
+  fn walk(&mut self, state: &mut FileSystem, sector: BlockSectorT, parent: Option<BlockSectorT>) -> Result<(), FSErrors> {
+    let mut stack = vec![(sector, parent)];
+
+    while let Some((dir_sector, parent)) = stack.pop() {
+      let dir = MemoryDirectory::open_sector(state, dir_sector)?;
+      let found = dir.as_ref().borrow().parent(state);
+      let links = dir.as_ref().borrow().read_links(state);
+      dir.as_ref().borrow().close(state)?;
+
+      if let (Some(expected), found) = (parent, found?) {
+        if found != expected {
+          self.problems.push(Problem::BadParent { dir: dir_sector, found, expected });
+          self.reparents.push((dir_sector, expected));
+        }
+      }
+
+      for (name, target) in links? {
+        match self.claim(state, target)? {
+          Claim::Dir => stack.push((target, Some(dir_sector))),
+          Claim::File => {},
+          Claim::Invalid => {
+            self.problems.push(Problem::DanglingEntry { dir: dir_sector, name: name.clone(), target });
+            self.unlinks.push((dir_sector, name));
+          },
+          Claim::Conflict => self.unlinks.push((dir_sector, name)),
+          Claim::Corrupt => {}
+        }
+      }
+    }
+    Ok(())
+  }
+
+  ///Finds valid INODEs that are marked used but were not reached from ROOT_DIR_SECTOR
+  ///
+  ///Only the top of an unreachable tree is an orphan, whatever lies under it is claimed along with it
+  fn find_orphans(&mut self, state: &mut FileSystem) -> Result<(), FSErrors> {
+    let reserved = Self::reserved(state);
+    let mut candidates = Vec::<(BlockSectorT, bool)>::new();
+
+    for sector in 0..state.block.get_size() {
+      if !state.freemap.inner.test(sector) || reserved.contains(&sector) || self.claimed.contains_key(&sector) {
+        continue
+      }
+
+      if let Some(inode) = Self::read_inode(state, sector)? {
+        candidates.push((sector, inode.is_dir == 1));
+      }
+    }
+
+    let mut referenced = BTreeSet::<BlockSectorT>::new();
+
+    for &(sector, is_dir) in &candidates {
+      if is_dir {
+        let dir = MemoryDirectory::open_sector(state, sector)?;
+        let links = dir.as_ref().borrow().read_links(state);
+        dir.as_ref().borrow().close(state)?;
+        referenced.extend(links.unwrap_or_default().into_iter().map(|(_, target)| target));
+      }
+    }
+
+    for (sector, _) in candidates {
+      if referenced.contains(&sector) || self.claimed.contains_key(&sector) {
+        continue
+      }
+
+      let claim = self.claim(state, sector)?;
+      match claim {
+        Claim::Dir => self.walk(state, sector, None)?,
+        Claim::File => {},
+        _ => continue
+      }
+
+      self.problems.push(Problem::OrphanInode { sector });
+      self.orphans.push((sector, matches!(claim, Claim::Dir)));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::{
+    file::FileTable,
+    file_sys::tests::setup_file_sys,
+    inode::EMPTY_BUFFER
+  };
+
+  use bytemuck::from_bytes_mut;
+
+  ///Returns the INODE sector of the file at PATH
+  fn inode_of(state: &mut FileSystem, path: &str) -> BlockSectorT {
+    let file = state.open(path).unwrap();
+    let sector = file.as_ref().borrow().inode(state).unwrap().as_ref().borrow().inode_num();
+
+    InodeList::close_inode(state, sector).unwrap();
+    file.as_ref().borrow().close(state).unwrap();
+    sector
+  }
+
+  ///Returns the data sectors of the file at PATH
+  fn data_of(state: &mut FileSystem, path: &str) -> Vec<BlockSectorT> {
+    let sector = inode_of(state, path);
+    let inode = state.inode_list.open_inode(&state.block, &state.cache, sector).unwrap();
+    let sectors = inode.as_ref().borrow().data_sectors(&state.block, &state.cache).unwrap();
+
+    InodeList::close_inode(state, sector).unwrap();
+    sectors
+  }
+
+  fn repair_and_recheck(state: &mut FileSystem) {
+    Fsck::check(state).unwrap().repair(state).unwrap();
+    assert_eq!(Fsck::check(state).unwrap().problems(), &[]);
+  }
+
+  #[test]
+  fn test_clean_image() {
+    let mut file_sys = setup_file_sys(2048);
+
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("dir/file", 70_000, false).unwrap();
+    file_sys.create("file", 0, false).unwrap();
+    file_sys.util_write("file", &[7u8; 3000], 3000).unwrap();
+
+    assert_eq!(Fsck::check(&mut file_sys).unwrap().problems(), &[]);
+  }
+
+  #[test]
+  fn test_leaked_and_unmarked_sectors() {
+    let mut file_sys = setup_file_sys(1024);
+    file_sys.create("file", 2048, false).unwrap();
+
+    let data = data_of(&mut file_sys, "file");
+    let leaked = file_sys.freemap.inner.scan(0, 1, false).unwrap();
+    file_sys.freemap.inner.mark(leaked);
+    file_sys.freemap.inner.reset(data[1]);
+
+    let problems = Fsck::check(&mut file_sys).unwrap().problems;
+    assert_eq!(problems, vec![
+      Problem::UnmarkedSector { sector: data[1] },
+      Problem::LeakedSector { sector: leaked }
+    ]);
+
+    repair_and_recheck(&mut file_sys);
+    assert!(file_sys.freemap.inner.test(data[1]));
+    assert!(!file_sys.freemap.inner.test(leaked));
+  }
+
+  #[test]
+  fn test_dangling_entry_and_bad_parent() {
+    let mut file_sys = setup_file_sys(1024);
+
+    file_sys.create("file", 1024, false).unwrap();
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("dir/sub", 0, true).unwrap();
+
+    let file = inode_of(&mut file_sys, "file");
+    let data = data_of(&mut file_sys, "file");
+    let sub = inode_of(&mut file_sys, "dir/sub");
+
+    //Wipe the INODE of FILE, and point the parent of SUB at the root
+    file_sys.cache.write_from_buffer(&file_sys.block, file, &EMPTY_BUFFER).unwrap();
+    let dir = MemoryDirectory::open_sector(&mut file_sys, sub).unwrap();
+    dir.as_ref().borrow().set_parent(&mut file_sys, ROOT_DIR_SECTOR).unwrap();
+    dir.as_ref().borrow().close(&mut file_sys).unwrap();
+
+    let problems = Fsck::check(&mut file_sys).unwrap().problems;
+    let dir_sector = inode_of(&mut file_sys, "dir");
+
+    assert!(problems.contains(&Problem::DanglingEntry { dir: ROOT_DIR_SECTOR, name: "file".to_string(), target: file }));
+    assert!(problems.contains(&Problem::BadParent { dir: sub, found: ROOT_DIR_SECTOR, expected: dir_sector }));
+    assert!(data.iter().all(|&sector| problems.contains(&Problem::LeakedSector { sector })));
+
+    repair_and_recheck(&mut file_sys);
+    assert!(file_sys.open("file").is_err());
+    assert_eq!(inode_of(&mut file_sys, "dir/sub/.."), dir_sector);
+  }
+
+  #[test]
+  fn test_corrupt_inode_is_kept() {
+    let mut file_sys = setup_file_sys(1024);
+    file_sys.create("file", 1024, false).unwrap();
+    let file = inode_of(&mut file_sys, "file");
+    let data = data_of(&mut file_sys, "file");
+
+    //Longer than triple indirection reaches
+    let mut buffer = EMPTY_BUFFER;
+    file_sys.cache.read_to_buffer(&file_sys.block, file, &mut buffer).unwrap();
+    from_bytes_mut::<DiskInode>(&mut buffer).len = u32::MAX;
+    file_sys.cache.write_from_buffer(&file_sys.block, file, &buffer).unwrap();
+
+    //Still referenced, so neither the INODE nor the sectors its pointers map are freed
+    let problems = Fsck::check(&mut file_sys).unwrap().problems;
+    assert_eq!(problems, vec![Problem::CorruptInode { sector: file }]);
+
+    Fsck::check(&mut file_sys).unwrap().repair(&mut file_sys).unwrap();
+    assert_eq!(Fsck::check(&mut file_sys).unwrap().problems(), &[Problem::CorruptInode { sector: file }]);
+    assert!(std::iter::once(file).chain(data).all(|sector| file_sys.freemap.inner.test(sector)));
+  }
+
+  #[test]
+  fn test_orphan_and_double_allocated() {
+    let mut file_sys = setup_file_sys(1024);
+
+    file_sys.create("orphan", 0, false).unwrap();
+    file_sys.util_write("orphan", b"still here", 10).unwrap();
+    file_sys.create("shared", 600, false).unwrap();
+    FileTable::remove_by_name(&mut file_sys, "orphan").unwrap();
+
+    let orphan = inode_of(&mut file_sys, "orphan");
+    let shared = inode_of(&mut file_sys, "shared");
+
+    let root = MemoryDirectory::open_sector(&mut file_sys, ROOT_DIR_SECTOR).unwrap();
+    MemoryDirectory::unlink(root.borrow_mut(), &mut file_sys, "orphan").unwrap();
+    MemoryDirectory::add(root.borrow_mut(), &mut file_sys, "alias", shared, false).unwrap();
+    root.as_ref().borrow().close(&mut file_sys).unwrap();
+
+    let problems = Fsck::check(&mut file_sys).unwrap().problems;
+    assert!(problems.contains(&Problem::OrphanInode { sector: orphan }));
+    assert!(problems.contains(&Problem::DoubleAllocated { sector: shared, inode: shared, owner: shared }));
+
+    repair_and_recheck(&mut file_sys);
+
+    let mut buffer = [0u8; 10];
+    file_sys.util_read(&format!("{}/#{}", LOST_FOUND, orphan), &mut buffer, 10).unwrap();
+    assert_eq!(&buffer, b"still here");
+    assert!(file_sys.open("alias").is_err() || file_sys.open("shared").is_err());
+  }
+}
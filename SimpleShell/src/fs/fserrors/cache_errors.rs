@@ -15,6 +15,8 @@ use crate::fs::{
 pub (crate) enum CacheError {
   FlushUnoccupiedEntry(),
   FlushNullDiskSector(),
+  JournalFull(u32),
+  JournalAborted(),
   BlockError(Box<BlockError>)
 }
 
@@ -25,6 +27,8 @@ impl Display for CacheError {
     match self {
       Self::FlushUnoccupiedEntry() => write!(f, "Attempted to flush unoccupied entry"),
       Self::FlushNullDiskSector() => write!(f, "Attempted to flush entry with no disk sector"),
+      Self::JournalFull(capacity) => write!(f, "Transaction logs more than the {} sectors the journal holds, it was aborted", capacity),
+      Self::JournalAborted() => write!(f, "Journal was aborted, remount to write again"),
       Self::BlockError(e) => write!(f, "Block error: {:?}", e)
     }
   }
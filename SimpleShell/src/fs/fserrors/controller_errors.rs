@@ -11,14 +11,18 @@ use std::{
 #[derive(Debug)]
 pub(crate) enum ControllerError {
   ChannelOccupied(usize),
-  IOError(io::Error)
+  ControllerFull,
+  IOError(io::Error),
+  NoSuchDevice(String)
 }
 
 impl Display for ControllerError {
   fn fmt(&self, f: &mut Formatter) -> Result {
     match self {
       Self::ChannelOccupied(channel_num) => write!(f, "Channel #: {} fully occupied", channel_num),
-      Self::IOError(e) => write!(f, "IO error: {:?}", e)
+      Self::ControllerFull => write!(f, "Every channel is fully occupied"),
+      Self::IOError(e) => write!(f, "IO error: {:?}", e),
+      Self::NoSuchDevice(name) => write!(f, "No device named {}", name)
     }
   }
 }
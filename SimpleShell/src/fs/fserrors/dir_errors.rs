@@ -14,6 +14,7 @@ use super::{
 #[derive(Debug)]
 pub enum DirError {
   CannotDeleteNonEmptyDir(String),
+  CorruptHeader(u32),
   CreationFailedBytesMissing(),
   EntryNotFound(String),
   EntryAlreadyExists(String),
@@ -27,6 +28,7 @@ impl Display for DirError {
   fn fmt(&self, f: &mut Formatter<'_>) -> Result {
     match self {
       Self::CannotDeleteNonEmptyDir(pat) => write!(f, "Cannot delete non-empty directory: {}", pat),
+      Self::CorruptHeader(sector) => write!(f, "Directory with inode num: {} has no valid header", sector),
       Self::CreationFailedBytesMissing() => write!(f, "Creation failed, full directory not written to disk"),
       Self::EntryNotFound(pat) => write!(f, "Entry not found with path: {}", pat),
       Self::EntryAlreadyExists(pat) => write!(f, "Entry with name: {}, already exists", pat),
@@ -9,6 +9,7 @@ use std::{
 
 use super::{
   bitmap_errors::BitmapError,
+  cache_errors::CacheError,
   file_errors::FileError,
   inode_errors::InodeError
 };
@@ -17,6 +18,7 @@ use super::{
 pub (crate) enum FreemapError {
   NoFileAssigned(),
   BitmapError(Box<BitmapError>),
+  CacheError(Box<CacheError>),
   FileError(Box<FileError>),
   InodeError(Box<InodeError>)
 }
@@ -29,6 +31,7 @@ impl Display for FreemapError {
     match self {
       Self::NoFileAssigned() => write!(f, "Attempted to write/read to file when unassigned"),
       Self::BitmapError(e) => write!(f, "Bitmap Error: {:?}", e),
+      Self::CacheError(e) => write!(f, "Cache Error: {:?}", e),
       Self::FileError(e) => write!(f, "File Error: {:?}", e),
       Self::InodeError(e) => write!(f, "Inode Error: {:?}", e)
     }
@@ -41,6 +44,12 @@ impl From<BitmapError> for FreemapError {
   }
 }
 
+impl From<CacheError> for FreemapError {
+  fn from(e: CacheError) -> Self {
+    Self::CacheError(Box::new(e))
+  }
+}
+
 impl From<FileError> for FreemapError {
   fn from(e: FileError) -> Self {
     Self::FileError(Box::new(e))
@@ -16,8 +16,12 @@ use super::{
 pub (crate) enum InodeError {
   OffsetOutOfBounds(u32, u32),
   IndexOutOfBounds(u32),
+  ///A length past the largest an INODE can map, with the length and that largest one
+  LengthOutOfBounds(u32, u32),
   InodeNotFound(u32),
   WriteDenied(),
+  ///The overflow chain of an extent-based INODE does not hold as many extents as it should, broken at the given sector
+  BrokenExtentChain(u32),
   CacheError(Box<CacheError>),
   FreemapError(Box<FreemapError>)
 }
@@ -29,8 +33,10 @@ impl Display for InodeError {
     match self {
       Self::OffsetOutOfBounds(offset, actual) => write!(f, "Offset: {} past inode length: {}", offset, actual),
       Self::IndexOutOfBounds(idx) => write!(f, "Index: {} past max inode length", idx),
+      Self::LengthOutOfBounds(len, max) => write!(f, "Length: {} past max inode length: {}", len, max),
       Self::InodeNotFound(inode_num) => write!(f, "Inode with num: {}, not found", inode_num),
       Self::WriteDenied() => write!(f, "Write denied for given inode"),
+      Self::BrokenExtentChain(sector) => write!(f, "Extent chain broken at sector: {}", sector),
       Self::CacheError(e) => write!(f, "Cache Error: {:?}", e),
       Self::FreemapError(e) => write!(f, "Freemap Error: {:?}", e)
     }
@@ -7,11 +7,15 @@ use std::{
   }
 };
 
+use block_errors::BlockError;
 use cache_errors::CacheError;
+use controller_errors::ControllerError;
 use dir_errors::DirError;
 use file_errors::FileError;
 use freemap_errors::FreemapError;
 use inode_errors::InodeError;
+use mount_errors::MountError;
+use superblock_errors::SuperblockError;
 
 pub(crate) mod bitmap_errors;
 pub(crate) mod block_errors;
@@ -21,6 +25,8 @@ pub(crate) mod dir_errors;
 pub(crate) mod file_errors;
 pub(crate) mod freemap_errors;
 pub(crate) mod inode_errors;
+pub(crate) mod mount_errors;
+pub(crate) mod superblock_errors;
 
 #[derive(Debug)]
 pub enum FSErrors {
@@ -32,7 +38,10 @@ pub enum FSErrors {
   FreemapError(freemap_errors::FreemapError),
   InodeError(inode_errors::InodeError),
   InvalidName(String, usize),
-  IOError(std::io::Error)
+  IOError(std::io::Error),
+  MountError(mount_errors::MountError),
+  PermissionDenied(String),
+  SuperblockError(superblock_errors::SuperblockError)
 }
 
 impl Error for FSErrors {}
@@ -48,17 +57,32 @@ impl Display for FSErrors {
       Self::FreemapError(e) => write!(f, "Freemap Error: {:?}", e),
       Self::InodeError(e) => write!(f, "Inode Error: {:?}", e),
       Self::InvalidName(name, len) => write!(f, "Invalid name: {}, len: {}, max len 256", name, len),
-      Self::IOError(e) => write!(f, "IO Error: {}", e)
+      Self::IOError(e) => write!(f, "IO Error: {}", e),
+      Self::MountError(e) => write!(f, "Mount Error: {}", e),
+      Self::PermissionDenied(path) => write!(f, "Permission denied: {}", path),
+      Self::SuperblockError(e) => write!(f, "Superblock Error: {}", e)
     }
   }
 }
 
+impl From<BlockError> for FSErrors {
+  fn from(e: BlockError) -> Self {
+    Self::BlockError(e)
+  }
+}
+
 impl From<CacheError> for FSErrors {
   fn from(e: CacheError) -> Self {
     Self::CacheError(e)
   }
 }
 
+impl From<ControllerError> for FSErrors {
+  fn from(e: ControllerError) -> Self {
+    Self::ControllerError(e)
+  }
+}
+
 impl From<DirError> for FSErrors {
   fn from(e: DirError) -> Self {
     Self::DirError(e)
@@ -88,3 +112,15 @@ impl From<std::io::Error> for FSErrors {
     Self::IOError(e)
   }
 }
+
+impl From<MountError> for FSErrors {
+  fn from(e: MountError) -> Self {
+    Self::MountError(e)
+  }
+}
+
+impl From<SuperblockError> for FSErrors {
+  fn from(e: SuperblockError) -> Self {
+    Self::SuperblockError(e)
+  }
+}
@@ -0,0 +1,36 @@
+use std::{
+  error::Error,
+  fmt::{
+    Display,
+    Formatter,
+    Result
+  }
+};
+
+#[derive(Debug)]
+pub(crate) enum MountError {
+  AlreadyMounted(String),
+  NotMounted(String),
+  ///Mount points must be absolute paths other than the root directory
+  InvalidMountPoint(String),
+  ///Another file system is mounted below the mount point
+  Busy(String),
+  NoSuchPartition(u32),
+  ///The image has this many partitions and none was chosen
+  PartitionRequired(usize)
+}
+
+impl Error for MountError {}
+
+impl Display for MountError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    match self {
+      Self::AlreadyMounted(point) => write!(f, "{} is already a mount point", point),
+      Self::NotMounted(point) => write!(f, "Nothing is mounted at {}", point),
+      Self::InvalidMountPoint(point) => write!(f, "{} can not be a mount point", point),
+      Self::Busy(point) => write!(f, "Another file system is mounted below {}", point),
+      Self::NoSuchPartition(number) => write!(f, "No partition #: {}", number),
+      Self::PartitionRequired(cnt) => write!(f, "Image has {} partitions, choose one", cnt)
+    }
+  }
+}
@@ -0,0 +1,49 @@
+use std::{
+  error::Error,
+  fmt::{
+    Display,
+    Formatter,
+    Result
+  }
+};
+
+use super::block_errors::BlockError;
+
+#[derive(Debug)]
+pub(crate) enum SuperblockError {
+  ///A format option, with the value given and the range it must lie in
+  InvalidOption(&'static str, u32, (u32, u32)),
+  ///The device holds no SUPERBLOCK, being unformatted or holding something else
+  NotFormatted,
+  ///The device was formatted by a newer version, with the version found and the newest one supported
+  UnsupportedVersion(u32, u32),
+  ///The device is formatted for a different number of sectors than it has, with the number found and the actual one
+  GeometryMismatch(u32, u32),
+  ///A part of the layout is not where this version puts it, with the value found and the one expected
+  LayoutMismatch(&'static str, u32, u32),
+  ///Incompatible features this version does not know
+  UnsupportedFeatures(u32),
+  BlockError(Box<BlockError>)
+}
+
+impl Error for SuperblockError {}
+
+impl Display for SuperblockError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    match self {
+      Self::InvalidOption(name, value, (min, max)) => write!(f, "Invalid {}: {}, must be {} to {}", name, value, min, max),
+      Self::NotFormatted => write!(f, "No superblock found, the image is not formatted"),
+      Self::UnsupportedVersion(found, supported) => write!(f, "Image is version {}, newest supported is {}", found, supported),
+      Self::GeometryMismatch(found, actual) => write!(f, "Image was formatted for {} sectors, device has {}", found, actual),
+      Self::LayoutMismatch(name, found, expected) => write!(f, "Image has {} {}, expected {}", name, found, expected),
+      Self::UnsupportedFeatures(features) => write!(f, "Image uses unsupported features: {:#x}", features),
+      Self::BlockError(e) => write!(f, "Block Error: {:?}", e)
+    }
+  }
+}
+
+impl From<BlockError> for SuperblockError {
+  fn from(e: BlockError) -> Self {
+    Self::BlockError(Box::new(e))
+  }
+}
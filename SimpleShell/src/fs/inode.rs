@@ -1,25 +1,79 @@
 use std::{
-  cell::RefCell, cmp::min, rc::Rc
+  cell::{Cell, RefCell}, cmp::{max, min}, mem::size_of, rc::Rc, time::{SystemTime, UNIX_EPOCH}
 };
 
 use crate::fs::{
   block::{
     Block, BlockSectorT, BLOCK_SECTOR_SIZE
   },
-  cache::Cache,
-  file_sys::FileSystem,
+  cache::{
+    Cache, CacheMode
+  },
+  extent::{
+    Extent, ExtentList, ExtentTable
+  },
+  file_sys::{
+    FileSystem,
+    FREE_MAP_SECTOR
+  },
   freemap::Freemap,
-  fserrors::inode_errors::InodeError
+  fserrors::inode_errors::InodeError,
+  iostat::Origin,
+  journal::Journal
 };
 
 use bytemuck::{
-  bytes_of, from_bytes, Pod, Zeroable
+  bytes_of, bytes_of_mut, from_bytes, Pod, Zeroable
 };
+use clap::ValueEnum;
 
-const DIRECT_BLOCKS_CNT: u32 = 123u32;
+pub(crate) const DIRECT_BLOCKS_CNT: u32 = 118u32;
 const INDIRECT_BLOCKS_PER_SECTOR: u32 = 128u32;
+const MAX_INDIRECTION: u32 = 3u32;
 pub const INODE_SIGNATURE: u32 = 0x494e4f44;
-const EMPTY_BUFFER: [u8; BLOCK_SECTOR_SIZE as usize] = [0u8; BLOCK_SECTOR_SIZE as usize];
+const POINTER_AREA_SIZE: usize = (DIRECT_BLOCKS_CNT + MAX_INDIRECTION) as usize * size_of::<BlockSectorT>();
+pub(crate) const EMPTY_BUFFER: [u8; BLOCK_SECTOR_SIZE as usize] = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+pub const ACCESS_READ: u16 = 0o4;
+pub const ACCESS_WRITE: u16 = 0o2;
+pub const ACCESS_EXEC: u16 = 0o1;
+const DEFAULT_FILE_MODE: u16 = 0o644;
+const DEFAULT_DIR_MODE: u16 = 0o755;
+///Number of sectors read into the cache past the end of a sequential read
+const READ_AHEAD_SECTORS: u32 = 4u32;
+///Seconds after which the access time is updated on a read even if INODE has not changed
+const ATIME_REFRESH: u32 = 24 * 60 * 60;
+
+///How an INODE maps its data sectors, chosen for new INODEs when the file system is formatted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum InodeFormat {
+  ///Sector by sector through direct and indirect blocks
+  #[default]
+  Indexed,
+  ///Runs of contiguous sectors
+  Extent
+}
+
+impl InodeFormat {
+  fn from_byte(byte: u8) -> Self {
+    match byte {
+      1 => Self::Extent,
+      _ => Self::Indexed
+    }
+  }
+
+  fn to_byte(self) -> u8 {
+    match self {
+      Self::Indexed => 0u8,
+      Self::Extent => 1u8
+    }
+  }
+}
+
+///Returns the current time in seconds since the UNIX epoch
+fn now() -> u32 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as u32).unwrap_or(0)
+}
 
 ///A data structure that maintains the currently open INODEs
 ///
@@ -64,39 +118,25 @@ impl InodeList {
   ///
   ///This does not guarantee dropping the INODE, the caller must ensure they go out of scope in order to be dropped
   pub fn close_inode(state: &mut FileSystem, inode_num: BlockSectorT) -> Result<(), InodeError> {
-    let mut idx_to_remove = None;
-    let mut removed = false;
-    let mut sector = 0;
-    let mut inode_to_deallocate = None;
-
-    match state.inode_list.inner.iter().enumerate().find(|(_, inode)| inode.borrow().sector == inode_num) {
+    let (idx, close_inode, removed) = match state.inode_list.inner.iter().enumerate().find(|(_, inode)| inode.borrow().sector == inode_num) {
       Some((idx, rc_inode)) => {
         let mut inode = rc_inode.borrow_mut();
         inode.open_cnt -= 1;
-        let close_inode = inode.open_cnt == 0;
-
-        if inode.removed() {
-          removed = true;
-          sector = inode.sector;
-          inode_to_deallocate = Some(rc_inode.clone())
-        }
-
-        if close_inode {
-          idx_to_remove = Some(idx);
-        }
+        (idx, inode.open_cnt == 0, inode.removed())
       },
       None => return Err(InodeError::InodeNotFound(inode_num))
-    }
+    };
 
-    if removed {
-      Freemap::release(state, sector, 1)?;
-      if let Some(inode) = inode_to_deallocate  {
-        inode.borrow().deallocate(state)?;
-      }
-    }
+    //Only the last opener of a removed INODE gives its sectors back
+    if close_inode {
+      let inode = state.inode_list.inner.remove(idx);
 
-    if let Some(idx) = idx_to_remove {
-      state.inode_list.inner.remove(idx);
+      if removed {
+        Journal::transaction(state, |state| {
+          Freemap::release(state, inode_num, 1)?;
+          inode.borrow().deallocate(state)
+        })?;
+      }
     }
     Ok(())
   }
@@ -108,13 +148,25 @@ fn bytes_to_sectors(bytes: u32) -> u32 {
   bytes.div_ceil(BLOCK_SECTOR_SIZE)
 }
 
+///Returns the number of data sectors reachable from a block with LVL degrees of indirection
+///
+///A data sector itself (LVL 0) spans 1
+#[inline(always)]
+fn lvl_span(lvl: u32) -> u32 {
+  INDIRECT_BLOCKS_PER_SECTOR.pow(lvl)
+}
+
 ///An in-memory representation of an on-disk INODE
 pub(crate) struct MemoryInode {
   data: DiskInode,
   deny_write_cnt: u32,
   open_cnt: u32,
   removed: bool,
-  sector: BlockSectorT
+  sector: BlockSectorT,
+  ///Offset the last read ended at, where the next one starts if reads are sequential
+  read_end: Cell<u32>,
+  ///Extents of an extent-based INODE as last read, dropped before anything that may change them
+  extent_list: RefCell<Option<ExtentList>>
 }
 
 impl MemoryInode {
@@ -133,7 +185,9 @@ impl MemoryInode {
         deny_write_cnt: 0u32,
         open_cnt: 1u32,
         removed: false,
-        sector
+        sector,
+        read_end: Cell::new(0u32),
+        extent_list: RefCell::new(None)
       }
     )
   }
@@ -149,6 +203,11 @@ impl MemoryInode {
     self.removed
   }
 
+  ///Marks INODE to be deleted once it is closed by its last opener
+  pub fn remove(&mut self) {
+    self.removed = true;
+  }
+
   pub fn inode_num(&self) -> BlockSectorT {
     self.sector
   }
@@ -157,6 +216,115 @@ impl MemoryInode {
     self.data.len
   }
 
+  ///Returns how INODE maps its data sectors
+  pub fn format(&self) -> InodeFormat {
+    self.data.format()
+  }
+
+  ///Returns the extents of INODE in file order, or NONE if it is not extent-based
+  pub fn extents(&self, block: &Block, cache: &Cache) -> Result<Option<Vec<Extent>>, InodeError> {
+    match self.format() {
+      InodeFormat::Extent => Ok(Some(self.with_extents(block, cache, |list| list.extents().to_vec())?)),
+      InodeFormat::Indexed => Ok(None)
+    }
+  }
+
+  ///Returns the permission bits of INODE
+  pub fn mode(&self) -> u16 {
+    self.data.mode
+  }
+
+  ///Returns the (UID, GID) owning INODE
+  pub fn owner(&self) -> (u16, u16) {
+    (self.data.uid, self.data.gid)
+  }
+
+  ///Returns the creation, modification and access times of INODE in seconds since the UNIX epoch
+  pub fn times(&self) -> (u32, u32, u32) {
+    (self.data.ctime, self.data.mtime, self.data.atime)
+  }
+
+  ///Returns whether the user UID in group GID is granted every permission in ACCESS
+  ///
+  ///UID 0 is granted everything, otherwise the owner, group or other bits apply, in that order
+  pub fn check_access(&self, uid: u16, gid: u16, access: u16) -> bool {
+    let bits = match (uid, gid) {
+      (0, _) => return true,
+      (uid, _) if uid == self.data.uid => self.data.mode >> 6,
+      (_, gid) if gid == self.data.gid => self.data.mode >> 3,
+      _ => self.data.mode
+    };
+    bits & access == access
+  }
+
+  ///Sets the permission bits of INODE to MODE, writing INODE to disk
+  pub fn set_mode(&mut self, block: &Block, cache: &Cache, mode: u16) -> Result<(), InodeError> {
+    self.data.mode = mode & 0o777;
+    self.write_back(block, cache)
+  }
+
+  ///Sets the owner of INODE to UID and GID, writing INODE to disk
+  pub fn set_owner(&mut self, block: &Block, cache: &Cache, uid: u16, gid: u16) -> Result<(), InodeError> {
+    self.data.uid = uid;
+    self.data.gid = gid;
+    self.write_back(block, cache)
+  }
+
+  ///Updates the access time of INODE, writing INODE to disk
+  ///
+  ///As with relatime, that is only done if INODE changed since it was last accessed, or the access time is stale
+  pub fn touch_access(&mut self, block: &Block, cache: &Cache) -> Result<(), InodeError> {
+    let (time, atime) = (now(), self.data.atime);
+    let changed = atime <= self.data.mtime || atime <= self.data.ctime;
+
+    if time == atime || (!changed && time < atime.saturating_add(ATIME_REFRESH)) {
+      return Ok(())
+    }
+    self.data.atime = time;
+    self.write_back(block, cache)
+  }
+
+  ///Writes the ON DISK INODE back to its sector
+  fn write_back(&self, block: &Block, cache: &Cache) -> Result<(), InodeError> {
+    cache.write_from_buffer(block, self.sector, bytes_of(&self.data))?;
+    Ok(())
+  }
+
+  ///Returns whether INODE holds file data rather than metadata
+  ///
+  ///Directories and the FREEMAP hold metadata, which is journaled. The contents of regular files are not
+  fn holds_data(&self) -> bool {
+    !self.is_dir() && self.sector != FREE_MAP_SECTOR
+  }
+
+  ///Returns what the data sectors of INODE are counted as on BLOCK
+  fn origin(&self) -> Origin {
+    match (self.is_dir(), self.sector) {
+      (true, _) => Origin::Directory,
+      (false, FREE_MAP_SECTOR) => Origin::Freemap,
+      (false, _) => Origin::Data
+    }
+  }
+
+  ///Writes BUFFER, which holds whole sectors, to the consecutive data sectors of INODE from SECTOR
+  ///
+  ///They bypass CACHE at once if DIRECT and INODE holds file data, otherwise they go through it one by one
+  fn write_sectors(&self, block: &Block, cache: &Cache, sector: BlockSectorT, buffer: &[u8], direct: bool) -> Result<(), InodeError> {
+    block.tagged(self.origin(), || {
+      if self.holds_data() && direct {
+        return cache.write_direct(block, sector, buffer)
+      }
+
+      buffer.chunks_exact(BLOCK_SECTOR_SIZE as usize)
+        .zip(sector..)
+        .try_for_each(|(image, sector)| match self.holds_data() {
+          true => cache.write_data(block, sector, image),
+          false => cache.write_from_buffer(block, sector, image)
+        })
+    })?;
+    Ok(())
+  }
+
   pub fn allow_write(&mut self) {
     assert!(self.deny_write_cnt > 0);
     assert!(self.deny_write_cnt <= self.open_cnt);
@@ -168,141 +336,242 @@ impl MemoryInode {
     assert!(self.deny_write_cnt <= self.open_cnt);
   }
 
+  ///Shrinks INODE down to LEN bytes, writing the new INODE to disk before releasing the sectors past it
+  pub fn shrink(&mut self, state: &mut FileSystem, len: u32) -> Result<(), InodeError> {
+    if self.deny_write_cnt > 0 { return Err(InodeError::WriteDenied()) }
+    if len >= self.len() { return Ok(()) }
+
+    Journal::transaction(state, |state| {
+      //Bytes past the new end of the last sector must read as zeros if INODE grows again
+      let sector_end = min(bytes_to_sectors(len) * BLOCK_SECTOR_SIZE, self.len());
+      self.zero_range(&state.block, &state.cache, len, sector_end)?;
+
+      let mut released = Vec::<Extent>::new();
+      self.extent_list.take();
+      self.data.shrink(state, len, &mut released)?;
+      self.data.mtime = now();
+      self.write_back_and_release(state, released)
+    })
+  }
+
+  ///Sets the length of INODE to LEN, shrinking it or extending it with a hole
+  pub fn truncate(&mut self, state: &mut FileSystem, len: u32) -> Result<(), InodeError> {
+    if self.deny_write_cnt > 0 { return Err(InodeError::WriteDenied()) }
+    self.data.check_len(len)?;
+
+    if len < self.len() {
+      return self.shrink(state, len)
+    }
+
+    self.data.len = len;
+    self.data.mtime = now();
+    self.write_back(&state.block, &state.cache)
+  }
+
   ///Returns a vector of BlockSectorT's that are allocated to INODE
   ///
-  ///Return values are in-order of visitation
+  ///Return values are in-order of visitation, holes are skipped
   pub fn data_sectors(&self, block: &Block, cache: &Cache) -> Result<Vec<BlockSectorT>, InodeError> {
-    let data_len = self.data.len;
+    let mut num_sectors = bytes_to_sectors(self.data.len);
 
-    let mut num_sectors = bytes_to_sectors(data_len);
-    let mut sectors = Vec::<BlockSectorT>::with_capacity(num_sectors as usize);
+    if self.format() == InodeFormat::Extent {
+      return self.with_extents(block, cache, ExtentList::sectors)
+    }
 
-    let mut curr_idx = 0usize;
-    let mut limit = min(num_sectors, DIRECT_BLOCKS_CNT);
+    let mut sectors = Vec::<BlockSectorT>::with_capacity(num_sectors as usize);
 
     //Direct blocks
-    (0..limit).for_each(|i| {
-      sectors[curr_idx] = self.data.direct_blocks[i as usize];
-      curr_idx += 1;
-    });
+    let limit = min(num_sectors, DIRECT_BLOCKS_CNT);
+    sectors.extend({ self.data.direct_blocks }[..limit as usize].iter().filter(|&&sector| sector != 0));
+    num_sectors -= limit;
+
+    //Indirect blocks, each level covering the range following the previous one
+    for lvl in 1..=MAX_INDIRECTION {
+      if num_sectors == 0 {
+        return Ok(sectors)
+      }
 
-    if {num_sectors -= limit; num_sectors} == 0 {
-      return Ok(sectors)
+      let limit = min(num_sectors, lvl_span(lvl));
+      Self::collect_indirect(block, cache, self.data.indirect_root(lvl), limit, lvl, &mut sectors)?;
+      num_sectors -= limit;
     }
 
-    //Indirect block
-    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
-    limit = min(num_sectors, INDIRECT_BLOCKS_PER_SECTOR);
-
-    cache.read_to_buffer(block, self.data.indirect_block, &mut buffer)?;
-    let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
-
-    (0..limit).for_each(|i| {
-      sectors[curr_idx] = indirect_block.inner[i as usize];
-      curr_idx += 1;
-    });
+    //Only a corrupt length covers more than triple indirection reaches
+    match num_sectors {
+      0 => Ok(sectors),
+      _ => Err(InodeError::IndexOutOfBounds(bytes_to_sectors(self.data.len)))
+    }
+  }
 
-    if {num_sectors -= limit; num_sectors} == 0 {
-      return Ok(sectors)
+  ///Appends the first NUM_SECTORS data sectors under the block at SECTOR, with LVL degrees of indirection, to SECTORS
+  fn collect_indirect(block: &Block, cache: &Cache, sector: BlockSectorT, mut num_sectors: u32, lvl: u32, sectors: &mut Vec<BlockSectorT>) -> Result<(), InodeError> {
+    if sector == 0 {
+      return Ok(())
     }
 
-    //Doubly indirect block
-    limit = min(num_sectors, INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR);
+    if lvl == 0 {
+      sectors.push(sector);
+      return Ok(())
+    }
 
-    cache.read_to_buffer(block, self.data.doubly_indirect_block, &mut buffer)?;
-    let doubly_indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
-    let doubly_indirect_limit = limit.div_ceil(INDIRECT_BLOCKS_PER_SECTOR);
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    cache.read_to_buffer(block, sector, &mut buffer)?;
+    let indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
 
-    let mut num_indirect_sectors = limit;
+    let unit = lvl_span(lvl - 1);
+    let limit = num_sectors.div_ceil(unit);
 
-    (0..doubly_indirect_limit).try_for_each::<_, Result<(), InodeError>>(|i| {
-      let subsize = min(num_indirect_sectors, INDIRECT_BLOCKS_PER_SECTOR);
+    for &sector in &indirect_block.inner[..limit as usize] {
+      let subsize = min(num_sectors, unit);
+      Self::collect_indirect(block, cache, sector, subsize, lvl - 1, sectors)?;
+      num_sectors -= subsize;
+    }
+    Ok(())
+  }
 
-      cache.read_to_buffer(block, doubly_indirect_block.inner[i as usize], &mut buffer)?;
-      let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
+  ///Returns the number of data sectors allocated to INODE, which is less than its length covers if it has holes
+  pub fn allocated_sectors(&self, block: &Block, cache: &Cache) -> Result<u32, InodeError> {
+    Ok(self.data_sectors(block, cache)?.len() as u32)
+  }
 
-      (0..subsize).for_each(|j| {
-        sectors[curr_idx] = indirect_block.inner[j as usize];
-        curr_idx += 1;
-      });
-      num_indirect_sectors -= subsize;
-      Ok(())
-    })?;
+  ///Returns every sector INODE holds on to other than its own, indirect and overflow blocks included
+  pub fn owned_sectors(&self, block: &Block, cache: &Cache) -> Result<Vec<BlockSectorT>, InodeError> {
+    let owned = self.owned_extents(block, cache)?;
+    Ok(owned.iter().flat_map(|extent| extent.start..extent.end()).collect())
+  }
 
-    if {num_sectors -= limit; num_sectors} == 0 {
-      return Ok(sectors)
-    }
-    panic!("Number of sectors were not 0 at end of traversal");
+  ///Returns the sectors INODE holds on to other than its own as far as its pointers can map them, cutting short a
+  ///length past that instead of failing on it
+  pub fn mappable_sectors(&self, block: &Block, cache: &Cache) -> Result<Vec<BlockSectorT>, InodeError> {
+    let owned = self.owned_extents_to(block, cache, min(self.data.len, self.data.max_len()))?;
+    Ok(owned.iter().flat_map(|extent| extent.start..extent.end()).collect())
   }
 
   ///Deallocates a sectors allocated to INODE by marking them as free on the FREEMAP
   ///
   ///This operation does NOT clear the data at those sectors, making them recoverable
   fn deallocate(&self, state: &mut FileSystem) -> Result<(), InodeError> {
-    let data_len = self.data.len;
+    let released = self.owned_extents(&state.block, &state.cache)?;
+    Self::release_all(state, released)
+  }
 
-    let mut num_sectors = bytes_to_sectors(data_len);
-    let mut limit = min(num_sectors, DIRECT_BLOCKS_CNT);
+  ///Collects every run of sectors INODE holds on to other than its own
+  fn owned_extents(&self, block: &Block, cache: &Cache) -> Result<Vec<Extent>, InodeError> {
+    self.owned_extents_to(block, cache, self.data.len)
+  }
 
-    for i in 0..limit {
-      Freemap::release(state, self.data.direct_blocks[i as usize], 1)?;
-    }
+  ///Collects every run of sectors INODE holds on to other than its own, within its first LEN bytes
+  fn owned_extents_to(&self, block: &Block, cache: &Cache, len: u32) -> Result<Vec<Extent>, InodeError> {
+    let mut released = Vec::<Extent>::new();
 
-    if {num_sectors -= limit; num_sectors} == 0 {
-      return Ok(())
+    if self.format() == InodeFormat::Extent {
+      self.data.extent_list(block, cache)?.release(&mut released);
+      return Ok(released)
     }
 
-    limit = min(num_sectors, INDIRECT_BLOCKS_PER_SECTOR);
-    Self::deallocate_indirect(state, self.data.indirect_block, limit, 1)?;
+    let mut num_sectors = bytes_to_sectors(len);
+    let limit = min(num_sectors, DIRECT_BLOCKS_CNT);
 
-    if {num_sectors -= limit; num_sectors} == 0 {
-      return Ok(())
+    for i in 0..limit {
+      let sector = self.data.direct_blocks[i as usize];
+
+      if sector != 0 {
+        released.push(Extent::single(sector));
+      }
     }
+    num_sectors -= limit;
 
-    limit = min(num_sectors, INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR);
-    Self::deallocate_indirect(state, self.data.doubly_indirect_block, limit, 2)?;
+    for lvl in 1..=MAX_INDIRECTION {
+      if num_sectors == 0 {
+        break;
+      }
 
-    assert_eq!(num_sectors, 0, "Number of sectors was not 0 at end of traversal");
-    Ok(())
+      let limit = min(num_sectors, lvl_span(lvl));
+      Self::deallocate_indirect(block, cache, self.data.indirect_root(lvl), limit, lvl, &mut released)?;
+      num_sectors -= limit;
+    }
+
+    //Only a corrupt length covers more than triple indirection reaches
+    match num_sectors {
+      0 => Ok(released),
+      _ => Err(InodeError::IndexOutOfBounds(bytes_to_sectors(len)))
+    }
   }
 
-  ///Deallocate INDIRECT BLOCKS (single or doubly),
+  ///Collects INDIRECT BLOCKS (single, doubly or triply) into RELEASED,
   ///where the parent block is at SECTOR with NUM_SECTORS to be deallocated, and LVL degrees of indirection
-  fn deallocate_indirect(state: &mut FileSystem, sector: BlockSectorT, mut num_sectors: u32, lvl: u32) -> Result<(), InodeError> {
-    assert!(lvl <= 2, "Only double indirection is supported");
+  fn deallocate_indirect(block: &Block, cache: &Cache, sector: BlockSectorT, mut num_sectors: u32, lvl: u32, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    assert!(lvl <= MAX_INDIRECTION, "Only up to triple indirection is supported");
+
+    //Nothing was ever written under a hole
+    if sector == 0 {
+      return Ok(())
+    }
 
     //Base case
     if lvl == 0 {
-      Freemap::release(state, sector, 1)?;
+      released.push(Extent::single(sector));
       return Ok(());
     }
 
     let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
 
-    state.cache.read_to_buffer(&state.block, sector, &mut buffer)?;
+    cache.read_to_buffer(block, sector, &mut buffer)?;
     let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
 
-    //At single indirection (lvl 1), we only want to deallocate 1 sector
-    //At double indirection (lvl 2), we want to deallocate either an entire section, or the remaing amount
-    let unit = if lvl == 1 { 1 } else { INDIRECT_BLOCKS_PER_SECTOR };
+    //Each child either has an entire span to deallocate, or the remaining amount
+    let unit = lvl_span(lvl - 1);
+    let limit = num_sectors.div_ceil(unit);
 
-    for sector in indirect_block.inner {
+    for &sector in &indirect_block.inner[..limit as usize] {
       let subsize = min(num_sectors, unit);
-      Self::deallocate_indirect(state, sector, subsize, lvl - 1)?;
+      Self::deallocate_indirect(block, cache, sector, subsize, lvl - 1, released)?;
       num_sectors -= subsize;
     }
+    released.push(Extent::single(sector));
+    Ok(())
+  }
 
-    assert_eq!(num_sectors, 0, "Number of sectors was not 0 at end of traversal");
-    Freemap::release(state, sector, 1)?;
+  ///Marks every run in RELEASED as free on the FREEMAP
+  fn release_all(state: &mut FileSystem, released: Vec<Extent>) -> Result<(), InodeError> {
+    for extent in released {
+      Freemap::release(state, extent.start, extent.len)?;
+    }
+    Ok(())
+  }
+
+  ///Writes INODE to disk, then releases the sectors in RELEASED that it no longer points to
+  ///
+  ///The cache is flushed in between, so a crash can leak those sectors but never leave INODE pointing at free ones
+  fn write_back_and_release(&self, state: &mut FileSystem, released: Vec<Extent>) -> Result<(), InodeError> {
+    self.write_back(&state.block, &state.cache)?;
+
+    if !released.is_empty() {
+      state.cache.flush(&state.block)?;
+      Self::release_all(state, released)?;
+    }
     Ok(())
   }
 
   ///Reads LENGTH bytes into BUFFER, starting at OFFSET. Returns the number of bytes read
-  pub fn read_at(&self, block: &Block, cache: &Cache, buffer: &mut [u8], mut len: u32, mut ofst: u32) -> Result<u32, InodeError> {
+  ///
+  ///If the read starts where the last one ended, the sectors after it are read ahead into CACHE
+  pub fn read_at(&self, block: &Block, cache: &Cache, buffer: &mut [u8], len: u32, ofst: u32) -> Result<u32, InodeError> {
+    self.read_chunks(block, cache, buffer, len, ofst, cache.mode() == CacheMode::Direct)
+  }
+
+  ///Reads like READ_AT, except that whole sectors of file data are read straight from BLOCK without caching them
+  pub fn read_direct(&self, block: &Block, cache: &Cache, buffer: &mut [u8], len: u32, ofst: u32) -> Result<u32, InodeError> {
+    self.read_chunks(block, cache, buffer, len, ofst, true)
+  }
+
+  fn read_chunks(&self, block: &Block, cache: &Cache, buffer: &mut [u8], mut len: u32, mut ofst: u32, direct: bool) -> Result<u32, InodeError> {
+    let direct = direct && self.holds_data();
+    let sequential = ofst == self.read_end.get() && !direct;
     let mut bytes_read = 0usize;
     let mut bounce: Option<[u8; BLOCK_SECTOR_SIZE as usize]> = None;
 
-    while len > 0 {
+    while len > 0 && ofst < self.len() {
       let sector_idx = self.byte_to_sector(block, cache, ofst)?;
       let sector_ofst = (ofst % BLOCK_SECTOR_SIZE) as usize;
 
@@ -310,20 +579,29 @@ impl MemoryInode {
       let rmn_sector = BLOCK_SECTOR_SIZE as usize - sector_ofst;
       let rmn_min = min(rmn_inode, rmn_sector as u32);
 
-      let chunk_size = min(len, rmn_min) as usize;
+      let mut chunk_size = min(len, rmn_min) as usize;
 
       if chunk_size == 0 { break }
 
-      //If we can read an entire SECTOR
-      if sector_ofst == 0 && chunk_size == BLOCK_SECTOR_SIZE as usize {
-        let buffer_slice = &mut buffer[bytes_read..(bytes_read + BLOCK_SECTOR_SIZE as usize)];
-        cache.read_to_buffer(block, sector_idx, buffer_slice)?;
+      //Holes read as zeros
+      if sector_idx == 0 {
+        buffer[bytes_read..(bytes_read + chunk_size)].fill(0);
+      } else if sector_ofst == 0 && chunk_size == BLOCK_SECTOR_SIZE as usize {
+        //Entire SECTORs that follow each other on disk are read together
+        let cnt = self.contiguous(block, cache, ofst, sector_idx, min(len, rmn_inode) / BLOCK_SECTOR_SIZE)?;
+        chunk_size = (cnt * BLOCK_SECTOR_SIZE) as usize;
+
+        let buffer_slice = &mut buffer[bytes_read..(bytes_read + chunk_size)];
+        block.tagged(self.origin(), || match direct {
+          true => cache.read_direct(block, sector_idx, buffer_slice),
+          false => cache.read_sectors(block, sector_idx, buffer_slice)
+        })?;
       } else {
         if bounce.is_none() {
           bounce = Some(EMPTY_BUFFER);
         }
         let bounce = bounce.as_mut().unwrap();
-        cache.read_to_buffer(block, sector_idx,bounce)?;
+        block.tagged(self.origin(), || cache.read_to_buffer(block, sector_idx, bounce))?;
 
         let buffer_slice = &mut buffer[bytes_read..(bytes_read + chunk_size)];
         let bounce_slice = &bounce[sector_ofst..(sector_ofst + chunk_size)];
@@ -335,37 +613,117 @@ impl MemoryInode {
       ofst += chunk_size as u32;
       bytes_read += chunk_size;
     }
+
+    self.read_end.set(ofst);
+    if sequential && bytes_read > 0 {
+      //Reading ahead is only a hint, so a sector that fails is left for the read that needs it to report
+      let _ = self.read_ahead(block, cache, ofst.next_multiple_of(BLOCK_SECTOR_SIZE));
+    }
     Ok(bytes_read as u32)
   }
 
-  pub fn write_at(&mut self, state: &mut FileSystem, buffer: &[u8], mut len: u32, mut ofst: u32) -> Result<u32, InodeError> {
-    let mut bytes_wrote = 0usize;
-    let mut bounce: Option<[u8; BLOCK_SECTOR_SIZE as usize]> = None;
+  ///Reads up to READ_AHEAD_SECTORS sectors of data from OFST into CACHE, stopping at the end of INODE
+  ///
+  ///Sectors contiguous on disk are read ahead together
+  fn read_ahead(&self, block: &Block, cache: &Cache, ofst: u32) -> Result<(), InodeError> {
+    let mut pos = ofst;
+    let end = min(self.len(), ofst.saturating_add(READ_AHEAD_SECTORS * BLOCK_SECTOR_SIZE));
+
+    while pos < end {
+      let cnt = match self.byte_to_sector(block, cache, pos)? {
+        0 => 1,
+        sector => {
+          let cnt = self.contiguous(block, cache, pos, sector, (end - pos).div_ceil(BLOCK_SECTOR_SIZE))?;
+          block.tagged(self.origin(), || cache.read_ahead(block, sector, cnt))?;
+          cnt
+        }
+      };
+      pos += cnt * BLOCK_SECTOR_SIZE;
+    }
+    Ok(())
+  }
+
+  ///Returns how many of the MAX sectors from the one holding OFST, which is FIRST, follow each other on disk
+  fn contiguous(&self, block: &Block, cache: &Cache, ofst: u32, first: BlockSectorT, max: u32) -> Result<u32, InodeError> {
+    let mut cnt = 1u32;
+    while cnt < max && self.byte_to_sector(block, cache, ofst + cnt * BLOCK_SECTOR_SIZE)? == first + cnt {
+      cnt += 1;
+    }
+    Ok(cnt)
+  }
+
+  pub fn write_at(&mut self, state: &mut FileSystem, buffer: &[u8], len: u32, ofst: u32) -> Result<u32, InodeError> {
+    let direct = state.cache.mode() == CacheMode::Direct;
+    self.write_with(state, buffer, len, ofst, direct)
+  }
 
+  ///Writes like WRITE_AT, except that whole sectors of file data are written straight to disk without caching them
+  pub fn write_direct(&mut self, state: &mut FileSystem, buffer: &[u8], len: u32, ofst: u32) -> Result<u32, InodeError> {
+    self.write_with(state, buffer, len, ofst, true)
+  }
+
+  fn write_with(&mut self, state: &mut FileSystem, buffer: &[u8], len: u32, ofst: u32, direct: bool) -> Result<u32, InodeError> {
     if self.deny_write_cnt > 0 { return Err(InodeError::WriteDenied()) }
+    if len == 0 { return Ok(0) }
+
+    let end = ofst.checked_add(len).ok_or(InodeError::LengthOutOfBounds(u32::MAX, self.data.max_len()))?;
+    self.data.check_len(end)?;
+
+    Journal::transaction(state, |state| {
+      //Extending the file leaves a hole up to OFST, sectors are only allocated as they are written
+      let len_before = self.len();
+      self.data.len = max(len_before, end);
+      self.data.mtime = now();
+
+      //Only the bytes that reached the file extend it, and sectors filled past them are released again. INODE is
+      //written back even if a sector fails, so the sectors allocated so far are committed along with it
+      let mut bytes_wrote = 0u32;
+      let written = self.write_chunks(state, buffer, len, ofst, direct, &mut bytes_wrote);
+      let shrunk = self.shrink(state, max(len_before, ofst + bytes_wrote));
+      self.write_back(&state.block, &state.cache)?;
+      written.and(shrunk).map(|_| bytes_wrote)
+    })
+  }
 
-    //If we need to extend the file
-    if let Err(InodeError::OffsetOutOfBounds(_, _)) = self.byte_to_sector(&state.block, &state.cache, ofst + len - 1) {
-      self.data.reserve(state, ofst + len)?;
-      self.data.len = ofst + len;
-      state.cache.write_from_buffer(&state.block, self.sector, bytes_of(&self.data))?;
-    }
+  ///Writes LEN bytes of BUFFER from OFST, filling holes as they are reached, counting the bytes written in BYTES_WROTE
+  fn write_chunks(&mut self, state: &mut FileSystem, buffer: &[u8], mut len: u32, mut ofst: u32, direct: bool, bytes_wrote: &mut u32) -> Result<(), InodeError> {
+    let mut bounce: Option<[u8; BLOCK_SECTOR_SIZE as usize]> = None;
 
     while len > 0 {
-      let sector_idx = self.byte_to_sector(&state.block, &state.cache, ofst)?;
+      let sector_idx = match self.byte_to_sector(&state.block, &state.cache, ofst)? {
+        0 => self.fill_hole(state, ofst / BLOCK_SECTOR_SIZE)?,
+        sector => sector
+      };
       let sector_ofst = (ofst % BLOCK_SECTOR_SIZE) as usize;
 
       let rmn_inode = self.len() - ofst;
       let rmn_sector = BLOCK_SECTOR_SIZE as usize - sector_ofst;
       let rmn_min = min(rmn_inode, rmn_sector as u32);
 
-      let chunk_size = min(len, rmn_min) as usize;
+      let mut chunk_size = min(len, rmn_min) as usize;
 
       if chunk_size == 0 { break }
 
       if sector_ofst == 0 && chunk_size == BLOCK_SECTOR_SIZE as usize {
-        let buffer_slice = &buffer[bytes_wrote..(bytes_wrote + BLOCK_SECTOR_SIZE as usize)];
-        state.cache.write_from_buffer(&state.block, sector_idx, buffer_slice)?;
+        let whole = min(len, rmn_inode) / BLOCK_SECTOR_SIZE;
+
+        //Entire SECTORs allocated one after another on disk are written together. Holes among them are filled as they
+        //are reached when bypassing the cache, so they can reach the device in one go along with this one
+        let mut cnt = 1u32;
+        while cnt < whole {
+          let pos = ofst + cnt * BLOCK_SECTOR_SIZE;
+          let next = match self.byte_to_sector(&state.block, &state.cache, pos)? {
+            0 if direct && self.holds_data() => self.fill_hole(state, pos / BLOCK_SECTOR_SIZE)?,
+            sector => sector
+          };
+
+          if next != sector_idx + cnt { break }
+          cnt += 1;
+        }
+        chunk_size = (cnt * BLOCK_SECTOR_SIZE) as usize;
+
+        let buffer_slice = &buffer[*bytes_wrote as usize..(*bytes_wrote as usize + chunk_size)];
+        self.write_sectors(&state.block, &state.cache, sector_idx, buffer_slice, direct)?;
       } else {
         if bounce.is_none() {
           bounce = Some(EMPTY_BUFFER);
@@ -373,32 +731,138 @@ impl MemoryInode {
         let bounce = bounce.as_mut().unwrap();
 
         if sector_ofst > 0 || chunk_size < rmn_sector {
-          state.cache.read_to_buffer(&state.block, sector_idx, bounce)?;
+          state.block.tagged(self.origin(), || state.cache.read_to_buffer(&state.block, sector_idx, bounce))?;
         } else {
           bounce.fill(0);
         }
-        let buffer_slice = &buffer[bytes_wrote..(bytes_wrote + chunk_size)];
+        let buffer_slice = &buffer[*bytes_wrote as usize..(*bytes_wrote as usize + chunk_size)];
         let bounce_slice = &mut bounce[sector_ofst..(sector_ofst + chunk_size)];
         bounce_slice.copy_from_slice(buffer_slice);
 
-        state.cache.write_from_buffer(&state.block, sector_idx, bounce)?
+        self.write_sectors(&state.block, &state.cache, sector_idx, bounce, false)?
       }
       len -= chunk_size as u32;
       ofst += chunk_size as u32;
-      bytes_wrote += chunk_size;
+      *bytes_wrote += chunk_size as u32;
+    }
+    Ok(())
+  }
+
+  ///Ensures every sector needed to hold LEN bytes is allocated, filling holes and extending INODE if needed
+  pub fn reserve(&mut self, state: &mut FileSystem, len: u32) -> Result<(), InodeError> {
+    if self.deny_write_cnt > 0 { return Err(InodeError::WriteDenied()) }
+
+    Journal::transaction(state, |state| {
+      self.data.len = self.data.len.max(len);
+      self.extent_list.take();
+      let reserved = self.data.reserve(state, self.data.len);
+      self.write_back(&state.block, &state.cache)?;
+      reserved
+    })
+  }
+
+  ///Deallocates the LEN bytes starting at OFST, leaving a hole that reads as zeros
+  ///
+  ///Sectors only partly inside the range are zeroed instead. The length of INODE is unchanged
+  pub fn punch(&mut self, state: &mut FileSystem, ofst: u32, len: u32) -> Result<(), InodeError> {
+    if self.deny_write_cnt > 0 { return Err(InodeError::WriteDenied()) }
+
+    let end = min(ofst.saturating_add(len), self.len());
+    if ofst >= end { return Ok(()) }
+
+    Journal::transaction(state, |state| {
+      //The tail of the last sector lies past EOF, so that sector may go entirely
+      let first = ofst.div_ceil(BLOCK_SECTOR_SIZE);
+      let last = if end == self.len() { bytes_to_sectors(end) } else { end / BLOCK_SECTOR_SIZE };
+
+      let mut released = Vec::<Extent>::new();
+
+      if first < last {
+        self.extent_list.take();
+        self.data.punch(state, first, last - first, &mut released)?;
+      }
+
+      self.zero_range(&state.block, &state.cache, ofst, min(end, first * BLOCK_SECTOR_SIZE))?;
+      self.zero_range(&state.block, &state.cache, max(last, first) * BLOCK_SECTOR_SIZE, end)?;
+
+      self.data.mtime = now();
+      self.write_back_and_release(state, released)
+    })
+  }
+
+  ///Moves the data sectors of INODE, in order, into the contiguous run starting at TARGET, which the caller has allocated
+  ///
+  ///The data is copied before any pointer changes, and the old sectors are released once INODE is on disk
+  pub fn relocate(&mut self, state: &mut FileSystem, target: BlockSectorT) -> Result<(), InodeError> {
+    Journal::transaction(state, |state| {
+      let mut buffer = EMPTY_BUFFER;
+
+      for (i, sector) in self.data_sectors(&state.block, &state.cache)?.into_iter().enumerate() {
+        state.block.tagged(self.origin(), || state.cache.read_to_buffer(&state.block, sector, &mut buffer))?;
+        self.write_sectors(&state.block, &state.cache, target + i as BlockSectorT, &buffer, false)?;
+      }
+
+      let mut released = Vec::<Extent>::new();
+      self.extent_list.take();
+      self.data.relocate(state, target, &mut released)?;
+      self.write_back_and_release(state, released)
+    })
+  }
+
+  ///Exchanges the data sectors, length and format of INODE with those of OTHER, writing both to disk
+  pub fn swap_data(&mut self, other: &mut MemoryInode, block: &Block, cache: &Cache) -> Result<(), InodeError> {
+    self.extent_list.take();
+    other.extent_list.take();
+    self.data.swap_data(&mut other.data);
+    self.write_back(block, cache)?;
+    other.write_back(block, cache)
+  }
+
+  ///Zeroes the bytes from FROM up to TO, which must lie within a single sector
+  fn zero_range(&self, block: &Block, cache: &Cache, from: u32, to: u32) -> Result<(), InodeError> {
+    if from >= to {
+      return Ok(())
+    }
+
+    let sector = self.byte_to_sector(block, cache, from)?;
+    if sector == 0 {
+      return Ok(())
     }
-    Ok(bytes_wrote as u32)
+
+    let mut bounce = EMPTY_BUFFER;
+    block.tagged(self.origin(), || cache.read_to_buffer(block, sector, &mut bounce))?;
+    bounce[(from % BLOCK_SECTOR_SIZE) as usize..((to - 1) % BLOCK_SECTOR_SIZE + 1) as usize].fill(0);
+    self.write_sectors(block, cache, sector, &bounce, false)
   }
 
-  ///Finds the SECTOR in which POS is located in on the given INODE
+  ///Finds the SECTOR in which POS is located in on the given INODE, or 0 if POS lies in a hole
   fn byte_to_sector(&self, block: &Block, cache: &Cache, pos: u32) -> Result<BlockSectorT, InodeError> {
     if pos >= self.data.len {
       return Err(InodeError::OffsetOutOfBounds(pos, self.data.len))
     }
 
     let idx = pos / BLOCK_SECTOR_SIZE;
+    if self.format() == InodeFormat::Extent {
+      return self.with_extents(block, cache, |list| list.idx_to_sector(idx).unwrap_or(0))
+    }
     self.data.idx_to_sector(block, cache, idx)
   }
+
+  ///Allocates a zeroed sector for the hole at IDX and returns it
+  fn fill_hole(&mut self, state: &mut FileSystem, idx: u32) -> Result<BlockSectorT, InodeError> {
+    self.extent_list.take();
+    self.data.fill_hole(state, idx)
+  }
+
+  ///Applies F to the extents of an extent-based INODE, reading them only if they are not held already
+  fn with_extents<T>(&self, block: &Block, cache: &Cache, f: impl FnOnce(&ExtentList) -> T) -> Result<T, InodeError> {
+    let mut extent_list = self.extent_list.borrow_mut();
+    let list = match extent_list.take() {
+      Some(list) => list,
+      None => self.data.extent_list(block, cache)?
+    };
+    Ok(f(extent_list.insert(list)))
+  }
 }
 
 ///An interface to an ON DISK INODE
@@ -410,72 +874,178 @@ pub struct DiskInode {
   direct_blocks: [BlockSectorT; DIRECT_BLOCKS_CNT as usize],
   indirect_block: BlockSectorT,
   doubly_indirect_block: BlockSectorT,
+  triply_indirect_block: BlockSectorT,
 
   pub is_dir: u8, //We need this to satisfy bytemuck
   pub len: u32,
   pub sign: u32,
-  _padding: [u8; 3] //We need this to satisfy bytemuck
+
+  ctime: u32,
+  mtime: u32,
+  atime: u32,
+  mode: u16,
+  uid: u16,
+  gid: u16,
+  format: u8
 }
 
 impl DiskInode {
   ///Creates a new ON DISK INODE at SECTOR with LEN, and writes it to BLOCK
+  ///
+  ///The INODE is owned by the current user of STATE, with the default permissions for its type
   pub fn new(state: &mut FileSystem, sector: BlockSectorT, len: u32, dir: bool) -> Result<(), InodeError> {
+    let time = now();
+
     let mut disk_inode = Self {
       direct_blocks: [0u32; DIRECT_BLOCKS_CNT as usize],
       indirect_block: 0u32,
       doubly_indirect_block: 0u32,
+      triply_indirect_block: 0u32,
       is_dir: match dir {
         false => { 0u8 },
         true => { 1u8 },
       },
       len,
       sign: INODE_SIGNATURE,
-      _padding: [0u8; 3]
+      ctime: time,
+      mtime: time,
+      atime: time,
+      mode: if dir { DEFAULT_DIR_MODE } else { DEFAULT_FILE_MODE },
+      uid: state.uid,
+      gid: state.gid,
+      format: state.inode_format.to_byte()
     };
 
-    disk_inode.allocate(state)?;
+    //INODE is written even if allocating fails, so the sectors allocated so far are released through it
+    let allocated = disk_inode.allocate(state);
     state.cache.write_from_buffer(&state.block, sector, bytes_of(&disk_inode))?;
-    Ok(())
+    allocated
   }
 
-  ///Finds the SECTOR that IDX belongs to
-  fn idx_to_sector(&self, block: &Block, cache: &Cache, idx: u32) -> Result<BlockSectorT, InodeError> {
-    let mut idx_limit = DIRECT_BLOCKS_CNT;
-    let mut idx_base = idx_limit;
+  ///Returns whether this was written as an INODE, though its fields may since have been corrupted
+  pub fn has_signature(&self) -> bool {
+    self.sign == INODE_SIGNATURE
+  }
 
-    //Direct blocks (123)
-    if idx < idx_limit {
-      return Ok(self.direct_blocks[idx as usize])
+  ///Returns whether this looks like an INODE, rather than some other sector
+  ///
+  ///Beyond the signature, every field with a bounded range must lie within it
+  pub fn is_valid(&self) -> bool {
+    self.sign == INODE_SIGNATURE && self.is_dir <= 1 && self.format <= 1 && self.len <= self.max_len()
+  }
+
+  ///Returns the largest length the pointers of this INODE can map
+  fn max_len(&self) -> u32 {
+    match self.format() {
+      InodeFormat::Indexed => (DIRECT_BLOCKS_CNT + (1..=MAX_INDIRECTION).map(lvl_span).sum::<u32>()) * BLOCK_SECTOR_SIZE,
+      InodeFormat::Extent => u32::MAX
+    }
+  }
+
+  ///Checks that LEN is no longer than this INODE can map
+  fn check_len(&self, len: u32) -> Result<(), InodeError> {
+    match len <= self.max_len() {
+      true => Ok(()),
+      false => Err(InodeError::LengthOutOfBounds(len, self.max_len()))
     }
+  }
 
-    //Indirect block
-    idx_limit += INDIRECT_BLOCKS_PER_SECTOR;
+  fn format(&self) -> InodeFormat {
+    InodeFormat::from_byte(self.format)
+  }
 
-    if idx < idx_limit {
-      let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
-      cache.read_to_buffer(block, self.indirect_block, &mut buffer)?;
+  ///Exchanges the pointer area, length and format of this INODE with those of OTHER
+  fn swap_data(&mut self, other: &mut Self) {
+    let table = self.extent_table();
+    self.set_extent_table(&other.extent_table());
+    other.set_extent_table(&table);
 
-      let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
+    (self.len, other.len) = (other.len, self.len);
+    (self.format, other.format) = (other.format, self.format);
+  }
+
+  ///Returns the pointer area of an extent-based INODE
+  fn extent_table(&self) -> ExtentTable {
+    *from_bytes::<ExtentTable>(&bytes_of(self)[..POINTER_AREA_SIZE])
+  }
 
-      return Ok(indirect_block.inner[(idx - idx_base) as usize])
+  fn set_extent_table(&mut self, table: &ExtentTable) {
+    bytes_of_mut(self)[..POINTER_AREA_SIZE].copy_from_slice(bytes_of(table));
+  }
+
+  ///Reads every extent of an extent-based INODE
+  fn extent_list(&self, block: &Block, cache: &Cache) -> Result<ExtentList, InodeError> {
+    ExtentList::load(block, cache, &self.extent_table())
+  }
+
+  ///Applies F to the extents of an extent-based INODE, then stores them back
+  ///
+  ///Sectors the INODE no longer needs are added to RELEASED
+  fn update_extents<T>(&mut self, state: &mut FileSystem, released: &mut Vec<Extent>, f: impl FnOnce(&mut ExtentList, &mut FileSystem, &mut Vec<Extent>) -> Result<T, InodeError>) -> Result<T, InodeError> {
+    let mut list = self.extent_list(&state.block, &state.cache)?;
+    let mut table = self.extent_table();
+
+    //The list is stored even if F fails, so the extents allocated so far stay with INODE
+    let result = f(&mut list, state, released);
+    list.store(state, &mut table, released)?;
+    self.set_extent_table(&table);
+    result
+  }
+
+  ///Returns the root block of the tree with LVL degrees of indirection
+  fn indirect_root(&self, lvl: u32) -> BlockSectorT {
+    match lvl {
+      1 => self.indirect_block,
+      2 => self.doubly_indirect_block,
+      3 => self.triply_indirect_block,
+      _ => panic!("Only up to triple indirection is supported")
     }
+  }
 
-    //Doubly indirect block
-    idx_base = idx_limit;
-    idx_limit += INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR;
+  fn set_indirect_root(&mut self, lvl: u32, sector: BlockSectorT) {
+    match lvl {
+      1 => self.indirect_block = sector,
+      2 => self.doubly_indirect_block = sector,
+      3 => self.triply_indirect_block = sector,
+      _ => panic!("Only up to triple indirection is supported")
+    }
+  }
 
-    if idx < idx_limit {
-      let idx_first = (idx - idx_base) / INDIRECT_BLOCKS_PER_SECTOR;
-      let idx_scnd = (idx - idx_base) % INDIRECT_BLOCKS_PER_SECTOR;
+  ///Finds the SECTOR that IDX belongs to
+  fn idx_to_sector(&self, block: &Block, cache: &Cache, idx: u32) -> Result<BlockSectorT, InodeError> {
+    if self.format() == InodeFormat::Extent {
+      return Ok(self.extent_list(block, cache)?.idx_to_sector(idx).unwrap_or(0))
+    }
 
-      let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
-      cache.read_to_buffer(block, self.doubly_indirect_block, &mut buffer)?;
-      let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
+    //Direct blocks (118)
+    if idx < DIRECT_BLOCKS_CNT {
+      return Ok(self.direct_blocks[idx as usize])
+    }
+
+    //Indirect blocks, each level covering the range following the previous one
+    let mut idx_base = DIRECT_BLOCKS_CNT;
 
-      cache.read_to_buffer(block, indirect_block.inner[idx_first as usize], &mut buffer)?;
-      let doubly_indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
+    for lvl in 1..=MAX_INDIRECTION {
+      if idx - idx_base < lvl_span(lvl) {
+        let mut rel_idx = idx - idx_base;
+        let mut sector = self.indirect_root(lvl);
+        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
 
-      return Ok(doubly_indirect_block.inner[idx_scnd as usize])
+        //Walk down one block per degree of indirection, stopping at a hole
+        for sub_lvl in (0..lvl).rev() {
+          if sector == 0 {
+            return Ok(0)
+          }
+
+          cache.read_to_buffer(block, sector, &mut buffer)?;
+          let indirect_block = from_bytes::<IndirectBlockSector>(&buffer);
+
+          sector = indirect_block.inner[(rel_idx / lvl_span(sub_lvl)) as usize];
+          rel_idx %= lvl_span(sub_lvl);
+        }
+        return Ok(sector)
+      }
+      idx_base += lvl_span(lvl);
     }
 
     Err(InodeError::IndexOutOfBounds(idx))
@@ -486,74 +1056,369 @@ impl DiskInode {
     self.reserve(state, self.len)
   }
 
+  ///Ensures every sector needed to hold LEN bytes is allocated, leaving already allocated sectors untouched
   fn reserve(&mut self, state: &mut FileSystem, len: u32) -> Result<(), InodeError> {
+    if self.format() == InodeFormat::Extent {
+      return self.update_extents(state, &mut Vec::new(), |list, state, _| {
+        list.fill_holes(state)?;
+        list.grow(state, bytes_to_sectors(len).saturating_sub(list.sector_cnt()))
+      })
+    }
+
     let mut num_sectors = bytes_to_sectors(len);
-    let mut limit = min(num_sectors, DIRECT_BLOCKS_CNT);
-    let mut idx;
+    let limit = min(num_sectors, DIRECT_BLOCKS_CNT);
 
     //Direct blocks
     for i in 0..limit {
       if self.direct_blocks[i as usize] == 0 {
-        idx = Freemap::allocate(state, 1)?;
-        self.direct_blocks[i as usize] = idx;
-        state.cache.write_from_buffer(&state.block, idx, &EMPTY_BUFFER)?;
+        self.direct_blocks[i as usize] = Self::allocate_zeroed(state)?;
+      }
+    }
+    num_sectors -= limit;
+
+    //Indirect blocks, each level covering the range following the previous one
+    for lvl in 1..=MAX_INDIRECTION {
+      if num_sectors == 0 {
+        return Ok(())
       }
+
+      let limit = min(num_sectors, lvl_span(lvl));
+      let mut root = self.indirect_root(lvl);
+      let reserved = Self::reserve_indirect(state, &mut root, limit, lvl);
+      self.set_indirect_root(lvl, root);
+      reserved?;
+      num_sectors -= limit;
+    }
+
+    match num_sectors {
+      0 => Ok(()),
+      _ => Err(InodeError::IndexOutOfBounds(bytes_to_sectors(len) - 1))
     }
+  }
+
+  ///Reserves NUM_SECTORS under the block at SECTOR with LVL degrees of indirection
+  ///
+  ///A SECTOR of 0 has not been allocated yet and is allocated here, SECTOR is set as soon as it is
+  fn reserve_indirect(state: &mut FileSystem, sector: &mut BlockSectorT, mut num_sectors: u32, lvl: u32) -> Result<(), InodeError> {
+    assert!(lvl <= MAX_INDIRECTION, "Only up to triple indirection is supported");
 
-    if {num_sectors -= limit; num_sectors} == 0 {
+    if *sector == 0 {
+      *sector = Self::allocate_zeroed(state)?;
+    }
+
+    if lvl == 0 {
       return Ok(())
     }
 
-    //Indirect blocks
-    limit = min(num_sectors,INDIRECT_BLOCKS_PER_SECTOR);
-    idx = Self::reserve_indirect(state, limit, 1)?;
-    self.indirect_block = idx;
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    state.cache.read_to_buffer(&state.block, *sector, &mut buffer)?;
+
+    let mut indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
+    let unit = lvl_span(lvl - 1);
+    let limit = num_sectors.div_ceil(unit);
+    let mut reserved = Ok(());
+
+    for i in 0..limit {
+      let subsize = min(num_sectors, unit);
+      reserved = Self::reserve_indirect(state, &mut indirect_block.inner[i as usize], subsize, lvl - 1);
+
+      if reserved.is_err() {
+        break;
+      }
+      num_sectors -= subsize;
+    }
+
+    //The block is written even if a child failed, so the sectors allocated under it stay reachable. One left as it
+    //was is not, so growing a file only logs the blocks that changed
+    if bytes_of(&indirect_block) != buffer.as_slice() {
+      state.cache.write_from_buffer(&state.block, *sector, bytes_of(&indirect_block))?;
+    }
+    reserved
+  }
+
+  ///Allocates a zeroed data sector for the hole at IDX, along with any indirect blocks leading to it, returning its sector
+  fn fill_hole(&mut self, state: &mut FileSystem, idx: u32) -> Result<BlockSectorT, InodeError> {
+    if self.format() == InodeFormat::Extent {
+      return self.update_extents(state, &mut Vec::new(), |list, state, _| list.fill(state, idx))
+    }
+
+    if idx < DIRECT_BLOCKS_CNT {
+      let sector = Self::allocate_zeroed(state)?;
+      self.direct_blocks[idx as usize] = sector;
+      return Ok(sector)
+    }
+
+    let mut idx_base = DIRECT_BLOCKS_CNT;
+
+    for lvl in 1..=MAX_INDIRECTION {
+      if idx - idx_base < lvl_span(lvl) {
+        let mut rel_idx = idx - idx_base;
+
+        if self.indirect_root(lvl) == 0 {
+          let root = Self::allocate_zeroed(state)?;
+          self.set_indirect_root(lvl, root);
+        }
+
+        //Walk down one block per degree of indirection, allocating missing blocks on the way
+        let mut sector = self.indirect_root(lvl);
+        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+        for sub_lvl in (0..lvl).rev() {
+          state.cache.read_to_buffer(&state.block, sector, &mut buffer)?;
+          let mut indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
+          let i = (rel_idx / lvl_span(sub_lvl)) as usize;
+
+          if indirect_block.inner[i] == 0 {
+            indirect_block.inner[i] = Self::allocate_zeroed(state)?;
+            state.cache.write_from_buffer(&state.block, sector, bytes_of(&indirect_block))?;
+          }
+
+          sector = indirect_block.inner[i];
+          rel_idx %= lvl_span(sub_lvl);
+        }
+        return Ok(sector)
+      }
+      idx_base += lvl_span(lvl);
+    }
+
+    Err(InodeError::IndexOutOfBounds(idx))
+  }
+
+  ///Allocates a single sector and zeroes it
+  ///
+  ///The zeroes are written as data, an indirect block is logged once pointers are written to it
+  fn allocate_zeroed(state: &mut FileSystem) -> Result<BlockSectorT, InodeError> {
+    let sector = Freemap::allocate(state, 1)?;
 
-    if {num_sectors -= limit; num_sectors} == 0 {
+    //Nothing points at SECTOR yet, so it goes straight back if it can not be zeroed
+    if let Err(e) = state.cache.write_data(&state.block, sector, &EMPTY_BUFFER) {
+      Freemap::release(state, sector, 1)?;
+      return Err(e.into())
+    }
+    Ok(sector)
+  }
+
+  ///Turns the CNT data sectors starting at FIRST into a hole, adding the sectors they used to RELEASED
+  fn punch(&mut self, state: &mut FileSystem, first: u32, cnt: u32, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    if self.format() == InodeFormat::Extent {
+      return self.update_extents(state, released, |list, _, released| {
+        list.punch(first, cnt, released);
+        Ok(())
+      })
+    }
+
+    for idx in first..first + cnt {
+      self.punch_sector(state, idx, released)?;
+    }
+    Ok(())
+  }
+
+  ///Turns the data sector at IDX of an indexed INODE into a hole, adding it to RELEASED
+  ///
+  ///Indirect blocks left without any sector under them are added too
+  fn punch_sector(&mut self, state: &mut FileSystem, idx: u32, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    if idx < DIRECT_BLOCKS_CNT {
+      let sector = self.direct_blocks[idx as usize];
+
+      if sector != 0 {
+        released.push(Extent::single(sector));
+        self.direct_blocks[idx as usize] = 0;
+      }
       return Ok(())
     }
 
-    //Doubly indirect block
-    limit = min(num_sectors, INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR);
-    idx = Self::reserve_indirect(state, limit, 2)?;
-    self.doubly_indirect_block = idx;
+    let mut idx_base = DIRECT_BLOCKS_CNT;
 
-    if {num_sectors -= limit; num_sectors} == 0 {
+    for lvl in 1..=MAX_INDIRECTION {
+      if idx - idx_base < lvl_span(lvl) {
+        if Self::punch_indirect(state, self.indirect_root(lvl), idx - idx_base, lvl, released)? {
+          self.set_indirect_root(lvl, 0);
+        }
+        return Ok(())
+      }
+      idx_base += lvl_span(lvl);
+    }
+
+    Err(InodeError::IndexOutOfBounds(idx))
+  }
+
+  ///Turns the data sector at REL_IDX under the block at SECTOR with LVL degrees of indirection into a hole
+  ///
+  ///Returns whether SECTOR itself was added to RELEASED, having nothing left under it
+  fn punch_indirect(state: &mut FileSystem, sector: BlockSectorT, rel_idx: u32, lvl: u32, released: &mut Vec<Extent>) -> Result<bool, InodeError> {
+    if sector == 0 {
+      return Ok(false)
+    }
+
+    if lvl == 0 {
+      released.push(Extent::single(sector));
+      return Ok(true)
+    }
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    state.cache.read_to_buffer(&state.block, sector, &mut buffer)?;
+
+    let mut indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
+    let unit = lvl_span(lvl - 1);
+    let i = (rel_idx / unit) as usize;
+
+    if !Self::punch_indirect(state, indirect_block.inner[i], rel_idx % unit, lvl - 1, released)? {
+      return Ok(false)
+    }
+
+    indirect_block.inner[i] = 0;
+
+    if indirect_block.inner.iter().all(|&child| child == 0) {
+      released.push(Extent::single(sector));
+      return Ok(true)
+    }
+
+    state.cache.write_from_buffer(&state.block, sector, bytes_of(&indirect_block))?;
+    Ok(false)
+  }
+
+  ///Points the data sectors of INODE, in order, at the run starting at TARGET, adding the old ones to RELEASED
+  fn relocate(&mut self, state: &mut FileSystem, target: BlockSectorT, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    if self.format() == InodeFormat::Extent {
+      return self.update_extents(state, released, |list, _, released| {
+        list.relocate(target, released);
+        Ok(())
+      })
+    }
+
+    let mut next = target;
+
+    for idx in 0..bytes_to_sectors(self.len) {
+      let sector = self.idx_to_sector(&state.block, &state.cache, idx)?;
+
+      if sector != 0 {
+        self.set_sector(state, idx, next)?;
+        released.push(Extent::single(sector));
+        next += 1;
+      }
+    }
+    Ok(())
+  }
+
+  ///Points the data sector at IDX of an indexed INODE, which must not be a hole, at SECTOR
+  fn set_sector(&mut self, state: &mut FileSystem, idx: u32, sector: BlockSectorT) -> Result<(), InodeError> {
+    if idx < DIRECT_BLOCKS_CNT {
+      self.direct_blocks[idx as usize] = sector;
       return Ok(())
     }
 
-    panic!("Number of sectors were not 0 at end of traversal");
+    let mut idx_base = DIRECT_BLOCKS_CNT;
+
+    for lvl in 1..=MAX_INDIRECTION {
+      if idx - idx_base < lvl_span(lvl) {
+        let mut rel_idx = idx - idx_base;
+        let mut block_sector = self.indirect_root(lvl);
+        let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+
+        //Walk down to the block holding the pointer and rewrite only that one
+        for sub_lvl in (0..lvl).rev() {
+          state.cache.read_to_buffer(&state.block, block_sector, &mut buffer)?;
+          let mut indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
+          let i = (rel_idx / lvl_span(sub_lvl)) as usize;
+
+          if sub_lvl == 0 {
+            indirect_block.inner[i] = sector;
+            state.cache.write_from_buffer(&state.block, block_sector, bytes_of(&indirect_block))?;
+            return Ok(())
+          }
+
+          block_sector = indirect_block.inner[i];
+          rel_idx %= lvl_span(sub_lvl);
+        }
+      }
+      idx_base += lvl_span(lvl);
+    }
+
+    Err(InodeError::IndexOutOfBounds(idx))
   }
 
-  fn reserve_indirect(state: &mut FileSystem, mut num_sectors: u32, lvl: u32) -> Result<BlockSectorT, InodeError> {
-    assert!(lvl <= 2, "Only double indirection is supported");
+  ///Drops every sector past the first LEN bytes, including indirect blocks that no longer hold anything
+  ///
+  ///The dropped sectors are added to RELEASED, for the caller to release once the INODE is on disk
+  fn shrink(&mut self, state: &mut FileSystem, len: u32, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    let old_sectors = bytes_to_sectors(self.len);
+    let new_sectors = bytes_to_sectors(len);
+
+    if self.format() == InodeFormat::Extent {
+      self.update_extents(state, released, |list, _, released| {
+        list.truncate(new_sectors, released);
+        Ok(())
+      })?;
+      self.len = len;
+      return Ok(())
+    }
 
-    let idx;
+    //Direct blocks
+    for i in new_sectors..min(old_sectors, DIRECT_BLOCKS_CNT) {
+      let sector = self.direct_blocks[i as usize];
 
-    if lvl == 0 {
-      idx = Freemap::allocate(state, 1)?;
-      state.cache.write_from_buffer(&state.block, idx, &EMPTY_BUFFER)?;
-      return Ok(idx)
+      if sector != 0 {
+        released.push(Extent::single(sector));
+        self.direct_blocks[i as usize] = 0;
+      }
     }
 
-    idx = Freemap::allocate(state, 1)?;
-    state.cache.write_from_buffer(&state.block, idx, &EMPTY_BUFFER)?;
+    //Indirect blocks, each level covering the range following the previous one
+    let mut base = DIRECT_BLOCKS_CNT;
 
-    let mut indirect_block = IndirectBlockSector::new();
-    let unit = if lvl == 1 { 1 } else { INDIRECT_BLOCKS_PER_SECTOR };
-    let limit = num_sectors.div_ceil(unit);
+    for lvl in 1..=MAX_INDIRECTION {
+      let span = lvl_span(lvl);
 
-    for i in 0..limit {
-      let subsize = min(num_sectors, unit);
-      let indirect_idx = Self::reserve_indirect(state, subsize, lvl - 1)?;
+      if old_sectors > base {
+        let have = min(old_sectors - base, span);
+        let keep = min(new_sectors.saturating_sub(base), span);
+        let sector = self.indirect_root(lvl);
 
-      indirect_block.inner[i as usize] = indirect_idx;
-      num_sectors -= subsize;
+        if keep == 0 {
+          MemoryInode::deallocate_indirect(&state.block, &state.cache, sector, have, lvl, released)?;
+          self.set_indirect_root(lvl, 0);
+        } else if keep < have {
+          Self::shrink_indirect(state, sector, keep, have, lvl, released)?;
+        }
+      }
+      base += span;
     }
 
-    assert_eq!(num_sectors, 0, "Number of sectors was not 0 at end of traversal");
-    state.cache.write_from_buffer(&state.block, idx, bytes_of(&indirect_block))?;
-    Ok(idx)
+    self.len = len;
+    Ok(())
+  }
+
+  ///Shrinks the block at SECTOR with LVL degrees of indirection from HAVE to KEEP sectors, where 0 < KEEP < HAVE
+  fn shrink_indirect(state: &mut FileSystem, sector: BlockSectorT, keep: u32, have: u32, lvl: u32, released: &mut Vec<Extent>) -> Result<(), InodeError> {
+    if sector == 0 {
+      return Ok(())
+    }
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    state.cache.read_to_buffer(&state.block, sector, &mut buffer)?;
+
+    let mut indirect_block = from_bytes::<IndirectBlockSector>(&buffer).to_owned();
+    let unit = lvl_span(lvl - 1);
+
+    //Children past the last one still in use are dropped entirely
+    for i in keep.div_ceil(unit)..have.div_ceil(unit) {
+      let subsize = min(have - i * unit, unit);
+      MemoryInode::deallocate_indirect(&state.block, &state.cache, indirect_block.inner[i as usize], subsize, lvl - 1, released)?;
+      indirect_block.inner[i as usize] = 0;
+    }
+
+    //The last child in use may itself need shrinking
+    if !keep.is_multiple_of(unit) {
+      let i = keep / unit;
+      let subhave = min(have - i * unit, unit);
+
+      if keep % unit < subhave {
+        Self::shrink_indirect(state, indirect_block.inner[i as usize], keep % unit, subhave, lvl - 1, released)?;
+      }
+    }
+
+    state.cache.write_from_buffer(&state.block, sector, bytes_of(&indirect_block))?;
+    Ok(())
   }
 }
 
@@ -571,3 +1436,190 @@ impl IndirectBlockSector {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::mem::size_of;
+
+  use super::*;
+  use crate::fs::file_sys::tests::setup_file_sys;
+
+  fn open(state: &mut FileSystem, path: &str) -> Rc<RefCell<MemoryInode>> {
+    state.create(path, 0, false).unwrap();
+    let file = state.open(path).unwrap();
+    let inode = file.borrow().inode(state).unwrap();
+    file.borrow().close(state).unwrap();
+    inode
+  }
+
+  ///Returns the number of sectors, indirect blocks included, a file of NUM_SECTORS data sectors occupies
+  fn occupied_sectors(num_sectors: u32) -> u32 {
+    let mut rmn = num_sectors.saturating_sub(DIRECT_BLOCKS_CNT);
+    let mut total = num_sectors;
+
+    for lvl in 1..=MAX_INDIRECTION {
+      let covered = min(rmn, lvl_span(lvl));
+      total += (1..=lvl).map(|sub_lvl| covered.div_ceil(lvl_span(sub_lvl))).sum::<u32>();
+      rmn -= covered;
+    }
+    total
+  }
+
+  ///Sector counts on either side of every level boundary
+  const BOUNDARIES: [u32; 6] = [
+    DIRECT_BLOCKS_CNT,
+    DIRECT_BLOCKS_CNT + 1,
+    DIRECT_BLOCKS_CNT + INDIRECT_BLOCKS_PER_SECTOR,
+    DIRECT_BLOCKS_CNT + INDIRECT_BLOCKS_PER_SECTOR + 1,
+    DIRECT_BLOCKS_CNT + INDIRECT_BLOCKS_PER_SECTOR + INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR,
+    DIRECT_BLOCKS_CNT + INDIRECT_BLOCKS_PER_SECTOR + INDIRECT_BLOCKS_PER_SECTOR * INDIRECT_BLOCKS_PER_SECTOR + 1
+  ];
+
+  #[test]
+  fn test_level_boundaries() {
+    let mut file_sys = setup_file_sys(17_000);
+    let free_before = file_sys.freemap.num_free_sectors();
+
+    for num_sectors in BOUNDARIES {
+      let len = num_sectors * BLOCK_SECTOR_SIZE;
+      let inode = open(&mut file_sys, "big");
+      inode.borrow_mut().reserve(&mut file_sys, len).unwrap();
+      inode.borrow_mut().write_at(&mut file_sys, &[0xAB], 1, len - 1).unwrap();
+
+      //Plus one for the inode itself
+      assert_eq!(free_before - file_sys.freemap.num_free_sectors(), occupied_sectors(num_sectors) + 1);
+
+      let sectors = inode.borrow().data_sectors(&file_sys.block, &file_sys.cache).unwrap();
+      assert_eq!(sectors.len() as u32, num_sectors);
+      assert_eq!(inode.borrow().byte_to_sector(&file_sys.block, &file_sys.cache, len - 1).unwrap(), sectors[num_sectors as usize - 1]);
+      assert!(inode.borrow().byte_to_sector(&file_sys.block, &file_sys.cache, len).is_err());
+
+      let mut buffer = [0u8; 2];
+      assert_eq!(inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 2, len - 2).unwrap(), 2);
+      assert_eq!(buffer, [0x00, 0xAB]);
+
+      let sector = inode.borrow().inode_num();
+      file_sys.remove("big").unwrap();
+      InodeList::close_inode(&mut file_sys, sector).unwrap();
+      assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+    }
+  }
+
+  #[test]
+  fn test_grow_and_shrink_across_boundaries() {
+    let mut file_sys = setup_file_sys(17_000);
+    let free_before = file_sys.freemap.num_free_sectors();
+    let inode = open(&mut file_sys, "big");
+
+    //Grow one boundary at a time, each write extending the existing trees
+    for (i, num_sectors) in BOUNDARIES.into_iter().enumerate() {
+      let marker = [i as u8 + 1];
+      inode.borrow_mut().reserve(&mut file_sys, num_sectors * BLOCK_SECTOR_SIZE).unwrap();
+      inode.borrow_mut().write_at(&mut file_sys, &marker, 1, num_sectors * BLOCK_SECTOR_SIZE - 1).unwrap();
+      assert_eq!(free_before - file_sys.freemap.num_free_sectors(), occupied_sectors(num_sectors) + 1);
+    }
+
+    for (i, num_sectors) in BOUNDARIES.into_iter().enumerate() {
+      let mut buffer = [0u8];
+      inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 1, num_sectors * BLOCK_SECTOR_SIZE - 1).unwrap();
+      assert_eq!(buffer, [i as u8 + 1]);
+    }
+
+    //Then shrink back down through them
+    for num_sectors in BOUNDARIES.into_iter().rev().chain([1]) {
+      inode.borrow_mut().shrink(&mut file_sys, num_sectors * BLOCK_SECTOR_SIZE).unwrap();
+      assert_eq!(free_before - file_sys.freemap.num_free_sectors(), occupied_sectors(num_sectors) + 1);
+    }
+  }
+
+  #[test]
+  fn test_sparse_write_and_punch() {
+    let mut file_sys = setup_file_sys(1024);
+    let free_before = file_sys.freemap.num_free_sectors();
+    let inode = open(&mut file_sys, "sparse");
+
+    //A single sector deep in the doubly indirect range, plus the two indirect blocks leading to it
+    let ofst = (DIRECT_BLOCKS_CNT + INDIRECT_BLOCKS_PER_SECTOR + 5) * BLOCK_SECTOR_SIZE + 7;
+    inode.borrow_mut().write_at(&mut file_sys, b"hole", 4, ofst).unwrap();
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 2 + 1);
+    assert_eq!(inode.borrow().allocated_sectors(&file_sys.block, &file_sys.cache).unwrap(), 1);
+
+    let mut buffer = [0xFFu8; 11];
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 11, ofst - 7).unwrap();
+    assert_eq!(&buffer, b"\0\0\0\0\0\0\0hole");
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 11, 100).unwrap();
+    assert_eq!(buffer, [0u8; 11]);
+
+    //Punching part of the sector only zeroes it
+    inode.borrow_mut().punch(&mut file_sys, ofst, 2).unwrap();
+    inode.borrow().read_at(&file_sys.block, &file_sys.cache, &mut buffer, 4, ofst).unwrap();
+    assert_eq!(&buffer[..4], b"\0\0le");
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1 + 2 + 1);
+
+    //Punching the whole of it releases the indirect blocks too
+    inode.borrow_mut().punch(&mut file_sys, ofst - 7, 11).unwrap();
+    assert_eq!(free_before - file_sys.freemap.num_free_sectors(), 1);
+    assert_eq!(inode.borrow().len(), ofst + 4);
+  }
+
+  #[test]
+  fn test_disk_inode_fills_sector() {
+    assert_eq!(size_of::<DiskInode>(), BLOCK_SECTOR_SIZE as usize);
+  }
+
+  #[test]
+  fn test_new_inode_metadata() {
+    let mut file_sys = setup_file_sys(1024);
+    file_sys.set_user(7, 3);
+
+    let inode = open(&mut file_sys, "a");
+    let (ctime, mtime, atime) = inode.borrow().times();
+
+    assert_eq!(inode.borrow().mode(), DEFAULT_FILE_MODE);
+    assert_eq!(inode.borrow().owner(), (7, 3));
+    assert!(ctime > 0 && ctime == mtime && mtime <= atime);
+  }
+
+  #[test]
+  fn test_access_time_is_relative() {
+    let mut file_sys = setup_file_sys(1024);
+    let inode = open(&mut file_sys, "a");
+    let time = now();
+
+    //Accessed since the last change, so reading again leaves it
+    inode.borrow_mut().data.ctime = time - 10;
+    inode.borrow_mut().data.mtime = time - 10;
+    inode.borrow_mut().data.atime = time - 5;
+    inode.borrow_mut().touch_access(&file_sys.block, &file_sys.cache).unwrap();
+    assert_eq!(inode.borrow().times().2, time - 5);
+
+    //Changed since the last access
+    inode.borrow_mut().data.mtime = time - 1;
+    inode.borrow_mut().touch_access(&file_sys.block, &file_sys.cache).unwrap();
+    assert!(inode.borrow().times().2 >= time);
+
+    //Unchanged, but last accessed too long ago
+    inode.borrow_mut().data.ctime = time - 3 * ATIME_REFRESH;
+    inode.borrow_mut().data.mtime = time - 3 * ATIME_REFRESH;
+    inode.borrow_mut().data.atime = time - 2 * ATIME_REFRESH;
+    inode.borrow_mut().touch_access(&file_sys.block, &file_sys.cache).unwrap();
+    assert!(inode.borrow().times().2 >= time);
+  }
+
+  #[test]
+  fn test_check_access() {
+    let mut file_sys = setup_file_sys(1024);
+    file_sys.set_user(7, 3);
+
+    let inode = open(&mut file_sys, "a");
+    inode.borrow_mut().set_mode(&file_sys.block, &file_sys.cache, 0o640).unwrap();
+    let inode = inode.borrow();
+
+    assert!(inode.check_access(7, 3, ACCESS_READ | ACCESS_WRITE));
+    assert!(!inode.check_access(7, 3, ACCESS_EXEC));
+    assert!(inode.check_access(8, 3, ACCESS_READ));
+    assert!(!inode.check_access(8, 3, ACCESS_WRITE));
+    assert!(!inode.check_access(8, 4, ACCESS_READ));
+    assert!(inode.check_access(0, 0, ACCESS_READ | ACCESS_WRITE | ACCESS_EXEC));
+  }
+}
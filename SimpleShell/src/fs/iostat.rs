@@ -0,0 +1,212 @@
+use std::{
+  fmt::{
+    Display,
+    Formatter,
+    Result
+  },
+  ops::AddAssign
+};
+
+use crate::fs::block::BLOCK_SECTOR_SIZE;
+
+///What a sector read or written on a BLOCK belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+  Superblock,
+  Journal,
+  ///INODEs themselves, along with their index and extent blocks
+  Inode,
+  ///Entries of a DIRECTORY
+  Directory,
+  ///The FREEMAP file
+  Freemap,
+  ///Contents of a regular file
+  Data
+}
+
+pub const ORIGINS: [Origin; 6] = [Origin::Superblock, Origin::Journal, Origin::Inode, Origin::Directory, Origin::Freemap, Origin::Data];
+
+impl Origin {
+  fn idx(self) -> usize {
+    self as usize
+  }
+
+  ///Returns whether sectors of this ORIGIN are metadata, which all but file contents are
+  pub fn is_metadata(self) -> bool {
+    self != Self::Data
+  }
+}
+
+impl Display for Origin {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    let name = match self {
+      Self::Superblock => "superblock",
+      Self::Journal => "journal",
+      Self::Inode => "inode",
+      Self::Directory => "directory",
+      Self::Freemap => "freemap",
+      Self::Data => "data"
+    };
+    f.pad(name)
+  }
+}
+
+///How a sector was accessed, as it appears in a trace
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+  ///Read from the device
+  Read,
+  ///Written to the device
+  Write,
+  ///Read or written in the CACHE, without reaching the device
+  Hit
+}
+
+impl Display for Access {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    let letter = match self {
+      Self::Read => "R",
+      Self::Write => "W",
+      Self::Hit => "H"
+    };
+    f.write_str(letter)
+  }
+}
+
+///Number of sectors read from and written to a BLOCK, by ORIGIN
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct IoStats {
+  reads: [u64; ORIGINS.len()],
+  writes: [u64; ORIGINS.len()]
+}
+
+impl IoStats {
+  pub fn count(&mut self, access: Access, origin: Origin) {
+    match access {
+      Access::Read => self.reads[origin.idx()] += 1,
+      Access::Write => self.writes[origin.idx()] += 1,
+      Access::Hit => {}
+    }
+  }
+
+  pub fn reads(&self, origin: Origin) -> u64 {
+    self.reads[origin.idx()]
+  }
+
+  pub fn writes(&self, origin: Origin) -> u64 {
+    self.writes[origin.idx()]
+  }
+
+  ///Returns the sectors read and written of every ORIGIN for which IS_METADATA returns METADATA
+  pub fn totals(&self, metadata: bool) -> (u64, u64) {
+    ORIGINS.iter()
+      .filter(|origin| origin.is_metadata() == metadata)
+      .fold((0, 0), |(reads, writes), &origin| (reads + self.reads(origin), writes + self.writes(origin)))
+  }
+
+  pub fn total_reads(&self) -> u64 {
+    self.reads.iter().sum()
+  }
+
+  pub fn total_writes(&self) -> u64 {
+    self.writes.iter().sum()
+  }
+}
+
+impl AddAssign for IoStats {
+  fn add_assign(&mut self, other: Self) {
+    for i in 0..ORIGINS.len() {
+      self.reads[i] += other.reads[i];
+      self.writes[i] += other.writes[i];
+    }
+  }
+}
+
+///Formats CNT sectors as a number of bytes in the largest unit that keeps it above 1
+pub fn format_bytes(cnt: u64) -> String {
+  let bytes = cnt * BLOCK_SECTOR_SIZE as u64;
+
+  match bytes {
+    0..1024 => format!("{} B", bytes),
+    1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+    _ => format!("{:.1} MiB", bytes as f64 / 1048576.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::file_sys::tests::setup_file_sys;
+
+  const CONTENT: [u8; 4 * BLOCK_SECTOR_SIZE as usize] = [7u8; 4 * BLOCK_SECTOR_SIZE as usize];
+
+  #[test]
+  fn test_io_stats() {
+    let mut stats = IoStats::default();
+    stats.count(Access::Read, Origin::Inode);
+    stats.count(Access::Read, Origin::Data);
+    stats.count(Access::Write, Origin::Directory);
+    stats.count(Access::Hit, Origin::Data);
+
+    assert_eq!(stats.totals(true), (1, 1));
+    assert_eq!(stats.totals(false), (1, 0));
+
+    let mut sum = stats;
+    sum += stats;
+    assert_eq!((sum.total_reads(), sum.total_writes()), (4, 2));
+
+    assert_eq!(format_bytes(1), "512 B");
+    assert_eq!(format_bytes(3), "1.5 KiB");
+    assert_eq!(format_bytes(4096), "2.0 MiB");
+  }
+
+  #[test]
+  fn test_counted_by_origin() {
+    let mut file_sys = setup_file_sys(2048);
+    file_sys.create("d", 0, true).unwrap();
+    file_sys.sync().unwrap();
+    file_sys.block.reset_io_stats();
+
+    file_sys.create("d/a", 0, false).unwrap();
+    file_sys.util_write("d/a", &CONTENT, CONTENT.len() as u32).unwrap();
+    file_sys.sync().unwrap();
+
+    //Whole sectors are written without being read first, and only once
+    let stats = file_sys.block.io_stats();
+    assert_eq!(stats.totals(false), (0, 4));
+    for origin in [Origin::Journal, Origin::Inode, Origin::Directory, Origin::Freemap] {
+      assert!(stats.writes(origin) > 0, "{}", origin);
+    }
+    assert_eq!(stats.writes(Origin::Superblock), 0);
+
+    file_sys.block.reset_io_stats();
+    assert_eq!(file_sys.block.io_stats(), IoStats::default());
+  }
+
+  #[test]
+  fn test_trace() {
+    let mut file_sys = setup_file_sys(2048);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace");
+    let path = path.to_str().unwrap();
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_trace(true, Some(path)).unwrap();
+
+    file_sys.util_write("a", &CONTENT, CONTENT.len() as u32).unwrap();
+    file_sys.sync().unwrap();
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    file_sys.util_read("a", &mut buffer, BLOCK_SECTOR_SIZE).unwrap();
+
+    file_sys.util_trace(false, None).unwrap();
+    file_sys.util_write("a", &CONTENT, CONTENT.len() as u32).unwrap();
+    file_sys.sync().unwrap();
+
+    //Accesses after tracing stopped are not logged
+    let trace = std::fs::read_to_string(path).unwrap();
+    let lines = trace.lines().map(|line| line.split(' ').collect::<Vec<_>>()).collect::<Vec<_>>();
+    assert!(lines.iter().all(|line| line.len() == 4 && line[0] == "hd0"));
+    assert_eq!(lines.iter().filter(|line| line[1] == "W" && line[3] == "data").count(), 4);
+    assert!(lines.iter().any(|line| line[1] == "H" && line[3] == "data"));
+  }
+}
@@ -0,0 +1,609 @@
+use std::{
+  cell::{
+    Cell,
+    RefCell
+  },
+  collections::{
+    BTreeMap,
+    BTreeSet
+  }
+};
+
+use bytemuck::{
+  bytes_of, from_bytes, Pod, Zeroable
+};
+
+use crate::fs::{
+  block::{
+    Block,
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
+  file_sys::FileSystem,
+  fserrors::cache_errors::CacheError,
+  iostat::Origin
+};
+
+pub const JOURNAL_SECTOR: BlockSectorT = 3u32;
+pub const JOURNAL_SIZE: u32 = 64u32;
+const JOURNAL_MAGIC: u32 = 0x4a524e4c;
+const MAX_TARGETS: usize = 123usize;
+///The header and one image for every target it can hold
+pub const MAX_JOURNAL_SIZE: u32 = MAX_TARGETS as u32 + 1;
+///Room kept back in a transaction for what it logs after the FREEMAP: the blocks pointing at the sectors it allocated,
+///the INODEs holding them and the entry naming a new one
+const FREEMAP_SLACK: usize = 8usize;
+
+type SectorImage = [u8; BLOCK_SECTOR_SIZE as usize];
+
+///The first sector of the journal region, followed by the logged sector images
+///
+///A TARGET_CNT above 0 marks a committed transaction whose images have not all reached their TARGETS yet
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct JournalHeader {
+  magic: u32,
+  size: u32,
+  sequence: u32,
+  target_cnt: u32,
+  checksum: u32,
+  targets: [BlockSectorT; MAX_TARGETS]
+}
+
+///Returns the FNV-1a hash of SEQUENCE, TARGETS and IMAGES, so a torn commit is never replayed
+fn checksum<'a>(sequence: u32, targets: &[BlockSectorT], images: impl Iterator<Item = &'a SectorImage>) -> u32 {
+  let mut hash = 0x811c9dc5u32;
+  let mut feed = |bytes: &[u8]| {
+    for &byte in bytes {
+      hash ^= byte as u32;
+      hash = hash.wrapping_mul(0x01000193);
+    }
+  };
+
+  feed(&sequence.to_le_bytes());
+  targets.iter().for_each(|target| feed(&target.to_le_bytes()));
+  images.for_each(|image| feed(image));
+  hash
+}
+
+///A write-ahead log of metadata sectors, kept by the CACHE
+///
+///Metadata written during a transaction is held in memory until the outermost transaction commits.
+///Data sectors are never logged, but are flushed before the metadata pointing at them is committed.
+///A transaction whose FREEMAP changes outgrow the journal is committed in pieces as the FREEMAP is written.
+///One still too large to log is aborted, after which nothing is written until the journal is opened again
+pub(crate) struct Journal {
+  size: Cell<u32>,
+  sequence: Cell<u32>,
+  depth: Cell<u32>,
+  aborted: Cell<bool>,
+  pending: RefCell<BTreeMap<BlockSectorT, SectorImage>>,
+  ///What each logged sector belongs to, which its write to the home sector is counted as
+  origins: RefCell<BTreeMap<BlockSectorT, Origin>>,
+  data: RefCell<BTreeSet<BlockSectorT>>,
+  ///Sectors allocated since the last commit that were free in it, so nothing on disk points at them yet
+  fresh: RefCell<BTreeSet<BlockSectorT>>,
+  ///Sectors released since the last commit that were in use in it
+  freed: RefCell<BTreeSet<BlockSectorT>>
+}
+
+impl Journal {
+  ///Builds a disabled JOURNAL, which is enabled once opened on a formatted BLOCK
+  pub fn new() -> Self {
+    Self {
+      size: Cell::new(0u32),
+      sequence: Cell::new(0u32),
+      depth: Cell::new(0u32),
+      aborted: Cell::new(false),
+      pending: RefCell::new(BTreeMap::new()),
+      origins: RefCell::new(BTreeMap::new()),
+      data: RefCell::new(BTreeSet::new()),
+      fresh: RefCell::new(BTreeSet::new()),
+      freed: RefCell::new(BTreeSet::new())
+    }
+  }
+
+  ///Writes an empty journal region of SIZE sectors starting at JOURNAL_SECTOR on BLOCK
+  pub fn create_on_disk(block: &Block, size: u32) -> Result<(), CacheError> {
+    let header = JournalHeader {
+      magic: JOURNAL_MAGIC,
+      size,
+      ..JournalHeader::zeroed()
+    };
+
+    block.tagged(Origin::Journal, || block.write_from_buffer(JOURNAL_SECTOR, bytes_of(&header)))?;
+    Ok(())
+  }
+
+  ///Opens the journal region on BLOCK, replaying a committed transaction left behind by a crash
+  ///
+  ///Returns the number of sectors replayed. BLOCKs formatted without a journal leave it disabled
+  pub fn open(&self, block: &Block) -> Result<u32, CacheError> {
+    //What the replayed sectors belonged to is not logged, so they are counted as the journal's
+    block.tagged(Origin::Journal, || self.replay(block))
+  }
+
+  fn replay(&self, block: &Block) -> Result<u32, CacheError> {
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    block.read_to_buffer(JOURNAL_SECTOR, &mut buffer)?;
+    let mut header = *from_bytes::<JournalHeader>(&buffer);
+
+    if header.magic != JOURNAL_MAGIC || header.size < 2 {
+      return Ok(0)
+    }
+
+    self.size.set(header.size);
+    self.sequence.set(header.sequence);
+
+    let cnt = header.target_cnt as usize;
+    if cnt == 0 {
+      return Ok(0)
+    }
+
+    let mut images = Vec::<SectorImage>::new();
+
+    if cnt <= self.capacity() {
+      images.resize(cnt, [0u8; BLOCK_SECTOR_SIZE as usize]);
+      let mut buffers = images.iter_mut().map(|image| image.as_mut_slice()).collect::<Vec<_>>();
+      block.read_vectored(JOURNAL_SECTOR + 1, &mut buffers)?;
+    }
+
+    let targets = &header.targets[..cnt.min(MAX_TARGETS)];
+    let replayed = match images.len() == cnt && checksum(header.sequence, targets, images.iter()) == header.checksum {
+      true => {
+        for (&target, image) in targets.iter().zip(&images) {
+          block.write_from_buffer(target, image)?;
+        }
+        cnt as u32
+      },
+      false => 0
+    };
+
+    header.target_cnt = 0;
+    block.write_from_buffer(JOURNAL_SECTOR, bytes_of(&header))?;
+    Ok(replayed)
+  }
+
+  ///Returns the number of sectors the journal region takes up, 0 while disabled
+  pub fn size(&self) -> u32 {
+    self.size.get()
+  }
+
+  ///Returns the number of sectors a single commit can log
+  pub fn capacity(&self) -> usize {
+    (self.size.get().saturating_sub(1) as usize).min(MAX_TARGETS)
+  }
+
+  ///Returns whether writes are currently logged, being inside a transaction on a journaled BLOCK
+  pub fn in_transaction(&self) -> bool {
+    self.size.get() > 0 && self.depth.get() > 0
+  }
+
+  pub fn begin(&self) {
+    self.depth.set(self.depth.get() + 1);
+  }
+
+  ///Ends a transaction, returning whether it was the outermost one
+  pub fn end(&self) -> bool {
+    let depth = self.depth.get();
+    assert!(depth > 0, "Ended a transaction that was never begun");
+
+    self.depth.set(depth - 1);
+    depth == 1
+  }
+
+  ///Logs IMAGE as the new content of the metadata sector SECTOR, which belongs to ORIGIN
+  pub fn record(&self, sector: BlockSectorT, image: &[u8], origin: Origin) {
+    let mut copy = [0u8; BLOCK_SECTOR_SIZE as usize];
+    copy.copy_from_slice(image);
+
+    self.data.borrow_mut().remove(&sector);
+    self.pending.borrow_mut().insert(sector, copy);
+    self.origins.borrow_mut().insert(sector, origin);
+  }
+
+  ///Tracks SECTOR as data that must reach disk before the transaction commits
+  ///
+  ///A sector freed from metadata and reused as data in the same transaction is no longer logged
+  pub fn record_data(&self, sector: BlockSectorT) {
+    self.pending.borrow_mut().remove(&sector);
+    self.origins.borrow_mut().remove(&sector);
+    self.data.borrow_mut().insert(sector);
+  }
+
+  ///Tracks the CNT sectors from SECTOR as allocated by the current transaction
+  pub fn record_allocated(&self, sector: BlockSectorT, cnt: u32) {
+    if !self.in_transaction() {
+      return
+    }
+
+    let freed = self.freed.borrow();
+    self.fresh.borrow_mut().extend((sector..sector + cnt).filter(|sector| !freed.contains(sector)));
+  }
+
+  ///Tracks the CNT sectors from SECTOR as released by the current transaction
+  ///
+  ///A fresh sector stays fresh, as it is still free in the last commit
+  pub fn record_released(&self, sector: BlockSectorT, cnt: u32) {
+    if !self.in_transaction() {
+      return
+    }
+
+    let fresh = self.fresh.borrow();
+    self.freed.borrow_mut().extend((sector..sector + cnt).filter(|sector| !fresh.contains(sector)));
+  }
+
+  ///Returns whether SECTOR was free in the last commit and has since been allocated, so it need not be logged
+  pub fn is_fresh(&self, sector: BlockSectorT) -> bool {
+    self.fresh.borrow().contains(&sector)
+  }
+
+  ///Copies the logged image of SECTOR into BUFFER, returning whether there was one
+  pub fn lookup(&self, sector: BlockSectorT, buffer: &mut [u8]) -> bool {
+    match self.pending.borrow().get(&sector) {
+      Some(image) => {
+        buffer.copy_from_slice(image);
+        true
+      },
+      None => false
+    }
+  }
+
+  ///Returns whether SECTOR is logged, so the home sector is stale until the commit
+  pub fn is_logged(&self, sector: BlockSectorT) -> bool {
+    self.pending.borrow().contains_key(&sector)
+  }
+
+  ///Returns whether logging SECTOR would overflow the journal region
+  pub fn is_full(&self, sector: BlockSectorT) -> bool {
+    let pending = self.pending.borrow();
+    pending.len() >= self.capacity() && !pending.contains_key(&sector)
+  }
+
+  ///Returns how many more FREEMAP sectors the current transaction can log, keeping FREEMAP_SLACK back for the rest
+  ///
+  ///Outside a transaction nothing is logged, so there is no limit
+  pub fn freemap_room(&self) -> usize {
+    if !self.in_transaction() {
+      return usize::MAX
+    }
+
+    let slack = FREEMAP_SLACK.min(self.capacity().saturating_sub(1));
+    (self.capacity() - slack).saturating_sub(self.pending.borrow().len())
+  }
+
+  ///Takes the data sectors tracked by the current transaction
+  pub fn take_data(&self) -> BTreeSet<BlockSectorT> {
+    self.data.take()
+  }
+
+  ///Drops everything the current transaction logged, returning the sectors it wrote, both logged and data
+  ///
+  ///Their home sectors still hold what was last committed. The journal stays aborted, as what is in memory no
+  ///longer matches them
+  pub fn abort(&self) -> BTreeSet<BlockSectorT> {
+    self.aborted.set(true);
+    self.origins.borrow_mut().clear();
+    self.fresh.borrow_mut().clear();
+    self.freed.borrow_mut().clear();
+
+    let mut sectors = self.data.take();
+    sectors.extend(self.pending.take().into_keys());
+    sectors
+  }
+
+  pub fn is_aborted(&self) -> bool {
+    self.aborted.get()
+  }
+
+  ///Commits every logged sector to BLOCK
+  ///
+  ///The images are written to the journal region first and the header last, which is the commit point.
+  ///Only then are they written to their home sectors, after which the header is cleared.
+  ///If BLOCK fails partway, the images stay logged, so reads still see them and the next commit retries them
+  pub fn commit(&self, block: &Block) -> Result<(), CacheError> {
+    let pending = self.pending.take();
+    if pending.is_empty() {
+      self.settle();
+      return Ok(())
+    }
+
+    let result = self.write_out(block, &pending);
+    match result {
+      Ok(()) => {
+        self.origins.borrow_mut().clear();
+        self.settle();
+      },
+      Err(_) => {
+        self.pending.replace(pending);
+      }
+    }
+    result
+  }
+
+  ///Forgets which sectors were allocated and released, as the commit now matches them
+  fn settle(&self) {
+    self.fresh.borrow_mut().clear();
+    self.freed.borrow_mut().clear();
+  }
+
+  fn write_out(&self, block: &Block, pending: &BTreeMap<BlockSectorT, SectorImage>) -> Result<(), CacheError> {
+    let sequence = self.sequence.get().wrapping_add(1);
+    let mut header = JournalHeader {
+      magic: JOURNAL_MAGIC,
+      size: self.size.get(),
+      sequence,
+      target_cnt: pending.len() as u32,
+      ..JournalHeader::zeroed()
+    };
+
+    block.tagged(Origin::Journal, || {
+      for (i, &target) in pending.keys().enumerate() {
+        header.targets[i] = target;
+      }
+      block.write_vectored(JOURNAL_SECTOR + 1, &pending.values().map(|image| image.as_slice()).collect::<Vec<_>>())?;
+      header.checksum = checksum(sequence, &header.targets[..pending.len()], pending.values());
+      block.write_from_buffer(JOURNAL_SECTOR, bytes_of(&header))
+    })?;
+
+    for (&target, image) in pending {
+      let origin = self.origins.borrow().get(&target).copied().unwrap_or(Origin::Journal);
+      block.tagged(origin, || block.write_from_buffer(target, image))?;
+    }
+
+    header.target_cnt = 0;
+    block.tagged(Origin::Journal, || block.write_from_buffer(JOURNAL_SECTOR, bytes_of(&header)))?;
+    self.sequence.set(sequence);
+    Ok(())
+  }
+
+  ///Runs F on STATE as a single transaction, committing it once F and any enclosing transaction are done
+  ///
+  ///The transaction is committed even if F fails, as STATE in memory already reflects what F did.
+  ///One that would log more than the journal holds is aborted instead, leaving none of it on disk
+  pub fn transaction<T, E: From<CacheError>>(state: &mut FileSystem, f: impl FnOnce(&mut FileSystem) -> Result<T, E>) -> Result<T, E> {
+    state.cache.begin();
+    let result = f(state);
+    let committed = state.cache.commit(&state.block);
+
+    let value = result?;
+    committed?;
+    Ok(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cmp::min, io, rc::Rc};
+
+  use super::*;
+  use crate::fs::{
+    block::{BlockDevice, HardwareOps},
+    cache::CacheOptions,
+    device::RamDisk,
+    file_sys::{FREE_MAP_SECTOR, ROOT_DIR_SECTOR},
+    fserrors::{controller_errors::ControllerError, FSErrors},
+    fsck::{Fsck, Problem},
+    inode::{InodeFormat, InodeList},
+    superblock::{FormatOptions, SUPERBLOCK_SECTOR}
+  };
+
+  const SECTORS: u32 = 1024;
+  const KEEP: [u8; 1500] = [0x4b; 1500];
+  const NEW: [u8; 3000] = [0x4e; 3000];
+
+  ///Mounts DISK, failing every write once FAIL_AFTER writes have reached it
+  fn mount(disk: &Rc<RamDisk>, fail_after: Option<u32>, format: Option<InodeFormat>) -> Result<FileSystem<'static>, FSErrors> {
+    let options = format.map(|inode_format| FormatOptions { inode_format, ..FormatOptions::default() });
+    mount_with(disk, fail_after, options)
+  }
+
+  ///Mounts DISK like MOUNT, first formatting it as OPTIONS describe if given
+  fn mount_with(disk: &Rc<RamDisk>, fail_after: Option<u32>, options: Option<FormatOptions>) -> Result<FileSystem<'static>, FSErrors> {
+    let size = disk.size();
+    let (reader, disk) = (disk.clone(), disk.clone());
+    let writes = Cell::new(0u32);
+
+    let hardware = HardwareOps::from_fns(
+      move |sector, buffer| reader.read(sector, buffer),
+      move |sector, buffer| {
+        if fail_after.is_some_and(|limit| writes.get() >= limit) {
+          return Err(ControllerError::IOError(io::Error::other("simulated crash")))
+        }
+        writes.set(writes.get() + 1);
+        disk.write(sector, buffer)
+      }
+    );
+
+    FileSystem::with_options(Block::new("hd0".to_string(), "test".to_string(), size, hardware), options, CacheOptions::default())
+  }
+
+  ///Formats a new disk holding the file KEEP, cleanly closed
+  fn formatted_disk() -> Rc<RamDisk> {
+    let disk = Rc::new(RamDisk::new(SECTORS));
+
+    let mut file_sys = mount(&disk, None, Some(InodeFormat::Indexed)).unwrap();
+    file_sys.create("keep", 0, false).unwrap();
+    file_sys.util_write("keep", &KEEP, KEEP.len() as u32).unwrap();
+    file_sys.close().unwrap();
+    disk
+  }
+
+  fn workload(file_sys: &mut FileSystem) -> Result<(), FSErrors> {
+    file_sys.create("new", 0, false)?;
+    file_sys.util_write("new", &NEW, NEW.len() as u32)?;
+    file_sys.remove("keep")?;
+    file_sys.close()
+  }
+
+  ///Returns the INODE sector and data sectors of the file at NAME if it exists, checking its content is one of EXPECTED
+  fn file_sectors(file_sys: &mut FileSystem, name: &str, expected: &[&[u8]]) -> Option<Vec<BlockSectorT>> {
+    let file = file_sys.open(name).ok()?;
+    let inode = file.as_ref().borrow().inode(file_sys).unwrap();
+    let inode_num = inode.as_ref().borrow().inode_num();
+
+    let mut content = vec![0u8; inode.as_ref().borrow().len() as usize];
+    let len = content.len() as u32;
+    inode.as_ref().borrow().read_at(&file_sys.block, &file_sys.cache, &mut content, len, 0).unwrap();
+    assert!(expected.contains(&content.as_slice()), "{} was torn, {} bytes", name, len);
+
+    let mut sectors = inode.as_ref().borrow().data_sectors(&file_sys.block, &file_sys.cache).unwrap();
+    sectors.push(inode_num);
+
+    InodeList::close_inode(file_sys, inode_num).unwrap();
+    file.as_ref().borrow().close(file_sys).unwrap();
+    Some(sectors)
+  }
+
+  ///Checks every file is either whole or absent, and that the FREEMAP marks exactly the sectors in use
+  fn check_consistent(file_sys: &mut FileSystem) -> (bool, bool) {
+    let mut used = BTreeSet::from([FREE_MAP_SECTOR, ROOT_DIR_SECTOR, SUPERBLOCK_SECTOR]);
+    used.extend(JOURNAL_SECTOR..JOURNAL_SECTOR + min(JOURNAL_SIZE, SECTORS / 16));
+
+    for sector in [FREE_MAP_SECTOR, ROOT_DIR_SECTOR] {
+      let inode = file_sys.inode_list.open_inode(&file_sys.block, &file_sys.cache, sector).unwrap();
+      used.extend(inode.as_ref().borrow().data_sectors(&file_sys.block, &file_sys.cache).unwrap());
+      InodeList::close_inode(file_sys, sector).unwrap();
+    }
+
+    let keep = file_sectors(file_sys, "keep", &[&KEEP]);
+    let new = file_sectors(file_sys, "new", &[&[], &NEW]);
+
+    for sectors in keep.iter().chain(new.iter()) {
+      for &sector in sectors {
+        assert!(used.insert(sector), "Sector {} is used twice", sector);
+      }
+    }
+
+    let bitmap = &file_sys.freemap.inner;
+    assert!(used.iter().all(|&sector| bitmap.test(sector)), "A sector in use is free");
+    assert_eq!(bitmap.count(0, SECTORS, true) as usize, used.len(), "Sectors leaked");
+    (keep.is_some(), new.is_some())
+  }
+
+  #[test]
+  fn test_header_fills_sector() {
+    assert_eq!(std::mem::size_of::<JournalHeader>(), BLOCK_SECTOR_SIZE as usize);
+  }
+
+  #[test]
+  fn test_crash_at_every_write() {
+    let mut fail_after = 0u32;
+
+    loop {
+      let disk = &formatted_disk();
+
+      //Dropping the FILESYSTEM without closing it loses everything still in the cache, as a crash would
+      let result = mount(disk, Some(fail_after), None).and_then(|mut file_sys| workload(&mut file_sys));
+
+      //Crashing again while replaying must not matter either
+      drop(mount(disk, Some(1), None));
+
+      let mut file_sys = mount(disk, None, None).unwrap();
+      let (keep, new) = check_consistent(&mut file_sys);
+
+      if result.is_ok() {
+        assert!(!keep && new);
+        break;
+      }
+      fail_after += 1;
+    }
+
+    assert!(fail_after > 10);
+  }
+
+  ///Formats a new disk holding 80 empty files, cleanly closed
+  fn many_files_disk() -> Rc<RamDisk> {
+    let disk = Rc::new(RamDisk::new(SECTORS));
+    let mut file_sys = mount(&disk, None, Some(InodeFormat::Indexed)).unwrap();
+
+    for i in 0..80 {
+      file_sys.create(&format!("file{}", i), 0, false).unwrap();
+    }
+    file_sys.close().unwrap();
+    disk
+  }
+
+  #[test]
+  fn test_large_transaction_is_split() {
+    let disk = &many_files_disk();
+
+    //Writing every file changes more INODEs than the journal holds, but each write allocates sectors first
+    let mut file_sys = mount(disk, None, None).unwrap();
+    Journal::transaction(&mut file_sys, |file_sys| {
+      (0..80).try_for_each(|i| file_sys.util_write(&format!("file{}", i), &NEW, NEW.len() as u32))
+    }).unwrap();
+    file_sys.create("late", 0, false).unwrap();
+    file_sys.close().unwrap();
+
+    let mut file_sys = mount(disk, None, None).unwrap();
+    let mut buffer = [0u8; NEW.len()];
+    for i in 0..80 {
+      let file = file_sys.open(&format!("file{}", i)).unwrap();
+      file.as_ref().borrow().read(&file_sys.block, &file_sys.cache, &mut buffer, NEW.len() as u32).unwrap();
+      assert_eq!(buffer, NEW, "file{} was not written", i);
+      file.as_ref().borrow().close(&mut file_sys).unwrap();
+    }
+    assert!(Fsck::check(&mut file_sys).unwrap().problems().is_empty());
+    file_sys.close().unwrap();
+  }
+
+  #[test]
+  fn test_crash_while_split() {
+    let options = FormatOptions { journal_size: Some(10), inode_format: InodeFormat::Extent, ..FormatOptions::default() };
+    let mut fail_after = 0u32;
+
+    loop {
+      let disk = Rc::new(RamDisk::new(40960));
+      mount_with(&disk, None, Some(options)).unwrap().close().unwrap();
+
+      //Creating the file changes more FREEMAP sectors than the journal holds
+      let result = mount_with(&disk, Some(fail_after), None).and_then(|mut file_sys| {
+        file_sys.create("big", 37000 * BLOCK_SECTOR_SIZE, false)?;
+        file_sys.close()
+      });
+
+      //A crash between the pieces only leaks the sectors allocated so far
+      let mut file_sys = mount_with(&disk, None, None).unwrap();
+      let fsck = Fsck::check(&mut file_sys).unwrap();
+      assert!(fsck.problems().iter().all(|problem| matches!(problem, Problem::LeakedSector { .. })));
+
+      if result.is_ok() {
+        assert!(fsck.problems().is_empty());
+        assert!(file_sys.open("big").is_ok());
+        break;
+      }
+      fail_after += 4999;
+    }
+  }
+
+  #[test]
+  fn test_large_transaction_is_aborted() {
+    let disk = &many_files_disk();
+
+    //Changing the mode of every file logs more INODEs than the journal holds, with no FREEMAP write to split it at
+    let mut file_sys = mount(disk, None, None).unwrap();
+    let free_before = file_sys.freemap.num_free_sectors();
+    let result = Journal::transaction(&mut file_sys, |file_sys| {
+      (0..80).try_for_each(|i| file_sys.util_chmod(&format!("file{}", i), 0o600))
+    });
+    assert!(result.is_err());
+
+    //Nothing is written once aborted, not even by closing
+    assert!(file_sys.create("late", 0, false).is_err());
+    assert!(file_sys.close().is_err());
+
+    let mut file_sys = mount(disk, None, None).unwrap();
+    for i in 0..80 {
+      let file = file_sys.open(&format!("file{}", i)).unwrap();
+      let inode = file.as_ref().borrow().inode(&mut file_sys).unwrap();
+      let (mode, sector) = (inode.as_ref().borrow().mode(), inode.as_ref().borrow().inode_num());
+      assert_ne!(mode, 0o600, "file{} was changed", i);
+      InodeList::close_inode(&mut file_sys, sector).unwrap();
+      file.as_ref().borrow().close(&mut file_sys).unwrap();
+    }
+    assert!(file_sys.open("late").is_err());
+    assert_eq!(file_sys.freemap.num_free_sectors(), free_before);
+    file_sys.close().unwrap();
+  }
+}
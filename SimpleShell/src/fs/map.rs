@@ -0,0 +1,221 @@
+use std::collections::BTreeSet;
+
+use crate::fs::{
+  block::BlockSectorT,
+  file_sys::{
+    FileSystem,
+    FREE_MAP_SECTOR,
+    ROOT_DIR_SECTOR
+  },
+  fserrors::FSErrors,
+  inode::InodeList,
+  journal::JOURNAL_SECTOR,
+  superblock::SUPERBLOCK_SECTOR
+};
+
+///What a sector on the device holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SectorKind {
+  Free,
+  ///The FREEMAP INODE and its data
+  Freemap,
+  Superblock,
+  Journal,
+  ///The INODE of the root directory
+  RootDir,
+  Inode,
+  ///Indirect blocks and EXTENT BLOCKS
+  Indirect,
+  DirData,
+  FileData,
+  ///Marked in use on the FREEMAP, but reachable from no INODE
+  Unaccounted,
+  ///In use, but filtered out of the map
+  Other
+}
+
+impl SectorKind {
+  pub fn symbol(self) -> char {
+    match self {
+      Self::Free => '.',
+      Self::Freemap => 'F',
+      Self::Superblock => 'S',
+      Self::Journal => 'J',
+      Self::RootDir => 'R',
+      Self::Inode => 'I',
+      Self::Indirect => 'X',
+      Self::DirData => 'D',
+      Self::FileData => '#',
+      Self::Unaccounted => '?',
+      Self::Other => '-'
+    }
+  }
+}
+
+const LEGEND: [(SectorKind, &str); 11] = [
+  (SectorKind::Free, "free"),
+  (SectorKind::Freemap, "freemap"),
+  (SectorKind::Superblock, "superblock"),
+  (SectorKind::Journal, "journal"),
+  (SectorKind::RootDir, "root dir"),
+  (SectorKind::Inode, "inode"),
+  (SectorKind::Indirect, "indirect"),
+  (SectorKind::DirData, "dir data"),
+  (SectorKind::FileData, "file data"),
+  (SectorKind::Unaccounted, "unaccounted"),
+  (SectorKind::Other, "other")
+];
+
+///What every sector of a FILESYSTEM holds, built from the FREEMAP and the INODEs reachable from the root directory
+pub(crate) struct SectorMap {
+  kinds: Vec<SectorKind>
+}
+
+impl SectorMap {
+  pub fn collect(state: &mut FileSystem) -> Result<Self, FSErrors> {
+    let kinds = (0..state.block.get_size()).map(|sector| match state.freemap.inner.test(sector) {
+      true => SectorKind::Unaccounted,
+      false => SectorKind::Free
+    }).collect();
+    let mut map = Self { kinds };
+
+    if state.freemap.inner.test(SUPERBLOCK_SECTOR) {
+      map.mark(SUPERBLOCK_SECTOR, SectorKind::Superblock);
+    }
+
+    for sector in JOURNAL_SECTOR..JOURNAL_SECTOR + state.cache.journal_size() {
+      map.mark(sector, SectorKind::Journal);
+    }
+
+    let mut inodes = vec![(FREE_MAP_SECTOR, false)];
+    inodes.extend(state.walk_tree()?.into_iter().map(|(_, sector, is_dir)| (sector, is_dir)));
+
+    for (sector, is_dir) in inodes {
+      let (data, owned) = Self::inode_sectors(state, sector)?;
+
+      let (inode_kind, data_kind) = match sector {
+        FREE_MAP_SECTOR => (SectorKind::Freemap, SectorKind::Freemap),
+        ROOT_DIR_SECTOR => (SectorKind::RootDir, SectorKind::DirData),
+        _ if is_dir => (SectorKind::Inode, SectorKind::DirData),
+        _ => (SectorKind::Inode, SectorKind::FileData)
+      };
+
+      map.mark(sector, inode_kind);
+      //Owned sectors that hold no data are the blocks pointing at it
+      owned.iter().for_each(|&owned| map.mark(owned, SectorKind::Indirect));
+      data.iter().for_each(|&data| map.mark(data, data_kind));
+    }
+    Ok(map)
+  }
+
+  ///Returns the data sectors of the INODE at SECTOR, and every sector it owns besides its own
+  pub fn inode_sectors(state: &mut FileSystem, sector: BlockSectorT) -> Result<(Vec<BlockSectorT>, Vec<BlockSectorT>), FSErrors> {
+    let inode = state.inode_list.open_inode(&state.block, &state.cache, sector)?;
+    let data = inode.as_ref().borrow().data_sectors(&state.block, &state.cache);
+    let owned = inode.as_ref().borrow().owned_sectors(&state.block, &state.cache);
+    InodeList::close_inode(state, sector)?;
+    Ok((data?, owned?))
+  }
+
+  ///Sets the kind of SECTOR, ignoring sectors past the end of the device
+  fn mark(&mut self, sector: BlockSectorT, kind: SectorKind) {
+    if let Some(entry) = self.kinds.get_mut(sector as usize) {
+      *entry = kind;
+    }
+  }
+
+  pub fn kind(&self, sector: BlockSectorT) -> SectorKind {
+    self.kinds[sector as usize]
+  }
+
+  ///Shows every sector in use outside of KEEP as OTHER
+  pub fn filter(&mut self, keep: &BTreeSet<BlockSectorT>) {
+    for (sector, kind) in self.kinds.iter_mut().enumerate() {
+      if *kind != SectorKind::Free && !keep.contains(&(sector as BlockSectorT)) {
+        *kind = SectorKind::Other;
+      }
+    }
+  }
+
+  ///Renders the map as rows of WIDTH sectors, each prefixed with the sector it starts at, followed by a legend
+  pub fn render(&self, width: u32) -> String {
+    let width = width.max(1) as usize;
+    let mut rendered = String::new();
+
+    for (row, kinds) in self.kinds.chunks(width).enumerate() {
+      rendered.push_str(&format!("{:>8} ", row * width));
+      rendered.extend(kinds.iter().map(|kind| kind.symbol()));
+      rendered.push('\n');
+    }
+
+    let legend = LEGEND.iter()
+      .filter(|(kind, _)| self.kinds.contains(kind))
+      .map(|(kind, name)| format!("{} {}", kind.symbol(), name))
+      .collect::<Vec<_>>();
+    rendered.push_str(&legend.join("  "));
+    rendered.push('\n');
+    rendered
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::{
+    block::BLOCK_SECTOR_SIZE,
+    file_sys::tests::setup_file_sys
+  };
+
+  #[test]
+  fn test_collect() {
+    let mut file_sys = setup_file_sys(1024);
+    let content = vec![1u8; 200 * BLOCK_SECTOR_SIZE as usize];
+
+    file_sys.create("dir", 0, true).unwrap();
+    file_sys.create("dir/big", 0, false).unwrap();
+    file_sys.util_write("dir/big", &content, content.len() as u32).unwrap();
+
+    let map = SectorMap::collect(&mut file_sys).unwrap();
+    assert_eq!(map.kind(FREE_MAP_SECTOR), SectorKind::Freemap);
+    assert_eq!(map.kind(ROOT_DIR_SECTOR), SectorKind::RootDir);
+    assert_eq!(map.kind(SUPERBLOCK_SECTOR), SectorKind::Superblock);
+    assert_eq!(map.kind(JOURNAL_SECTOR), SectorKind::Journal);
+
+    //Every sector in use is accounted for, and the file needs an indirect block past its direct blocks
+    assert!((0..1024).all(|sector| map.kind(sector) != SectorKind::Unaccounted));
+    assert!((0..1024).any(|sector| map.kind(sector) == SectorKind::Indirect));
+    assert!((0..1024).any(|sector| map.kind(sector) == SectorKind::DirData));
+    assert_eq!((0..1024).filter(|&sector| map.kind(sector) == SectorKind::FileData).count(), 200);
+
+    let free = (0..1024).filter(|&sector| map.kind(sector) == SectorKind::Free).count() as u32;
+    assert_eq!(free, file_sys.freemap.num_free_sectors());
+  }
+
+  #[test]
+  fn test_filter_and_render() {
+    let mut file_sys = setup_file_sys(256);
+
+    file_sys.create("a", 0, false).unwrap();
+    file_sys.util_write("a", b"hello", 5).unwrap();
+
+    let file = file_sys.open("a").unwrap();
+    let sector = file.as_ref().borrow().inode(&mut file_sys).unwrap().as_ref().borrow().inode_num();
+    InodeList::close_inode(&mut file_sys, sector).unwrap();
+    file.as_ref().borrow().close(&mut file_sys).unwrap();
+
+    let (data, _) = SectorMap::inode_sectors(&mut file_sys, sector).unwrap();
+    let mut map = SectorMap::collect(&mut file_sys).unwrap();
+    map.filter(&data.iter().copied().chain([sector]).collect());
+
+    assert_eq!(map.kind(ROOT_DIR_SECTOR), SectorKind::Other);
+    assert_eq!(map.kind(sector), SectorKind::Inode);
+    assert_eq!(map.kind(data[0]), SectorKind::FileData);
+
+    let rendered = map.render(64);
+    let rows = rendered.lines().collect::<Vec<_>>();
+    assert_eq!(rows.len(), 5);
+    assert!(rows[0].starts_with("       0 --"));
+    assert!(rows[3].starts_with("     192 "));
+    assert_eq!(rows[4], ". free  I inode  # file data  - other");
+  }
+}
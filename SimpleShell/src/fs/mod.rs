@@ -2,21 +2,37 @@ mod ata;
 mod block;
 mod bitmap;
 mod cache;
+mod device;
 mod directory;
+mod extent;
+mod fault;
 mod file;
 mod file_sys;
+mod frag;
 mod freemap;
+mod fsck;
 mod fserrors;
 mod inode;
+mod iostat;
+mod journal;
+mod map;
+mod partition;
+mod policy;
+mod superblock;
 mod util;
 
-use std::f64::consts::PI;
+use std::{fs::read, io::{stdin, Read}, path::Path, time::Duration};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use block::Block;
+use ata::AtaController;
+use block::{Block, BLOCK_SECTOR_SIZE};
+use cache::{CacheMode, CacheOptions, WriteBehind, DEFAULT_FLUSH_INTERVAL};
 use file_sys::FileSystem;
-use fserrors::FSErrors;
+use fserrors::{mount_errors::MountError, FSErrors};
+use inode::InodeFormat;
+use policy::CachePolicy;
+use superblock::{FormatOptions, DEFAULT_CACHE_SIZE, DEFAULT_ROOT_CAPACITY};
 
 #[derive(Parser)]
 #[command(name = "fs")]
@@ -48,18 +64,34 @@ pub enum FSSubcommands {
     name: String
   },
   #[command(about = "List files and directories of CWD")]
-  List {},
+  List {
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
   #[command(about = "Write content to a file")]
   Write {
     #[arg(help = "Name of file to write to")]
     name: String,
-    #[arg(help = "Contents to write")]
-    content: String
+    #[arg(help = "Contents to write (Default stdin)")]
+    content: Option<String>,
+    #[arg(short, long, conflicts_with = "content", help = "Host file to take the contents from")]
+    file: Option<String>
+  },
+  #[command(about = "Write content to the end of a file")]
+  Append {
+    #[arg(help = "Name of file to append to")]
+    name: String,
+    #[arg(help = "Contents to append (Default stdin)")]
+    content: Option<String>,
+    #[arg(short, long, conflicts_with = "content", help = "Host file to take the contents from")]
+    file: Option<String>
   },
   #[command(about = "Find files or directories whose content contains a pattern")]
   Find {
     #[arg(help = "Pattern to search for")]
-    pat: String
+    pat: String,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
   },
   #[command(about = "Read content from file")]
   Read {
@@ -71,18 +103,38 @@ pub enum FSSubcommands {
   #[command(about = "Copy in a file from host device to device")]
   CopyIn {
     #[arg(help = "Name of file to read from")]
-    name: String
+    name: String,
+    #[arg(help = "Path on device to copy to, the file name of NAME if not given")]
+    target: Option<String>
   },
   #[command(about = "Copy out a file on device to host device")]
   CopyOut {
     #[arg(help = "Name of file to read from")]
-    name: String
+    name: String,
+    #[arg(help = "Path on host device to copy to, the file name of NAME if not given")]
+    target: Option<String>
   },
   #[command(about = "Size of a file")]
   Size {
     #[arg(help = "Name of file to read from")]
     name: String
   },
+  #[command(about = "Shrink or extend a file to a given length")]
+  Truncate {
+    #[arg(help = "Name of file to truncate")]
+    name: String,
+    #[arg(help = "New length in bytes")]
+    len: u32
+  },
+  #[command(about = "Deallocate a range of a file, leaving a hole that reads as zeros")]
+  Punch {
+    #[arg(help = "Name of file to punch")]
+    name: String,
+    #[arg(help = "Offset to start from")]
+    ofst: u32,
+    #[arg(help = "Number of bytes to deallocate")]
+    len: u32
+  },
   #[command(about = "Set offset of a file")]
   Seek {
     #[arg(help = "Name of file to read from")]
@@ -91,80 +143,599 @@ pub enum FSSubcommands {
     ofst: u32
   },
   #[command(about = "Number of free sectors on device")]
-  FreeSpace {},
-  #[command(about = "Degree of fragmentation of all files on device")]
-  FragmentationDegree {},
-  #[command(about = "Defragment all files")]
-  Defragment {},
+  FreeSpace {
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Extents of every file and directory, and a histogram of free space")]
+  FragmentationDegree {
+    #[arg(short, long, help = "Print the report as JSON")]
+    json: bool,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Defragment all files and directories in place, moving their sectors into contiguous runs")]
+  Defragment {
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
   #[command(about = "Recover deleted files")]
-  Recover {}
+  Recover {
+    #[arg(short, long, help = "List the files that would be recovered without recovering them")]
+    dry_run: bool,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Draw a map of what every sector holds")]
+  Map {
+    #[arg(help = "Only show the sectors owned by this file or directory")]
+    path: Option<String>,
+    #[arg(short, long, default_value_t = 64, help = "Number of sectors per row")]
+    width: u32,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Write every dirty sector in the cache back to disk")]
+  Sync {
+    #[arg(short, long, help = "Mount point of the file system to sync (Default all of them)")]
+    mount: Option<String>
+  },
+  #[command(about = "Display the hits and misses of the cache")]
+  Cachestats {
+    #[arg(short, long, help = "Zero the counters after displaying them")]
+    reset: bool,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Display the sectors read and written on each device, by what they hold")]
+  Iostat {
+    #[arg(short, long, help = "Zero the counters after displaying them")]
+    reset: bool,
+    #[arg(short, long, help = "Mount point of the file system to use (Default all of them)")]
+    mount: Option<String>
+  },
+  #[command(about = "Log every sector accessed on a device along with what it holds")]
+  Trace {
+    #[arg(value_enum, help = "Whether to start or stop logging")]
+    state: TraceState,
+    #[arg(short, long, help = "Host file to append the log to (Default stdout)")]
+    file: Option<String>,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Check the file system for inconsistencies")]
+  Fsck {
+    #[arg(short, long, help = "Fix the inconsistencies found")]
+    repair: bool,
+    #[arg(short, long, help = "Mount point of the file system to use (Default /)")]
+    mount: Option<String>
+  },
+  #[command(about = "Display the metadata of a file or directory")]
+  Stat {
+    #[arg(help = "Path of file or directory")]
+    path: String
+  },
+  #[command(about = "Change the permissions of a file or directory")]
+  Chmod {
+    #[arg(help = "Path of file or directory")]
+    path: String,
+    #[arg(help = "Permissions in octal (e.g. 644)", value_parser = |mode: &str| u16::from_str_radix(mode, 8))]
+    mode: u16
+  },
+  #[command(about = "Change the owner of a file or directory")]
+  Chown {
+    #[arg(help = "Path of file or directory")]
+    path: String,
+    #[arg(help = "User ID of new owner")]
+    uid: u16,
+    #[arg(help = "Group ID of new owner")]
+    gid: u16
+  },
+  #[command(about = "Act as another user for following commands")]
+  User {
+    #[arg(help = "User ID (0 bypasses permission checks)")]
+    uid: u16,
+    #[arg(help = "Group ID")]
+    gid: u16
+  },
+  #[command(about = "Mount a disk image, or one of its partitions, at a mount point")]
+  Mount {
+    #[arg(help = "Path of the disk image on the host")]
+    image: String,
+    #[arg(help = "Absolute path to mount it at")]
+    mountpoint: String,
+    #[arg(short, long, help = "Number of the partition to mount (Default the only one, or the whole image)")]
+    partition: Option<u32>,
+    #[arg(short, long, help = "Format it first, giving new inodes this format")]
+    format: Option<InodeFormat>,
+    #[arg(short, long, help = "Number of sectors the cache holds (Default the size it was formatted with)")]
+    cache_size: Option<u32>,
+    #[arg(long, value_enum, default_value_t = CachePolicy::Clock, help = "Which sectors the cache evicts first")]
+    cache_policy: CachePolicy,
+    #[arg(long, value_enum, default_value_t = CacheMode::WriteBack, help = "When writes through the cache reach the image")]
+    cache_mode: CacheMode,
+    #[arg(long, help = "Number of dirty sectors that starts a write-behind (Default half the cache)")]
+    dirty_limit: Option<u32>,
+    #[arg(long, default_value_t = DEFAULT_FLUSH_INTERVAL.as_secs(), help = "Seconds between write-behinds, 0 for none")]
+    flush_interval: u64
+  },
+  #[command(about = "Create a sparse disk image and format it")]
+  Mkfs {
+    #[arg(help = "Path of the disk image to create on the host")]
+    image: String,
+    #[arg(help = "Size of the image in bytes, optionally suffixed with K, M or G", value_parser = parse_size)]
+    size: u32,
+    #[arg(short, long, default_value_t = DEFAULT_CACHE_SIZE, help = "Number of sectors the cache holds")]
+    cache_size: u32,
+    #[arg(short, long, default_value_t = DEFAULT_ROOT_CAPACITY, help = "Number of entries the root directory holds before growing")]
+    root_capacity: u32,
+    #[arg(short, long, value_enum, default_value_t = InodeFormat::Indexed, help = "Format of new inodes")]
+    inode_format: InodeFormat,
+    #[arg(short, long, help = "Number of sectors in the journal, 0 for none (Default scaled to the image)")]
+    journal_size: Option<u32>
+  },
+  #[command(about = "Unmount the file system at a mount point")]
+  Unmount {
+    #[arg(help = "Mount point to unmount")]
+    mountpoint: String
+  },
+  #[command(about = "List mounted file systems")]
+  Mounts {},
+  #[command(about = "Reclaim free entries at the end of a directory")]
+  Compact {
+    #[arg(help = "Path of directory to compact (Default CWD)")]
+    path: Option<String>
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TraceState {
+  On,
+  Off
+}
+
+///Returns CONTENT, or the contents of the host file at FILE, or everything on stdin if neither is given
+fn read_content(content: Option<String>, file: Option<String>) -> Result<Vec<u8>, FSErrors> {
+  match (content, file) {
+    (Some(content), _) => Ok(content.into_bytes()),
+    (None, Some(file)) => Ok(read(file)?),
+    (None, None) => {
+      let mut content = Vec::new();
+      stdin().read_to_end(&mut content)?;
+      Ok(content)
+    }
+  }
+}
+
+///Returns the last component of PATH, which a copy is named after when no target is given
+fn file_name(path: &str) -> Result<String, FSErrors> {
+  Path::new(path).file_name()
+    .and_then(|name| name.to_str())
+    .map(str::to_owned)
+    .ok_or_else(|| FSErrors::InvalidName(path.to_string(), path.len()))
+}
+
+///A FILESYSTEM mounted at POINT, along with the name of the device holding it if it was mounted from an image
+struct Mount<'a> {
+  point: String,
+  device: Option<String>,
+  file_sys: FileSystem<'a>
+}
+
+///Parses a size in bytes with an optional K, M or G suffix into a number of whole sectors
+fn parse_size(size: &str) -> Result<u32, String> {
+  let (digits, unit) = match size.char_indices().last() {
+    Some((i, 'K' | 'k')) => (&size[..i], 1u64 << 10),
+    Some((i, 'M' | 'm')) => (&size[..i], 1u64 << 20),
+    Some((i, 'G' | 'g')) => (&size[..i], 1u64 << 30),
+    _ => (size, 1u64)
+  };
+
+  let bytes = digits.parse::<u64>().map_err(|e| e.to_string())?.checked_mul(unit);
+  let sectors = bytes.ok_or_else(|| format!("{} is too large", size))? / BLOCK_SECTOR_SIZE as u64;
+
+  match sectors {
+    0..16 => Err(format!("{} is too small, an image needs at least 16 sectors", size)),
+    sectors => u32::try_from(sectors).map_err(|_| format!("{} is too large", size))
+  }
 }
 
 pub struct FSModule<'a> {
-  inner: FileSystem<'a>
+  controller: AtaController,
+  ///The root file system first, then everything mounted from images in mount order
+  mounts: Vec<Mount<'a>>
+}
+
+///Checks that POINT is an absolute path other than the root directory, dropping any trailing slashes
+fn mount_point(point: &str) -> Result<String, MountError> {
+  let trimmed = point.trim_end_matches('/');
+
+  match trimmed.starts_with('/') {
+    true => Ok(trimmed.to_owned()),
+    false => Err(MountError::InvalidMountPoint(point.to_owned()))
+  }
 }
 
 impl<'a> FSModule<'a> {
-  pub fn new(block: Block<'a>, fmt: bool) -> Result<Self, FSErrors> {
-    let file_sys = FileSystem::new(block, fmt)?;
+  pub fn new(block: Block<'a>, fmt: Option<InodeFormat>, cache_options: CacheOptions) -> Result<Self, FSErrors> {
+    let file_sys = FileSystem::new(block, fmt, cache_options)?;
     Ok(
       Self {
-        inner: file_sys
+        controller: AtaController::new(),
+        mounts: vec![Mount { point: "/".to_owned(), device: None, file_sys }]
       }
     )
   }
 
+  ///Returns the index of the mount PATH lies in, and PATH relative to its root
+  ///
+  ///Relative paths always lie in the root file system, the one holding the CWD
+  fn route(&self, path: &str) -> (usize, String) {
+    if !path.starts_with('/') {
+      return (0, path.to_owned())
+    }
+
+    self.mounts.iter().enumerate().skip(1)
+      .filter_map(|(i, mount)| match path.strip_prefix(mount.point.as_str()) {
+        Some("") => Some((i, "/".to_owned())),
+        Some(rest) if rest.starts_with('/') => Some((i, rest.to_owned())),
+        _ => None
+      })
+      .max_by_key(|&(i, _)| self.mounts[i].point.len())
+      .unwrap_or((0, path.to_owned()))
+  }
+
+  ///Returns the file system PATH lies in, along with PATH relative to its root
+  fn routed(&mut self, path: &str) -> (&mut FileSystem<'a>, String) {
+    let (i, path) = self.route(path);
+    (&mut self.mounts[i].file_sys, path)
+  }
+
+  ///Returns the file system mounted at POINT, or the root file system if none is given
+  fn mounted(&mut self, point: Option<String>) -> Result<&mut FileSystem<'a>, FSErrors> {
+    let point = match point {
+      Some(point) if point.trim_end_matches('/').is_empty() => return Ok(&mut self.mounts[0].file_sys),
+      Some(point) => mount_point(&point)?,
+      None => return Ok(&mut self.mounts[0].file_sys)
+    };
+
+    match self.mounts.iter_mut().find(|mount| mount.point == point) {
+      Some(mount) => Ok(&mut mount.file_sys),
+      None => Err(MountError::NotMounted(point).into())
+    }
+  }
+
+  ///Mounts the disk IMAGE at POINT with a cache as CACHE_OPTIONS describe, formatting it first if FORMAT is given
+  ///
+  ///If IMAGE has a partition table, the partition numbered PARTITION is mounted instead, which may be left out
+  ///when there is only one
+  pub fn mount(&mut self, image: &str, point: &str, partition: Option<u32>, format: Option<InodeFormat>, cache_options: CacheOptions) -> Result<(), FSErrors> {
+    let point = mount_point(point)?;
+
+    if self.mounts.iter().any(|mount| mount.point == point) {
+      return Err(MountError::AlreadyMounted(point).into())
+    }
+
+    let disk = self.controller.attach(image)?;
+
+    let mounted = (|| -> Result<FileSystem<'a>, FSErrors> {
+      let whole = Block::new(disk.name().to_owned(), image.to_owned(), disk.size()?, disk.clone());
+      let partitions = partition::scan(&whole)?;
+
+      let block = match (partition, partitions.as_slice()) {
+        (Some(number), _) => partitions.iter()
+          .find(|found| found.number == number)
+          .ok_or(MountError::NoSuchPartition(number))?
+          .block(disk.name(), image, disk.clone()),
+        (None, []) => whole,
+        (None, [only]) => only.block(disk.name(), image, disk.clone()),
+        (None, _) => return Err(MountError::PartitionRequired(partitions.len()).into())
+      };
+
+      FileSystem::new(block, format, cache_options)
+    })();
+
+    match mounted {
+      Ok(file_sys) => {
+        println!("Mounted {} on {}", file_sys.block.get_name(), point);
+        self.mounts.push(Mount { point, device: Some(disk.name().to_owned()), file_sys });
+        Ok(())
+      },
+      Err(e) => {
+        self.controller.detach(disk.name())?;
+        Err(e)
+      }
+    }
+  }
+
+  ///Writes everything the file system at POINT holds in memory back to its image and detaches the image
+  pub fn unmount(&mut self, point: &str) -> Result<(), FSErrors> {
+    let point = mount_point(point)?;
+    let nested = format!("{}/", point);
+
+    let i = match self.mounts.iter().position(|mount| mount.point == point) {
+      Some(0) | None => return Err(MountError::NotMounted(point).into()),
+      Some(i) => i
+    };
+
+    if self.mounts.iter().any(|mount| mount.point.starts_with(&nested)) {
+      return Err(MountError::Busy(point).into())
+    }
+
+    self.mounts[i].file_sys.close()?;
+    let mount = self.mounts.remove(i);
+
+    if let Some(device) = mount.device {
+      self.controller.detach(&device)?;
+    }
+    Ok(())
+  }
+
   pub fn exec_cmd(&mut self, cmd: FSSubcommands) -> Result<(), FSErrors> {
     match cmd {
       FSSubcommands::Create { name, size, is_dir } => {
-        self.inner.util_create(&name, size.unwrap_or(0), is_dir)
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_create(&name, size.unwrap_or(0), is_dir)
       },
       FSSubcommands::Cat { name } => {
-        self.inner.util_cat(&name)
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_cat(&name)
       },
       FSSubcommands::Remove { name } => {
-        self.inner.util_rm(&name)
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_rm(&name)
+      },
+      FSSubcommands::List { mount } => {
+        self.mounted(mount)?.util_ls()
       },
-      FSSubcommands::List {} => {
-        self.inner.util_ls()
+      FSSubcommands::Write { name, content, file } => {
+        let content = read_content(content, file)?;
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_write(&name, &content, content.len() as u32)
       },
-      FSSubcommands::Write { name, content } => {
-        self.inner.util_write(&name, content.as_bytes(), content.len() as u32)
+      FSSubcommands::Append { name, content, file } => {
+        let content = read_content(content, file)?;
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_append(&name, &content, content.len() as u32)
       },
-      FSSubcommands::Find { pat } => {
-        self.inner.util_find_file(&pat)
+      FSSubcommands::Find { pat, mount } => {
+        self.mounted(mount)?.util_find_file(&pat)
       },
       FSSubcommands::Read { name, size } => {
         let mut buffer = vec![0u8; size as usize];
-        self.inner.util_read(&name, &mut buffer, size)?;
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_read(&name, &mut buffer, size)?;
         println!("{}", String::from_utf8_lossy(&buffer));
         Ok(())
       },
-      FSSubcommands::CopyIn { name } => {
-        self.inner.util_copy_in(&name)
+      FSSubcommands::CopyIn { name, target } => {
+        let target = match target {
+          Some(target) => target,
+          None => file_name(&name)?
+        };
+        let (file_sys, target) = self.routed(&target);
+        file_sys.util_copy_in(&name, &target)
       },
-      FSSubcommands::CopyOut { name } => {
-        self.inner.util_copy_out(&name)
+      FSSubcommands::CopyOut { name, target } => {
+        let target = match target {
+          Some(target) => target,
+          None => file_name(&name)?
+        };
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_copy_out(&name, &target)
       },
       FSSubcommands::Size { name } => {
-        self.inner.util_size(&name)
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_size(&name)
+      },
+      FSSubcommands::Truncate { name, len } => {
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_truncate(&name, len)
+      },
+      FSSubcommands::Punch { name, ofst, len } => {
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_punch(&name, ofst, len)
       },
       FSSubcommands::Seek { name, ofst } => {
-        self.inner.util_seek(&name, ofst)
+        let (file_sys, name) = self.routed(&name);
+        file_sys.util_seek(&name, ofst)
+      },
+      FSSubcommands::FreeSpace { mount } => {
+        self.mounted(mount)?.util_freespace();
+        Ok(())
+      },
+      FSSubcommands::FragmentationDegree { json, mount } => {
+        self.mounted(mount)?.util_frag_degree(json)
+      },
+      FSSubcommands::Defragment { mount } => {
+        self.mounted(mount)?.util_defrag()
+      },
+      FSSubcommands::Recover { dry_run, mount } => {
+        self.mounted(mount)?.util_recover(dry_run)
+      },
+      FSSubcommands::Map { path, width, mount } => {
+        match path {
+          Some(path) => {
+            let (file_sys, path) = self.routed(&path);
+            file_sys.util_map(Some(&path), width)
+          },
+          None => self.mounted(mount)?.util_map(None, width)
+        }
+      },
+      FSSubcommands::Sync { mount } => {
+        match mount {
+          Some(_) => self.mounted(mount)?.util_sync(),
+          None => self.mounts.iter().try_for_each(|mount| mount.file_sys.util_sync())
+        }
+      },
+      FSSubcommands::Cachestats { reset, mount } => {
+        self.mounted(mount)?.util_cachestats(reset);
+        Ok(())
+      },
+      FSSubcommands::Iostat { reset, mount } => {
+        match mount {
+          Some(_) => self.mounted(mount)?.util_iostat(reset),
+          None => self.mounts.iter().for_each(|mount| mount.file_sys.util_iostat(reset))
+        }
+        Ok(())
+      },
+      FSSubcommands::Trace { state, file, mount } => {
+        self.mounted(mount)?.util_trace(state == TraceState::On, file.as_deref())
+      },
+      FSSubcommands::Fsck { repair, mount } => {
+        self.mounted(mount)?.util_fsck(repair)
+      },
+      FSSubcommands::Mount { image, mountpoint, partition, format, cache_size, cache_policy, cache_mode, dirty_limit, flush_interval } => {
+        let write_behind = WriteBehind {
+          dirty_limit,
+          interval: Some(Duration::from_secs(flush_interval)).filter(|interval| !interval.is_zero())
+        };
+        self.mount(&image, &mountpoint, partition, format, CacheOptions { size: cache_size, policy: cache_policy, mode: cache_mode, write_behind })
+      },
+      FSSubcommands::Mkfs { image, size, cache_size, root_capacity, inode_format, journal_size } => {
+        FileSystem::mkfs(&image, size, FormatOptions { cache_size, root_capacity, inode_format, journal_size })
+      },
+      FSSubcommands::Unmount { mountpoint } => {
+        self.unmount(&mountpoint)
       },
-      FSSubcommands::FreeSpace {} => {
-        self.inner.util_freespace();
+      FSSubcommands::Mounts {} => {
+        for mount in &self.mounts {
+          println!("{} on {} ({} sectors)", mount.file_sys.block.get_name(), mount.point, mount.file_sys.block.get_size());
+        }
         Ok(())
       },
-      FSSubcommands::FragmentationDegree {} => {
-        self.inner.util_frag_degree()
+      FSSubcommands::Compact { path } => {
+        let (file_sys, path) = self.routed(&path.unwrap_or_default());
+        file_sys.util_compact(&path)
       },
-      FSSubcommands::Defragment {} => {
-        self.inner.util_defrag()
+      FSSubcommands::Stat { path } => {
+        let (file_sys, path) = self.routed(&path);
+        file_sys.util_stat(&path)
       },
-      FSSubcommands::Recover {} => {
-        self.inner.util_recover()
+      FSSubcommands::Chmod { path, mode } => {
+        let (file_sys, path) = self.routed(&path);
+        file_sys.util_chmod(&path, mode)
       },
+      FSSubcommands::Chown { path, uid, gid } => {
+        let (file_sys, path) = self.routed(&path);
+        file_sys.util_chown(&path, uid, gid)
+      },
+      FSSubcommands::User { uid, gid } => {
+        self.mounts.iter_mut().for_each(|mount| mount.file_sys.set_user(uid, gid));
+        Ok(())
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::NamedTempFile;
+
+  use super::*;
+  use crate::fs::device::RamDisk;
+
+  ///Creates a host file of SECTORS sectors, with an MBR holding one partition over all but sector 0 if PARTITIONED
+  fn image(sectors: u32, partitioned: bool) -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    file.as_file().set_len((sectors * BLOCK_SECTOR_SIZE) as u64).unwrap();
+
+    if partitioned {
+      let mut mbr = vec![0u8; BLOCK_SECTOR_SIZE as usize];
+      mbr[450] = 0x83;
+      mbr[454..458].copy_from_slice(&1u32.to_le_bytes());
+      mbr[458..462].copy_from_slice(&(sectors - 1).to_le_bytes());
+      mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+      std::fs::write(file.path(), &mbr).unwrap();
+      file.as_file().set_len((sectors * BLOCK_SECTOR_SIZE) as u64).unwrap();
     }
+    file
+  }
+
+  ///Builds a module whose root file system is held in memory
+  fn setup_module() -> FSModule<'static> {
+    let block = Block::new("hd0".to_owned(), String::new(), 256, RamDisk::new(256));
+    FSModule::new(block, Some(InodeFormat::Indexed), CacheOptions::default()).unwrap()
+  }
+
+  fn write(name: &str, content: &str) -> FSSubcommands {
+    FSSubcommands::Write { name: name.to_owned(), content: Some(content.to_owned()), file: None }
+  }
+
+  fn read(module: &mut FSModule, path: &str, len: usize) -> Vec<u8> {
+    let (file_sys, path) = module.routed(path);
+    let mut buffer = vec![0u8; len];
+    file_sys.util_read(&path, &mut buffer, len as u32).unwrap();
+    buffer
+  }
+
+  #[test]
+  fn test_parse_size() {
+    assert_eq!(parse_size("8192"), Ok(16));
+    assert_eq!(parse_size("64K"), Ok(128));
+    assert_eq!(parse_size("2m"), Ok(4096));
+    assert!(parse_size("1K").is_err());
+    assert!(parse_size("4096G").is_err());
+    assert!(parse_size("18446744073709551615K").is_err());
+    assert!(parse_size("lots").is_err());
+  }
+
+  #[test]
+  fn test_route() {
+    let disk = image(256, false);
+    let mut module = setup_module();
+
+    module.mount(disk.path().to_str().unwrap(), "/mnt/", None, Some(InodeFormat::Indexed), CacheOptions::default()).unwrap();
+    assert_eq!(module.route("/mnt"), (1, "/".to_owned()));
+    assert_eq!(module.route("/mnt/a/b"), (1, "/a/b".to_owned()));
+    assert_eq!(module.route("/mntx/a"), (0, "/mntx/a".to_owned()));
+    assert_eq!(module.route("mnt/a"), (0, "mnt/a".to_owned()));
+
+    assert!(matches!(module.mount(disk.path().to_str().unwrap(), "/mnt", None, None, CacheOptions::default()), Err(FSErrors::MountError(MountError::AlreadyMounted(_)))));
+    assert!(matches!(module.unmount("/"), Err(FSErrors::MountError(MountError::InvalidMountPoint(_)))));
+    assert!(matches!(module.unmount("/nope"), Err(FSErrors::MountError(MountError::NotMounted(_)))));
+  }
+
+  #[test]
+  fn test_copy_routed() {
+    let disk = image(256, false);
+    let dir = tempfile::tempdir().unwrap();
+    let (source, copied) = (dir.path().join("source"), dir.path().join("copied"));
+    std::fs::write(&source, b"from host").unwrap();
+
+    let mut module = setup_module();
+    module.mount(disk.path().to_str().unwrap(), "/mnt", None, Some(InodeFormat::Indexed), CacheOptions::default()).unwrap();
+
+    let name = source.to_str().unwrap().to_owned();
+    module.exec_cmd(FSSubcommands::CopyIn { name, target: Some("/mnt/a".to_owned()) }).unwrap();
+    assert_eq!(read(&mut module, "/mnt/a", 9), b"from host");
+    assert!(module.mounts[0].file_sys.open("a").is_err());
+
+    let target = Some(copied.to_str().unwrap().to_owned());
+    module.exec_cmd(FSSubcommands::CopyOut { name: "/mnt/a".to_owned(), target }).unwrap();
+    assert!(std::fs::read(&copied).unwrap().starts_with(b"from host"));
+    module.unmount("/mnt").unwrap();
+  }
+
+  #[test]
+  fn test_mount_partition_and_remount() {
+    let disk = image(257, true);
+    let path = disk.path().to_str().unwrap();
+    let mut module = setup_module();
+
+    module.mount(path, "/mnt", None, Some(InodeFormat::Extent), CacheOptions::default()).unwrap();
+    assert_eq!(module.mounts[1].file_sys.block.get_size(), 256);
+
+    module.exec_cmd(FSSubcommands::Create { name: "/mnt/a".to_owned(), size: None, is_dir: false }).unwrap();
+    module.exec_cmd(write("/mnt/a", "mounted")).unwrap();
+    assert!(module.mounts[0].file_sys.open("a").is_err());
+    module.unmount("/mnt").unwrap();
+
+    //The partition table survived formatting, and the file survived unmounting
+    module.mount(path, "/mnt", Some(1), None, CacheOptions::default()).unwrap();
+    assert_eq!(module.mounts[1].file_sys.inode_format, InodeFormat::Extent);
+    assert_eq!(read(&mut module, "/mnt/a", 7), b"mounted");
+    assert!(matches!(module.mount(path, "/other", Some(2), None, CacheOptions::default()), Err(FSErrors::MountError(MountError::NoSuchPartition(2)))));
+    module.unmount("/mnt").unwrap();
   }
 }
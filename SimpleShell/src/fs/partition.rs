@@ -0,0 +1,215 @@
+use std::{
+  collections::BTreeSet,
+  rc::Rc
+};
+
+use bytemuck::{
+  from_bytes, Pod, Zeroable
+};
+
+use crate::fs::{
+  block::{
+    Block,
+    BlockDevice,
+    BlockSectorT,
+    HardwareOps,
+    BLOCK_SECTOR_SIZE
+  },
+  fserrors::block_errors::BlockError
+};
+
+const PARTITION_TABLE_SIGNATURE: u16 = 0xaa55;
+
+///DOS, Windows 98, Linux and DR-DOS extended partitions
+const EXTENDED_TYPES: [u8; 4] = [0x05, 0x0f, 0x85, 0xc5];
+
+///One of the four entries of a PARTITION TABLE
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct PartitionTableEntry {
+  bootable: u8,
+  start_chs: [u8; 3],
+  part_type: u8,
+  end_chs: [u8; 3],
+  ///First sector, relative to the PARTITION TABLE it is found in
+  offset: u32,
+  size: u32
+}
+
+///The MBR in sector 0, or an extended partition table
+///
+///Safety: the size of this struct must be exactly BLOCK_SECTOR_SIZE bytes in size
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct PartitionTable {
+  loader: [u8; 446],
+  partitions: [PartitionTableEntry; 4],
+  signature: u16
+}
+
+///A primary or logical partition of a BLOCK, numbered from 1 in the order found
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Partition {
+  pub number: u32,
+  pub part_type: u8,
+  pub start: BlockSectorT,
+  pub size: BlockSectorT
+}
+
+impl Partition {
+  ///Builds a BLOCK covering just this partition of DISK, named after DISK_NAME and the partition number
+  pub fn block<D: BlockDevice + 'static>(&self, disk_name: &str, file_name: &str, disk: Rc<D>) -> Block<'static> {
+    let name = format!("{}{}", disk_name, self.number);
+    Block::new(name, file_name.to_owned(), self.size, HardwareOps::from_disk(disk, self.start))
+  }
+}
+
+///Scans BLOCK for partitions, following extended partitions to the logical partitions inside them
+///
+///A BLOCK without a partition table has no partitions. Partitions reaching past the end of BLOCK are skipped,
+///but still take up a number
+pub fn scan(block: &Block) -> Result<Vec<Partition>, BlockError> {
+  let mut scanner = Scanner {
+    block,
+    partitions: Vec::new(),
+    part_nr: 0,
+    visited: BTreeSet::new()
+  };
+
+  scanner.read_table(0, 0)?;
+  Ok(scanner.partitions)
+}
+
+struct Scanner<'a, 'disk> {
+  block: &'a Block<'disk>,
+  partitions: Vec<Partition>,
+  ///Number of non-empty primary and logical partitions found so far
+  part_nr: u32,
+  ///PARTITION TABLEs already read, so a chain of extended partitions pointing back at itself ends
+  visited: BTreeSet<BlockSectorT>
+}
+
+impl Scanner<'_, '_> {
+  ///Reads the PARTITION TABLE at SECTOR, where PRIMARY_EXTENDED is the extended partition the MBR points at
+  fn read_table(&mut self, sector: BlockSectorT, primary_extended: BlockSectorT) -> Result<(), BlockError> {
+    if sector >= self.block.get_size() || !self.visited.insert(sector) {
+      return Ok(())
+    }
+
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    self.block.read_to_buffer(sector, &mut buffer)?;
+    let table = *from_bytes::<PartitionTable>(&buffer);
+
+    if { table.signature } != PARTITION_TABLE_SIGNATURE {
+      return Ok(())
+    }
+
+    for entry in table.partitions {
+      let (part_type, offset, size) = (entry.part_type, entry.offset, entry.size);
+
+      if size == 0 || part_type == 0 {
+        continue
+      }
+
+      //Offsets of extended partitions are absolute in the MBR, and relative to the extended partition
+      //the MBR points at everywhere else, however deeply nested
+      if EXTENDED_TYPES.contains(&part_type) {
+        match sector {
+          0 => self.read_table(offset, offset)?,
+          _ => self.read_table(offset.saturating_add(primary_extended), primary_extended)?
+        }
+        continue
+      }
+
+      self.part_nr += 1;
+      let start = offset.saturating_add(sector);
+
+      if start.checked_add(size).is_some_and(|end| end <= self.block.get_size()) {
+        self.partitions.push(Partition { number: self.part_nr, part_type, start, size });
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::mem::size_of;
+
+  use bytemuck::bytes_of;
+
+  use super::*;
+  use crate::fs::device::RamDisk;
+
+  const LINUX: u8 = 0x83;
+  const EXTENDED: u8 = 0x05;
+
+  fn entry(part_type: u8, offset: u32, size: u32) -> PartitionTableEntry {
+    PartitionTableEntry { part_type, offset, size, ..Zeroable::zeroed() }
+  }
+
+  fn table(entries: &[PartitionTableEntry]) -> PartitionTable {
+    let mut table = PartitionTable { signature: PARTITION_TABLE_SIGNATURE, ..Zeroable::zeroed() };
+    table.partitions[..entries.len()].copy_from_slice(entries);
+    table
+  }
+
+  ///Builds a disk of SECTORS sectors with each of TABLES written at its sector
+  fn image(sectors: u32, tables: &[(BlockSectorT, PartitionTable)]) -> Rc<RamDisk> {
+    let disk = Rc::new(RamDisk::new(sectors));
+    for (sector, table) in tables {
+      disk.write(*sector, bytes_of(table)).unwrap();
+    }
+    disk
+  }
+
+  fn whole_disk(disk: &Rc<RamDisk>) -> Block<'static> {
+    Block::new("hd0".to_owned(), String::new(), disk.size(), disk.clone())
+  }
+
+  #[test]
+  fn test_table_fills_sector() {
+    assert_eq!(size_of::<PartitionTable>(), BLOCK_SECTOR_SIZE as usize);
+  }
+
+  #[test]
+  fn test_no_partition_table() {
+    let disk = image(16, &[]);
+    assert!(scan(&whole_disk(&disk)).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_primary_and_logical_partitions() {
+    //Two primary partitions, then an extended one holding two logical partitions chained together
+    let mbr = table(&[entry(LINUX, 1, 99), entry(LINUX, 100, 100), entry(EXTENDED, 200, 300), entry(LINUX, 900, 200)]);
+    let first_logical = table(&[entry(LINUX, 1, 49), entry(EXTENDED, 50, 100)]);
+    let second_logical = table(&[entry(LINUX, 1, 99), entry(EXTENDED, 0, 300)]);
+    let disk = image(512, &[(0, mbr), (200, first_logical), (250, second_logical)]);
+
+    let partitions = scan(&whole_disk(&disk)).unwrap();
+    let found = partitions.iter().map(|p| (p.number, p.start, p.size)).collect::<Vec<_>>();
+
+    //The last primary partition runs past the end of the disk, but still took number 5
+    assert_eq!(found, vec![(1, 1, 99), (2, 100, 100), (3, 201, 49), (4, 251, 99)]);
+  }
+
+  #[test]
+  fn test_partition_block_is_offset() {
+    let mbr = table(&[entry(LINUX, 8, 8)]);
+    let disk = image(16, &[(0, mbr)]);
+
+    let partition = scan(&whole_disk(&disk)).unwrap().remove(0);
+    let block = partition.block("hd0", "", disk.clone());
+    assert_eq!(block.get_name(), "hd01");
+    assert_eq!(block.get_size(), 8);
+
+    let written = [7u8; BLOCK_SECTOR_SIZE as usize];
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    block.write_from_buffer(0, &written).unwrap();
+    disk.read(8, &mut buffer).unwrap();
+    assert_eq!(buffer, written);
+
+    //Nothing past the partition is reachable through it
+    assert!(block.read_to_buffer(8, &mut buffer).is_err());
+  }
+}
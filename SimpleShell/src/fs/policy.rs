@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+
+use crate::fs::block::BlockSectorT;
+
+///Which entry the CACHE evicts once every slot is filled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CachePolicy {
+  ///Least recently used
+  Lru,
+  ///Second chance, sweeping a hand over the slots
+  #[default]
+  Clock,
+  ///Sectors must be hit twice before they are kept for long, so a scan passes through
+  #[value(name = "2q")]
+  TwoQ,
+  ///Adaptive replacement, balancing recency and frequency by what it evicted too early
+  Arc
+}
+
+///Picks the slot to evict when the CACHE is full
+///
+///Slots are only handed to a POLICY filled, and each one is either inserted or removed before it is reused
+pub(crate) trait Policy {
+  ///Records a hit on SLOT
+  fn touch(&mut self, slot: usize);
+
+  ///Records that SLOT now holds SECTOR after a miss
+  fn insert(&mut self, slot: usize, sector: BlockSectorT);
+
+  ///Returns the slot to evict so that SECTOR can be cached, without evicting it yet
+  fn victim(&mut self, sector: BlockSectorT) -> usize;
+
+  ///Records that the entry in SLOT was evicted
+  fn remove(&mut self, slot: usize);
+}
+
+impl CachePolicy {
+  ///Builds an empty POLICY over CAPACITY slots
+  pub(crate) fn build(self, capacity: usize) -> Box<dyn Policy> {
+    match self {
+      Self::Lru => Box::new(Lru::new(capacity)),
+      Self::Clock => Box::new(Clock::new(capacity)),
+      Self::TwoQ => Box::new(TwoQ::new(capacity)),
+      Self::Arc => Box::new(Arc::new(capacity))
+    }
+  }
+}
+
+const NIL: usize = usize::MAX;
+
+///Slots from front to back, linked through arrays indexed by slot so that any one is unlinked in constant time
+struct SlotList {
+  prev: Vec<usize>,
+  next: Vec<usize>,
+  linked: Vec<bool>,
+  head: usize,
+  tail: usize,
+  len: usize
+}
+
+impl SlotList {
+  fn new(capacity: usize) -> Self {
+    Self {
+      prev: vec![NIL; capacity],
+      next: vec![NIL; capacity],
+      linked: vec![false; capacity],
+      head: NIL,
+      tail: NIL,
+      len: 0
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  fn back(&self) -> Option<usize> {
+    (self.tail != NIL).then_some(self.tail)
+  }
+
+  fn push_front(&mut self, slot: usize) {
+    self.prev[slot] = NIL;
+    self.next[slot] = self.head;
+    match self.head {
+      NIL => self.tail = slot,
+      head => self.prev[head] = slot
+    }
+
+    self.head = slot;
+    self.linked[slot] = true;
+    self.len += 1;
+  }
+
+  ///Removes SLOT, returning whether it was in the list
+  fn unlink(&mut self, slot: usize) -> bool {
+    if !self.linked[slot] {
+      return false
+    }
+
+    let (prev, next) = (self.prev[slot], self.next[slot]);
+    match prev {
+      NIL => self.head = next,
+      prev => self.next[prev] = next
+    }
+    match next {
+      NIL => self.tail = prev,
+      next => self.prev[next] = prev
+    }
+
+    self.linked[slot] = false;
+    self.len -= 1;
+    true
+  }
+}
+
+///Sectors evicted from a list, from most to least recently evicted, linked by sector so any one is forgotten in constant time
+#[derive(Default)]
+struct GhostList {
+  ///The previous and next sector of each one remembered
+  links: HashMap<BlockSectorT, (Option<BlockSectorT>, Option<BlockSectorT>)>,
+  head: Option<BlockSectorT>,
+  tail: Option<BlockSectorT>
+}
+
+impl GhostList {
+  fn len(&self) -> usize {
+    self.links.len()
+  }
+
+  fn contains(&self, sector: BlockSectorT) -> bool {
+    self.links.contains_key(&sector)
+  }
+
+  fn push_front(&mut self, sector: BlockSectorT) {
+    self.forget(sector);
+    self.links.insert(sector, (None, self.head));
+    match self.head {
+      Some(head) => self.links.get_mut(&head).expect("the head is remembered").0 = Some(sector),
+      None => self.tail = Some(sector)
+    }
+    self.head = Some(sector);
+  }
+
+  ///Removes SECTOR, returning whether it was remembered
+  fn forget(&mut self, sector: BlockSectorT) -> bool {
+    let Some((prev, next)) = self.links.remove(&sector) else {
+      return false
+    };
+
+    match prev {
+      Some(prev) => self.links.get_mut(&prev).expect("a linked sector is remembered").1 = next,
+      None => self.head = next
+    }
+    match next {
+      Some(next) => self.links.get_mut(&next).expect("a linked sector is remembered").0 = prev,
+      None => self.tail = prev
+    }
+    true
+  }
+
+  ///Forgets the least recently evicted sector, returning whether there was one
+  fn pop_back(&mut self) -> bool {
+    self.tail.is_some_and(|tail| self.forget(tail))
+  }
+
+  ///Forgets the least recently evicted sectors until at most LEN are left
+  fn truncate(&mut self, len: usize) {
+    while self.len() > len && self.pop_back() {}
+  }
+}
+
+///Slots from most to least recently used
+struct Lru {
+  order: SlotList
+}
+
+impl Lru {
+  fn new(capacity: usize) -> Self {
+    Self { order: SlotList::new(capacity) }
+  }
+}
+
+impl Policy for Lru {
+  fn touch(&mut self, slot: usize) {
+    self.order.unlink(slot);
+    self.order.push_front(slot);
+  }
+
+  fn insert(&mut self, slot: usize, _sector: BlockSectorT) {
+    self.order.push_front(slot);
+  }
+
+  fn victim(&mut self, _sector: BlockSectorT) -> usize {
+    self.order.back().expect("a full cache has a least recently used slot")
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.order.unlink(slot);
+  }
+}
+
+///A reference bit per slot, cleared as the HAND passes over it. Slots whose bit is already clear are evicted
+struct Clock {
+  referenced: Vec<bool>,
+  hand: usize
+}
+
+impl Clock {
+  fn new(capacity: usize) -> Self {
+    Self { referenced: vec![false; capacity], hand: 0 }
+  }
+}
+
+impl Policy for Clock {
+  fn touch(&mut self, slot: usize) {
+    self.referenced[slot] = true;
+  }
+
+  fn insert(&mut self, slot: usize, _sector: BlockSectorT) {
+    self.referenced[slot] = true;
+  }
+
+  fn victim(&mut self, _sector: BlockSectorT) -> usize {
+    loop {
+      let slot = self.hand;
+      self.hand = (self.hand + 1) % self.referenced.len();
+
+      match self.referenced[slot] {
+        true => self.referenced[slot] = false,
+        false => return slot
+      }
+    }
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.referenced[slot] = false;
+  }
+}
+
+///The full 2Q of Johnson and Shasha
+///
+///New sectors enter the FIFO A1IN. Those evicted from it are remembered in A1OUT, and only sectors missed again while
+///remembered enter the LRU AM
+struct TwoQ {
+  a1in: SlotList,
+  a1out: GhostList,
+  am: SlotList,
+  ///Sector held by each slot, for remembering it in A1OUT
+  sectors: Vec<BlockSectorT>,
+  kin: usize,
+  kout: usize
+}
+
+impl TwoQ {
+  fn new(capacity: usize) -> Self {
+    Self {
+      a1in: SlotList::new(capacity),
+      a1out: GhostList::default(),
+      am: SlotList::new(capacity),
+      sectors: vec![0; capacity],
+      kin: (capacity / 4).max(1),
+      kout: (capacity / 2).max(1)
+    }
+  }
+}
+
+impl Policy for TwoQ {
+  fn touch(&mut self, slot: usize) {
+    //Hits in A1IN are correlated references, which do not promote a sector
+    if self.am.unlink(slot) {
+      self.am.push_front(slot);
+    }
+  }
+
+  fn insert(&mut self, slot: usize, sector: BlockSectorT) {
+    self.sectors[slot] = sector;
+
+    match self.a1out.forget(sector) {
+      true => self.am.push_front(slot),
+      false => self.a1in.push_front(slot)
+    }
+  }
+
+  fn victim(&mut self, _sector: BlockSectorT) -> usize {
+    match (self.a1in.back(), self.am.back()) {
+      (Some(slot), _) if self.a1in.len() > self.kin => slot,
+      (_, Some(slot)) => slot,
+      (Some(slot), None) => slot,
+      (None, None) => panic!("a full cache has a slot in 2Q")
+    }
+  }
+
+  fn remove(&mut self, slot: usize) {
+    if self.a1in.unlink(slot) {
+      self.a1out.push_front(self.sectors[slot]);
+      self.a1out.truncate(self.kout);
+    } else {
+      self.am.unlink(slot);
+    }
+  }
+}
+
+///ARC of Megiddo and Modha
+///
+///T1 holds sectors hit once and T2 those hit again, both from most to least recently used. B1 and B2 remember the
+///sectors evicted from each, and a miss on one of them moves the TARGET size of T1 towards the list that would have kept it
+struct Arc {
+  t1: SlotList,
+  t2: SlotList,
+  b1: GhostList,
+  b2: GhostList,
+  sectors: Vec<BlockSectorT>,
+  target: usize,
+  capacity: usize
+}
+
+impl Arc {
+  fn new(capacity: usize) -> Self {
+    Self {
+      t1: SlotList::new(capacity),
+      t2: SlotList::new(capacity),
+      b1: GhostList::default(),
+      b2: GhostList::default(),
+      sectors: vec![0; capacity],
+      target: 0,
+      capacity
+    }
+  }
+}
+
+impl Policy for Arc {
+  fn touch(&mut self, slot: usize) {
+    if !self.t1.unlink(slot) {
+      self.t2.unlink(slot);
+    }
+    self.t2.push_front(slot);
+  }
+
+  fn insert(&mut self, slot: usize, sector: BlockSectorT) {
+    self.sectors[slot] = sector;
+
+    match self.b1.forget(sector) || self.b2.forget(sector) {
+      true => self.t2.push_front(slot),
+      false => self.t1.push_front(slot)
+    }
+
+    //T1 and B1 together never remember more than the cache holds, nor all four lists twice that
+    while self.t1.len() + self.b1.len() > self.capacity && self.b1.pop_back() {}
+    while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity {
+      if !self.b2.pop_back() && !self.b1.pop_back() {
+        break
+      }
+    }
+  }
+
+  fn victim(&mut self, sector: BlockSectorT) -> usize {
+    let in_b1 = self.b1.contains(sector);
+    let in_b2 = self.b2.contains(sector);
+
+    //The smaller ghost list is the stronger evidence, so it moves the target further
+    if in_b1 {
+      let delta = (self.b2.len() / self.b1.len()).max(1);
+      self.target = (self.target + delta).min(self.capacity);
+    } else if in_b2 {
+      let delta = (self.b1.len() / self.b2.len()).max(1);
+      self.target = self.target.saturating_sub(delta);
+    }
+
+    let from_t1 = !self.t1.is_empty() && (self.t1.len() > self.target || (in_b2 && self.t1.len() == self.target));
+    match (from_t1, self.t1.back(), self.t2.back()) {
+      (true, Some(slot), _) => slot,
+      (_, _, Some(slot)) => slot,
+      (_, Some(slot), None) => slot,
+      (_, None, None) => panic!("a full cache has a slot in ARC")
+    }
+  }
+
+  fn remove(&mut self, slot: usize) {
+    if self.t1.unlink(slot) {
+      self.b1.push_front(self.sectors[slot]);
+    } else if self.t2.unlink(slot) {
+      self.b2.push_front(self.sectors[slot]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  ///Runs the reference string SECTORS through POLICY over CAPACITY slots, returning the number of hits
+  fn hits(policy: CachePolicy, capacity: usize, sectors: impl IntoIterator<Item = BlockSectorT>) -> u32 {
+    let mut policy = policy.build(capacity);
+    let mut slots: Vec<BlockSectorT> = Vec::new();
+    let mut hits = 0u32;
+
+    for sector in sectors {
+      match slots.iter().position(|&cached| cached == sector) {
+        Some(slot) => {
+          policy.touch(slot);
+          hits += 1;
+        },
+        None if slots.len() < capacity => {
+          slots.push(sector);
+          policy.insert(slots.len() - 1, sector);
+        },
+        None => {
+          let slot = policy.victim(sector);
+          policy.remove(slot);
+          slots[slot] = sector;
+          policy.insert(slot, sector);
+        }
+      }
+    }
+    hits
+  }
+
+  #[test]
+  fn test_lru_and_clock() {
+    //A loop one larger than the cache misses every time under LRU
+    let looped = (0..5).flat_map(|_| 0..5);
+    assert_eq!(hits(CachePolicy::Lru, 4, looped), 0);
+
+    //Sector 1 is the least recently used, and the only one not hit since the hand last passed it
+    assert_eq!(hits(CachePolicy::Lru, 3, [0, 1, 2, 3, 2, 4, 2, 3]), 3);
+    assert_eq!(hits(CachePolicy::Clock, 3, [0, 1, 2, 3, 2, 4, 2, 3]), 3);
+  }
+
+  #[test]
+  fn test_scan_resistance() {
+    //A hot set hit repeatedly, then again between scans of sectors never used again, each too long for LRU to keep it
+    let scans = (0..20u32).flat_map(|round| (0..4).chain(100 + round * 6..106 + round * 6));
+    let workload = || (0..10).flat_map(|_| 0..4).chain(scans.clone());
+
+    let lru = hits(CachePolicy::Lru, 8, workload());
+    let two_q = hits(CachePolicy::TwoQ, 8, workload());
+    let arc = hits(CachePolicy::Arc, 8, workload());
+
+    assert!(two_q > lru, "2Q {} against LRU {}", two_q, lru);
+    assert!(arc > lru, "ARC {} against LRU {}", arc, lru);
+  }
+
+  #[test]
+  fn test_every_slot_reused() {
+    //Sectors leave and come back in every list, which must never run out of victims
+    for policy in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::TwoQ, CachePolicy::Arc] {
+      let sectors = (0..500u32).map(|i| (i * i) % 13 + (i / 50) * 3);
+      assert!(hits(policy, 6, sectors) > 0, "{:?}", policy);
+    }
+  }
+
+  #[test]
+  fn test_lists_unlink_anywhere() {
+    let mut slots = SlotList::new(4);
+    (0..4).for_each(|slot| slots.push_front(slot));
+    assert!(slots.unlink(0) && slots.unlink(2) && !slots.unlink(2));
+    assert_eq!((slots.len(), slots.back()), (2, Some(1)));
+    assert!(slots.unlink(1) && slots.unlink(3));
+    assert_eq!(slots.back(), None);
+
+    let mut ghosts = GhostList::default();
+    (10..14).for_each(|sector| ghosts.push_front(sector));
+    assert!(ghosts.forget(12) && ghosts.forget(10) && !ghosts.contains(10));
+    ghosts.truncate(1);
+    assert!(ghosts.contains(13) && ghosts.len() == 1);
+  }
+}
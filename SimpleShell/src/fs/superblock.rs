@@ -0,0 +1,299 @@
+use std::mem::size_of;
+
+use bytemuck::{
+  bytes_of, from_bytes, Pod, Zeroable
+};
+
+use crate::fs::{
+  block::{
+    Block,
+    BlockSectorT,
+    BLOCK_SECTOR_SIZE
+  },
+  directory::NAME_MAX,
+  file_sys::{
+    FREE_MAP_SECTOR,
+    ROOT_DIR_SECTOR
+  },
+  fserrors::{
+    block_errors::BlockError,
+    superblock_errors::SuperblockError
+  },
+  inode::{
+    InodeFormat,
+    DIRECT_BLOCKS_CNT
+  },
+  iostat::Origin,
+  journal::{
+    JOURNAL_SECTOR,
+    JOURNAL_SIZE,
+    MAX_JOURNAL_SIZE
+  }
+};
+
+pub const SUPERBLOCK_SECTOR: BlockSectorT = 2u32;
+const SUPERBLOCK_MAGIC: u32 = 0x53555042;
+const SUPERBLOCK_VERSION: u32 = 1u32;
+
+pub const DEFAULT_CACHE_SIZE: u32 = 64u32;
+const CACHE_SIZE_RANGE: (u32, u32) = (8, 4096);
+///Entries the root directory holds before it first grows
+pub const DEFAULT_ROOT_CAPACITY: u32 = 14u32;
+const ROOT_CAPACITY_RANGE: (u32, u32) = (1, 1 << 16);
+
+///How to lay out a newly formatted FILESYSTEM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FormatOptions {
+  ///Number of sectors the CACHE holds
+  pub cache_size: u32,
+  ///Number of entries the root directory has room for before it grows
+  pub root_capacity: u32,
+  ///Format of every new INODE
+  pub inode_format: InodeFormat,
+  ///Number of sectors in the journal region, 0 for none. Scaled to the device if not given
+  pub journal_size: Option<u32>
+}
+
+impl Default for FormatOptions {
+  fn default() -> Self {
+    Self {
+      cache_size: DEFAULT_CACHE_SIZE,
+      root_capacity: DEFAULT_ROOT_CAPACITY,
+      inode_format: InodeFormat::default(),
+      journal_size: None
+    }
+  }
+}
+
+///New INODEs map their data in extents, which older versions can not read
+pub const FEATURE_EXTENTS: u32 = 1u32 << 0;
+///Metadata goes through the journal, which older versions would not replay
+pub const FEATURE_JOURNAL: u32 = 1u32 << 1;
+///Incompatible features this version knows. Any compatible feature may be ignored
+const FEATURES_INCOMPAT: u32 = FEATURE_EXTENTS | FEATURE_JOURNAL;
+
+///Sector SUPERBLOCK_SECTOR of a formatted device, describing its layout and the options it was formatted with
+///
+///Safety: the size of this struct must be exactly BLOCK_SECTOR_SIZE bytes in size
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub(crate) struct Superblock {
+  magic: u32,
+  pub version: u32,
+  pub sector_size: u32,
+  pub sector_cnt: u32,
+  pub freemap_sector: BlockSectorT,
+  pub root_dir_sector: BlockSectorT,
+  pub journal_sector: BlockSectorT,
+  pub journal_size: u32,
+  pub direct_blocks: u32,
+  pub name_max: u32,
+  pub cache_size: u32,
+  pub root_capacity: u32,
+  ///0 for InodeFormat::Indexed, 1 for InodeFormat::Extent
+  inode_format: u32,
+  pub features_compat: u32,
+  pub features_incompat: u32,
+  _padding: [u8; BLOCK_SECTOR_SIZE as usize - 15 * size_of::<u32>()]
+}
+
+impl Superblock {
+  ///Describes a device of SECTOR_CNT sectors formatted with OPTIONS, checking that each one is in range
+  ///
+  ///A journal size that was not given is scaled down on small devices
+  pub fn new(sector_cnt: u32, options: &FormatOptions) -> Result<Self, SuperblockError> {
+    let journal_size = options.journal_size.unwrap_or(match (sector_cnt / 16).min(JOURNAL_SIZE) {
+      0 | 1 => 0,
+      size => size
+    });
+
+    let mut features_incompat = 0u32;
+    if options.inode_format == InodeFormat::Extent {
+      features_incompat |= FEATURE_EXTENTS;
+    }
+    if journal_size > 0 {
+      features_incompat |= FEATURE_JOURNAL;
+    }
+
+    let superblock = Self {
+      magic: SUPERBLOCK_MAGIC,
+      version: SUPERBLOCK_VERSION,
+      sector_size: BLOCK_SECTOR_SIZE,
+      sector_cnt,
+      freemap_sector: FREE_MAP_SECTOR,
+      root_dir_sector: ROOT_DIR_SECTOR,
+      journal_sector: JOURNAL_SECTOR,
+      journal_size,
+      direct_blocks: DIRECT_BLOCKS_CNT,
+      name_max: NAME_MAX as u32,
+      cache_size: options.cache_size,
+      root_capacity: options.root_capacity,
+      inode_format: match options.inode_format {
+        InodeFormat::Indexed => 0,
+        InodeFormat::Extent => 1
+      },
+      features_compat: 0,
+      features_incompat,
+      ..Zeroable::zeroed()
+    };
+
+    superblock.check_options()?;
+    Ok(superblock)
+  }
+
+  ///Reads the SUPERBLOCK of BLOCK, checking that this version can mount the device it describes
+  pub fn read(block: &Block) -> Result<Self, SuperblockError> {
+    let mut buffer = [0u8; BLOCK_SECTOR_SIZE as usize];
+    block.tagged(Origin::Superblock, || block.read_to_buffer(SUPERBLOCK_SECTOR, &mut buffer))?;
+    let superblock = *from_bytes::<Self>(&buffer);
+
+    if superblock.magic != SUPERBLOCK_MAGIC {
+      return Err(SuperblockError::NotFormatted)
+    }
+    if superblock.version > SUPERBLOCK_VERSION {
+      return Err(SuperblockError::UnsupportedVersion(superblock.version, SUPERBLOCK_VERSION))
+    }
+    if superblock.sector_cnt != block.get_size() {
+      return Err(SuperblockError::GeometryMismatch(superblock.sector_cnt, block.get_size()))
+    }
+
+    let layout = [
+      ("sector size", superblock.sector_size, BLOCK_SECTOR_SIZE),
+      ("freemap sector", superblock.freemap_sector, FREE_MAP_SECTOR),
+      ("root directory sector", superblock.root_dir_sector, ROOT_DIR_SECTOR),
+      ("journal sector", superblock.journal_sector, JOURNAL_SECTOR),
+      ("direct blocks per inode", superblock.direct_blocks, DIRECT_BLOCKS_CNT),
+      ("maximum name length", superblock.name_max, NAME_MAX as u32)
+    ];
+
+    if let Some(&(name, found, expected)) = layout.iter().find(|(_, found, expected)| found != expected) {
+      return Err(SuperblockError::LayoutMismatch(name, found, expected))
+    }
+
+    let unsupported = superblock.features_incompat & !FEATURES_INCOMPAT;
+    if unsupported != 0 {
+      return Err(SuperblockError::UnsupportedFeatures(unsupported))
+    }
+
+    superblock.check_options()?;
+    Ok(superblock)
+  }
+
+  pub fn write(&self, block: &Block) -> Result<(), BlockError> {
+    block.tagged(Origin::Superblock, || block.write_from_buffer(SUPERBLOCK_SECTOR, bytes_of(self)))
+  }
+
+  pub fn inode_format(&self) -> InodeFormat {
+    match self.inode_format {
+      0 => InodeFormat::Indexed,
+      _ => InodeFormat::Extent
+    }
+  }
+
+  ///Returns whether every feature in FEATURES is in use
+  pub fn has_features(&self, features: u32) -> bool {
+    (self.features_compat | self.features_incompat) & features == features
+  }
+
+  ///Checks that the format options lie in the ranges this version supports
+  fn check_options(&self) -> Result<(), SuperblockError> {
+    check_cache_size(self.cache_size)?;
+    check_range("root directory capacity", self.root_capacity, ROOT_CAPACITY_RANGE)?;
+    check_range("inode format", self.inode_format, (0, 1))?;
+
+    if self.journal_size != 0 {
+      check_range("journal size", self.journal_size, (2, MAX_JOURNAL_SIZE.min(self.sector_cnt / 4).max(2)))?;
+    }
+    Ok(())
+  }
+}
+
+///Checks that a CACHE of SIZE sectors is in the range this version supports
+pub fn check_cache_size(size: u32) -> Result<(), SuperblockError> {
+  check_range("cache size", size, CACHE_SIZE_RANGE)
+}
+
+fn check_range(name: &'static str, value: u32, (min, max): (u32, u32)) -> Result<(), SuperblockError> {
+  match (min..=max).contains(&value) {
+    true => Ok(()),
+    false => Err(SuperblockError::InvalidOption(name, value, (min, max)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fs::{
+    cache::CacheOptions,
+    file_sys::{
+      tests::setup_file_sys,
+      FileSystem
+    },
+    fserrors::FSErrors
+  };
+
+  #[test]
+  fn test_superblock_fills_sector() {
+    assert_eq!(size_of::<Superblock>(), BLOCK_SECTOR_SIZE as usize);
+  }
+
+  #[test]
+  fn test_options_checked() {
+    let superblock = Superblock::new(4096, &FormatOptions::default()).unwrap();
+    assert_eq!(superblock.journal_size, 64);
+    assert_eq!(Superblock::new(256, &FormatOptions::default()).unwrap().journal_size, 16);
+
+    let options = |cache_size, journal_size| FormatOptions { cache_size, journal_size, ..FormatOptions::default() };
+    assert!(Superblock::new(4096, &options(4, None)).is_err());
+    assert!(Superblock::new(4096, &options(64, Some(1))).is_err());
+    assert!(Superblock::new(4096, &options(64, Some(MAX_JOURNAL_SIZE + 1))).is_err());
+    assert_eq!(Superblock::new(4096, &options(64, Some(0))).unwrap().journal_size, 0);
+  }
+
+  ///Formats a FILESYSTEM of SECTORS sectors, passing its SUPERBLOCK to CHANGE before mounting it again
+  fn remount_with(sectors: u32, change: impl FnOnce(&mut Superblock)) -> Result<FileSystem<'static>, FSErrors> {
+    let mut file_sys = setup_file_sys(sectors);
+    file_sys.close().unwrap();
+    let mut superblock = Superblock::read(&file_sys.block).unwrap();
+    change(&mut superblock);
+    superblock.write(&file_sys.block).unwrap();
+
+    let FileSystem { block, .. } = file_sys;
+    FileSystem::new(block, None, CacheOptions::default())
+  }
+
+  fn superblock_error(result: Result<FileSystem<'static>, FSErrors>) -> SuperblockError {
+    match result {
+      Err(FSErrors::SuperblockError(e)) => e,
+      Err(e) => panic!("unexpected error: {}", e),
+      Ok(_) => panic!("mounted an incompatible image")
+    }
+  }
+
+  #[test]
+  fn test_incompatible_images_rejected() {
+    let e = superblock_error(remount_with(256, |superblock| superblock.magic = 0));
+    assert!(matches!(e, SuperblockError::NotFormatted));
+
+    let e = superblock_error(remount_with(256, |superblock| superblock.version = SUPERBLOCK_VERSION + 1));
+    assert!(matches!(e, SuperblockError::UnsupportedVersion(2, 1)));
+
+    let e = superblock_error(remount_with(256, |superblock| superblock.sector_cnt = 512));
+    assert!(matches!(e, SuperblockError::GeometryMismatch(512, 256)));
+
+    let e = superblock_error(remount_with(256, |superblock| superblock.name_max += 1));
+    assert!(matches!(e, SuperblockError::LayoutMismatch("maximum name length", _, _)));
+
+    let e = superblock_error(remount_with(256, |superblock| superblock.features_incompat |= 1 << 7));
+    assert!(matches!(e, SuperblockError::UnsupportedFeatures(0x80)));
+  }
+
+  #[test]
+  fn test_compatible_features_ignored() {
+    let mut file_sys = remount_with(256, |superblock| superblock.features_compat |= 1 << 7).unwrap();
+    let superblock = Superblock::read(&file_sys.block).unwrap();
+    assert!(superblock.has_features(FEATURE_JOURNAL | 1 << 7));
+    assert!(!superblock.has_features(FEATURE_EXTENTS));
+    file_sys.create("a", 0, false).unwrap();
+  }
+}